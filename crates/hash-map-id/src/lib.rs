@@ -35,6 +35,14 @@ where
     pub fn get(&self, id: u64) -> Option<&T> {
         self.store.get(&id)
     }
+
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
 }
 
 impl<T> Default for HashMapId<T>
@@ -95,6 +103,19 @@ mod tests {
         assert!(item.is_none());
     }
 
+    // len
+
+    #[test]
+    fn len_counts_stored_items() {
+        let mut hash: HashMapId<i32> = HashMapId::new();
+        assert!(hash.is_empty());
+        let id = hash.add(10);
+        hash.add(20);
+        assert_eq!(hash.len(), 2);
+        hash.remove(id);
+        assert_eq!(hash.len(), 1);
+    }
+
     // add
 
     #[test]
@@ -130,7 +151,7 @@ mod tests {
     fn add_panics_on_integer_overflow() {
         let mut hash: HashMapId<i32> = HashMapId::new();
         let item = 10;
-        hash.id_seed = std::u64::MAX;
+        hash.id_seed = u64::MAX;
         hash.add(item);
     }
 
@@ -5,6 +5,7 @@ use axum::{
     Extension, Json, Router,
     body::Bytes,
     extract::{DefaultBodyLimit, Query},
+    http::StatusCode,
     routing::{get, post},
 };
 use lunatic_control::{NodeInfo, api::*};
@@ -185,3 +186,24 @@ pub fn init_routes() -> Router {
         .layer(DefaultBodyLimit::disable())
         .layer(RequestBodyLimitLayer::new(50 * 1024 * 1024)) // 50 mb
 }
+
+/// Liveness probe, answers 200 without touching any server state
+pub async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness probe, answers 200 once the server is bound and 503 while it shuts down
+pub async fn readyz(control: Extension<Arc<ControlServer>>) -> StatusCode {
+    if control.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Routes for load balancer health checks, kept outside of the body limits of the API
+pub fn health_routes() -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
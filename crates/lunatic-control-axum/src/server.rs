@@ -1,9 +1,10 @@
 use std::{
     collections::HashMap,
+    future::Future,
     net::SocketAddr,
     sync::{
         Arc,
-        atomic::{self, AtomicU64},
+        atomic::{self, AtomicBool, AtomicU64},
     },
 };
 
@@ -28,6 +29,7 @@ pub struct ControlServer {
     next_registration_id: AtomicU64,
     next_node_id: AtomicU64,
     next_module_id: AtomicU64,
+    ready: AtomicBool,
 }
 
 #[derive(Clone)]
@@ -63,9 +65,19 @@ impl ControlServer {
             next_registration_id: AtomicU64::new(1),
             next_node_id: AtomicU64::new(1),
             next_module_id: AtomicU64::new(1),
+            ready: AtomicBool::new(false),
         }
     }
 
+    /// Returns `true` while the server is bound and not shutting down, see `/readyz`.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, atomic::Ordering::Relaxed);
+    }
+
     pub fn register(&self, reg: &Register, cert_pem: &str, authentication_token: &str) {
         let id = self
             .next_registration_id
@@ -107,7 +119,7 @@ impl ControlServer {
     }
 }
 
-fn prepare_app() -> Result<Router> {
+fn prepare_app() -> Result<(Router, Arc<ControlServer>)> {
     let ca_cert_str = lunatic_distributed::distributed::server::test_root_cert();
     let (ca_cert, ca_key_pair) = lunatic_distributed::control::cert::test_root_cert()?;
     let (ctrl_cert, ctrl_pk) =
@@ -116,9 +128,10 @@ fn prepare_app() -> Result<Router> {
         lunatic_distributed::quic::new_quic_client(&ca_cert_str, &ctrl_cert, &ctrl_pk)?;
     let control = Arc::new(ControlServer::new(ca_cert, ca_key_pair, quic_client));
     let app = Router::new()
-        .nest("/", routes::init_routes())
-        .layer(Extension(control));
-    Ok(app)
+        .merge(routes::init_routes())
+        .merge(routes::health_routes())
+        .layer(Extension(control.clone()));
+    Ok((app, control))
 }
 
 pub async fn control_server(http_socket: SocketAddr) -> Result<()> {
@@ -127,8 +140,32 @@ pub async fn control_server(http_socket: SocketAddr) -> Result<()> {
 }
 
 pub async fn control_server_from_tcp(listener: TcpListener) -> Result<()> {
-    let app = prepare_app()?;
+    let (app, control) = prepare_app()?;
+    control.set_ready(true);
 
     axum::serve(listener, app.into_make_service()).await?;
     Ok(())
 }
+
+/// Serve the control API on `listener` until the `shutdown` future resolves,
+/// then stop accepting connections and wait for in-flight requests to finish.
+///
+/// `/readyz` reports the server as unavailable from the moment `shutdown` resolves.
+pub async fn control_server_from_tcp_with_shutdown<F>(
+    listener: TcpListener,
+    shutdown: F,
+) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (app, control) = prepare_app()?;
+    control.set_ready(true);
+
+    axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            control.set_ready(false);
+        })
+        .await?;
+    Ok(())
+}
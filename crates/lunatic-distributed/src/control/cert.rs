@@ -1,7 +1,10 @@
 use std::path::Path;
+use std::sync::{Arc, RwLock};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use rcgen::*;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
 
 pub static TEST_ROOT_CERT: &str = r#"""
 -----BEGIN CERTIFICATE-----
@@ -61,3 +64,205 @@ pub fn default_server_certificates(
     let key_pem = ctrl_key_pair.serialize_pem();
     Ok((cert_pem, key_pem))
 }
+
+/// Issues a client certificate for `node_id`, signed by the root CA.
+///
+/// The node id is used as both the common name and the subject alternative name, so the
+/// control server can identify the node when it authenticates over mutual TLS. Returns the
+/// PEM encoded certificate and private key.
+pub fn client_certificate(
+    root_cert: &Certificate,
+    root_key_pair: &KeyPair,
+    node_id: &str,
+) -> Result<(String, String)> {
+    let mut client_params = CertificateParams::new(vec![node_id.into()])?;
+    client_params
+        .distinguished_name
+        .push(DnType::OrganizationName, "Lunatic Inc.");
+    client_params
+        .distinguished_name
+        .push(DnType::CommonName, node_id);
+    client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client_key_pair = KeyPair::generate()?;
+    let cert = client_params.signed_by(&client_key_pair, root_cert, root_key_pair)?;
+    Ok((cert.pem(), client_key_pair.serialize_pem()))
+}
+
+/// TLS server certificate resolver that allows swapping the certificate at runtime.
+///
+/// Every handshake resolves the most recently installed certificate, so calling
+/// [`ReloadableServerCert::reload`] rotates the certificate without restarting the
+/// server. Already established connections keep their negotiated certificate.
+#[derive(Debug)]
+pub struct ReloadableServerCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableServerCert {
+    /// Creates a resolver serving the given PEM encoded certificate chain and private key.
+    pub fn new(cert_pem: &str, key_pem: &str) -> Result<Self> {
+        Ok(Self {
+            current: RwLock::new(Arc::new(certified_key(cert_pem, key_pem)?)),
+        })
+    }
+
+    /// Creates a resolver serving a fresh control server certificate signed by the root CA.
+    pub fn from_root(root_cert: &Certificate, root_key_pair: &KeyPair) -> Result<Self> {
+        let (cert_pem, key_pem) = default_server_certificates(root_cert, root_key_pair)?;
+        Self::new(&cert_pem, &key_pem)
+    }
+
+    /// Replaces the served certificate. The next handshake will use the new one.
+    pub fn reload(&self, cert_pem: &str, key_pem: &str) -> Result<()> {
+        let key = Arc::new(certified_key(cert_pem, key_pem)?);
+        *self
+            .current
+            .write()
+            .map_err(|_| anyhow!("Server certificate lock poisoned"))? = key;
+        Ok(())
+    }
+
+    /// Reloads the root CA from files and serves a fresh control server certificate
+    /// signed by it.
+    pub fn reload_from_root_files(&self, ca_cert: &str, ca_keys: &str) -> Result<()> {
+        let (root_cert, root_key_pair) = root_cert(ca_cert, ca_keys)?;
+        let (cert_pem, key_pem) = default_server_certificates(&root_cert, &root_key_pair)?;
+        self.reload(&cert_pem, &key_pem)
+    }
+}
+
+impl ResolvesServerCert for ReloadableServerCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|key| Arc::clone(&key))
+    }
+}
+
+fn certified_key(cert_pem: &str, key_pem: &str) -> Result<CertifiedKey> {
+    let cert_chain = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("Failed to parse certificate: {e}"))?;
+    if cert_chain.is_empty() {
+        return Err(anyhow!("No certificate found in PEM data"));
+    }
+    let key = rustls_pemfile::private_key(&mut key_pem.as_bytes())?
+        .ok_or_else(|| anyhow!("No private key found in PEM data"))?;
+    Ok(CertifiedKey::from_der(
+        cert_chain,
+        key,
+        &rustls::crypto::ring::default_provider(),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::CertificateDer;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+
+    use super::*;
+
+    /// Performs an in-memory TLS handshake against `resolver` and returns the leaf
+    /// certificate presented by the server.
+    fn negotiated_leaf(resolver: Arc<ReloadableServerCert>) -> CertificateDer<'static> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut TEST_ROOT_CERT.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut client = ClientConnection::new(
+            Arc::new(client_config),
+            CTRL_SERVER_NAME.try_into().unwrap(),
+        )
+        .unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                break;
+            }
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets().unwrap();
+
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        assert!(!client.is_handshaking());
+
+        client.peer_certificates().unwrap()[0].clone().into_owned()
+    }
+
+    fn leaf_der(cert_pem: &str) -> CertificateDer<'static> {
+        rustls_pemfile::certs(&mut cert_pem.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn reload_is_used_by_next_handshake() {
+        let (root_cert, root_key_pair) = test_root_cert().unwrap();
+        let (first_cert, first_key) =
+            default_server_certificates(&root_cert, &root_key_pair).unwrap();
+        let (second_cert, second_key) =
+            default_server_certificates(&root_cert, &root_key_pair).unwrap();
+
+        let resolver = Arc::new(ReloadableServerCert::new(&first_cert, &first_key).unwrap());
+        assert_eq!(negotiated_leaf(resolver.clone()), leaf_der(&first_cert));
+
+        resolver.reload(&second_cert, &second_key).unwrap();
+        assert_eq!(negotiated_leaf(resolver), leaf_der(&second_cert));
+    }
+
+    #[test]
+    fn client_certificate_chains_to_test_root() {
+        use rustls::pki_types::UnixTime;
+        use rustls::server::WebPkiClientVerifier;
+
+        let (root_cert, root_key_pair) = test_root_cert().unwrap();
+        let (cert_pem, key_pem) =
+            client_certificate(&root_cert, &root_key_pair, "node-1234").unwrap();
+        assert!(key_pem.contains("PRIVATE KEY"));
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut TEST_ROOT_CERT.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::new(rustls::crypto::ring::default_provider()),
+        )
+        .build()
+        .unwrap();
+        verifier
+            .verify_client_cert(&leaf_der(&cert_pem), &[], UnixTime::now())
+            .unwrap();
+
+        let params = CertificateParams::from_ca_cert_pem(&cert_pem).unwrap();
+        assert_eq!(
+            params.distinguished_name.get(&DnType::CommonName),
+            Some(&DnValue::Utf8String("node-1234".into()))
+        );
+    }
+
+    #[test]
+    fn reload_rejects_invalid_pem() {
+        let (root_cert, root_key_pair) = test_root_cert().unwrap();
+        let resolver = ReloadableServerCert::from_root(&root_cert, &root_key_pair).unwrap();
+        assert!(resolver.reload("not a cert", "not a key").is_err());
+    }
+}
@@ -82,6 +82,12 @@ fn read_single_private_key(pem_bytes: &[u8]) -> Result<PrivateKeyDer<'static>> {
     Ok(key)
 }
 
+// rustls is built with more than one crypto backend, so there is no process-wide default
+// provider to fall back on.
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
 pub fn new_quic_client(ca_cert: &str, cert: &str, key: &str) -> Result<Client> {
     let ca_cert_der = read_single_cert_der(ca_cert.as_bytes())?;
     let mut roots = rustls::RootCertStore::empty();
@@ -91,7 +97,8 @@ pub fn new_quic_client(ca_cert: &str, cert: &str, key: &str) -> Result<Client> {
     let cert_der = read_single_cert_der(cert.as_bytes())?;
     let cert_chain = vec![cert_der];
 
-    let client_crypto = rustls::ClientConfig::builder()
+    let client_crypto = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
         .with_root_certificates(roots)
         .with_client_auth_cert(cert_chain, pk)?;
 
@@ -124,9 +131,11 @@ pub fn new_quic_server(
         cert_chain.push(cert_der);
     }
 
-    let client_verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+    let client_verifier =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider()).build()?;
 
-    let server_crypto = rustls::ServerConfig::builder()
+    let server_crypto = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
         .with_client_cert_verifier(client_verifier)
         .with_single_cert(cert_chain, pk)?;
     let quinn_server_config = quinn::crypto::rustls::QuicServerConfig::try_from(server_crypto)?;
@@ -15,8 +15,9 @@ use wasmtime::{Caller, Linker};
 
 use lunatic_process::{
     Signal,
+    config::ProcessConfig,
     message::{DataMessage, Message},
-    state::ProcessState,
+    state::{ProcessState, SendDecision},
 };
 
 // Register the mailbox APIs to the linker
@@ -36,7 +37,7 @@ pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static
     linker.func_wrap("lunatic::message", "take_tcp_stream", take_tcp_stream)?;
     linker.func_wrap("lunatic::message", "push_tls_stream", push_tls_stream)?;
     linker.func_wrap("lunatic::message", "take_tls_stream", take_tls_stream)?;
-    linker.func_wrap("lunatic::message", "send", send)?;
+    linker.func_wrap_async("lunatic::message", "send", send)?;
     linker.func_wrap_async(
         "lunatic::message",
         "send_receive_skip_search",
@@ -45,6 +46,8 @@ pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static
     linker.func_wrap_async("lunatic::message", "receive", receive)?;
     linker.func_wrap("lunatic::message", "push_udp_socket", push_udp_socket)?;
     linker.func_wrap("lunatic::message", "take_udp_socket", take_udp_socket)?;
+    linker.func_wrap("lunatic::message", "mailbox_depth", mailbox_depth)?;
+    linker.func_wrap("lunatic::message", "mailbox_bytes", mailbox_bytes)?;
 
     Ok(())
 }
@@ -125,6 +128,9 @@ pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static
 // Arguments:
 // * tag - An identifier that can be used for selective receives. If value is 0, no tag is used.
 // * buffer_capacity - A hint to the message to pre-allocate a large enough buffer for writes.
+//
+// The message can carry at most as many resources of each kind as the process configuration's
+// message resource limits allow.
 fn create_data<T: ProcessState + ProcessCtx<T>>(
     mut caller: Caller<T>,
     tag: i64,
@@ -134,7 +140,8 @@ fn create_data<T: ProcessState + ProcessCtx<T>>(
         0 => None,
         tag => Some(tag),
     };
-    let message = DataMessage::new(tag, buffer_capacity as usize);
+    let mut message = DataMessage::new(tag, buffer_capacity as usize);
+    message.set_resource_limits(caller.data().config().get_message_resource_limits());
     caller
         .data_mut()
         .message_scratch_area()
@@ -290,6 +297,7 @@ fn data_size<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u
 // Traps:
 // * If module ID doesn't exist
 // * If no data message is in the scratch area.
+// * If the message can't hold more resources of this type.
 fn push_module<T: ProcessState + ProcessCtx<T> + NetworkingCtx + 'static>(
     mut caller: Caller<T>,
     module_id: u64,
@@ -306,7 +314,7 @@ fn push_module<T: ProcessState + ProcessCtx<T> + NetworkingCtx + 'static>(
         .as_mut()
         .or_trap("lunatic::message::push_module")?;
     let index = match message {
-        Message::Data(data) => data.add_resource(module) as u64,
+        Message::Data(data) => data.add_resource_typed(module)? as u64,
         Message::LinkDied(_) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"));
         }
@@ -352,6 +360,7 @@ fn take_module<T: ProcessState + ProcessCtx<T> + NetworkingCtx + 'static>(
 // Traps:
 // * If TCP stream ID doesn't exist
 // * If no data message is in the scratch area.
+// * If the message can't hold more resources of this type.
 fn push_tcp_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
     mut caller: Caller<T>,
     stream_id: u64,
@@ -367,7 +376,7 @@ fn push_tcp_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .as_mut()
         .or_trap("lunatic::message::push_tcp_stream")?;
     let index = match message {
-        Message::Data(data) => data.add_resource(stream) as u64,
+        Message::Data(data) => data.add_resource_typed(stream)? as u64,
         Message::LinkDied(_) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"));
         }
@@ -415,6 +424,7 @@ fn take_tcp_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
 // Traps:
 // * If TLS stream ID doesn't exist
 // * If no data message is in the scratch area.
+// * If the message can't hold more resources of this type.
 fn push_tls_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
     mut caller: Caller<T>,
     stream_id: u64,
@@ -429,7 +439,7 @@ fn push_tls_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .as_mut()
         .or_trap("lunatic::message::push_tls_stream")?;
     let index = match message {
-        Message::Data(data) => data.add_resource(stream) as u64,
+        Message::Data(data) => data.add_resource_typed(stream)? as u64,
         Message::LinkDied(_) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"));
         }
@@ -471,23 +481,56 @@ fn take_tls_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
 
 // Sends the message to a process.
 //
-// There are no guarantees that the message will be received.
+// There are no guarantees that the message will be received. The process state may delay the
+// send or reject it, see `ProcessState::on_send`.
+//
+// Returns:
+// * 0 if the message was sent.
+// * 1 if the message was rejected and dropped.
 //
 // Traps:
 // * If the process ID doesn't exist.
 // * If it's called before creating the next message.
-fn send<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, process_id: u64) -> Result<u32> {
-    let message = caller
-        .data_mut()
-        .message_scratch_area()
-        .take()
-        .or_trap("lunatic::message::send::no_message")?;
+fn send<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    (process_id,): (u64,),
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::message::send::no_message")?;
+
+        if deliver(&mut caller, process_id, message).await {
+            Ok(0)
+        } else {
+            Ok(1)
+        }
+    })
+}
+
+// Sends `message` to `process_id` once the process state allows it. Returns false if the state
+// rejected the message.
+async fn deliver<T: ProcessState + ProcessCtx<T>>(
+    caller: &mut Caller<'_, T>,
+    process_id: u64,
+    message: Message,
+) -> bool {
+    let size = match &message {
+        Message::Data(data) => data.size(),
+        Message::LinkDied(_) | Message::ProcessDied(_) => 0,
+    };
+    match caller.data().on_send(process_id, size) {
+        SendDecision::Proceed => {}
+        SendDecision::Throttle(delay) => tokio::time::sleep(delay).await,
+        SendDecision::Reject => return false,
+    }
 
     if let Some(process) = caller.data_mut().environment().get_process(process_id) {
         process.send(Signal::Message(message));
     }
-
-    Ok(0)
+    true
 }
 
 // Sends the message to a process and waits for a reply, but doesn't look through existing
@@ -503,6 +546,7 @@ fn send<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, process_id: u64)
 //
 // Returns:
 // * 0    if message arrived.
+// * 1    if the message was rejected by the process state and not sent.
 // * 9027 if call timed out.
 //
 // Traps:
@@ -519,8 +563,8 @@ fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
             .take()
             .or_trap("lunatic::message::send_receive_skip_search")?;
 
-        if let Some(process) = caller.data_mut().environment().get_process(process_id) {
-            process.send(Signal::Message(message));
+        if !deliver(&mut caller, process_id, message).await {
+            return Ok(1);
         }
 
         let tags = [wait_on_tag];
@@ -610,6 +654,7 @@ fn receive<T: ProcessState + ProcessCtx<T> + Send>(
 // Traps:
 // * If UDP socket ID doesn't exist
 // * If no data message is in the scratch area.
+// * If the message can't hold more resources of this type.
 fn push_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
     mut caller: Caller<T>,
     socket_id: u64,
@@ -624,7 +669,7 @@ fn push_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .as_mut()
         .or_trap("lunatic::message::push_udp_socket")?;
     let index = match message {
-        Message::Data(data) => data.add_resource(socket) as u64,
+        Message::Data(data) => data.add_resource_typed(socket)? as u64,
         Message::LinkDied(_) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"));
         }
@@ -663,3 +708,14 @@ fn take_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
     };
     Ok(caller.data_mut().udp_resources_mut().add(udp_socket))
 }
+
+// Returns the number of messages waiting in the mailbox of the current process.
+fn mailbox_depth<T: ProcessState>(caller: Caller<T>) -> u64 {
+    caller.data().message_mailbox().len() as u64
+}
+
+// Returns the total size in bytes of the data messages waiting in the mailbox of the current
+// process. Together with `mailbox_depth` this lets a process shed load when it falls behind.
+fn mailbox_bytes<T: ProcessState>(caller: Caller<T>) -> u64 {
+    caller.data().message_mailbox().total_buffered_bytes() as u64
+}
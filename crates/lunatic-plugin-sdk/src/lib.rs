@@ -2,6 +2,18 @@
 
 use anyhow::{Result, anyhow};
 
+pub mod templates;
+
+/// Version of the `lunatic_plugin` host function set a plugin is built against.
+///
+/// Plugins declare it by exporting a function `lunatic_plugin_abi_version() -> i32` whose body
+/// only returns a constant, which hosts read without running the plugin. Hosts refuse plugins
+/// that declare a newer version than theirs, plugins without the export are treated as version 1.
+pub const PLUGIN_ABI_VERSION: i32 = 1;
+
+/// Oldest ABI version hosts support without warning
+pub const MIN_PLUGIN_ABI_VERSION: i32 = 1;
+
 /// WebAssembly value types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValType {
@@ -128,8 +140,25 @@ impl PluginBuilder {
     }
 
     /// Export a function by name
-    pub fn add_function_export(&mut self, name: impl Into<String>, func_idx: FuncIndex) {
-        self.exports.push((name.into(), func_idx));
+    ///
+    /// Fails if `func_idx` doesn't refer to a function added with
+    /// [`add_function`](Self::add_function).
+    pub fn add_function_export(
+        &mut self,
+        name: impl Into<String>,
+        func_idx: FuncIndex,
+    ) -> Result<()> {
+        let name = name.into();
+        // The builder doesn't support imports, so indices start at the first added function
+        if func_idx.0 as usize >= self.functions.len() {
+            return Err(anyhow!(
+                "Cannot export '{name}': function index {} is out of range, only {} functions were added",
+                func_idx.0,
+                self.functions.len()
+            ));
+        }
+        self.exports.push((name, func_idx));
+        Ok(())
     }
 
     /// Get the types added so far
@@ -238,13 +267,30 @@ mod tests {
         );
         assert_eq!(func_idx, FuncIndex(0));
 
-        builder.add_function_export("my_func", func_idx);
+        builder.add_function_export("my_func", func_idx).unwrap();
 
         assert_eq!(builder.types().len(), 1);
         assert_eq!(builder.functions().len(), 1);
         assert_eq!(builder.exports().len(), 1);
     }
 
+    #[test]
+    fn test_plugin_builder_rejects_bogus_export_index() {
+        let mut builder = PluginBuilder::new();
+        let err = builder
+            .add_function_export("early", FuncIndex(0))
+            .unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{err}");
+
+        let type_idx = builder.add_function_type(FunctionType::new(vec![], vec![]));
+        builder.add_function(type_idx, vec![], vec![0x0B]);
+        let err = builder
+            .add_function_export("bogus", FuncIndex(999))
+            .unwrap_err();
+        assert!(err.to_string().contains("999"), "{err}");
+        assert!(builder.exports().is_empty());
+    }
+
     #[test]
     fn test_leb128_u32() {
         assert_eq!(encode_leb128_u32(0), vec![0x00]);
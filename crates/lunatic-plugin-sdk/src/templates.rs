@@ -0,0 +1,134 @@
+//! Ready-to-register plugin modules, as a starting point for plugin authors and as
+//! module sources for tests.
+
+use crate::{ValType, encode_leb128_u32};
+
+/// Hooks exported by [`noop_lifecycle`] with their parameters
+const LIFECYCLE_HOOKS: [(&str, &[ValType]); 9] = [
+    ("lunatic_on_process_spawning", &[ValType::I64]),
+    ("lunatic_on_process_spawned", &[ValType::I64]),
+    ("lunatic_on_process_exiting", &[ValType::I64]),
+    ("lunatic_on_process_exited", &[ValType::I64, ValType::I32]),
+    ("lunatic_on_module_loading", &[ValType::I32, ValType::I32]),
+    ("lunatic_on_module_loaded", &[ValType::I32, ValType::I32]),
+    (
+        "lunatic_on_custom",
+        &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+    ),
+    ("lunatic_on_runtime_started", &[]),
+    ("lunatic_on_runtime_stopping", &[]),
+];
+
+/// A transform plugin that hands every module back unchanged.
+///
+/// It exports one page of `memory` and a `lunatic_transform_module` that only calls the
+/// `lunatic_plugin::passthrough` host function.
+pub fn passthrough_transform() -> Vec<u8> {
+    let mut module = MODULE_HEADER.to_vec();
+    section(&mut module, SECTION_TYPE, &[func_type(&[])]);
+    section(
+        &mut module,
+        SECTION_IMPORT,
+        &[[
+            name("lunatic_plugin"),
+            name("passthrough"),
+            vec![0x00, 0x00],
+        ]
+        .concat()],
+    );
+    section(&mut module, SECTION_FUNCTION, &[encode_leb128_u32(0)]);
+    section(&mut module, SECTION_MEMORY, &[vec![0x00, 0x01]]);
+    section(
+        &mut module,
+        SECTION_EXPORT,
+        &[
+            export("memory", EXPORT_MEMORY, 0),
+            export("lunatic_transform_module", EXPORT_FUNC, 1),
+        ],
+    );
+    // call 0 (passthrough), end
+    section(&mut module, SECTION_CODE, &[code(&[0x10, 0x00, 0x0B])]);
+    module
+}
+
+/// A lifecycle plugin that observes every event and does nothing with it.
+///
+/// It exports one page of `memory` and an empty function for each `lunatic_on_*` hook,
+/// with the parameters the host passes to it.
+pub fn noop_lifecycle() -> Vec<u8> {
+    let mut module = MODULE_HEADER.to_vec();
+    let types: Vec<_> = LIFECYCLE_HOOKS
+        .iter()
+        .map(|(_, params)| func_type(params))
+        .collect();
+    section(&mut module, SECTION_TYPE, &types);
+    let functions: Vec<_> = (0..LIFECYCLE_HOOKS.len() as u32)
+        .map(encode_leb128_u32)
+        .collect();
+    section(&mut module, SECTION_FUNCTION, &functions);
+    section(&mut module, SECTION_MEMORY, &[vec![0x00, 0x01]]);
+    let mut exports = vec![export("memory", EXPORT_MEMORY, 0)];
+    exports.extend(
+        LIFECYCLE_HOOKS
+            .iter()
+            .enumerate()
+            .map(|(i, (hook, _))| export(hook, EXPORT_FUNC, i as u32)),
+    );
+    section(&mut module, SECTION_EXPORT, &exports);
+    let bodies = vec![code(&[0x0B]); LIFECYCLE_HOOKS.len()];
+    section(&mut module, SECTION_CODE, &bodies);
+    module
+}
+
+// Magic number and version 1
+const MODULE_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00];
+
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_MEMORY: u8 = 5;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_CODE: u8 = 10;
+
+const EXPORT_FUNC: u8 = 0x00;
+const EXPORT_MEMORY: u8 = 0x02;
+
+/// Append a section made of a vector of already encoded `items`
+fn section(module: &mut Vec<u8>, id: u8, items: &[Vec<u8>]) {
+    let mut contents = encode_leb128_u32(items.len() as u32);
+    contents.extend(items.iter().flatten());
+    module.push(id);
+    module.extend(encode_leb128_u32(contents.len() as u32));
+    module.extend(contents);
+}
+
+fn name(name: &str) -> Vec<u8> {
+    let mut encoded = encode_leb128_u32(name.len() as u32);
+    encoded.extend_from_slice(name.as_bytes());
+    encoded
+}
+
+/// A function type with `params` and no results
+fn func_type(params: &[ValType]) -> Vec<u8> {
+    let mut encoded = vec![0x60];
+    encoded.extend(encode_leb128_u32(params.len() as u32));
+    encoded.extend(params.iter().map(|param| param.to_byte()));
+    encoded.push(0x00);
+    encoded
+}
+
+fn export(export_name: &str, kind: u8, index: u32) -> Vec<u8> {
+    let mut encoded = name(export_name);
+    encoded.push(kind);
+    encoded.extend(encode_leb128_u32(index));
+    encoded
+}
+
+/// A function body without locals
+fn code(instructions: &[u8]) -> Vec<u8> {
+    let mut body = encode_leb128_u32(0);
+    body.extend_from_slice(instructions);
+    let mut encoded = encode_leb128_u32(body.len() as u32);
+    encoded.extend(body);
+    encoded
+}
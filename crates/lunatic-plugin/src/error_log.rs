@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+/// How many of the most recent plugin failures are kept
+const MAX_RECORDED_ERRORS: usize = 64;
+
+/// What a plugin was being used for when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginOperation {
+    /// Running the plugin's `lunatic_transform_module`
+    Transform,
+    /// Instantiating the plugin or calling one of its lifecycle hooks
+    Lifecycle,
+}
+
+impl Display for PluginOperation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginOperation::Transform => write!(f, "transform"),
+            PluginOperation::Lifecycle => write!(f, "lifecycle"),
+        }
+    }
+}
+
+/// A recorded plugin failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginError {
+    pub plugin: String,
+    pub operation: PluginOperation,
+    pub message: String,
+}
+
+/// Ring buffer of the most recent plugin failures, shared between the registry
+/// and its lifecycle dispatcher.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ErrorLog {
+    errors: Arc<Mutex<VecDeque<PluginError>>>,
+}
+
+impl ErrorLog {
+    /// Record a failure, dropping the oldest one if the log is full
+    pub(crate) fn record(&self, plugin: &str, operation: PluginOperation, error: impl Display) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == MAX_RECORDED_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(PluginError {
+            plugin: plugin.to_string(),
+            operation,
+            message: format!("{error:#}"),
+        });
+    }
+
+    /// Recorded failures, oldest first
+    pub(crate) fn snapshot(&self) -> Vec<PluginError> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let log = ErrorLog::default();
+        for i in 0..MAX_RECORDED_ERRORS + 2 {
+            log.record("p", PluginOperation::Transform, i);
+        }
+        let errors = log.snapshot();
+        assert_eq!(errors.len(), MAX_RECORDED_ERRORS);
+        assert_eq!(errors[0].message, "2");
+        assert_eq!(
+            errors.last().unwrap().message,
+            (MAX_RECORDED_ERRORS + 1).to_string()
+        );
+    }
+}
@@ -1,19 +1,42 @@
 #![forbid(unsafe_code)]
 
+mod error_log;
 mod lifecycle;
 mod module_context;
+mod shared;
 #[cfg(test)]
 mod test_plugin;
-
-pub use lifecycle::{LifecycleDispatcher, LifecycleEvent};
-pub use module_context::ModuleContext;
-
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::Arc;
-
-use anyhow::Result;
-use wasmtime::{Caller, Engine, Linker, Module, Store};
+mod transform_cache;
+mod wasi_shim;
+
+pub use error_log::{PluginError, PluginOperation};
+pub use lifecycle::{ExitReason, LifecycleDispatcher, LifecycleEvent};
+pub use module_context::{EntryKind, ExportDiff, ModuleContext};
+pub use shared::SharedPluginRegistry;
+pub use wasi_shim::WasiShimTransform;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use error_log::ErrorLog;
+use lunatic_plugin_sdk::{MIN_PLUGIN_ABI_VERSION, PLUGIN_ABI_VERSION};
+use sha2::{Digest, Sha256};
+use transform_cache::TransformCache;
+use wasmtime::{
+    Cache, CacheConfig, Caller, Engine, ExternType, Linker, Module, Store, UpdateDeadline, Val,
+};
+
+/// How often a cancellable transform checks its cancellation flag
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Start of every binary Wasm module, anything else `register_wasm` parses as text
+const WASM_MAGIC: &[u8] = b"\0asm";
 
 /// Capability that a plugin may request
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -42,12 +65,84 @@ pub struct PluginInfo {
     pub dependencies: Vec<PluginDependency>,
 }
 
+impl PluginInfo {
+    /// Check if the plugin requests `capability`
+    pub fn requires_capability(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+/// Set of capabilities a registry allows plugins to request.
+///
+/// Capabilities are compared exactly, e.g. allowing `HostFunctions("math")`
+/// doesn't permit `HostFunctions("io")`.
+#[derive(Debug, Clone, Default)]
+pub struct CapabilityPolicy {
+    pub allowed: Vec<Capability>,
+}
+
+impl CapabilityPolicy {
+    /// Check that every capability requested by the plugin is allowed.
+    /// On failure returns the capabilities that are not.
+    pub fn permits(&self, info: &PluginInfo) -> std::result::Result<(), Vec<Capability>> {
+        let denied: Vec<Capability> = info
+            .capabilities
+            .iter()
+            .filter(|capability| !self.allowed.contains(capability))
+            .cloned()
+            .collect();
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(denied)
+        }
+    }
+}
+
 /// A loaded plugin
 pub struct Plugin {
     pub info: PluginInfo,
     pub module: Module,
 }
 
+impl Plugin {
+    /// Compile a binary plugin module for `engine`.
+    ///
+    /// Fails if the plugin requires a newer plugin ABI version than
+    /// [`PLUGIN_ABI_VERSION`]. The version is read from the body of the exported
+    /// `lunatic_plugin_abi_version() -> i32` function, without instantiating the
+    /// plugin, so the body must be a single `i32.const`. Plugins without the export
+    /// are version 1.
+    pub fn from_binary(engine: &Engine, info: PluginInfo, wasm: &[u8]) -> Result<Self> {
+        let module = Module::from_binary(engine, wasm)?;
+        let name = &info.name;
+        let abi_version = declared_abi_version(wasm)
+            .with_context(|| format!("Plugin '{name}' has an invalid ABI version"))?;
+        if abi_version > PLUGIN_ABI_VERSION {
+            return Err(anyhow!(
+                "Plugin '{name}' requires plugin ABI version {abi_version}, but only {PLUGIN_ABI_VERSION} is supported"
+            ));
+        }
+        if abi_version < MIN_PLUGIN_ABI_VERSION {
+            log::warn!(
+                "Plugin '{name}' was built against plugin ABI version {abi_version}, \
+                 the oldest supported version is {MIN_PLUGIN_ABI_VERSION}"
+            );
+        }
+        Ok(Self { info, module })
+    }
+
+    /// Human readable list of the capabilities this plugin requests
+    pub fn capabilities_summary(&self) -> String {
+        self.info
+            .capabilities
+            .iter()
+            .map(|capability| format!("{capability:?}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
 impl std::fmt::Debug for Plugin {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Plugin")
@@ -56,10 +151,105 @@ impl std::fmt::Debug for Plugin {
     }
 }
 
+/// Error returned by [`PluginRegistry::transform_module_cancellable`] when the
+/// transform was cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Module transform was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Error returned by [`PluginRegistry::transform_module`] when the whole transform
+/// took longer than the [deadline](PluginRegistry::set_transform_deadline)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded {
+    /// Index in the transform chain of the transform that was running, or about to
+    /// start, when the deadline passed. All transforms before it completed.
+    pub step: usize,
+}
+
+impl Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Module transform exceeded its deadline at transform {}",
+            self.step
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// What a transform does when a plugin fails, see
+/// [`PluginRegistry::set_transform_error_mode`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransformErrorMode {
+    /// The whole transform fails and the module isn't loaded
+    #[default]
+    Fatal,
+    /// The failure is logged and recorded, and the bytes the plugin received are passed
+    /// on to the next transform as if the plugin had skipped the module
+    SkipAndContinue,
+}
+
+/// Hashes recorded by [`PluginRegistry::transform_module_audited`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformAudit {
+    /// SHA-256 of the module before any transform ran
+    pub input_sha256: [u8; 32],
+    /// [`PluginRegistry::pipeline_fingerprint`] of the pipeline that ran
+    pub pipeline_fingerprint: [u8; 32],
+    /// SHA-256 of the transformed module
+    pub output_sha256: [u8; 32],
+    /// Name and SHA-256 of the output of every transform, in the order they ran.
+    /// Native transforms are named `"<native>"`.
+    pub per_plugin: Vec<(String, [u8; 32])>,
+}
+
 /// Host state for plugin transform invocations
 struct PluginHostState {
     input_bytes: Vec<u8>,
     output_bytes: Vec<u8>,
+    max_output_size: Option<usize>,
+    skipped: bool,
+    // Fixed time, in seconds since the Unix epoch, reported to the plugin instead of the real time
+    clock: Option<i64>,
+    parameters: Arc<HashMap<String, String>>,
+}
+
+impl PluginHostState {
+    /// The bytes passed on to the next transform: the input if the plugin skipped
+    /// the module or wrote no output, otherwise the output
+    fn output(&self) -> &[u8] {
+        if self.skipped || self.output_bytes.is_empty() {
+            &self.input_bytes
+        } else {
+            &self.output_bytes
+        }
+    }
+
+    fn into_output(self) -> Vec<u8> {
+        if self.skipped || self.output_bytes.is_empty() {
+            self.input_bytes
+        } else {
+            self.output_bytes
+        }
+    }
+}
+
+/// A module transform implemented in Rust, see
+/// [`PluginRegistry::add_native_transform`]
+pub type NativeTransform = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+/// One step of the transform chain
+enum TransformStep {
+    Wasm(Arc<Plugin>),
+    Native(NativeTransform),
 }
 
 /// Registry that manages loaded plugins
@@ -67,9 +257,28 @@ pub struct PluginRegistry {
     engine: Engine,
     plugins: HashMap<String, Arc<Plugin>>,
     module_transform_plugins: Vec<Arc<Plugin>>,
+    // Transform plugins and native transforms with their priority, in the order they run:
+    // by priority, ties in the order they were added
+    transform_chain: Vec<(i32, TransformStep)>,
     host_function_plugins: HashMap<String, Vec<Arc<Plugin>>>,
     lifecycle_plugins: Vec<Arc<Plugin>>,
     lifecycle_dispatcher: LifecycleDispatcher,
+    disabled: HashSet<String>,
+    max_output_size: Option<usize>,
+    policy: Option<CapabilityPolicy>,
+    errors: ErrorLog,
+    transform_clock: Option<i64>,
+    transform_deadline: Option<Duration>,
+    transform_error_mode: TransformErrorMode,
+    // Parameters transform plugins read through `lunatic_plugin::param_get`, see `set_parameter`
+    parameters: Arc<HashMap<String, String>>,
+    // Output of `transform_module` for the current pipeline, cleared whenever it changes
+    transform_cache: Mutex<TransformCache>,
+    // Wasm bytes of the plugins registered with `register_wasm`, in registration order, so
+    // they can be recompiled for another engine by `merge`
+    sources: Vec<(String, Vec<u8>)>,
+    // On-disk cache of compiled plugin modules, see `enable_cache`
+    compilation_cache: Option<Cache>,
 }
 
 impl Default for PluginRegistry {
@@ -80,28 +289,74 @@ impl Default for PluginRegistry {
 
 impl PluginRegistry {
     pub fn new() -> Self {
-        let mut config = wasmtime::Config::new();
-        config.async_support(false);
-        let engine = Engine::new(&config).expect("failed to create plugin engine");
+        let engine = plugin_engine(None).expect("failed to create plugin engine");
+        let errors = ErrorLog::default();
         Self {
             engine,
             plugins: HashMap::new(),
             module_transform_plugins: Vec::new(),
+            transform_chain: Vec::new(),
             host_function_plugins: HashMap::new(),
             lifecycle_plugins: Vec::new(),
-            lifecycle_dispatcher: LifecycleDispatcher::new(),
+            lifecycle_dispatcher: LifecycleDispatcher::with_error_log(errors.clone()),
+            disabled: HashSet::new(),
+            max_output_size: None,
+            policy: None,
+            errors,
+            transform_clock: None,
+            transform_deadline: None,
+            transform_error_mode: TransformErrorMode::Fatal,
+            parameters: Arc::default(),
+            transform_cache: Mutex::default(),
+            sources: Vec::new(),
+            compilation_cache: None,
         }
     }
 
+    /// Cache compiled plugin modules in `dir`, so that registering the same
+    /// bytes again, e.g. on the next startup, reuses the compiled code.
+    ///
+    /// The engine is replaced, so this fails if any plugin is already
+    /// registered.
+    pub fn enable_cache(&mut self, dir: &Path) -> Result<()> {
+        if !self.plugins.is_empty() {
+            return Err(anyhow!(
+                "The compilation cache must be enabled before registering plugins"
+            ));
+        }
+        let mut config = CacheConfig::new();
+        config.with_directory(dir);
+        let cache = Cache::new(config)?;
+        self.engine = plugin_engine(Some(cache.clone()))?;
+        self.compilation_cache = Some(cache);
+        Ok(())
+    }
+
+    /// The compilation cache enabled with [`enable_cache`](Self::enable_cache),
+    /// e.g. to read its hit and miss counts
+    pub fn compilation_cache(&self) -> Option<&Cache> {
+        self.compilation_cache.as_ref()
+    }
+
     /// Register a plugin in the registry
+    ///
+    /// Fails if a [`CapabilityPolicy`] is set and the plugin requests a
+    /// capability it doesn't allow, or if it's a lifecycle plugin with imports
+    /// the [`LifecycleDispatcher`] can't provide. The plugin ABI version is
+    /// checked when the plugin is compiled, see [`Plugin::from_binary`].
     pub fn register(&mut self, plugin: Plugin) -> Result<()> {
         let name = plugin.info.name.clone();
+        self.validate(&plugin)?;
         let plugin = Arc::new(plugin);
+        if plugin.info.requires_capability(&Capability::LifecycleHooks) {
+            self.lifecycle_dispatcher.add_plugin(Arc::clone(&plugin))?;
+        }
 
         for cap in &plugin.info.capabilities {
             match cap {
                 Capability::ModuleTransform => {
                     self.module_transform_plugins.push(Arc::clone(&plugin));
+                    self.insert_transform(0, TransformStep::Wasm(Arc::clone(&plugin)));
                 }
                 Capability::HostFunctions(namespace) => {
                     self.host_function_plugins
@@ -111,21 +366,353 @@ impl PluginRegistry {
                 }
                 Capability::LifecycleHooks => {
                     self.lifecycle_plugins.push(Arc::clone(&plugin));
-                    self.lifecycle_dispatcher.add_plugin(Arc::clone(&plugin));
                 }
                 _ => {}
             }
         }
 
         self.plugins.insert(name, plugin);
+        self.clear_transform_cache();
+        Ok(())
+    }
+
+    /// Check a plugin against the [`CapabilityPolicy`], as done by
+    /// [`register`](Self::register)
+    fn validate(&self, plugin: &Plugin) -> Result<()> {
+        let name = &plugin.info.name;
+        if let Some(policy) = &self.policy {
+            policy.permits(&plugin.info).map_err(|denied| {
+                anyhow!(
+                    "Plugin '{name}' requests capabilities not permitted by the policy: {denied:?}"
+                )
+            })?;
+        }
         Ok(())
     }
 
+    /// Replace the module of the registered plugin `name`, e.g. after it was
+    /// rebuilt during development.
+    ///
+    /// The plugin keeps its info, its enabled state and its position in the
+    /// transform chain and lifecycle dispatch order. The new module is validated
+    /// like in [`register`](Self::register), against the current policy.
+    pub fn reload_wasm(&mut self, name: &str, wasm: &[u8]) -> Result<()> {
+        let old = self
+            .plugins
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Plugin '{name}' is not registered"))?;
+        let wasm = wat::parse_bytes(wasm)?;
+        let plugin = Plugin::from_binary(&self.engine, old.info.clone(), &wasm)?;
+        self.validate(&plugin)?;
+        let plugin = Arc::new(plugin);
+        self.lifecycle_dispatcher
+            .replace_plugin(&old, Arc::clone(&plugin))?;
+
+        let plugins = self
+            .module_transform_plugins
+            .iter_mut()
+            .chain(self.host_function_plugins.values_mut().flatten())
+            .chain(self.lifecycle_plugins.iter_mut())
+            .chain(
+                self.transform_chain
+                    .iter_mut()
+                    .filter_map(|(_, step)| match step {
+                        TransformStep::Wasm(plugin) => Some(plugin),
+                        TransformStep::Native(_) => None,
+                    }),
+            );
+        for entry in plugins {
+            if Arc::ptr_eq(entry, &old) {
+                *entry = Arc::clone(&plugin);
+            }
+        }
+        self.plugins.insert(name.to_string(), plugin);
+        self.set_source(name, &wasm);
+        self.clear_transform_cache();
+        Ok(())
+    }
+
+    /// Remove the plugin `name` from the registry and return it.
+    ///
+    /// Fails if the plugin isn't registered or another registered plugin
+    /// depends on it.
+    pub fn unregister(&mut self, name: &str) -> Result<Arc<Plugin>> {
+        let plugin = self
+            .plugins
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Plugin '{name}' is not registered"))?;
+        if let Some(dependent) = self.plugins.values().find(|other| {
+            other
+                .info
+                .dependencies
+                .iter()
+                .any(|dependency| dependency.name == name)
+        }) {
+            return Err(anyhow!(
+                "Plugin '{name}' is required by '{}'",
+                dependent.info.name
+            ));
+        }
+
+        self.lifecycle_dispatcher.remove_plugin(&plugin);
+        self.module_transform_plugins
+            .retain(|entry| !Arc::ptr_eq(entry, &plugin));
+        self.transform_chain.retain(|(_, step)| match step {
+            TransformStep::Wasm(entry) => !Arc::ptr_eq(entry, &plugin),
+            TransformStep::Native(_) => true,
+        });
+        for plugins in self.host_function_plugins.values_mut() {
+            plugins.retain(|entry| !Arc::ptr_eq(entry, &plugin));
+        }
+        self.host_function_plugins
+            .retain(|_, plugins| !plugins.is_empty());
+        self.lifecycle_plugins
+            .retain(|entry| !Arc::ptr_eq(entry, &plugin));
+        self.sources.retain(|(source, _)| source != name);
+        self.disabled.remove(name);
+        self.plugins.remove(name);
+        self.clear_transform_cache();
+        Ok(plugin)
+    }
+
     /// Register a plugin from raw Wasm bytes
+    ///
+    /// Bytes starting with the `\0asm` magic are compiled as a binary module,
+    /// anything else is parsed as WebAssembly text first. If neither works the
+    /// error carries both reasons. With the `compression` feature, gzip and zstd
+    /// compressed bytes (e.g. a `.wasm.gz` file) are detected by their magic
+    /// header and decompressed first.
     pub fn register_wasm(&mut self, info: PluginInfo, wasm: &[u8]) -> Result<()> {
-        let module = Module::new(&self.engine, wasm)?;
-        let plugin = Plugin { info, module };
-        self.register(plugin)
+        #[cfg(feature = "compression")]
+        let wasm = &decompress(wasm, MAX_PLUGIN_SIZE)
+            .with_context(|| format!("Failed to decompress plugin '{}'", info.name))?;
+        let wasm = if wasm.starts_with(WASM_MAGIC) {
+            wasm.to_vec()
+        } else {
+            let binary_error = Module::from_binary(&self.engine, wasm).unwrap_err();
+            let wasm = wat::parse_bytes(wasm).map_err(|wat_error| {
+                anyhow!(
+                    "Plugin '{}': input is neither valid Wasm binary nor valid WAT text: \
+                     {binary_error}; {wat_error}",
+                    info.name
+                )
+            })?;
+            wasm.into_owned()
+        };
+        let name = info.name.clone();
+        let plugin = Plugin::from_binary(&self.engine, info, &wasm)?;
+        self.register(plugin)?;
+        self.set_source(&name, &wasm);
+        Ok(())
+    }
+
+    /// Register a plugin from WebAssembly text
+    ///
+    /// The text is parsed into a binary module first, so syntax errors are
+    /// reported as such. The binary is what [`merge`](Self::merge) recompiles.
+    pub fn register_wat(&mut self, info: PluginInfo, wat: &str) -> Result<()> {
+        let wasm = wat::parse_str(wat)
+            .with_context(|| format!("Failed to parse the WAT of plugin '{}'", info.name))?;
+        self.register_wasm(info, &wasm)
+    }
+
+    /// Register all plugins of `other` after the ones already registered, in
+    /// the order they were registered in `other`. Their enabled state is kept,
+    /// but from now on this registry's policy and settings apply to them.
+    ///
+    /// A wasmtime `Module` is tied to the [`Engine`] it was compiled with and
+    /// every registry has its own, so the plugins are recompiled from the bytes
+    /// they were registered with. Only [`register_wasm`](Self::register_wasm)
+    /// keeps those bytes, a plugin added to `other` with
+    /// [`register`](Self::register) can't be merged.
+    ///
+    /// Fails without registering anything if a plugin name is registered in both
+    /// registries, a plugin can't be recompiled, isn't permitted by the policy,
+    /// can't be linked for lifecycle events, or depends on a plugin that neither
+    /// registry has in a matching version.
+    /// Native transforms can't be merged either. Transform plugins keep their
+    /// priority.
+    pub fn merge(&mut self, other: PluginRegistry) -> Result<()> {
+        if other
+            .transform_chain
+            .iter()
+            .any(|(_, step)| matches!(step, TransformStep::Native(_)))
+        {
+            return Err(anyhow!("Native transforms can't be merged"));
+        }
+        let find = |name: &str| self.plugins.get(name).or_else(|| other.plugins.get(name));
+        for (name, plugin) in &other.plugins {
+            if self.plugins.contains_key(name) {
+                return Err(anyhow!("Plugin '{name}' is registered in both registries"));
+            }
+            if !other.sources.iter().any(|(source, _)| source == name) {
+                return Err(anyhow!(
+                    "Plugin '{name}' wasn't registered from Wasm bytes and can't be recompiled"
+                ));
+            }
+            self.validate(plugin)?;
+            for dependency in &plugin.info.dependencies {
+                let satisfied = find(&dependency.name)
+                    .is_some_and(|found| dependency.version_req.matches(&found.info.version));
+                if !satisfied {
+                    return Err(anyhow!(
+                        "Plugin '{name}' depends on '{} {}', which is not registered",
+                        dependency.name,
+                        dependency.version_req
+                    ));
+                }
+            }
+        }
+
+        // Everything `register` can fail on is checked before the first plugin is
+        // registered, so the merge never stops halfway
+        let mut plugins = Vec::with_capacity(other.sources.len());
+        for (name, wasm) in &other.sources {
+            let info = other.plugins[name].info.clone();
+            let plugin = Plugin::from_binary(&self.engine, info, wasm)
+                .with_context(|| format!("Failed to recompile plugin '{name}'"))?;
+            if plugin.info.requires_capability(&Capability::LifecycleHooks) {
+                LifecycleDispatcher::check_plugin(&plugin)?;
+            }
+            plugins.push((plugin, wasm));
+        }
+        for (plugin, wasm) in plugins {
+            let name = plugin.info.name.clone();
+            self.register(plugin)?;
+            self.set_source(&name, wasm);
+            if let Some(priority) = other.transform_priority(&name) {
+                self.set_transform_priority(&name, priority)?;
+            }
+            if other.disabled.contains(&name) {
+                self.disabled.insert(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Info and Wasm bytes of every plugin in registration order, e.g. to
+    /// register them on another node with [`register_wasm`](Self::register_wasm).
+    ///
+    /// Like [`merge`](Self::merge) this needs the bytes the plugins were
+    /// registered with and fails if a plugin was added with
+    /// [`register`](Self::register). Enabled state and transform priorities are
+    /// not part of the export.
+    pub fn export(&self) -> Result<Vec<(PluginInfo, Vec<u8>)>> {
+        if let Some(name) = self
+            .plugins
+            .keys()
+            .find(|name| !self.sources.iter().any(|(source, _)| source == *name))
+        {
+            return Err(anyhow!(
+                "Plugin '{name}' wasn't registered from Wasm bytes and can't be exported"
+            ));
+        }
+        Ok(self
+            .sources
+            .iter()
+            .map(|(name, wasm)| (self.plugins[name].info.clone(), wasm.clone()))
+            .collect())
+    }
+
+    fn set_source(&mut self, name: &str, wasm: &[u8]) {
+        match self.sources.iter_mut().find(|(source, _)| source == name) {
+            Some((_, bytes)) => *bytes = wasm.to_vec(),
+            None => self.sources.push((name.to_string(), wasm.to_vec())),
+        }
+    }
+
+    /// Restrict the capabilities plugins registered from now on may request.
+    /// `None` (the default) allows everything.
+    pub fn set_policy(&mut self, policy: Option<CapabilityPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Get the configured capability policy
+    pub fn policy(&self) -> Option<&CapabilityPolicy> {
+        self.policy.as_ref()
+    }
+
+    /// Limit the size of the module produced by any transform plugin.
+    ///
+    /// A plugin writing more than `max_output_size` bytes traps, failing the
+    /// transform. The output of every step of the chain, native transforms
+    /// included, is checked as well. `None` (the default) means unlimited.
+    pub fn set_max_output_size(&mut self, max_output_size: Option<usize>) {
+        self.max_output_size = max_output_size;
+        self.clear_transform_cache();
+    }
+
+    /// Get the configured transform output size limit
+    pub fn max_output_size(&self) -> Option<usize> {
+        self.max_output_size
+    }
+
+    /// Fix the time transform plugins read through `lunatic_plugin::host_time_unix_seconds`
+    /// and `host_time_unix_nanos` to `unix_seconds`, e.g. for reproducible builds.
+    /// `None` (the default) reports the real time.
+    pub fn set_transform_clock(&mut self, unix_seconds: Option<i64>) {
+        self.transform_clock = unix_seconds;
+        self.clear_transform_cache();
+    }
+
+    /// Get the fixed transform clock, if any
+    pub fn transform_clock(&self) -> Option<i64> {
+        self.transform_clock
+    }
+
+    /// Limit how long a whole [`transform_module`](Self::transform_module) call may take.
+    /// Once `deadline` has passed, the transform stops and returns an error that
+    /// downcasts to [`DeadlineExceeded`]. `None` (the default) means unlimited.
+    pub fn set_transform_deadline(&mut self, deadline: Option<Duration>) {
+        self.transform_deadline = deadline;
+    }
+
+    /// Get the transform deadline, if any
+    pub fn transform_deadline(&self) -> Option<Duration> {
+        self.transform_deadline
+    }
+
+    /// Choose whether a failing transform plugin, e.g. one that traps, fails the whole
+    /// transform ([`TransformErrorMode::Fatal`], the default) or is skipped.
+    ///
+    /// Failures are recorded in [`last_errors`](Self::last_errors) either way. Errors of
+    /// native transforms, cancellation and the deadline always stop the transform.
+    pub fn set_transform_error_mode(&mut self, mode: TransformErrorMode) {
+        self.transform_error_mode = mode;
+        self.clear_transform_cache();
+    }
+
+    /// Get the transform error mode
+    pub fn transform_error_mode(&self) -> TransformErrorMode {
+        self.transform_error_mode
+    }
+
+    /// Set a parameter shared by all transform plugins, e.g. the target architecture,
+    /// replacing any previous value of `key`.
+    ///
+    /// Plugins read parameters with `lunatic_plugin::param_size(key_ptr: i32, key_len: i32) -> i32`
+    /// and `lunatic_plugin::param_get(key_ptr: i32, key_len: i32, dest_ptr: i32) -> i32`, which
+    /// work like the lifecycle label functions: both return the length of the value in bytes,
+    /// or -1 if the parameter isn't set, and `param_get` writes the UTF-8 value to `dest_ptr`.
+    /// Plugins have no configuration of their own, so these parameters are the only ones a
+    /// plugin sees.
+    pub fn set_parameter(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        Arc::make_mut(&mut self.parameters).insert(key.into(), value.into());
+        self.clear_transform_cache();
+    }
+
+    /// Remove a parameter, returning its value if it was set
+    pub fn remove_parameter(&mut self, key: &str) -> Option<String> {
+        let value = Arc::make_mut(&mut self.parameters).remove(key);
+        self.clear_transform_cache();
+        value
+    }
+
+    /// Get the value of a parameter set with [`set_parameter`](Self::set_parameter)
+    pub fn parameter(&self, key: &str) -> Option<&str> {
+        self.parameters.get(key).map(String::as_str)
     }
 
     /// Get the plugin engine
@@ -138,285 +725,2232 @@ impl PluginRegistry {
         self.plugins.get(name)
     }
 
+    /// Add a transform implemented in Rust to the transform chain with the
+    /// default priority 0, e.g. a [`WasiShimTransform`].
+    ///
+    /// Native transforms can't be disabled and, like plugins, their output is
+    /// cached by [`transform_module`](Self::transform_module), so they must be
+    /// deterministic.
+    pub fn add_native_transform(&mut self, transform: NativeTransform) {
+        self.add_native_transform_with_priority(transform, 0);
+    }
+
+    /// Add a transform implemented in Rust that runs at `priority`.
+    ///
+    /// Transform plugins and native transforms share one chain ordered by
+    /// priority, lower priorities run first. Transforms with the same priority
+    /// run in the order they were added.
+    pub fn add_native_transform_with_priority(
+        &mut self,
+        transform: NativeTransform,
+        priority: i32,
+    ) {
+        self.insert_transform(priority, TransformStep::Native(transform));
+        self.clear_transform_cache();
+    }
+
+    /// Move the transform plugin `name` to `priority` in the transform chain,
+    /// after the transforms that already have that priority. Plugins start with
+    /// priority 0.
+    pub fn set_transform_priority(&mut self, name: &str, priority: i32) -> Result<()> {
+        let index = self
+            .transform_chain
+            .iter()
+            .position(
+                |(_, step)| matches!(step, TransformStep::Wasm(plugin) if plugin.info.name == name),
+            )
+            .ok_or_else(|| anyhow!("Plugin '{name}' is not a registered transform plugin"))?;
+        let (_, step) = self.transform_chain.remove(index);
+        self.insert_transform(priority, step);
+        self.clear_transform_cache();
+        Ok(())
+    }
+
+    /// Priority of the transform plugin `name` in the transform chain
+    pub fn transform_priority(&self, name: &str) -> Option<i32> {
+        self.transform_chain
+            .iter()
+            .find_map(|(priority, step)| match step {
+                TransformStep::Wasm(plugin) if plugin.info.name == name => Some(*priority),
+                _ => None,
+            })
+    }
+
+    fn insert_transform(&mut self, priority: i32, step: TransformStep) {
+        let index = self
+            .transform_chain
+            .partition_point(|(existing, _)| *existing <= priority);
+        self.transform_chain.insert(index, (priority, step));
+    }
+
     /// Get all module transform plugins (in registration order)
     pub fn module_transform_plugins(&self) -> &[Arc<Plugin>] {
         &self.module_transform_plugins
     }
 
+    /// Names and versions of the transform plugins, in the order
+    /// [`transform_module`](Self::transform_module) applies them. Disabled
+    /// plugins are left out since they don't take part in the transform.
+    pub fn transform_pipeline(&self) -> Vec<(&str, &semver::Version)> {
+        self.enabled_transform_plugins()
+            .map(|plugin| (plugin.info.name.as_str(), &plugin.info.version))
+            .collect()
+    }
+
+    /// Identifies the transform pipeline, e.g. to record which pipeline
+    /// produced a module.
+    ///
+    /// SHA-256 over the name, version and module hash of every enabled
+    /// transform plugin, in the order they are applied. Reordering, disabling
+    /// or reloading a plugin changes it. Modules are hashed from the bytes they
+    /// were registered with, plugins added with [`register`](Self::register)
+    /// from their serialized form. Native transforms only contribute their
+    /// position in the chain.
+    pub fn pipeline_fingerprint(&self) -> Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        for step in self.transform_steps() {
+            let plugin = match step {
+                TransformStep::Wasm(plugin) => plugin,
+                TransformStep::Native(_) => {
+                    // Can't be confused with the length of a plugin name
+                    hasher.update(u64::MAX.to_le_bytes());
+                    continue;
+                }
+            };
+            let name = plugin.info.name.as_bytes();
+            hasher.update((name.len() as u64).to_le_bytes());
+            hasher.update(name);
+            let version = plugin.info.version.to_string();
+            hasher.update((version.len() as u64).to_le_bytes());
+            hasher.update(version);
+            let source = self
+                .sources
+                .iter()
+                .find(|(source, _)| *source == plugin.info.name);
+            let module_hash = match source {
+                Some((_, wasm)) => Sha256::digest(wasm),
+                None => Sha256::digest(plugin.module.serialize()?),
+            };
+            hasher.update(module_hash);
+        }
+        Ok(hasher.finalize().into())
+    }
+
     /// Get host function plugins for a namespace
     pub fn host_function_plugins(&self, namespace: &str) -> Option<&Vec<Arc<Plugin>>> {
         self.host_function_plugins.get(namespace)
     }
 
+    /// Call the function `func_name` exported by a host function plugin in `namespace`.
+    ///
+    /// A fresh instance of the plugin is created for every call, so no state is
+    /// kept between calls. If several enabled plugins in the namespace export
+    /// `func_name`, the one registered first wins.
+    pub fn call_host_function(
+        &self,
+        namespace: &str,
+        func_name: &str,
+        args: &[Val],
+    ) -> Result<Vec<Val>> {
+        let plugin = self
+            .host_function_plugins
+            .get(namespace)
+            .into_iter()
+            .flatten()
+            .filter(|plugin| !self.disabled.contains(&plugin.info.name))
+            .find(|plugin| {
+                matches!(
+                    plugin.module.get_export(func_name),
+                    Some(ExternType::Func(_))
+                )
+            })
+            .ok_or_else(|| anyhow!("No plugin in namespace '{namespace}' exports '{func_name}'"))?;
+
+        let mut store = plugin_store(&self.engine, (), None);
+        let instance = Linker::<()>::new(&self.engine)
+            .instantiate(&mut store, &plugin.module)
+            .with_context(|| format!("Failed to instantiate plugin '{}'", plugin.info.name))?;
+        let func = instance.get_func(&mut store, func_name).ok_or_else(|| {
+            anyhow!(
+                "Plugin '{}' has no function '{func_name}'",
+                plugin.info.name
+            )
+        })?;
+
+        let mut results = vec![Val::I32(0); func.ty(&store).results().len()];
+        func.call(&mut store, args, &mut results).with_context(|| {
+            format!(
+                "Host function '{namespace}::{func_name}' of plugin '{}' failed",
+                plugin.info.name
+            )
+        })?;
+        Ok(results)
+    }
+
+    /// Enable or disable a registered plugin without unregistering it.
+    ///
+    /// A disabled transform plugin behaves as a passthrough, a disabled
+    /// lifecycle plugin receives no events and a disabled host function plugin
+    /// is skipped by [`call_host_function`](Self::call_host_function). The
+    /// plugin keeps its position in the transform chain and is picked up again
+    /// once re-enabled.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<()> {
+        if !self.plugins.contains_key(name) {
+            return Err(anyhow!("Plugin '{name}' is not registered"));
+        }
+        if enabled {
+            self.disabled.remove(name);
+        } else {
+            self.disabled.insert(name.to_string());
+        }
+        self.lifecycle_dispatcher.set_enabled(name, enabled);
+        self.clear_transform_cache();
+        Ok(())
+    }
+
+    /// Check if a plugin is currently enabled
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.plugins.contains_key(name) && !self.disabled.contains(name)
+    }
+
     /// Get the lifecycle dispatcher
     pub fn lifecycle_dispatcher(&self) -> &LifecycleDispatcher {
         &self.lifecycle_dispatcher
     }
 
+    /// The most recent plugin failures, oldest first.
+    ///
+    /// Covers failed transforms and lifecycle instantiation or hook failures,
+    /// including those that are only logged. Older entries are dropped once the
+    /// buffer is full, cancelled transforms are not recorded.
+    pub fn last_errors(&self) -> Vec<PluginError> {
+        self.errors.snapshot()
+    }
+
     /// Transform a module through all registered transform plugins.
     /// Each plugin's transform is applied sequentially.
+    ///
+    /// Successful results are cached by the SHA-256 of the input, transforming the
+    /// same bytes again returns the cached output as long as the transform pipeline
+    /// didn't change. The cache keeps up to 64 results and 64 MiB of output, evicting
+    /// the oldest first. Results of plugins reading the real time are not cached, and
+    /// neither are results a failed plugin was skipped for.
+    ///
+    /// With a [deadline](Self::set_transform_deadline) the elapsed time is checked
+    /// between transforms and, through epoch interruption, while a plugin is running.
     pub fn transform_module(&self, module_bytes: &[u8]) -> Result<Vec<u8>> {
-        if self.module_transform_plugins.is_empty() {
+        if self.transform_chain.is_empty() {
             return Ok(module_bytes.to_vec());
         }
 
-        let mut current_bytes = module_bytes.to_vec();
+        let cacheable = self.transform_clock.is_some()
+            || !self
+                .enabled_transform_plugins()
+                .any(|plugin| reads_clock(plugin));
+        let key = TransformCache::key(module_bytes);
+        let generation = {
+            let cache = self.transform_cache.lock().unwrap();
+            if cacheable && let Some(output) = cache.get(&key) {
+                log::trace!("Using cached transform output");
+                return Ok(output.clone());
+            }
+            cache.generation()
+        };
 
-        for plugin in &self.module_transform_plugins {
-            let engine = plugin.module.engine();
-            let state = PluginHostState {
-                input_bytes: current_bytes.clone(),
-                output_bytes: Vec::new(),
-            };
-            let mut store = Store::new(engine, state);
-
-            let mut linker: Linker<PluginHostState> = Linker::new(engine);
-
-            linker.func_wrap(
-                "lunatic_plugin",
-                "input_size",
-                |caller: Caller<PluginHostState>| -> i32 { caller.data().input_bytes.len() as i32 },
-            )?;
-
-            linker.func_wrap(
-                "lunatic_plugin",
-                "read_input",
-                |mut caller: Caller<PluginHostState>, dest_ptr: i32| -> Result<()> {
-                    let input = caller.data().input_bytes.clone();
-                    let memory = caller
-                        .get_export("memory")
-                        .and_then(|e| e.into_memory())
-                        .ok_or_else(|| anyhow::anyhow!("plugin must export memory"))?;
-                    memory.write(&mut caller, dest_ptr as usize, &input)?;
-                    Ok(())
-                },
-            )?;
-
-            linker.func_wrap(
-                "lunatic_plugin",
-                "write_output",
-                |mut caller: Caller<PluginHostState>, src_ptr: i32, len: i32| -> Result<()> {
-                    let memory = caller
-                        .get_export("memory")
-                        .and_then(|e| e.into_memory())
-                        .ok_or_else(|| anyhow::anyhow!("plugin must export memory"))?;
-                    let src = src_ptr as usize;
-                    let size = len as usize;
-                    let data = memory.data(&caller);
-                    anyhow::ensure!(
-                        src.checked_add(size).is_some_and(|end| end <= data.len()),
-                        "write_output: out-of-bounds read from plugin memory"
-                    );
-                    let output = data[src..src + size].to_vec();
-                    caller.data_mut().output_bytes = output;
-                    Ok(())
-                },
-            )?;
+        // Skipping a failed plugin may be temporary, e.g. a plugin running out of time
+        let mut failed = false;
+        let current_bytes = match self.transform_deadline {
+            Some(deadline) => self.transform_interruptible(
+                module_bytes,
+                None,
+                Some(Instant::now() + deadline),
+                &mut failed,
+                &mut |_, _| {},
+            )?,
+            None => {
+                let mut current_bytes = module_bytes.to_vec();
+                for (index, step) in self.transform_steps().enumerate() {
+                    current_bytes = match step {
+                        TransformStep::Wasm(plugin) => {
+                            self.run_transform(plugin, current_bytes, &mut failed)?
+                        }
+                        TransformStep::Native(transform) => {
+                            run_native_transform(transform, &current_bytes)?
+                        }
+                    };
+                    self.check_output_size(index, step, &current_bytes)?;
+                }
+                current_bytes
+            }
+        };
+
+        if cacheable && !failed {
+            self.transform_cache
+                .lock()
+                .unwrap()
+                .insert(key, generation, current_bytes.clone());
+        }
+        Ok(current_bytes)
+    }
+
+    /// Drop all cached [`transform_module`](Self::transform_module) results
+    pub fn clear_transform_cache(&self) {
+        self.transform_cache.lock().unwrap().clear();
+    }
+
+    /// Number of cached [`transform_module`](Self::transform_module) results
+    pub fn transform_cache_len(&self) -> usize {
+        self.transform_cache.lock().unwrap().len()
+    }
+
+    /// Transform a module like [`transform_module`](Self::transform_module), but
+    /// stop as soon as `cancel` is set.
+    ///
+    /// The flag is checked between plugins and, through epoch interruption,
+    /// while a plugin is running, so even a plugin stuck in a loop is stopped
+    /// promptly. Native transforms are only checked before they start. A
+    /// cancelled transform returns an error that downcasts to [`Cancelled`].
+    /// The [deadline](Self::set_transform_deadline) applies as well.
+    pub fn transform_module_cancellable(
+        &self,
+        module_bytes: &[u8],
+        cancel: &AtomicBool,
+    ) -> Result<Vec<u8>> {
+        let deadline = self
+            .transform_deadline
+            .map(|deadline| Instant::now() + deadline);
+        self.transform_interruptible(
+            module_bytes,
+            Some(cancel),
+            deadline,
+            &mut false,
+            &mut |_, _| {},
+        )
+    }
 
-            let instance = linker.instantiate(&mut store, &plugin.module)?;
+    /// Transform a module like [`transform_module`](Self::transform_module) and
+    /// record the hashes needed to audit the result.
+    ///
+    /// The cache is bypassed so every transform actually runs and its output can
+    /// be hashed. The [deadline](Self::set_transform_deadline) applies as usual.
+    /// Transforming the same bytes through the same pipeline yields the same
+    /// audit, unless a plugin reads the real time.
+    pub fn transform_module_audited(
+        &self,
+        module_bytes: &[u8],
+    ) -> Result<(Vec<u8>, TransformAudit)> {
+        let pipeline_fingerprint = self.pipeline_fingerprint()?;
+        let deadline = self
+            .transform_deadline
+            .map(|deadline| Instant::now() + deadline);
+        let mut per_plugin = Vec::new();
+        let output = self.transform_interruptible(
+            module_bytes,
+            None,
+            deadline,
+            &mut false,
+            &mut |step, output| {
+                let name = match step {
+                    TransformStep::Wasm(plugin) => plugin.info.name.clone(),
+                    TransformStep::Native(_) => "<native>".to_string(),
+                };
+                per_plugin.push((name, Sha256::digest(output).into()));
+            },
+        )?;
+        let audit = TransformAudit {
+            input_sha256: Sha256::digest(module_bytes).into(),
+            pipeline_fingerprint,
+            output_sha256: Sha256::digest(&output).into(),
+            per_plugin,
+        };
+        Ok((output, audit))
+    }
+
+    /// Check that transforming a module a second time leaves it unchanged, i.e.
+    /// that `transform(transform(x)) == transform(x)`.
+    ///
+    /// Meant for plugin authors validating transforms that should be idempotent,
+    /// like instrumentation that must not be applied twice. Errors from either
+    /// run are returned as is.
+    pub fn check_idempotent(&self, module_bytes: &[u8]) -> Result<bool> {
+        let once = self.transform_module(module_bytes)?;
+        let twice = self.transform_module(&once)?;
+        Ok(once == twice)
+    }
 
-            let func = instance.get_func(&mut store, "lunatic_transform_module");
-            if let Some(func) = func {
-                func.call(&mut store, &[], &mut [])?;
-                let output = &store.data().output_bytes;
-                if !output.is_empty() {
-                    current_bytes = output.clone();
+    /// Run the transform chain, stopping with [`Cancelled`] once `cancel` is set or
+    /// with [`DeadlineExceeded`] once `deadline` has passed. `failed` is set if a
+    /// failing plugin was skipped, `on_step` is called with the output of every transform.
+    fn transform_interruptible(
+        &self,
+        module_bytes: &[u8],
+        cancel: Option<&AtomicBool>,
+        deadline: Option<Instant>,
+        failed: &mut bool,
+        on_step: &mut dyn FnMut(&TransformStep, &[u8]),
+    ) -> Result<Vec<u8>> {
+        let is_cancelled = || cancel.is_some_and(|cancel| cancel.load(Ordering::Acquire));
+        let is_expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        // Only stores of this transform observe the interruption. The epoch is
+        // shared by the whole engine, so other stores just see a spurious tick.
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let done = AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    if is_cancelled() || is_expired() {
+                        interrupted.store(true, Ordering::Release);
+                        self.engine.increment_epoch();
+                        return;
+                    }
+                    std::thread::sleep(CANCEL_POLL_INTERVAL);
+                }
+            });
+
+            let interruption = |step| -> anyhow::Error {
+                if is_cancelled() {
+                    Cancelled.into()
+                } else {
+                    DeadlineExceeded { step }.into()
+                }
+            };
+            let result = (|| {
+                let mut current_bytes = module_bytes.to_vec();
+                for (index, step) in self.transform_steps().enumerate() {
+                    if is_cancelled() || is_expired() {
+                        return Err(interruption(index));
+                    }
+                    current_bytes = match step {
+                        TransformStep::Wasm(plugin) => self
+                            .run_transform_cancellable(
+                                plugin,
+                                current_bytes,
+                                Some(&interrupted),
+                                failed,
+                            )
+                            .map_err(|e| {
+                                if interrupted.load(Ordering::Acquire) {
+                                    interruption(index)
+                                } else {
+                                    e
+                                }
+                            })?,
+                        TransformStep::Native(transform) => {
+                            run_native_transform(transform, &current_bytes)?
+                        }
+                    };
+                    self.check_output_size(index, step, &current_bytes)?;
+                    on_step(step, &current_bytes);
                 }
-            } else {
+                Ok(current_bytes)
+            })();
+            done.store(true, Ordering::Release);
+            result
+        })
+    }
+
+    /// Transform a module like [`transform_module`](Self::transform_module), but run
+    /// the whole chain on a dedicated worker thread.
+    ///
+    /// Each plugin invocation is wrapped in `catch_unwind`, so a panic raised while
+    /// running a plugin is turned into an error naming that plugin instead of
+    /// unwinding into the caller.
+    pub fn transform_module_isolated(&self, module_bytes: &[u8]) -> Result<Vec<u8>> {
+        if self.transform_chain.is_empty() {
+            return Ok(module_bytes.to_vec());
+        }
+
+        std::thread::scope(|scope| {
+            let worker = std::thread::Builder::new()
+                .name("lunatic-plugin-transform".into())
+                .spawn_scoped(scope, || {
+                    let mut current_bytes = module_bytes.to_vec();
+                    for (index, step) in self.transform_steps().enumerate() {
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| match step {
+                            TransformStep::Wasm(plugin) => {
+                                self.run_transform(plugin, current_bytes, &mut false)
+                            }
+                            TransformStep::Native(transform) => {
+                                run_native_transform(transform, &current_bytes)
+                            }
+                        }));
+                        current_bytes = match (result, step) {
+                            (Ok(output), _) => output?,
+                            (Err(payload), TransformStep::Wasm(plugin)) => {
+                                return Err(anyhow!(
+                                    "Transform plugin '{}' panicked: {}",
+                                    plugin.info.name,
+                                    panic_message(payload.as_ref())
+                                ));
+                            }
+                            (Err(payload), TransformStep::Native(_)) => {
+                                return Err(anyhow!(
+                                    "Native transform panicked: {}",
+                                    panic_message(payload.as_ref())
+                                ));
+                            }
+                        };
+                        self.check_output_size(index, step, &current_bytes)?;
+                    }
+                    Ok(current_bytes)
+                })?;
+            worker
+                .join()
+                .map_err(|_| anyhow!("Transform worker thread panicked"))?
+        })
+    }
+
+    /// Transform plugins and native transforms in chain order, skipping
+    /// disabled plugins
+    fn transform_steps(&self) -> impl Iterator<Item = &TransformStep> {
+        self.transform_chain
+            .iter()
+            .map(|(_, step)| step)
+            .filter(|step| match step {
+                TransformStep::Wasm(plugin) => !self.disabled.contains(&plugin.info.name),
+                TransformStep::Native(_) => true,
+            })
+    }
+
+    /// Fail if `output`, the result of the `index`th step of the transform chain,
+    /// is larger than the [maximum output size](Self::set_max_output_size)
+    fn check_output_size(&self, index: usize, step: &TransformStep, output: &[u8]) -> Result<()> {
+        let Some(max) = self.max_output_size else {
+            return Ok(());
+        };
+        let len = output.len();
+        if len <= max {
+            return Ok(());
+        }
+        Err(match step {
+            TransformStep::Wasm(plugin) => anyhow!(
+                "Transform plugin '{}' produced {len} bytes, exceeding the limit of {max} bytes",
+                plugin.info.name
+            ),
+            TransformStep::Native(_) => anyhow!(
+                "Native transform {index} of the chain produced {len} bytes, exceeding the limit of {max} bytes"
+            ),
+        })
+    }
+
+    /// Transform plugins in chain order, skipping disabled ones
+    fn enabled_transform_plugins(&self) -> impl Iterator<Item = &Arc<Plugin>> {
+        self.transform_steps().filter_map(|step| match step {
+            TransformStep::Wasm(plugin) => Some(plugin),
+            TransformStep::Native(_) => None,
+        })
+    }
+
+    /// Run a single transform plugin over `input`, returning the bytes to feed
+    /// into the next plugin in the chain.
+    ///
+    /// A plugin that calls `lunatic_plugin::skip` opts out for this module: its
+    /// input is passed through unchanged, even if it also wrote output. `failed` is
+    /// set if the plugin failed and was skipped, see [`TransformErrorMode::SkipAndContinue`].
+    fn run_transform(&self, plugin: &Plugin, input: Vec<u8>, failed: &mut bool) -> Result<Vec<u8>> {
+        self.run_transform_cancellable(plugin, input, None, failed)
+    }
+
+    /// [`run_transform`](Self::run_transform), interrupting the plugin once
+    /// `cancelled` is set and the engine epoch is incremented.
+    fn run_transform_cancellable(
+        &self,
+        plugin: &Plugin,
+        input: Vec<u8>,
+        cancelled: Option<&Arc<AtomicBool>>,
+        failed: &mut bool,
+    ) -> Result<Vec<u8>> {
+        let state = PluginHostState {
+            input_bytes: input,
+            output_bytes: Vec::new(),
+            max_output_size: self.max_output_size,
+            skipped: false,
+            clock: self.transform_clock,
+            parameters: Arc::clone(&self.parameters),
+        };
+        let mut store = plugin_store(plugin.module.engine(), state, cancelled.cloned());
+        let result = self.try_run_transform(plugin, &mut store);
+        let state = store.into_data();
+        let e = match result {
+            Ok(()) => return Ok(state.into_output()),
+            Err(e) => e,
+        };
+        if cancelled.is_some_and(|cancelled| cancelled.load(Ordering::Acquire)) {
+            return Err(e);
+        }
+        self.errors
+            .record(&plugin.info.name, PluginOperation::Transform, &e);
+        match self.transform_error_mode {
+            TransformErrorMode::Fatal => Err(e),
+            TransformErrorMode::SkipAndContinue => {
                 log::warn!(
-                    "Transform plugin '{}' does not export 'lunatic_transform_module', skipping",
+                    "Transform plugin '{}' failed, continuing without it: {e:#}",
                     plugin.info.name
                 );
+                *failed = true;
+                Ok(state.input_bytes)
+            }
+        }
+    }
+
+    fn try_run_transform(&self, plugin: &Plugin, store: &mut Store<PluginHostState>) -> Result<()> {
+        let engine = plugin.module.engine();
+        let mut linker: Linker<PluginHostState> = Linker::new(engine);
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "input_size",
+            |caller: Caller<PluginHostState>| -> i32 { caller.data().input_bytes.len() as i32 },
+        )?;
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "read_input",
+            |mut caller: Caller<PluginHostState>, dest_ptr: i32| -> Result<()> {
+                let input = caller.data().input_bytes.clone();
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(|e| e.into_memory())
+                    .ok_or_else(|| anyhow::anyhow!("plugin must export memory"))?;
+                memory.write(&mut caller, dest_ptr as usize, &input)?;
+                Ok(())
+            },
+        )?;
+
+        // `scratch_base` grows the plugin's memory by room for as many bytes as the input has and
+        // returns the page-aligned offset of that room, e.g. to read the input or build the output
+        // there. It lies past everything the plugin used before, so it can't overlap its data,
+        // stack or heap. Every call reserves a new area.
+        linker.func_wrap(
+            "lunatic_plugin",
+            "scratch_base",
+            |mut caller: Caller<PluginHostState>| -> Result<i32> {
+                let input_len = caller.data().input_bytes.len() as u64;
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(|e| e.into_memory())
+                    .ok_or_else(|| anyhow::anyhow!("plugin must export memory"))?;
+                let page_size = memory.page_size(&caller);
+                let old_pages = memory
+                    .grow(&mut caller, input_len.div_ceil(page_size))
+                    .context("scratch_base: can't grow plugin memory")?;
+                i32::try_from(old_pages * page_size).context("scratch_base: input too large")
+            },
+        )?;
+
+        // `write_output` replaces the output, `passthrough` replaces it with a copy of the input
+        // and `append_output` extends whatever the output is at that point. Without any of them
+        // the output starts empty.
+        linker.func_wrap(
+            "lunatic_plugin",
+            "write_output",
+            |mut caller: Caller<PluginHostState>, src_ptr: i32, len: i32| -> Result<()> {
+                let output = read_plugin_memory(&mut caller, src_ptr, len, "write_output")?;
+                if let Some(max) = caller.data().max_output_size {
+                    anyhow::ensure!(
+                        output.len() <= max,
+                        "write_output: output of {} bytes exceeds the limit of {max} bytes",
+                        output.len()
+                    );
+                }
+                caller.data_mut().output_bytes = output;
+                Ok(())
+            },
+        )?;
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "append_output",
+            |mut caller: Caller<PluginHostState>, src_ptr: i32, len: i32| -> Result<()> {
+                let data = read_plugin_memory(&mut caller, src_ptr, len, "append_output")?;
+                let state = caller.data_mut();
+                let size = state.output_bytes.len() + data.len();
+                if let Some(max) = state.max_output_size {
+                    anyhow::ensure!(
+                        size <= max,
+                        "append_output: output of {size} bytes exceeds the limit of {max} bytes"
+                    );
+                }
+                state.output_bytes.extend_from_slice(&data);
+                Ok(())
+            },
+        )?;
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "passthrough",
+            |mut caller: Caller<PluginHostState>| -> Result<()> {
+                let state = caller.data_mut();
+                if let Some(max) = state.max_output_size {
+                    anyhow::ensure!(
+                        state.input_bytes.len() <= max,
+                        "passthrough: output of {} bytes exceeds the limit of {max} bytes",
+                        state.input_bytes.len()
+                    );
+                }
+                state.output_bytes.clone_from(&state.input_bytes);
+                Ok(())
+            },
+        )?;
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "skip",
+            |mut caller: Caller<PluginHostState>| {
+                caller.data_mut().skipped = true;
+            },
+        )?;
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "host_time_unix_seconds",
+            |caller: Caller<PluginHostState>| -> i64 {
+                caller
+                    .data()
+                    .clock
+                    .unwrap_or_else(|| unix_time().as_secs() as i64)
+            },
+        )?;
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "host_time_unix_nanos",
+            |caller: Caller<PluginHostState>| -> i64 {
+                match caller.data().clock {
+                    Some(seconds) => seconds.saturating_mul(1_000_000_000),
+                    None => i64::try_from(unix_time().as_nanos()).unwrap_or(i64::MAX),
+                }
+            },
+        )?;
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "param_size",
+            |mut caller: Caller<PluginHostState>, key_ptr: i32, key_len: i32| -> Result<i32> {
+                let value = read_parameter(&mut caller, key_ptr, key_len, "param_size")?;
+                Ok(value.map_or(-1, |value| value.len() as i32))
+            },
+        )?;
+
+        linker.func_wrap(
+            "lunatic_plugin",
+            "param_get",
+            |mut caller: Caller<PluginHostState>,
+             key_ptr: i32,
+             key_len: i32,
+             dest_ptr: i32|
+             -> Result<i32> {
+                let Some(value) = read_parameter(&mut caller, key_ptr, key_len, "param_get")?
+                else {
+                    return Ok(-1);
+                };
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(|e| e.into_memory())
+                    .ok_or_else(|| anyhow::anyhow!("plugin must export memory"))?;
+                memory.write(&mut caller, dest_ptr as usize, value.as_bytes())?;
+                Ok(value.len() as i32)
+            },
+        )?;
+
+        link_metrics(&mut linker)?;
+
+        let instance = linker.instantiate(&mut *store, &plugin.module)?;
+
+        let func = instance.get_func(&mut *store, "lunatic_transform_module");
+        if let Some(func) = func {
+            func.call(&mut *store, &[], &mut [])
+                .with_context(|| format!("Transform plugin '{}' failed", plugin.info.name))?;
+            let state = store.data();
+            if state.skipped {
+                log::trace!("Transform plugin '{}' skipped the module", plugin.info.name);
+                return Ok(());
+            }
+            if let Some(max) = self.max_output_size {
+                let len = state.output().len();
+                anyhow::ensure!(
+                    len <= max,
+                    "Transform plugin '{}' produced {len} bytes, exceeding the limit of {max} bytes",
+                    plugin.info.name,
+                );
             }
+            Ok(())
+        } else {
+            log::warn!(
+                "Transform plugin '{}' does not export 'lunatic_transform_module', skipping",
+                plugin.info.name
+            );
+            Ok(())
+        }
+    }
+
+    /// Check if any plugins are registered
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Number of registered plugins
+    pub fn len(&self) -> usize {
+        self.plugins.len()
+    }
+}
+
+/// Trait for process states that support plugins.
+/// Implemented by DefaultProcessState in the root crate.
+pub trait PluginCtx {
+    fn plugin_registry(&self) -> &Arc<PluginRegistry>;
+}
+
+/// Create a store for running plugin code on `engine`.
+///
+/// The plugin engine has epoch interruption enabled, so every store needs an
+/// epoch deadline. The store keeps running across epoch ticks until the
+/// optional `cancelled` flag is set, at which point it traps with [`Cancelled`].
+pub(crate) fn plugin_store<T: 'static>(
+    engine: &Engine,
+    data: T,
+    cancelled: Option<Arc<AtomicBool>>,
+) -> Store<T> {
+    let mut store = Store::new(engine, data);
+    store.epoch_deadline_callback(move |_| match &cancelled {
+        Some(cancelled) if cancelled.load(Ordering::Acquire) => Err(Cancelled.into()),
+        _ => Ok(UpdateDeadline::Continue(1)),
+    });
+    store
+}
+
+/// How large a compressed plugin may get once decompressed
+#[cfg(feature = "compression")]
+const MAX_PLUGIN_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Decompress gzip or zstd compressed bytes, returning anything else unchanged.
+/// Fails if the decompressed output would exceed `limit` bytes.
+#[cfg(feature = "compression")]
+fn decompress(bytes: &[u8], limit: u64) -> Result<std::borrow::Cow<'_, [u8]>> {
+    use std::borrow::Cow;
+    use std::io::Read;
+
+    const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+    const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+
+    let decoder: Box<dyn Read + '_> = if bytes.starts_with(GZIP_MAGIC) {
+        Box::new(flate2::read::GzDecoder::new(bytes))
+    } else if bytes.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::stream::read::Decoder::new(bytes)?)
+    } else {
+        return Ok(Cow::Borrowed(bytes));
+    };
+    let mut decompressed = Vec::new();
+    decoder.take(limit + 1).read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > limit {
+        return Err(anyhow!("decompressed plugin exceeds {limit} bytes"));
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+/// Look up the parameter whose key the plugin passed in its memory
+fn read_parameter(
+    caller: &mut Caller<PluginHostState>,
+    key_ptr: i32,
+    key_len: i32,
+    function: &str,
+) -> Result<Option<String>> {
+    let key = read_plugin_memory(caller, key_ptr, key_len, function)?;
+    let key = std::str::from_utf8(&key)
+        .map_err(|e| anyhow::anyhow!("{function}: key is not valid UTF-8: {e}"))?;
+    Ok(caller.data().parameters.get(key).cloned())
+}
+
+/// Define `lunatic_plugin::metric_increment(name_ptr: i32, name_len: i32, value: i64)`.
+///
+/// Increments the counter with the UTF-8 name read from the plugin's `memory` by `value`.
+/// With the `metrics` feature the counter is recorded through [`metrics::counter!`], so it
+/// ends up in the same registry as the runtime's own metrics, without it the call does
+/// nothing. Traps if the name can't be read or `value` is negative.
+pub(crate) fn link_metrics<T: 'static>(linker: &mut Linker<T>) -> Result<()> {
+    linker.func_wrap(
+        "lunatic_plugin",
+        "metric_increment",
+        |mut caller: Caller<T>, name_ptr: i32, name_len: i32, value: i64| -> Result<()> {
+            let name = read_plugin_memory(&mut caller, name_ptr, name_len, "metric_increment")?;
+            let name = String::from_utf8(name)
+                .map_err(|e| anyhow::anyhow!("metric_increment: name is not valid UTF-8: {e}"))?;
+            let value = u64::try_from(value)
+                .map_err(|_| anyhow::anyhow!("metric_increment: negative value {value}"))?;
+            #[cfg(feature = "metrics")]
+            metrics::counter!(name).increment(value);
+            #[cfg(not(feature = "metrics"))]
+            let _ = (name, value);
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
+
+/// Copy `len` bytes at `ptr` out of the plugin's exported memory
+pub(crate) fn read_plugin_memory<T>(
+    caller: &mut Caller<T>,
+    ptr: i32,
+    len: i32,
+    function: &str,
+) -> Result<Vec<u8>> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| anyhow::anyhow!("plugin must export memory"))?;
+    let src = ptr as usize;
+    let size = len as usize;
+    let data = memory.data(&*caller);
+    anyhow::ensure!(
+        src.checked_add(size).is_some_and(|end| end <= data.len()),
+        "{function}: out-of-bounds read from plugin memory"
+    );
+    Ok(data[src..src + size].to_vec())
+}
+
+/// Time since the Unix epoch, zero if the system clock is set before it
+fn unix_time() -> Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Check if the plugin imports one of the `lunatic_plugin` clock functions
+fn reads_clock(plugin: &Plugin) -> bool {
+    plugin.module.imports().any(|import| {
+        import.module() == "lunatic_plugin"
+            && matches!(
+                import.name(),
+                "host_time_unix_seconds" | "host_time_unix_nanos"
+            )
+    })
+}
+
+/// Read the constant returned by the `lunatic_plugin_abi_version` function export,
+/// or 1 if there is no such export
+fn declared_abi_version(wasm: &[u8]) -> Result<i32> {
+    let mut types = Vec::new();
+    let mut imported_functions = 0;
+    let mut function_types = Vec::new();
+    let mut export = None;
+    let mut bodies = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        match payload? {
+            wasmparser::Payload::TypeSection(reader) => {
+                for group in reader {
+                    types.extend(group?.into_types());
+                }
+            }
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    if matches!(import?.ty, wasmparser::TypeRef::Func(_)) {
+                        imported_functions += 1;
+                    }
+                }
+            }
+            wasmparser::Payload::FunctionSection(reader) => {
+                for ty in reader {
+                    function_types.push(ty?);
+                }
+            }
+            wasmparser::Payload::ExportSection(reader) => {
+                for item in reader {
+                    let item = item?;
+                    if item.name == "lunatic_plugin_abi_version" {
+                        export = Some((item.kind, item.index));
+                    }
+                }
+            }
+            wasmparser::Payload::CodeSectionEntry(body) => bodies.push(body),
+            _ => {}
+        }
+    }
+
+    let Some((kind, index)) = export else {
+        return Ok(1);
+    };
+    let defined = match kind {
+        wasmparser::ExternalKind::Func => index.checked_sub(imported_functions),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        anyhow!("`lunatic_plugin_abi_version` must be a function defined by the plugin")
+    })?;
+    let signature = function_types
+        .get(defined as usize)
+        .and_then(|&ty| types.get(ty as usize))
+        .and_then(|ty| match &ty.composite_type.inner {
+            wasmparser::CompositeInnerType::Func(ty) => Some(ty),
+            _ => None,
+        });
+    if !signature
+        .is_some_and(|ty| ty.params().is_empty() && ty.results() == [wasmparser::ValType::I32])
+    {
+        return Err(anyhow!(
+            "`lunatic_plugin_abi_version` must be a function without parameters returning an i32"
+        ));
+    }
+    let body = &bodies[defined as usize];
+    let mut operators = body.get_operators_reader()?;
+    let operators = [operators.read()?, operators.read()?];
+    match (body.get_locals_reader()?.get_count(), operators) {
+        (
+            0,
+            [
+                wasmparser::Operator::I32Const { value },
+                wasmparser::Operator::End,
+            ],
+        ) => Ok(value),
+        _ => Err(anyhow!(
+            "`lunatic_plugin_abi_version` must only return a constant, e.g. `i32.const 1`"
+        )),
+    }
+}
+
+/// Engine that plugins are compiled for and run on
+fn plugin_engine(cache: Option<Cache>) -> Result<Engine> {
+    let mut config = wasmtime::Config::new();
+    config.async_support(false);
+    // Lets a cancelled transform interrupt a plugin that is still running
+    config.epoch_interruption(true);
+    config.cache(cache);
+    Engine::new(&config)
+}
+
+fn run_native_transform(transform: &NativeTransform, input: &[u8]) -> Result<Vec<u8>> {
+    transform(input).context("Native transform failed")
+}
+
+/// Extract a readable message from a panic payload
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Check if a fully-qualified function name matches a namespace filter
+pub fn namespace_matches_filter(namespace: &str, name: &str, filter: &[String]) -> bool {
+    let full_name = format!("{namespace}::{name}");
+    filter.iter().any(|allowed| full_name.starts_with(allowed))
+}
+
+/// Check that every import of `wasm` is in one of the allowed namespaces, before spawning it.
+///
+/// Errors if the module can't be parsed, otherwise returns the (module, name) pairs of the
+/// imports that no namespace in `allowed_namespaces` matches, in module order. Namespaces are
+/// matched with [`namespace_matches_filter`].
+pub fn validate_imports(
+    wasm: &[u8],
+    allowed_namespaces: &[String],
+) -> Result<std::result::Result<(), Vec<(String, String)>>> {
+    let mut unsatisfied = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::ImportSection(reader) = payload? {
+            for import in reader {
+                let import = import?;
+                if !namespace_matches_filter(import.module, import.name, allowed_namespaces) {
+                    unsatisfied.push((import.module.to_string(), import.name.to_string()));
+                }
+            }
+        }
+    }
+    if unsatisfied.is_empty() {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(unsatisfied))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_plugin::tests::{appender_wat, plugin_info};
+
+    #[test]
+    fn test_namespace_matches_filter() {
+        let filter = vec![
+            "lunatic::".to_string(),
+            "wasi_snapshot_preview1".to_string(),
+        ];
+        assert!(namespace_matches_filter(
+            "lunatic::process",
+            "spawn",
+            &filter
+        ));
+        assert!(namespace_matches_filter(
+            "wasi_snapshot_preview1",
+            "fd_read",
+            &filter
+        ));
+        assert!(!namespace_matches_filter("custom", "foo", &filter));
+    }
+
+    #[test]
+    fn test_validate_imports() {
+        let filter = vec![
+            "lunatic::".to_string(),
+            "wasi_snapshot_preview1".to_string(),
+        ];
+        let wasm = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "spawn" (func))
+                (import "host_fs" "read_secret" (func))
+                (import "wasi_snapshot_preview1" "fd_write" (func))
+                (import "env" "memory" (memory 1))
+            )
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            validate_imports(&wasm, &filter).unwrap(),
+            Err(vec![
+                ("host_fs".to_string(), "read_secret".to_string()),
+                ("env".to_string(), "memory".to_string()),
+            ])
+        );
+
+        let wasm =
+            wat::parse_str(r#"(module (import "lunatic::process" "spawn" (func)))"#).unwrap();
+        assert_eq!(validate_imports(&wasm, &filter).unwrap(), Ok(()));
+        assert!(validate_imports(b"not wasm", &filter).is_err());
+    }
+
+    #[test]
+    fn test_empty_registry() {
+        let registry = PluginRegistry::new();
+        assert!(registry.is_empty());
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[test]
+    fn test_registry_register_and_get() {
+        let registry = PluginRegistry::new();
+        // We can't create a real wasmtime::Module without an engine, so we test
+        // the registry logic through type system and public API structure
+        assert!(registry.get("test-plugin").is_none());
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[test]
+    fn test_register_wasm() {
+        let mut registry = PluginRegistry::new();
+        let info = PluginInfo {
+            name: "test".into(),
+            version: semver::Version::new(0, 1, 0),
+            capabilities: vec![Capability::LifecycleHooks],
+            dependencies: vec![],
+        };
+        registry.register_wat(info, "(module)").unwrap();
+        assert_eq!(registry.len(), 1);
+        assert!(registry.get("test").is_some());
+    }
+
+    #[test]
+    fn test_register_wasm_invalid_module() {
+        let mut registry = PluginRegistry::new();
+        let info = PluginInfo {
+            name: "bad".into(),
+            version: semver::Version::new(0, 1, 0),
+            capabilities: vec![],
+            dependencies: vec![],
+        };
+        let result = registry.register_wasm(info, b"not valid wasm");
+        assert!(result.is_err());
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[test]
+    fn test_transform_module_no_plugins() {
+        let registry = PluginRegistry::new();
+        let input = b"some module bytes";
+        let output = registry.transform_module(input).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_transform_module_passthrough_plugin() {
+        let mut registry = PluginRegistry::new();
+        let wat = r#"
+            (module
+                (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
+                (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
+                (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
+                (memory (export "memory") 1)
+                (func (export "lunatic_transform_module")
+                    (local $size i32)
+                    (local.set $size (call $input_size))
+                    (call $read_input (i32.const 0))
+                    (call $write_output (i32.const 0) (local.get $size))
+                )
+            )
+        "#;
+        let info = PluginInfo {
+            name: "passthrough".into(),
+            version: semver::Version::new(0, 1, 0),
+            capabilities: vec![Capability::ModuleTransform],
+            dependencies: vec![],
+        };
+        registry.register_wat(info, wat).unwrap();
+
+        let input = b"hello wasm world";
+        let output = registry.transform_module(input).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_transform_module_plugin_no_export() {
+        let mut registry = PluginRegistry::new();
+        let info = PluginInfo {
+            name: "no-transform-export".into(),
+            version: semver::Version::new(0, 1, 0),
+            capabilities: vec![Capability::ModuleTransform],
+            dependencies: vec![],
+        };
+        registry.register_wat(info, "(module)").unwrap();
+
+        let input = b"original bytes";
+        let output = registry.transform_module(input).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_transform_module_chained_plugins() {
+        let mut registry = PluginRegistry::new();
+        let wat = r#"
+            (module
+                (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
+                (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
+                (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
+                (memory (export "memory") 1)
+                (func (export "lunatic_transform_module")
+                    (local $size i32)
+                    (local.set $size (call $input_size))
+                    (call $read_input (i32.const 0))
+                    (call $write_output (i32.const 0) (local.get $size))
+                )
+            )
+        "#;
+
+        let info1 = PluginInfo {
+            name: "passthrough1".into(),
+            version: semver::Version::new(0, 1, 0),
+            capabilities: vec![Capability::ModuleTransform],
+            dependencies: vec![],
+        };
+        registry.register_wat(info1, wat).unwrap();
+
+        let info2 = PluginInfo {
+            name: "passthrough2".into(),
+            version: semver::Version::new(0, 2, 0),
+            capabilities: vec![Capability::ModuleTransform],
+            dependencies: vec![],
+        };
+        registry.register_wat(info2, wat).unwrap();
+
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.module_transform_plugins().len(), 2);
+
+        let input = b"chained transform input";
+        let output = registry.transform_module(input).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_transform_module_isolated_trap_returns_err() {
+        let mut registry = PluginRegistry::new();
+        let wat = r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "lunatic_transform_module")
+                    (unreachable)
+                )
+            )
+        "#;
+        let info = plugin_info("trapper", &[Capability::ModuleTransform]);
+        registry.register_wat(info, wat).unwrap();
+
+        let result = registry.transform_module_isolated(b"input");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_transform_module_isolated_passthrough() {
+        let mut registry = PluginRegistry::new();
+        let info = plugin_info("passthrough", &[Capability::ModuleTransform]);
+        registry.register_wat(info, &appender_wat(b"")).unwrap();
+
+        let input = b"isolated input";
+        let output = registry.transform_module_isolated(input).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let payload = std::panic::catch_unwind(|| panic!("boom")).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "boom");
+        let payload = std::panic::catch_unwind(|| panic!("{}", 42)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "42");
+    }
+
+    #[test]
+    fn test_disable_transform_plugin_mid_chain() {
+        let mut registry = PluginRegistry::new();
+        for (name, byte) in [
+            ("append-aa", 0xAA),
+            ("append-bb", 0xBB),
+            ("append-cc", 0xCC),
+        ] {
+            registry
+                .register_wat(
+                    plugin_info(name, &[Capability::ModuleTransform]),
+                    &appender_wat(&[byte]),
+                )
+                .unwrap();
+        }
+
+        registry.set_enabled("append-bb", false).unwrap();
+        assert!(!registry.is_enabled("append-bb"));
+        assert_eq!(registry.module_transform_plugins().len(), 3);
+        let output = registry.transform_module(b"data").unwrap();
+        assert_eq!(&output, b"data\xAA\xCC");
+
+        registry.set_enabled("append-bb", true).unwrap();
+        assert!(registry.is_enabled("append-bb"));
+        let output = registry.transform_module(b"data").unwrap();
+        assert_eq!(&output, b"data\xAA\xBB\xCC");
+    }
+
+    #[test]
+    fn test_transform_pipeline_matches_applied_order() {
+        let mut registry = PluginRegistry::new();
+        for (name, minor, byte) in [("first", 1, 0x01), ("second", 2, 0x02), ("third", 3, 0x03)] {
+            registry
+                .register_wat(
+                    PluginInfo {
+                        version: semver::Version::new(1, minor, 0),
+                        ..plugin_info(name, &[Capability::ModuleTransform])
+                    },
+                    &appender_wat(&[byte]),
+                )
+                .unwrap();
+        }
+
+        let names = |registry: &PluginRegistry| -> Vec<String> {
+            registry
+                .transform_pipeline()
+                .into_iter()
+                .map(|(name, _)| name.to_string())
+                .collect()
+        };
+        let pipeline = registry.transform_pipeline();
+        assert_eq!(pipeline[1], ("second", &semver::Version::new(1, 2, 0)));
+        assert_eq!(names(&registry), ["first", "second", "third"]);
+        // Each plugin appends its own marker, so the output spells out the applied order
+        assert_eq!(registry.transform_module(b"").unwrap(), [0x01, 0x02, 0x03]);
+
+        registry.set_enabled("first", false).unwrap();
+        assert_eq!(names(&registry), ["second", "third"]);
+        assert_eq!(registry.transform_module(b"").unwrap(), [0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_merge_appends_other_registry() {
+        let marker_plugin = |registry: &mut PluginRegistry, name: &str, byte: u8| {
+            registry.register_wat(
+                PluginInfo {
+                    dependencies: vec![PluginDependency {
+                        name: "base".into(),
+                        version_req: semver::VersionReq::parse("^0.1").unwrap(),
+                    }],
+                    ..plugin_info(name, &[Capability::ModuleTransform])
+                },
+                &appender_wat(&[byte]),
+            )
+        };
+
+        let mut base = PluginRegistry::new();
+        base.register_wat(plugin_info("base", &[]), "(module)")
+            .unwrap();
+        marker_plugin(&mut base, "base-transform", 0x01).unwrap();
+
+        let mut tenant = PluginRegistry::new();
+        marker_plugin(&mut tenant, "tenant-first", 0x02).unwrap();
+        marker_plugin(&mut tenant, "tenant-second", 0x03).unwrap();
+        tenant.set_enabled("tenant-first", false).unwrap();
+
+        // Fails as a whole if a dependency is missing from both registries
+        let mut other = PluginRegistry::new();
+        marker_plugin(&mut other, "orphan", 0x04).unwrap();
+        assert!(PluginRegistry::new().merge(other).is_err());
+
+        base.merge(tenant).unwrap();
+        let names: Vec<_> = base
+            .module_transform_plugins()
+            .iter()
+            .map(|plugin| plugin.info.name.as_str())
+            .collect();
+        assert_eq!(names, ["base-transform", "tenant-first", "tenant-second"]);
+        assert!(!base.is_enabled("tenant-first"));
+        assert_eq!(base.transform_module(b"").unwrap(), [0x01, 0x03]);
+
+        // Names must not clash
+        let mut clashing = PluginRegistry::new();
+        marker_plugin(&mut clashing, "tenant-second", 0x05).unwrap();
+        assert!(base.merge(clashing).is_err());
+        assert_eq!(base.len(), 4);
+
+        // A plugin failing to link for lifecycle events after recompiling leaves
+        // the earlier plugins unregistered as well
+        let mut broken = PluginRegistry::new();
+        for name in ["linkable", "unlinkable"] {
+            broken
+                .register_wat(plugin_info(name, &[Capability::LifecycleHooks]), "(module)")
+                .unwrap();
+        }
+        broken.sources[1].1 =
+            wat::parse_str(r#"(module (import "env" "missing" (func)))"#).unwrap();
+        let err = base.merge(broken).unwrap_err();
+        assert!(err.to_string().contains("unlinkable"), "{err}");
+        assert_eq!(base.len(), 4);
+        assert!(base.get("linkable").is_none());
+    }
+
+    #[test]
+    fn test_passthrough_then_append_output() {
+        let mut registry = PluginRegistry::new();
+        registry
+            .register_wat(
+                plugin_info("appender", &[Capability::ModuleTransform]),
+                &appender_wat(b"MARK"),
+            )
+            .unwrap();
+
+        assert_eq!(registry.transform_module(b"input").unwrap(), b"inputMARK");
+
+        registry.set_max_output_size(Some(8));
+        let err = registry.transform_module(b"input").unwrap_err();
+        assert!(format!("{err:#}").contains("append_output"), "{err:#}");
+    }
+
+    #[test]
+    fn test_sdk_templates() {
+        let mut registry = PluginRegistry::new();
+        let info = |name: &str, capability| plugin_info(name, &[capability]);
+        registry
+            .register_wasm(
+                info("passthrough", Capability::ModuleTransform),
+                &lunatic_plugin_sdk::templates::passthrough_transform(),
+            )
+            .unwrap();
+        registry
+            .register_wasm(
+                info("noop", Capability::LifecycleHooks),
+                &lunatic_plugin_sdk::templates::noop_lifecycle(),
+            )
+            .unwrap();
+
+        let input = b"hello wasm world";
+        assert_eq!(registry.transform_module(input).unwrap(), input);
+        assert_eq!(registry.lifecycle_dispatcher().plugin_count(), 1);
+        registry
+            .lifecycle_dispatcher()
+            .dispatch(&LifecycleEvent::ProcessExited {
+                process_id: 1,
+                reason: ExitReason::Normal,
+            });
+    }
+
+    #[test]
+    fn test_register_wat_reports_syntax_errors() {
+        let mut registry = PluginRegistry::new();
+        let info = || plugin_info("broken", &[Capability::ModuleTransform]);
+        let err = registry
+            .register_wat(info(), "(module (func (export \"f\")")
+            .unwrap_err();
+        assert!(err.downcast_ref::<wat::Error>().is_some(), "{err:#}");
+        assert!(err.to_string().contains("WAT of plugin 'broken'"), "{err}");
+
+        registry.register_wat(info(), "(module)").unwrap();
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn test_register_wasm_detects_binary_and_text() {
+        let mut registry = PluginRegistry::new();
+        let info = |name: &str| plugin_info(name, &[Capability::LifecycleHooks]);
+
+        // Neither format
+        let err = registry
+            .register_wasm(info("garbage"), b"not valid wasm")
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("neither valid Wasm binary nor valid WAT text"),
+            "{err}"
+        );
+
+        // Text with a syntax error reports the WAT error too
+        let err = registry
+            .register_wasm(info("broken"), b"(module (func (export \"f\")")
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("nor valid WAT text"), "{message}");
+        assert!(message.contains("expected `)`"), "{message}");
+
+        // The magic header commits to the binary format
+        let err = registry
+            .register_wasm(info("truncated"), b"\0asm\x01\0")
+            .unwrap_err();
+        assert!(!err.to_string().contains("WAT"), "{err}");
+        assert_eq!(registry.len(), 0);
+
+        let binary = wat::parse_str("(module)").unwrap();
+        registry.register_wasm(info("binary"), &binary).unwrap();
+        registry.register_wasm(info("text"), b"(module)").unwrap();
+        assert_eq!(registry.len(), 2);
+        // Text is kept in its binary form, so it can be recompiled by `merge`
+        let mut merged = PluginRegistry::new();
+        merged.merge(registry).unwrap();
+        assert!(merged.get("text").is_some());
+    }
+
+    #[test]
+    fn test_pipeline_fingerprint() {
+        let mut registry = PluginRegistry::new();
+        let info = |name: &str| plugin_info(name, &[Capability::ModuleTransform]);
+        let empty = registry.pipeline_fingerprint().unwrap();
+        registry.register_wat(info("first"), "(module)").unwrap();
+        registry
+            .register_wat(
+                info("second"),
+                r#"(module (func (export "lunatic_transform_module")))"#,
+            )
+            .unwrap();
+        let both = registry.pipeline_fingerprint().unwrap();
+        assert_ne!(both, empty);
+
+        registry.set_enabled("first", false).unwrap();
+        let disabled = registry.pipeline_fingerprint().unwrap();
+        assert_ne!(disabled, both);
+        registry.set_enabled("first", true).unwrap();
+        assert_eq!(registry.pipeline_fingerprint().unwrap(), both);
+
+        registry
+            .reload_wasm("first", &wat::parse_str("(module (memory 1))").unwrap())
+            .unwrap();
+        assert_ne!(registry.pipeline_fingerprint().unwrap(), both);
+    }
+
+    #[test]
+    fn test_enable_cache_reuses_compiled_modules() {
+        let dir =
+            std::env::temp_dir().join(format!("lunatic-plugin-cache-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut registry = PluginRegistry::new();
+        registry.enable_cache(&dir).unwrap();
+        let info = |name: &str| plugin_info(name, &[Capability::ModuleTransform]);
+        let wasm =
+            wat::parse_str(r#"(module (func (export "lunatic_transform_module")))"#).unwrap();
+        registry.register_wasm(info("first"), &wasm).unwrap();
+        registry.register_wasm(info("second"), &wasm).unwrap();
+
+        let cache = registry.compilation_cache().unwrap();
+        assert_eq!(cache.cache_misses(), 1);
+        assert_eq!(cache.cache_hits(), 1);
+        assert_eq!(registry.transform_module(b"input").unwrap(), b"input");
+
+        let err = registry.enable_cache(&dir).unwrap_err();
+        assert!(err.to_string().contains("before registering"), "{err}");
+        // The cache worker may still be writing its statistics
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_native_and_wasm_transforms_run_in_order() {
+        let mut registry = PluginRegistry::new();
+        // Parses the module, so it fails if it runs after the appender
+        registry.add_native_transform(WasiShimTransform::preview1().into_native_transform());
+        registry
+            .register_wat(
+                plugin_info("appender", &[Capability::ModuleTransform]),
+                &appender_wat(b"\xAA"),
+            )
+            .unwrap();
+        // Only accepts the output of the appender
+        registry.add_native_transform(Arc::new(|bytes| match bytes.split_last() {
+            Some((0xAA, module)) => Ok(module.to_vec()),
+            _ => Err(anyhow!("missing appended byte")),
+        }));
+
+        let module =
+            wat::parse_str(r#"(module (import "wasi_unstable" "proc_exit" (func (param i32))))"#)
+                .unwrap();
+        let output = registry.transform_module(&module).unwrap();
+        let ctx = ModuleContext::new(&output).unwrap();
+        assert_eq!(
+            ctx.imported_namespaces().into_iter().collect::<Vec<_>>(),
+            ["wasi_snapshot_preview1"]
+        );
+        assert_eq!(registry.transform_module_isolated(&module).unwrap(), output);
+
+        // With the appender disabled the last transform fails
+        registry.set_enabled("appender", false).unwrap();
+        let err = registry.transform_module(&module).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("missing appended byte"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn test_native_and_wasm_transforms_follow_priority() {
+        let mut registry = PluginRegistry::new();
+        registry
+            .register_wat(
+                plugin_info("marker", &[Capability::ModuleTransform]),
+                &appender_wat(b"-marked"),
+            )
+            .unwrap();
+        registry.add_native_transform_with_priority(
+            Arc::new(|bytes| Ok(bytes.to_ascii_uppercase())),
+            -1,
+        );
+        assert_eq!(registry.transform_priority("marker"), Some(0));
+        assert_eq!(registry.transform_module(b"data").unwrap(), b"DATA-marked");
+
+        registry.set_transform_priority("marker", -2).unwrap();
+        assert_eq!(registry.transform_module(b"data").unwrap(), b"DATA-MARKED");
+        assert_eq!(
+            registry.transform_module_isolated(b"data").unwrap(),
+            b"DATA-MARKED"
+        );
+
+        assert!(registry.set_transform_priority("unknown", 1).is_err());
+    }
+
+    #[test]
+    fn test_unregister_respects_dependencies() {
+        let mut registry = PluginRegistry::new();
+        let info = |name: &str, dependencies| PluginInfo {
+            dependencies,
+            ..plugin_info(name, &[Capability::HostFunctions("env".into())])
+        };
+        registry
+            .register_wat(info("base", vec![]), "(module)")
+            .unwrap();
+        let dependency = PluginDependency {
+            name: "base".into(),
+            version_req: semver::VersionReq::parse("^0.1").unwrap(),
+        };
+        registry
+            .register_wat(info("extension", vec![dependency]), "(module)")
+            .unwrap();
+
+        let err = registry.unregister("base").unwrap_err();
+        assert!(err.to_string().contains("required by 'extension'"), "{err}");
+        registry.unregister("extension").unwrap();
+        assert_eq!(registry.host_function_plugins("env").unwrap().len(), 1);
+        registry.unregister("base").unwrap();
+        assert!(registry.host_function_plugins("env").is_none());
+        assert!(registry.is_empty());
+        assert!(registry.unregister("base").is_err());
+    }
+
+    #[test]
+    fn test_export_then_register_elsewhere() {
+        let appender = |byte: u8| wat::parse_str(appender_wat(&[byte])).unwrap();
+        let info = |name: &str| plugin_info(name, &[Capability::ModuleTransform]);
+        let mut registry = PluginRegistry::new();
+        registry
+            .register_wasm(info("first"), &appender(b'1'))
+            .unwrap();
+        registry
+            .register_wasm(info("second"), &appender(b'2'))
+            .unwrap();
+
+        let exported = registry.export().unwrap();
+        let names: Vec<_> = exported
+            .iter()
+            .map(|(info, _)| info.name.as_str())
+            .collect();
+        assert_eq!(names, ["first", "second"]);
+        let mut imported = PluginRegistry::new();
+        for (info, wasm) in exported {
+            imported.register_wasm(info, &wasm).unwrap();
+        }
+        assert_eq!(
+            imported.transform_module(b"data").unwrap(),
+            registry.transform_module(b"data").unwrap()
+        );
+        assert_eq!(imported.transform_module(b"data").unwrap(), b"data12");
+
+        let module = Module::new(registry.engine(), appender(b'3')).unwrap();
+        registry
+            .register(Plugin {
+                info: info("third"),
+                module,
+            })
+            .unwrap();
+        assert!(registry.export().is_err());
+    }
+
+    #[test]
+    fn test_transform_deadline_stops_chain() {
+        // Spins forever without calling back into the host
+        let spinning_wat = r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "lunatic_transform_module")
+                    (loop $spin (br $spin))
+                )
+            )
+        "#;
+        let mut registry = PluginRegistry::new();
+        for _ in 0..3 {
+            registry.add_native_transform_with_priority(
+                Arc::new(|bytes| {
+                    std::thread::sleep(Duration::from_millis(20));
+                    Ok(bytes.to_vec())
+                }),
+                -1,
+            );
         }
+        registry
+            .register_wat(
+                plugin_info("spinner", &[Capability::ModuleTransform]),
+                spinning_wat,
+            )
+            .unwrap();
 
-        Ok(current_bytes)
+        // The slow native transforms finish, the spinning plugin is interrupted
+        registry.set_transform_deadline(Some(Duration::from_millis(500)));
+        let start = Instant::now();
+        let err = registry.transform_module(b"data").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DeadlineExceeded>(),
+            Some(&DeadlineExceeded { step: 3 }),
+            "{err:?}"
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // An interruption isn't a plugin failure
+        assert!(registry.last_errors().is_empty());
+
+        // Trips between the native transforms, before the spinner starts
+        registry.set_transform_deadline(Some(Duration::from_millis(30)));
+        let err = registry.transform_module(b"data").unwrap_err();
+        let step = err.downcast_ref::<DeadlineExceeded>().unwrap().step;
+        assert!((1..3).contains(&step), "{step}");
     }
 
-    /// Check if any plugins are registered
-    pub fn is_empty(&self) -> bool {
-        self.plugins.is_empty()
+    #[test]
+    fn test_transform_reads_registry_parameters() {
+        // Appends the value of the "arch" parameter, if set, to the module
+        let wat = r#"
+            (module
+                (import "lunatic_plugin" "passthrough" (func $passthrough))
+                (import "lunatic_plugin" "append_output" (func $append_output (param i32 i32)))
+                (import "lunatic_plugin" "param_size" (func $param_size (param i32 i32) (result i32)))
+                (import "lunatic_plugin" "param_get"
+                    (func $param_get (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "arch")
+
+                (func (export "lunatic_transform_module")
+                    (local $len i32)
+                    (call $passthrough)
+                    (if (i32.lt_s (call $param_size (i32.const 0) (i32.const 4)) (i32.const 0))
+                        (then (return)))
+                    (local.set $len (call $param_get (i32.const 0) (i32.const 4) (i32.const 64)))
+                    (call $append_output (i32.const 64) (local.get $len))
+                )
+            )
+        "#;
+        let mut registry = PluginRegistry::new();
+        registry
+            .register_wat(plugin_info("arch", &[Capability::ModuleTransform]), wat)
+            .unwrap();
+        assert_eq!(registry.transform_module(b"mod:").unwrap(), b"mod:");
+
+        registry.set_parameter("arch", "x86_64");
+        assert_eq!(registry.parameter("arch"), Some("x86_64"));
+        assert_eq!(registry.transform_module(b"mod:").unwrap(), b"mod:x86_64");
+        // Changing a parameter invalidates cached results
+        registry.set_parameter("arch", "aarch64");
+        assert_eq!(registry.transform_module(b"mod:").unwrap(), b"mod:aarch64");
+
+        assert_eq!(
+            registry.remove_parameter("arch").as_deref(),
+            Some("aarch64")
+        );
+        assert_eq!(registry.transform_module(b"mod:").unwrap(), b"mod:");
     }
 
-    /// Number of registered plugins
-    pub fn len(&self) -> usize {
-        self.plugins.len()
+    #[test]
+    fn test_set_enabled_unknown_plugin() {
+        let mut registry = PluginRegistry::new();
+        assert!(registry.set_enabled("missing", false).is_err());
+        assert!(!registry.is_enabled("missing"));
     }
-}
 
-/// Trait for process states that support plugins.
-/// Implemented by DefaultProcessState in the root crate.
-pub trait PluginCtx {
-    fn plugin_registry(&self) -> &Arc<PluginRegistry>;
-}
+    #[test]
+    fn test_max_output_size_names_plugin() {
+        // Grows the 4 byte input to 1024 bytes
+        let wat = appender_wat(&[0; 1020]);
+        let mut registry = PluginRegistry::new();
+        assert_eq!(registry.max_output_size(), None);
+        registry
+            .register_wat(plugin_info("balloon", &[Capability::ModuleTransform]), &wat)
+            .unwrap();
 
-/// Check if a fully-qualified function name matches a namespace filter
-pub fn namespace_matches_filter(namespace: &str, name: &str, filter: &[String]) -> bool {
-    let full_name = format!("{namespace}::{name}");
-    filter.iter().any(|allowed| full_name.starts_with(allowed))
-}
+        // Unlimited by default
+        assert_eq!(registry.transform_module(b"tiny").unwrap().len(), 1024);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        registry.set_max_output_size(Some(16));
+        let err = registry.transform_module(b"tiny").unwrap_err();
+        assert!(format!("{err:#}").contains("balloon"));
+    }
 
     #[test]
-    fn test_namespace_matches_filter() {
-        let filter = vec![
-            "lunatic::".to_string(),
-            "wasi_snapshot_preview1".to_string(),
+    fn test_max_output_size_checked_between_steps() {
+        let passthrough = r#"
+            (module
+                (import "lunatic_plugin" "passthrough" (func $passthrough))
+                (func (export "lunatic_transform_module") (call $passthrough))
+            )
+        "#;
+        let mut registry = PluginRegistry::new();
+        registry.add_native_transform_with_priority(Arc::new(|input| Ok(input.repeat(8))), -1);
+        registry
+            .register_wat(
+                plugin_info("passthrough", &[Capability::ModuleTransform]),
+                passthrough,
+            )
+            .unwrap();
+        registry.set_max_output_size(Some(16));
+
+        // The native transform grows the module past the limit before the plugin runs
+        let cancel = AtomicBool::new(false);
+        let errors = [
+            registry.transform_module(b"tiny").unwrap_err(),
+            registry.transform_module_isolated(b"tiny").unwrap_err(),
+            registry
+                .transform_module_cancellable(b"tiny", &cancel)
+                .unwrap_err(),
         ];
-        assert!(namespace_matches_filter(
-            "lunatic::process",
-            "spawn",
-            &filter
-        ));
-        assert!(namespace_matches_filter(
-            "wasi_snapshot_preview1",
-            "fd_read",
-            &filter
-        ));
-        assert!(!namespace_matches_filter("custom", "foo", &filter));
+        for err in errors {
+            assert!(err.to_string().contains("Native transform 0"), "{err}");
+        }
+
+        // Input that already exceeds the limit can't be passed through either
+        let mut registry = PluginRegistry::new();
+        registry
+            .register_wat(
+                plugin_info("passthrough", &[Capability::ModuleTransform]),
+                passthrough,
+            )
+            .unwrap();
+        registry.set_max_output_size(Some(16));
+        let err = registry.transform_module(&[0; 32]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("passthrough: output of 32 bytes"),
+            "{err:#}"
+        );
     }
 
     #[test]
-    fn test_empty_registry() {
-        let registry = PluginRegistry::new();
-        assert!(registry.is_empty());
-        assert_eq!(registry.len(), 0);
+    fn test_transform_plugin_skip() {
+        // Replaces the module with "DONE" only if it starts with the 0x7F marker,
+        // otherwise opts out via `skip`.
+        let wat = r#"
+            (module
+                (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
+                (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
+                (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
+                (import "lunatic_plugin" "skip" (func $skip))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "DONE")
+                (func (export "lunatic_transform_module")
+                    (call $read_input (i32.const 16))
+                    (if (i32.ne (i32.load8_u (i32.const 16)) (i32.const 0x7F))
+                        (then
+                            ;; Output written before skipping is discarded
+                            (call $write_output (i32.const 0) (i32.const 4))
+                            (call $skip)
+                            (return)))
+                    (call $write_output (i32.const 0) (i32.const 4))
+                )
+            )
+        "#;
+        let mut registry = PluginRegistry::new();
+        registry
+            .register_wat(
+                plugin_info("conditional", &[Capability::ModuleTransform]),
+                wat,
+            )
+            .unwrap();
+
+        let plain = b"plain module";
+        assert_eq!(registry.transform_module(plain).unwrap(), plain);
+
+        let marked = b"\x7Fmarked module";
+        assert_eq!(registry.transform_module(marked).unwrap(), b"DONE");
     }
 
     #[test]
-    fn test_registry_register_and_get() {
-        let registry = PluginRegistry::new();
-        // We can't create a real wasmtime::Module without an engine, so we test
-        // the registry logic through type system and public API structure
-        assert!(registry.get("test-plugin").is_none());
-        assert_eq!(registry.len(), 0);
+    fn test_call_host_function() {
+        let mut registry = PluginRegistry::new();
+        let info = |name: &str| plugin_info(name, &[Capability::HostFunctions("math".into())]);
+        registry
+            .register_wat(
+                info("adder"),
+                r#"(module (func (export "add") (param i32 i32) (result i32)
+                    (i32.add (local.get 0) (local.get 1))))"#,
+            )
+            .unwrap();
+        registry
+            .register_wat(
+                info("shadowed"),
+                r#"(module (func (export "add") (param i32 i32) (result i32)
+                    (i32.const -1)))"#,
+            )
+            .unwrap();
+
+        // The first registered plugin exporting `add` wins
+        let results = registry
+            .call_host_function("math", "add", &[Val::I32(2), Val::I32(3)])
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].unwrap_i32(), 5);
+
+        registry.set_enabled("adder", false).unwrap();
+        let results = registry
+            .call_host_function("math", "add", &[Val::I32(2), Val::I32(3)])
+            .unwrap();
+        assert_eq!(results[0].unwrap_i32(), -1);
+
+        assert!(registry.call_host_function("math", "sub", &[]).is_err());
+        assert!(registry.call_host_function("other", "add", &[]).is_err());
+        // Wrong argument types are reported, not panicked on
+        assert!(
+            registry
+                .call_host_function("math", "add", &[Val::I64(2)])
+                .is_err()
+        );
     }
 
     #[test]
-    fn test_register_wasm() {
+    fn test_transform_module_cancellable() {
+        // Spins forever without calling back into the host
+        let spinning_wat = r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "lunatic_transform_module")
+                    (loop $spin (br $spin))
+                )
+            )
+        "#;
         let mut registry = PluginRegistry::new();
-        let info = PluginInfo {
-            name: "test".into(),
-            version: semver::Version::new(0, 1, 0),
-            capabilities: vec![Capability::LifecycleHooks],
-            dependencies: vec![],
-        };
-        registry.register_wasm(info, b"(module)").unwrap();
-        assert_eq!(registry.len(), 1);
-        assert!(registry.get("test").is_some());
+        registry
+            .register_wat(
+                plugin_info("spinner", &[Capability::ModuleTransform]),
+                spinning_wat,
+            )
+            .unwrap();
+
+        let cancel = AtomicBool::new(false);
+        let start = std::time::Instant::now();
+        let result = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                cancel.store(true, Ordering::Release);
+            });
+            registry.transform_module_cancellable(b"data", &cancel)
+        });
+        let err = result.unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some(), "{err:?}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Already cancelled: no plugin runs at all
+        assert!(
+            registry
+                .transform_module_cancellable(b"data", &cancel)
+                .unwrap_err()
+                .is::<Cancelled>()
+        );
     }
 
     #[test]
-    fn test_register_wasm_invalid_module() {
+    fn test_transform_module_cancellable_completes() {
         let mut registry = PluginRegistry::new();
-        let info = PluginInfo {
-            name: "bad".into(),
-            version: semver::Version::new(0, 1, 0),
-            capabilities: vec![],
-            dependencies: vec![],
-        };
-        let result = registry.register_wasm(info, b"not valid wasm");
-        assert!(result.is_err());
-        assert_eq!(registry.len(), 0);
+        registry
+            .register_wat(
+                plugin_info("noop", &[Capability::ModuleTransform]),
+                r#"(module (func (export "lunatic_transform_module")))"#,
+            )
+            .unwrap();
+        let cancel = AtomicBool::new(false);
+        let output = registry
+            .transform_module_cancellable(b"data", &cancel)
+            .unwrap();
+        assert_eq!(output, b"data");
+        // A cancellation in the engine doesn't affect other transforms
+        registry.engine().increment_epoch();
+        assert_eq!(registry.transform_module(b"data").unwrap(), b"data");
     }
 
     #[test]
-    fn test_transform_module_no_plugins() {
-        let registry = PluginRegistry::new();
-        let input = b"some module bytes";
-        let output = registry.transform_module(input).unwrap();
-        assert_eq!(output, input);
+    fn test_policy_rejects_over_privileged_plugin() {
+        let mut registry = PluginRegistry::new();
+        registry.set_policy(Some(CapabilityPolicy {
+            allowed: vec![Capability::LifecycleHooks, Capability::ModuleTransform],
+        }));
+
+        let spawner = plugin_info(
+            "spawner",
+            &[Capability::LifecycleHooks, Capability::ProcessSpawn],
+        );
+        assert!(spawner.requires_capability(&Capability::ProcessSpawn));
+        assert!(!spawner.requires_capability(&Capability::Networking));
+        assert_eq!(
+            registry.policy().unwrap().permits(&spawner),
+            Err(vec![Capability::ProcessSpawn])
+        );
+        let err = registry.register_wat(spawner, "(module)").unwrap_err();
+        assert!(err.to_string().contains("ProcessSpawn"), "{err}");
+        assert!(registry.is_empty());
+        assert_eq!(registry.lifecycle_dispatcher().plugin_count(), 0);
+
+        let observer = plugin_info("observer", &[Capability::LifecycleHooks]);
+        registry.register_wat(observer, "(module)").unwrap();
+        assert_eq!(
+            registry.get("observer").unwrap().capabilities_summary(),
+            "LifecycleHooks"
+        );
+
+        // Reloading is checked against the policy in place at that time
+        registry.set_policy(Some(CapabilityPolicy {
+            allowed: vec![Capability::ModuleTransform],
+        }));
+        let err = registry.reload_wasm("observer", b"(module)").unwrap_err();
+        assert!(err.to_string().contains("LifecycleHooks"), "{err}");
     }
 
     #[test]
-    fn test_transform_module_passthrough_plugin() {
+    fn test_register_rejects_unlinkable_lifecycle_plugin() {
         let mut registry = PluginRegistry::new();
+        let info = plugin_info("needs-import", &[Capability::LifecycleHooks]);
         let wat = r#"
             (module
-                (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
-                (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
-                (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
-                (memory (export "memory") 1)
-                (func (export "lunatic_transform_module")
-                    (local $size i32)
-                    (local.set $size (call $input_size))
-                    (call $read_input (i32.const 0))
-                    (call $write_output (i32.const 0) (local.get $size))
-                )
+                (import "env" "missing" (func))
+                (func (export "lunatic_on_process_spawned") (param i64))
             )
         "#;
-        let info = PluginInfo {
-            name: "passthrough".into(),
-            version: semver::Version::new(0, 1, 0),
-            capabilities: vec![Capability::ModuleTransform],
-            dependencies: vec![],
-        };
-        registry.register_wasm(info, wat.as_bytes()).unwrap();
+        let err = registry.register_wat(info, wat).unwrap_err();
+        assert!(err.to_string().contains("env::missing"), "{err}");
+        assert!(registry.get("needs-import").is_none());
+        assert_eq!(registry.lifecycle_dispatcher().plugin_count(), 0);
+        assert!(registry.last_errors().is_empty());
+    }
 
-        let input = b"hello wasm world";
-        let output = registry.transform_module(input).unwrap();
-        assert_eq!(output, input);
+    #[test]
+    fn test_last_errors_records_lifecycle_instantiation_failure() {
+        let mut registry = PluginRegistry::new();
+        let info = plugin_info("traps-on-start", &[Capability::LifecycleHooks]);
+        let wat = r#"
+            (module
+                (func $start unreachable)
+                (start $start)
+                (func (export "lunatic_on_process_spawned") (param i64))
+            )
+        "#;
+        registry.register_wat(info, wat).unwrap();
+        assert!(registry.last_errors().is_empty());
+
+        let event = LifecycleEvent::ProcessSpawned { process_id: 1 };
+        registry.lifecycle_dispatcher().dispatch(&event);
+        registry.lifecycle_dispatcher().dispatch(&event);
+
+        let errors = registry.last_errors();
+        assert_eq!(errors.len(), 2);
+        for error in &errors {
+            assert_eq!(error.plugin, "traps-on-start");
+            assert_eq!(error.operation, PluginOperation::Lifecycle);
+            assert!(error.message.contains("instantiate"), "{}", error.message);
+        }
     }
 
     #[test]
-    fn test_transform_module_plugin_no_export() {
+    fn test_register_rejects_newer_abi_version() {
         let mut registry = PluginRegistry::new();
-        let info = PluginInfo {
-            name: "no-transform-export".into(),
-            version: semver::Version::new(0, 1, 0),
-            capabilities: vec![Capability::ModuleTransform],
-            dependencies: vec![],
+        let info = |name: &str| plugin_info(name, &[Capability::ModuleTransform]);
+        // The start function would trap if the plugin was instantiated
+        let wat = |version: i32| {
+            format!(
+                r#"
+                (module
+                    (import "lunatic_plugin" "input_size" (func (result i32)))
+                    (func (export "lunatic_plugin_abi_version") (result i32)
+                        i32.const {version})
+                    (func $start unreachable)
+                    (start $start)
+                )
+            "#
+            )
         };
-        registry.register_wasm(info, b"(module)").unwrap();
 
-        let input = b"original bytes";
-        let output = registry.transform_module(input).unwrap();
-        assert_eq!(output, input);
+        let err = registry
+            .register_wat(info("future"), &wat(PLUGIN_ABI_VERSION + 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("ABI version"), "{err}");
+        assert!(registry.is_empty());
+
+        registry
+            .register_wat(info("current"), &wat(PLUGIN_ABI_VERSION))
+            .unwrap();
+        registry
+            .register_wat(info("unversioned"), "(module)")
+            .unwrap();
+        assert_eq!(registry.len(), 2);
+
+        // The version must be readable without running the plugin
+        for (name, wat) in [
+            (
+                "global",
+                r#"(module (global (export "lunatic_plugin_abi_version") i32 (i32.const 1)))"#,
+            ),
+            (
+                "computed",
+                r#"(module (func (export "lunatic_plugin_abi_version") (result i32)
+                    (i32.add (i32.const 1) (i32.const 0))))"#,
+            ),
+            (
+                "parameter",
+                r#"(module (func (export "lunatic_plugin_abi_version") (param i32) (result i32)
+                    i32.const 1))"#,
+            ),
+        ] {
+            let err = registry.register_wat(info(name), wat).unwrap_err();
+            assert!(
+                format!("{err:#}").contains("lunatic_plugin_abi_version"),
+                "{err:#}"
+            );
+        }
+        assert_eq!(registry.len(), 2);
     }
 
+    #[cfg(feature = "compression")]
     #[test]
-    fn test_transform_module_chained_plugins() {
+    fn test_register_compressed_wasm() {
+        use std::io::Write;
+
+        let wasm =
+            wat::parse_str(r#"(module (func (export "lunatic_on_process_spawned") (param i64)))"#)
+                .unwrap();
+        let info = |name: &str| plugin_info(name, &[Capability::LifecycleHooks]);
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&wasm).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(wasm.as_slice(), 0).unwrap();
+
+        let mut registry = PluginRegistry::new();
+        registry.register_wasm(info("gzip"), &gzip).unwrap();
+        registry.register_wasm(info("zstd"), &zstd).unwrap();
+        registry.register_wasm(info("plain"), &wasm).unwrap();
+        for name in ["gzip", "zstd", "plain"] {
+            let plugin = registry.get(name).unwrap();
+            assert!(
+                plugin
+                    .module
+                    .get_export("lunatic_on_process_spawned")
+                    .is_some()
+            );
+        }
+        assert!(registry.register_wasm(info("broken"), &gzip[..4]).is_err());
+
+        // Output past the limit is rejected instead of decompressed in full
+        let zeros = vec![0; 1025];
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&zeros).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(zeros.as_slice(), 0).unwrap();
+        for compressed in [&gzip, &zstd] {
+            assert_eq!(decompress(compressed, 1025).unwrap().len(), 1025);
+            let err = decompress(compressed, 1024).unwrap_err();
+            assert!(err.to_string().contains("exceeds 1024 bytes"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_transform_cache_hits_and_invalidates_on_reload() {
+        let info = plugin_info("appender", &[Capability::ModuleTransform]);
         let mut registry = PluginRegistry::new();
+        registry.register_wat(info, &appender_wat(b"a")).unwrap();
+
+        assert_eq!(registry.transform_module(b"x").unwrap(), b"xa");
+        assert_eq!(registry.transform_cache_len(), 1);
+        assert_eq!(registry.transform_module(b"x").unwrap(), b"xa");
+        assert_eq!(registry.transform_cache_len(), 1);
+        assert_eq!(registry.transform_module(b"y").unwrap(), b"ya");
+        assert_eq!(registry.transform_cache_len(), 2);
+
+        registry
+            .reload_wasm("appender", appender_wat(b"b").as_bytes())
+            .unwrap();
+        assert_eq!(registry.transform_cache_len(), 0);
+        assert_eq!(registry.transform_module(b"x").unwrap(), b"xb");
+        assert_eq!(registry.module_transform_plugins().len(), 1);
+
+        registry.set_enabled("appender", false).unwrap();
+        assert_eq!(registry.transform_module(b"x").unwrap(), b"x");
+        registry.set_enabled("appender", true).unwrap();
+        assert_eq!(registry.transform_module(b"x").unwrap(), b"xb");
+
+        registry.clear_transform_cache();
+        assert_eq!(registry.transform_cache_len(), 0);
+        assert!(registry.reload_wasm("missing", b"(module)").is_err());
+    }
+
+    #[test]
+    fn test_transform_clock() {
+        // Replaces the module with the 8 little-endian bytes of the current time
         let wat = r#"
             (module
-                (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
-                (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
                 (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
+                (import "lunatic_plugin" "host_time_unix_seconds" (func $seconds (result i64)))
+                (import "lunatic_plugin" "host_time_unix_nanos" (func $nanos (result i64)))
                 (memory (export "memory") 1)
                 (func (export "lunatic_transform_module")
-                    (local $size i32)
-                    (local.set $size (call $input_size))
-                    (call $read_input (i32.const 0))
-                    (call $write_output (i32.const 0) (local.get $size))
-                )
+                    (i64.store (i32.const 0) (call $seconds))
+                    (i64.store (i32.const 8) (call $nanos))
+                    (call $write_output (i32.const 0) (i32.const 16)))
             )
         "#;
-
-        let info1 = PluginInfo {
-            name: "passthrough1".into(),
-            version: semver::Version::new(0, 1, 0),
-            capabilities: vec![Capability::ModuleTransform],
-            dependencies: vec![],
-        };
-        registry.register_wasm(info1, wat.as_bytes()).unwrap();
-
-        let info2 = PluginInfo {
-            name: "passthrough2".into(),
-            version: semver::Version::new(0, 2, 0),
-            capabilities: vec![Capability::ModuleTransform],
-            dependencies: vec![],
+        let info = plugin_info("timestamp", &[Capability::ModuleTransform]);
+        let mut registry = PluginRegistry::new();
+        registry.register_wat(info, wat).unwrap();
+        let times = |output: Vec<u8>| {
+            let seconds = i64::from_le_bytes(output[..8].try_into().unwrap());
+            let nanos = i64::from_le_bytes(output[8..].try_into().unwrap());
+            (seconds, nanos)
         };
-        registry.register_wasm(info2, wat.as_bytes()).unwrap();
 
-        assert_eq!(registry.len(), 2);
-        assert_eq!(registry.module_transform_plugins().len(), 2);
+        registry.set_transform_clock(Some(1_700_000_000));
+        let output = registry.transform_module(b"module").unwrap();
+        assert_eq!(times(output), (1_700_000_000, 1_700_000_000_000_000_000));
 
-        let input = b"chained transform input";
-        let output = registry.transform_module(input).unwrap();
-        assert_eq!(output, input);
+        registry.set_transform_clock(None);
+        let (seconds, nanos) = times(registry.transform_module(b"module").unwrap());
+        assert!(seconds > 1_700_000_000);
+        assert_eq!(nanos / 1_000_000_000, seconds);
+        // The real time is never served from the cache
+        assert_eq!(registry.transform_cache_len(), 0);
     }
 
     // ---- Integration tests proving the plugin system works end-to-end ----
@@ -510,14 +3044,14 @@ mod tests {
 
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "marker-appender".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                wat.as_bytes(),
+                wat,
             )
             .unwrap();
 
@@ -562,26 +3096,26 @@ mod tests {
 
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "append-aa".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                appender_wat(0xAA).as_bytes(),
+                &appender_wat(0xAA),
             )
             .unwrap();
 
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "append-bb".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                appender_wat(0xBB).as_bytes(),
+                &appender_wat(0xBB),
             )
             .unwrap();
 
@@ -605,7 +3139,7 @@ mod tests {
                 (func (export "lunatic_on_process_spawning") (param i64))
                 (func (export "lunatic_on_process_spawned") (param i64))
                 (func (export "lunatic_on_process_exiting") (param i64))
-                (func (export "lunatic_on_process_exited") (param i64))
+                (func (export "lunatic_on_process_exited") (param i64 i32))
                 (func (export "lunatic_on_module_loading") (param i32 i32))
                 (func (export "lunatic_on_module_loaded") (param i32 i32))
             )
@@ -634,27 +3168,27 @@ mod tests {
 
         // Register lifecycle plugin
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "observer".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::LifecycleHooks],
                     dependencies: vec![],
                 },
-                lifecycle_wat.as_bytes(),
+                lifecycle_wat,
             )
             .unwrap();
 
         // Register transform plugin
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "transformer".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                transform_wat.as_bytes(),
+                transform_wat,
             )
             .unwrap();
 
@@ -669,7 +3203,7 @@ mod tests {
         dispatcher.dispatch(&LifecycleEvent::ProcessExiting { process_id: 1 });
         dispatcher.dispatch(&LifecycleEvent::ProcessExited {
             process_id: 1,
-            error: None,
+            reason: ExitReason::Normal,
         });
         dispatcher.dispatch(&LifecycleEvent::ModuleLoading {
             module_name: "test.wasm".into(),
@@ -698,7 +3232,7 @@ mod tests {
 
                 ;; Lifecycle hooks (no-ops, just prove they don't interfere)
                 (func (export "lunatic_on_process_spawned") (param i64))
-                (func (export "lunatic_on_process_exited") (param i64))
+                (func (export "lunatic_on_process_exited") (param i64 i32))
 
                 ;; Transform: uppercase all ASCII lowercase letters
                 (func (export "lunatic_transform_module")
@@ -735,14 +3269,14 @@ mod tests {
 
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "dual-plugin".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::LifecycleHooks, Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                wat.as_bytes(),
+                wat,
             )
             .unwrap();
 
@@ -751,10 +3285,10 @@ mod tests {
         assert_eq!(registry.lifecycle_dispatcher().plugin_count(), 1);
         assert_eq!(registry.module_transform_plugins().len(), 1);
 
-        // Note: lifecycle dispatch will fail to instantiate this module because the
-        // lifecycle linker doesn't provide lunatic_plugin imports. This is by design:
-        // lifecycle-only hooks should be in a separate module without transform imports.
-        // The transform still works independently.
+        // Lifecycle hooks run with no-op lunatic_plugin imports
+        registry
+            .lifecycle_dispatcher()
+            .dispatch(&LifecycleEvent::ProcessSpawned { process_id: 1 });
 
         // Transform uppercases ASCII
         let input = b"hello world";
@@ -1,8 +1,11 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 
-use wasmtime::{Linker, Store, Val};
+use wasmtime::{Caller, ExternType, Instance, InstancePre, Linker, Module, Store, Val};
 
-use crate::Plugin;
+use crate::error_log::{ErrorLog, PluginOperation};
+use crate::{Capability, Plugin};
 
 /// Events that plugins can hook into
 #[derive(Debug, Clone)]
@@ -14,66 +17,379 @@ pub enum LifecycleEvent {
     /// A process is about to exit
     ProcessExiting { process_id: u64 },
     /// A process has exited
-    ProcessExited {
-        process_id: u64,
-        error: Option<String>,
-    },
+    ProcessExited { process_id: u64, reason: ExitReason },
     /// A module is being loaded
     ModuleLoading { module_name: String },
     /// A module has been loaded
     ModuleLoaded { module_name: String },
+    /// The runtime started, sent once before any process is spawned
+    RuntimeStarted,
+    /// The runtime is shutting down, sent once after the last process finished
+    RuntimeStopping,
+    /// An event defined by the host or a plugin rather than the runtime, e.g. to let
+    /// plugins observe each other
+    Custom { name: String, payload: Vec<u8> },
+}
+
+/// Why a process exited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The process finished normally
+    Normal,
+    /// The process finished normally after giving a reason for exiting, e.g.
+    /// "config reload requested"
+    Requested(String),
+    /// The process failed with the given error, e.g. a wasm trap
+    Trapped(String),
+    /// The process ran out of fuel
+    OutOfFuel,
+    /// The process received a kill signal
+    Killed,
+    /// The process died because a linked process failed
+    LinkDied,
+    /// The process ran longer than its maximum wall time
+    Timeout,
+}
+
+impl ExitReason {
+    /// Reason code passed to the `lunatic_on_process_exited` hook
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitReason::Normal | ExitReason::Requested(_) => 0,
+            ExitReason::Trapped(_) => 1,
+            ExitReason::OutOfFuel => 2,
+            ExitReason::Killed => 3,
+            ExitReason::LinkDied => 4,
+            ExitReason::Timeout => 5,
+        }
+    }
+
+    /// The error string the event used to carry, `None` for a normal exit without a reason
+    pub fn error(&self) -> Option<String> {
+        match self {
+            ExitReason::Normal => None,
+            reason => Some(reason.to_string()),
+        }
+    }
+}
+
+impl Display for ExitReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Normal => write!(f, "Process exited normally"),
+            ExitReason::Requested(reason) => write!(f, "{reason}"),
+            ExitReason::Trapped(error) => write!(f, "{error}"),
+            ExitReason::OutOfFuel => write!(f, "Process ran out of fuel"),
+            ExitReason::Killed => write!(f, "Process received Kill signal"),
+            ExitReason::LinkDied => write!(f, "Linked process died"),
+            ExitReason::Timeout => write!(f, "Process exceeded its wall time limit"),
+        }
+    }
+}
+
+/// Treats any error as a trap, matching the old `error: Option<String>` field
+impl From<Option<String>> for ExitReason {
+    fn from(error: Option<String>) -> Self {
+        match error {
+            None => ExitReason::Normal,
+            Some(error) => ExitReason::Trapped(error),
+        }
+    }
+}
+
+impl From<ExitReason> for Option<String> {
+    fn from(reason: ExitReason) -> Self {
+        reason.error()
+    }
+}
+
+/// A lifecycle plugin with its imports already resolved
+struct LinkedPlugin {
+    plugin: Arc<Plugin>,
+    pre: InstancePre<LifecycleHostState>,
+}
+
+/// Host state of a lifecycle plugin instance
+struct LifecycleHostState {
+    // Labels of the events being delivered, see `LifecycleDispatcher::dispatch_labeled`
+    labels: Arc<HashMap<String, String>>,
 }
 
 /// Dispatches lifecycle events to registered plugins
 pub struct LifecycleDispatcher {
-    plugins: Vec<Arc<Plugin>>,
+    plugins: Vec<LinkedPlugin>,
+    disabled: HashSet<String>,
+    errors: ErrorLog,
 }
 
 impl LifecycleDispatcher {
     pub fn new() -> Self {
+        Self::with_error_log(ErrorLog::default())
+    }
+
+    /// Create a dispatcher that records plugin failures into `errors`
+    pub(crate) fn with_error_log(errors: ErrorLog) -> Self {
         Self {
             plugins: Vec::new(),
+            disabled: HashSet::new(),
+            errors,
         }
     }
 
     /// Add a plugin to receive lifecycle events
-    pub fn add_plugin(&mut self, plugin: Arc<Plugin>) {
-        self.plugins.push(plugin);
+    ///
+    /// The plugin's imports are resolved once here, so a plugin importing
+    /// anything the dispatcher can't provide is rejected instead of failing on
+    /// every event.
+    pub fn add_plugin(&mut self, plugin: Arc<Plugin>) -> anyhow::Result<()> {
+        let pre = Self::link(&plugin)?;
+        self.plugins.push(LinkedPlugin { plugin, pre });
+        Ok(())
+    }
+
+    /// Check that [`add_plugin`](Self::add_plugin) would accept `plugin`
+    pub(crate) fn check_plugin(plugin: &Plugin) -> anyhow::Result<()> {
+        Self::link(plugin).map(drop)
+    }
+
+    /// Deliver events to `new` instead of `old`, keeping its position
+    ///
+    /// Fails without replacing anything if `new` can't be linked, see
+    /// [`add_plugin`](Self::add_plugin).
+    pub(crate) fn replace_plugin(
+        &mut self,
+        old: &Arc<Plugin>,
+        new: Arc<Plugin>,
+    ) -> anyhow::Result<()> {
+        if !self
+            .plugins
+            .iter()
+            .any(|linked| Arc::ptr_eq(&linked.plugin, old))
+        {
+            return Ok(());
+        }
+        let pre = Self::link(&new)?;
+        for linked in &mut self.plugins {
+            if Arc::ptr_eq(&linked.plugin, old) {
+                *linked = LinkedPlugin {
+                    plugin: Arc::clone(&new),
+                    pre: pre.clone(),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop delivering events to `plugin`
+    pub(crate) fn remove_plugin(&mut self, plugin: &Arc<Plugin>) {
+        self.plugins
+            .retain(|linked| !Arc::ptr_eq(&linked.plugin, plugin));
+        self.disabled.remove(&plugin.info.name);
+    }
+
+    /// Resolve the imports of a plugin for lifecycle events
+    ///
+    /// A plugin that is also a transform plugin imports the `lunatic_plugin`
+    /// transform functions. Those have no meaning outside of a transform, so they
+    /// are satisfied with no-ops returning zero values.
+    ///
+    /// Every plugin can import `lunatic_plugin::label_len(key_ptr: i32, key_len: i32) -> i32`
+    /// and `lunatic_plugin::label_get(key_ptr: i32, key_len: i32, dest_ptr: i32) -> i32` to
+    /// look up a label of the event being delivered, see
+    /// [`dispatch_labeled`](Self::dispatch_labeled). The key is read from the plugin's
+    /// `memory` and `label_get` writes the value there as UTF-8 bytes, without a terminator.
+    /// Both return the length of the value in bytes, or -1 if the event has no such label.
+    ///
+    /// Plugins can also increment counters with `lunatic_plugin::metric_increment`, see
+    /// [`link_metrics`](crate::link_metrics).
+    fn link(plugin: &Plugin) -> anyhow::Result<InstancePre<LifecycleHostState>> {
+        let mut linker = Linker::<LifecycleHostState>::new(plugin.module.engine());
+        if plugin
+            .info
+            .capabilities
+            .contains(&Capability::ModuleTransform)
+        {
+            Self::stub_transform_imports(&plugin.module, &mut linker);
+        }
+        // The label functions replace their stubs
+        linker.allow_shadowing(true);
+        linker.func_wrap(
+            "lunatic_plugin",
+            "label_len",
+            |mut caller: Caller<LifecycleHostState>, key_ptr: i32, key_len: i32| {
+                let value = read_label(&mut caller, key_ptr, key_len, "label_len")?;
+                Ok(value.map_or(-1, |value| value.len() as i32))
+            },
+        )?;
+        linker.func_wrap(
+            "lunatic_plugin",
+            "label_get",
+            |mut caller: Caller<LifecycleHostState>, key_ptr: i32, key_len: i32, dest_ptr: i32| {
+                let Some(value) = read_label(&mut caller, key_ptr, key_len, "label_get")? else {
+                    return Ok(-1);
+                };
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(|e| e.into_memory())
+                    .ok_or_else(|| anyhow::anyhow!("plugin must export memory"))?;
+                memory.write(&mut caller, dest_ptr as usize, value.as_bytes())?;
+                Ok(value.len() as i32)
+            },
+        )?;
+        crate::link_metrics(&mut linker)?;
+        linker.instantiate_pre(&plugin.module).map_err(|e| {
+            anyhow::anyhow!(
+                "Plugin '{}' can't receive lifecycle events: {e}",
+                plugin.info.name
+            )
+        })
+    }
+
+    /// Enable or disable event delivery to the plugin with the given name
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        if enabled {
+            self.disabled.remove(name);
+        } else {
+            self.disabled.insert(name.to_string());
+        }
     }
 
     /// Dispatch a lifecycle event to all registered plugins
     ///
-    /// For each plugin, instantiates a fresh wasm instance and calls the
+    /// For each enabled plugin, instantiates a fresh wasm instance and calls the
     /// corresponding lifecycle hook export. Errors are logged and do not
     /// propagate -- a failing plugin never takes down the runtime.
     ///
     /// For module events, the module name string is written into the plugin's
-    /// exported `memory` at offset 0 and passed as `(ptr: i32, len: i32)`.
+    /// exported `memory` at offset 0 and passed as `(ptr: i32, len: i32)`. Custom
+    /// events are all delivered to `lunatic_on_custom`, see
+    /// [`build_args`](Self::build_args) for how the name and payload are passed.
     pub fn dispatch(&self, event: &LifecycleEvent) {
         log::trace!(
             "Lifecycle event: {event:?}, notifying {} plugins",
             self.plugins.len()
         );
+        self.deliver(std::slice::from_ref(event), &Arc::default());
+    }
+
+    /// Dispatch a lifecycle event like [`dispatch`](Self::dispatch), together with
+    /// `labels` the plugins can look up, e.g. a request ID or a tenant
+    ///
+    /// The labels aren't passed to the hook, a plugin reads the ones it cares about
+    /// with the `lunatic_plugin::label_len` and `label_get` host functions, see
+    /// [`link`](Self::link).
+    pub fn dispatch_labeled(&self, event: &LifecycleEvent, labels: &HashMap<String, String>) {
+        log::trace!(
+            "Lifecycle event: {event:?} with {} labels, notifying {} plugins",
+            labels.len(),
+            self.plugins.len()
+        );
+        self.deliver(std::slice::from_ref(event), &Arc::new(labels.clone()));
+    }
+
+    /// Dispatch a batch of lifecycle events to all registered plugins
+    ///
+    /// Each enabled plugin is instantiated once and the whole batch is replayed,
+    /// in order, against that instance. Plugins stay isolated from each other,
+    /// but within a plugin the hooks for all events in the batch share one
+    /// instance, so globals and linear memory written by an earlier hook are
+    /// visible to later ones. Errors are handled the same way as in
+    /// [`dispatch`](Self::dispatch).
+    pub fn dispatch_batch(&self, events: &[LifecycleEvent]) {
+        log::trace!(
+            "Lifecycle batch of {} events, notifying {} plugins",
+            events.len(),
+            self.plugins.len()
+        );
+        self.deliver(events, &Arc::default());
+    }
 
-        let export_name = Self::event_export_name(event);
+    fn deliver(&self, events: &[LifecycleEvent], labels: &Arc<HashMap<String, String>>) {
+        if events.is_empty() {
+            return;
+        }
+        for LinkedPlugin { plugin, pre } in &self.plugins {
+            if self.disabled.contains(&plugin.info.name) {
+                continue;
+            }
+            let Some((mut store, instance)) = self.instantiate(plugin, pre, labels) else {
+                continue;
+            };
+            self.replay(plugin, &instance, &mut store, events);
+        }
+    }
 
-        for plugin in &self.plugins {
-            let engine = plugin.module.engine();
-            let mut store = Store::new(engine, ());
-            let linker = Linker::<()>::new(engine);
+    /// Create a fresh instance of the plugin, logging and recording failures
+    fn instantiate(
+        &self,
+        plugin: &Plugin,
+        pre: &InstancePre<LifecycleHostState>,
+        labels: &Arc<HashMap<String, String>>,
+    ) -> Option<(Store<LifecycleHostState>, Instance)> {
+        let state = LifecycleHostState {
+            labels: Arc::clone(labels),
+        };
+        let mut store = crate::plugin_store(plugin.module.engine(), state, None);
+        match pre.instantiate(&mut store) {
+            Ok(instance) => Some((store, instance)),
+            Err(e) => {
+                log::warn!(
+                    "Failed to instantiate plugin '{}' for lifecycle events: {e}",
+                    plugin.info.name
+                );
+                self.errors.record(
+                    &plugin.info.name,
+                    PluginOperation::Lifecycle,
+                    format_args!("Failed to instantiate plugin: {e}"),
+                );
+                None
+            }
+        }
+    }
 
-            let instance = match linker.instantiate(&mut store, &plugin.module) {
-                Ok(inst) => inst,
-                Err(e) => {
-                    log::warn!(
-                        "Failed to instantiate plugin '{}' for event {export_name}: {e}",
-                        plugin.info.name
-                    );
-                    continue;
-                }
+    /// Define every `lunatic_plugin` function import of `module` as a no-op
+    fn stub_transform_imports<T: 'static>(module: &Module, linker: &mut Linker<T>) {
+        for import in module.imports() {
+            let ExternType::Func(ty) = import.ty() else {
+                continue;
+            };
+            if import.module() != "lunatic_plugin" {
+                continue;
+            }
+            // Results without a default value can't be stubbed, instantiation reports them
+            let Some(defaults) = ty
+                .results()
+                .map(|ty| Val::default_for_ty(&ty))
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
             };
+            let defined =
+                linker.func_new("lunatic_plugin", import.name(), ty, move |_, _, results| {
+                    results.clone_from_slice(&defaults);
+                    Ok(())
+                });
+            if let Err(e) = defined {
+                log::warn!(
+                    "Failed to stub import 'lunatic_plugin::{}': {e}",
+                    import.name()
+                );
+            }
+        }
+    }
+
+    /// Call the plugin's hook for each event, in order, on a single instance
+    fn replay(
+        &self,
+        plugin: &Plugin,
+        instance: &Instance,
+        store: &mut Store<LifecycleHostState>,
+        events: &[LifecycleEvent],
+    ) {
+        for event in events {
+            let export_name = Self::event_export_name(event);
 
-            let func = match instance.get_func(&mut store, export_name) {
+            let func = match instance.get_func(&mut *store, export_name) {
                 Some(f) => f,
                 None => {
                     log::trace!(
@@ -84,7 +400,7 @@ impl LifecycleDispatcher {
                 }
             };
 
-            let args = match Self::build_args(event, &instance, &mut store) {
+            let args = match Self::build_args(event, instance, store) {
                 Ok(args) => args,
                 Err(e) => {
                     log::warn!(
@@ -95,11 +411,16 @@ impl LifecycleDispatcher {
                 }
             };
 
-            if let Err(e) = func.call(&mut store, &args, &mut []) {
+            if let Err(e) = func.call(&mut *store, &args, &mut []) {
                 log::warn!(
                     "Plugin '{}' hook '{export_name}' failed: {e}",
                     plugin.info.name
                 );
+                self.errors.record(
+                    &plugin.info.name,
+                    PluginOperation::Lifecycle,
+                    format_args!("Hook '{export_name}' failed: {e}"),
+                );
             }
         }
     }
@@ -113,26 +434,36 @@ impl LifecycleDispatcher {
             LifecycleEvent::ProcessExited { .. } => "lunatic_on_process_exited",
             LifecycleEvent::ModuleLoading { .. } => "lunatic_on_module_loading",
             LifecycleEvent::ModuleLoaded { .. } => "lunatic_on_module_loaded",
+            LifecycleEvent::Custom { .. } => "lunatic_on_custom",
+            LifecycleEvent::RuntimeStarted => "lunatic_on_runtime_started",
+            LifecycleEvent::RuntimeStopping => "lunatic_on_runtime_stopping",
         }
     }
 
     /// Build the argument list for a lifecycle hook call.
     ///
-    /// Process events pass `(process_id: i64)`.
+    /// Process events pass `(process_id: i64)`, except `ProcessExited` which passes
+    /// `(process_id: i64, reason: i32)` with the code from [`ExitReason::code`].
     /// Module events write the module name into the plugin's exported memory
-    /// at offset 0 and pass `(ptr: i32, len: i32)`.
-    fn build_args(
+    /// at offset 0 and pass `(ptr: i32, len: i32)`. Custom events write the name
+    /// at offset 0 followed by the payload, and pass
+    /// `(name_ptr: i32, name_len: i32, payload_ptr: i32, payload_len: i32)`.
+    /// Runtime events pass no arguments.
+    fn build_args<T>(
         event: &LifecycleEvent,
         instance: &wasmtime::Instance,
-        store: &mut Store<()>,
+        store: &mut Store<T>,
     ) -> anyhow::Result<Vec<Val>> {
         match event {
             LifecycleEvent::ProcessSpawning { process_id }
             | LifecycleEvent::ProcessSpawned { process_id }
-            | LifecycleEvent::ProcessExiting { process_id }
-            | LifecycleEvent::ProcessExited { process_id, .. } => {
+            | LifecycleEvent::ProcessExiting { process_id } => {
                 Ok(vec![Val::I64(*process_id as i64)])
             }
+            LifecycleEvent::ProcessExited { process_id, reason } => {
+                Ok(vec![Val::I64(*process_id as i64), Val::I32(reason.code())])
+            }
+            LifecycleEvent::RuntimeStarted | LifecycleEvent::RuntimeStopping => Ok(Vec::new()),
             LifecycleEvent::ModuleLoading { module_name }
             | LifecycleEvent::ModuleLoaded { module_name, .. } => {
                 let name_bytes = module_name.as_bytes();
@@ -142,6 +473,19 @@ impl LifecycleDispatcher {
                 memory.write(&mut *store, 0, name_bytes)?;
                 Ok(vec![Val::I32(0), Val::I32(name_bytes.len() as i32)])
             }
+            LifecycleEvent::Custom { name, payload } => {
+                let memory = instance.get_memory(&mut *store, "memory").ok_or_else(|| {
+                    anyhow::anyhow!("plugin must export memory for custom events")
+                })?;
+                memory.write(&mut *store, 0, name.as_bytes())?;
+                memory.write(&mut *store, name.len(), payload)?;
+                Ok(vec![
+                    Val::I32(0),
+                    Val::I32(name.len() as i32),
+                    Val::I32(name.len() as i32),
+                    Val::I32(payload.len() as i32),
+                ])
+            }
         }
     }
 
@@ -157,9 +501,23 @@ impl Default for LifecycleDispatcher {
     }
 }
 
+/// Look up the label whose key the plugin passed in its memory
+fn read_label(
+    caller: &mut Caller<LifecycleHostState>,
+    key_ptr: i32,
+    key_len: i32,
+    function: &str,
+) -> anyhow::Result<Option<String>> {
+    let key = crate::read_plugin_memory(caller, key_ptr, key_len, function)?;
+    let key = std::str::from_utf8(&key)
+        .map_err(|e| anyhow::anyhow!("{function}: key is not valid UTF-8: {e}"))?;
+    Ok(caller.data().labels.get(key).cloned())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_plugin::tests::plugin_info;
 
     #[test]
     fn test_empty_dispatcher() {
@@ -192,7 +550,7 @@ mod tests {
         assert_eq!(
             LifecycleDispatcher::event_export_name(&LifecycleEvent::ProcessExited {
                 process_id: 1,
-                error: None
+                reason: ExitReason::Normal
             }),
             "lunatic_on_process_exited"
         );
@@ -208,6 +566,14 @@ mod tests {
             }),
             "lunatic_on_module_loaded"
         );
+        assert_eq!(
+            LifecycleDispatcher::event_export_name(&LifecycleEvent::RuntimeStarted),
+            "lunatic_on_runtime_started"
+        );
+        assert_eq!(
+            LifecycleDispatcher::event_export_name(&LifecycleEvent::RuntimeStopping),
+            "lunatic_on_runtime_stopping"
+        );
     }
 
     #[test]
@@ -231,14 +597,15 @@ mod tests {
         let args = LifecycleDispatcher::build_args(
             &LifecycleEvent::ProcessExited {
                 process_id: 99,
-                error: Some("oops".into()),
+                reason: ExitReason::Trapped("oops".into()),
             },
             &instance,
             &mut store,
         )
         .unwrap();
-        assert_eq!(args.len(), 1);
+        assert_eq!(args.len(), 2);
         assert_eq!(args[0].unwrap_i64(), 99);
+        assert_eq!(args[1].unwrap_i32(), 1);
     }
 
     #[test]
@@ -306,7 +673,7 @@ mod tests {
         });
 
         let mut dispatcher = LifecycleDispatcher::new();
-        dispatcher.add_plugin(plugin);
+        dispatcher.add_plugin(plugin).unwrap();
         // Must not panic
         dispatcher.dispatch(&LifecycleEvent::ProcessSpawned { process_id: 1 });
         dispatcher.dispatch(&LifecycleEvent::ModuleLoaded {
@@ -336,7 +703,7 @@ mod tests {
         });
 
         let mut dispatcher = LifecycleDispatcher::new();
-        dispatcher.add_plugin(plugin);
+        dispatcher.add_plugin(plugin).unwrap();
         // Must not panic -- the hook is called successfully
         dispatcher.dispatch(&LifecycleEvent::ProcessSpawned { process_id: 123 });
     }
@@ -365,7 +732,7 @@ mod tests {
         });
 
         let mut dispatcher = LifecycleDispatcher::new();
-        dispatcher.add_plugin(plugin);
+        dispatcher.add_plugin(plugin).unwrap();
         // Must not panic -- the hook receives the module name via memory
         dispatcher.dispatch(&LifecycleEvent::ModuleLoaded {
             module_name: "test-mod.wasm".into(),
@@ -422,4 +789,384 @@ mod tests {
         memory.read(&store, 1024, &mut buf).unwrap();
         assert_eq!(&buf, b"my_module.wasm");
     }
+
+    #[test]
+    fn test_dispatch_batch_shares_instance_per_plugin() {
+        // Traps unless the hook already ran for every earlier process in this instance
+        let wat = r#"
+            (module
+                (global $count (mut i64) (i64.const 0))
+                (func (export "lunatic_on_process_spawned") (param $id i64)
+                    (global.set $count (i64.add (global.get $count) (i64.const 1)))
+                    (if (i64.ne (global.get $count) (local.get $id))
+                        (then unreachable))
+                )
+            )
+        "#;
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let plugin = Arc::new(crate::Plugin {
+            info: plugin_info("counter", &[crate::Capability::LifecycleHooks]),
+            module,
+        });
+        let events = vec![
+            LifecycleEvent::ProcessSpawned { process_id: 1 },
+            LifecycleEvent::ModuleLoaded {
+                module_name: "not-hooked".into(),
+            },
+            LifecycleEvent::ProcessSpawned { process_id: 2 },
+            LifecycleEvent::ProcessSpawned { process_id: 3 },
+        ];
+
+        let mut dispatcher = LifecycleDispatcher::new();
+        dispatcher.add_plugin(plugin).unwrap();
+
+        // All hooked events in the batch land on the same instance
+        dispatcher.dispatch_batch(&events);
+        dispatcher.dispatch_batch(&[]);
+        assert!(dispatcher.errors.snapshot().is_empty());
+
+        // A later batch starts over on a fresh instance
+        dispatcher.dispatch_batch(&events[3..]);
+        let errors = dispatcher.errors.snapshot();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].message.contains("lunatic_on_process_spawned"),
+            "{}",
+            errors[0].message
+        );
+    }
+
+    #[test]
+    fn test_exit_reason_codes() {
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, "(module)").unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)
+            .unwrap();
+
+        let reasons = [
+            (ExitReason::Normal, 0),
+            (ExitReason::Trapped("unreachable".into()), 1),
+            (ExitReason::OutOfFuel, 2),
+            (ExitReason::Killed, 3),
+            (ExitReason::LinkDied, 4),
+            (ExitReason::Timeout, 5),
+        ];
+        for (reason, code) in reasons {
+            assert_eq!(reason.code(), code);
+            let args = LifecycleDispatcher::build_args(
+                &LifecycleEvent::ProcessExited {
+                    process_id: 7,
+                    reason,
+                },
+                &instance,
+                &mut store,
+            )
+            .unwrap();
+            assert_eq!(args[1].unwrap_i32(), code);
+        }
+    }
+
+    #[test]
+    fn test_exit_reason_error_string() {
+        assert_eq!(ExitReason::from(None), ExitReason::Normal);
+        assert_eq!(
+            ExitReason::from(Some("oops".to_string())),
+            ExitReason::Trapped("oops".into())
+        );
+        assert_eq!(Option::<String>::from(ExitReason::Normal), None);
+        assert_eq!(
+            Option::<String>::from(ExitReason::Trapped("oops".into())),
+            Some("oops".to_string())
+        );
+        assert_eq!(
+            ExitReason::Killed.error().as_deref(),
+            Some("Process received Kill signal")
+        );
+    }
+
+    #[test]
+    fn test_dual_plugin_lifecycle_hooks_run() {
+        let wat = r#"
+            (module
+                (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
+                (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
+                (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
+                (memory (export "memory") 1)
+                (global (export "spawned") (mut i32) (i32.const 0))
+
+                ;; The transform imports are callable from hooks, they just do nothing
+                (func (export "lunatic_on_process_spawned") (param i64)
+                    (global.set 0
+                        (i32.add (global.get 0)
+                            (i32.add (call $input_size) (i32.const 1)))))
+
+                ;; Transform: append a single '!'
+                (func (export "lunatic_transform_module")
+                    (local $size i32)
+                    (local.set $size (call $input_size))
+                    (call $read_input (i32.const 0))
+                    (i32.store8 (local.get $size) (i32.const 0x21))
+                    (call $write_output (i32.const 0)
+                        (i32.add (local.get $size) (i32.const 1))))
+            )
+        "#;
+        let mut registry = crate::PluginRegistry::new();
+        registry
+            .register_wat(
+                plugin_info(
+                    "dual",
+                    &[Capability::LifecycleHooks, Capability::ModuleTransform],
+                ),
+                wat,
+            )
+            .unwrap();
+        let plugin = registry.get("dual").unwrap();
+
+        let dispatcher = registry.lifecycle_dispatcher();
+        let pre = LifecycleDispatcher::link(plugin).unwrap();
+        let (mut store, instance) = dispatcher
+            .instantiate(plugin, &pre, &Arc::default())
+            .unwrap();
+        dispatcher.replay(
+            plugin,
+            &instance,
+            &mut store,
+            &[
+                LifecycleEvent::ProcessSpawned { process_id: 1 },
+                LifecycleEvent::ProcessSpawned { process_id: 2 },
+            ],
+        );
+        let spawned = instance
+            .get_global(&mut store, "spawned")
+            .unwrap()
+            .get(&mut store)
+            .unwrap_i32();
+        assert_eq!(spawned, 2);
+
+        assert_eq!(registry.transform_module(b"hi").unwrap(), b"hi!");
+    }
+
+    #[test]
+    fn test_dispatch_custom_event() {
+        // Records the name at offset 1024 and the payload at offset 2048
+        let wat = r#"
+            (module
+                (memory (export "memory") 1)
+                (global (export "name_len") (mut i32) (i32.const 0))
+                (global (export "payload_len") (mut i32) (i32.const 0))
+                (func (export "lunatic_on_custom")
+                    (param $name_ptr i32) (param $name_len i32)
+                    (param $payload_ptr i32) (param $payload_len i32)
+                    (global.set 0 (local.get $name_len))
+                    (global.set 1 (local.get $payload_len))
+                    (memory.copy (i32.const 1024) (local.get $name_ptr) (local.get $name_len))
+                    (memory.copy
+                        (i32.const 2048) (local.get $payload_ptr) (local.get $payload_len))
+                )
+            )
+        "#;
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let plugin = Arc::new(crate::Plugin {
+            info: plugin_info("custom-listener", &[crate::Capability::LifecycleHooks]),
+            module,
+        });
+        let event = LifecycleEvent::Custom {
+            name: "cache.evicted".into(),
+            payload: vec![1, 2, 3, 0xFF],
+        };
+        assert_eq!(
+            LifecycleDispatcher::event_export_name(&event),
+            "lunatic_on_custom"
+        );
+
+        let dispatcher = LifecycleDispatcher::new();
+        let pre = LifecycleDispatcher::link(&plugin).unwrap();
+        let (mut store, instance) = dispatcher
+            .instantiate(&plugin, &pre, &Arc::default())
+            .unwrap();
+        dispatcher.replay(&plugin, &instance, &mut store, std::slice::from_ref(&event));
+        let global = |store: &mut Store<LifecycleHostState>, name| {
+            instance
+                .get_global(&mut *store, name)
+                .unwrap()
+                .get(&mut *store)
+                .unwrap_i32()
+        };
+        assert_eq!(global(&mut store, "name_len"), 13);
+        assert_eq!(global(&mut store, "payload_len"), 4);
+
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let mut name = vec![0u8; 13];
+        memory.read(&store, 1024, &mut name).unwrap();
+        assert_eq!(name, b"cache.evicted");
+        let mut payload = vec![0u8; 4];
+        memory.read(&store, 2048, &mut payload).unwrap();
+        assert_eq!(payload, [1, 2, 3, 0xFF]);
+        assert!(dispatcher.errors.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_dispatch_runtime_events() {
+        // Counts the calls to each runtime hook
+        let wat = r#"
+            (module
+                (global (export "started") (mut i32) (i32.const 0))
+                (global (export "stopping") (mut i32) (i32.const 0))
+                (func (export "lunatic_on_runtime_started")
+                    (global.set 0 (i32.add (global.get 0) (i32.const 1))))
+                (func (export "lunatic_on_runtime_stopping")
+                    (global.set 1 (i32.add (global.get 1) (i32.const 1))))
+            )
+        "#;
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let plugin = Arc::new(crate::Plugin {
+            info: plugin_info("runtime-observer", &[crate::Capability::LifecycleHooks]),
+            module,
+        });
+        let events = [
+            LifecycleEvent::RuntimeStarted,
+            LifecycleEvent::ProcessSpawned { process_id: 1 },
+            LifecycleEvent::RuntimeStopping,
+        ];
+
+        let dispatcher = LifecycleDispatcher::new();
+        let pre = LifecycleDispatcher::link(&plugin).unwrap();
+        let (mut store, instance) = dispatcher
+            .instantiate(&plugin, &pre, &Arc::default())
+            .unwrap();
+        dispatcher.replay(&plugin, &instance, &mut store, &events);
+        let global = |store: &mut Store<LifecycleHostState>, name| {
+            instance
+                .get_global(&mut *store, name)
+                .unwrap()
+                .get(&mut *store)
+                .unwrap_i32()
+        };
+        assert_eq!(global(&mut store, "started"), 1);
+        assert_eq!(global(&mut store, "stopping"), 1);
+        assert!(dispatcher.errors.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_dispatch_labeled_exposes_labels() {
+        // Copies the "tenant" label to offset 1024 and records the lengths it gets back
+        let wat = r#"
+            (module
+                (import "lunatic_plugin" "label_len" (func $label_len (param i32 i32) (result i32)))
+                (import "lunatic_plugin" "label_get"
+                    (func $label_get (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "tenant")
+                (data (i32.const 16) "request")
+                (global (export "tenant_len") (mut i32) (i32.const 0))
+                (global (export "request_len") (mut i32) (i32.const 0))
+                (func (export "lunatic_on_process_spawned") (param i64)
+                    (global.set 0 (call $label_get (i32.const 0) (i32.const 6) (i32.const 1024)))
+                    (global.set 1 (call $label_len (i32.const 16) (i32.const 7)))
+                )
+            )
+        "#;
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let plugin = Arc::new(crate::Plugin {
+            info: plugin_info("labels", &[crate::Capability::LifecycleHooks]),
+            module,
+        });
+        let labels = HashMap::from([("tenant".to_string(), "acme".to_string())]);
+        let event = LifecycleEvent::ProcessSpawned { process_id: 1 };
+
+        let dispatcher = LifecycleDispatcher::new();
+        let pre = LifecycleDispatcher::link(&plugin).unwrap();
+        let (mut store, instance) = dispatcher
+            .instantiate(&plugin, &pre, &Arc::new(labels.clone()))
+            .unwrap();
+        dispatcher.replay(&plugin, &instance, &mut store, std::slice::from_ref(&event));
+        let global = |store: &mut Store<_>, name| {
+            instance
+                .get_global(&mut *store, name)
+                .unwrap()
+                .get(&mut *store)
+                .unwrap_i32()
+        };
+        assert_eq!(global(&mut store, "tenant_len"), 4);
+        // Not a label of this event
+        assert_eq!(global(&mut store, "request_len"), -1);
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let mut value = [0u8; 4];
+        memory.read(&store, 1024, &mut value).unwrap();
+        assert_eq!(&value, b"acme");
+
+        let mut dispatcher = LifecycleDispatcher::new();
+        dispatcher.add_plugin(plugin).unwrap();
+        dispatcher.dispatch_labeled(&event, &labels);
+        dispatcher.dispatch(&event);
+        assert!(dispatcher.errors.snapshot().is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_plugin_metric_increment_is_recorded() {
+        use std::sync::Mutex;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, SharedString, Unit};
+
+        #[derive(Default)]
+        struct CountingRecorder(Mutex<HashMap<String, Arc<AtomicU64>>>);
+
+        impl metrics::Recorder for CountingRecorder {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                let mut counters = self.0.lock().unwrap();
+                Counter::from_arc(counters.entry(key.name().to_string()).or_default().clone())
+            }
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+            fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+                Histogram::noop()
+            }
+        }
+
+        let wat = r#"
+            (module
+                (import "lunatic_plugin" "metric_increment" (func $increment (param i32 i32 i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "plugin.spawned")
+                (func (export "lunatic_on_process_spawned") (param i64)
+                    (call $increment (i32.const 0) (i32.const 14) (i64.const 3))
+                )
+                (func (export "lunatic_on_process_exiting") (param i64)
+                    (call $increment (i32.const 0) (i32.const 14) (i64.const -1))
+                )
+            )
+        "#;
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        let mut dispatcher = LifecycleDispatcher::new();
+        dispatcher
+            .add_plugin(Arc::new(crate::Plugin {
+                info: plugin_info("metrics", &[crate::Capability::LifecycleHooks]),
+                module,
+            }))
+            .unwrap();
+
+        let recorder = CountingRecorder::default();
+        metrics::with_local_recorder(&recorder, || {
+            dispatcher.dispatch(&LifecycleEvent::ProcessSpawned { process_id: 1 });
+            dispatcher.dispatch(&LifecycleEvent::ProcessSpawned { process_id: 2 });
+            // Counters can't go down, the hook traps
+            dispatcher.dispatch(&LifecycleEvent::ProcessExiting { process_id: 1 });
+        });
+        let counters = recorder.0.lock().unwrap();
+        assert_eq!(counters["plugin.spawned"].load(Ordering::Relaxed), 6);
+        assert_eq!(dispatcher.errors.snapshot().len(), 1);
+    }
 }
@@ -1,4 +1,5 @@
 use anyhow::{Result, anyhow};
+use wasm_encoder::Encode;
 use wasmparser::{Parser, Payload};
 
 /// Represents a type that was either parsed from the original module or newly added
@@ -25,12 +26,61 @@ enum ContextExport {
     },
 }
 
+/// Represents a data segment
+enum ContextData {
+    /// A data segment parsed from the original module, kept in its encoded form
+    Parsed(Vec<u8>),
+    /// A newly added active data segment (memory index, offset, bytes)
+    NewActive(u32, wasm_encoder::ConstExpr, Vec<u8>),
+}
+
+/// How a module is meant to be run, following the WASI conventions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Exports `_start`, run once from start to finish
+    Command,
+    /// Exports `_initialize` but no `_start`, initialized once and then called into
+    Reactor,
+    /// Exports neither, just a set of functions
+    Library,
+}
+
+/// Differences between the exports of two modules, see [`ModuleContext::export_diff`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportDiff {
+    /// Exports only present after
+    pub added: Vec<String>,
+    /// Exports only present before
+    pub removed: Vec<String>,
+    /// Exports present in both that point to a different kind or index
+    pub reindexed: Vec<String>,
+}
+
+impl ExportDiff {
+    /// Check if both modules export the same items under the same names
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.reindexed.is_empty()
+    }
+}
+
 /// A raw section from the original module that we preserve as-is
 struct RawSection {
     id: u8,
     data: Vec<u8>,
 }
 
+impl RawSection {
+    /// The name of a custom section, which prefixes its data
+    fn custom_name(&self) -> Option<&str> {
+        if self.id != 0 {
+            return None;
+        }
+        wasmparser::BinaryReader::new(&self.data, 0)
+            .read_string()
+            .ok()
+    }
+}
+
 /// Parsed import stored structurally for re-encoding
 struct ParsedImport {
     module: String,
@@ -50,6 +100,8 @@ pub struct ModuleContext {
     imports: Vec<ParsedImport>,
     import_func_count: u32,
     exports: Vec<ContextExport>,
+    data_segments: Vec<ContextData>,
+    has_data_count: bool,
     sections: Vec<RawSection>,
     function_names: std::collections::HashMap<String, u32>,
 }
@@ -63,6 +115,8 @@ impl ModuleContext {
         let mut imports = Vec::new();
         let mut import_func_count: u32 = 0;
         let mut exports = Vec::new();
+        let mut data_segments = Vec::new();
+        let mut has_data_count = false;
         let mut sections = Vec::new();
         let mut function_names = std::collections::HashMap::new();
 
@@ -172,17 +226,13 @@ impl ModuleContext {
                     });
                 }
                 Payload::DataSection(reader) => {
-                    let range = reader.range();
-                    sections.push(RawSection {
-                        id: 11,
-                        data: module[range.start..range.end].to_vec(),
-                    });
+                    for data in reader {
+                        let data = data?;
+                        data_segments.push(ContextData::Parsed(module[data.range].to_vec()));
+                    }
                 }
-                Payload::DataCountSection { range, .. } => {
-                    sections.push(RawSection {
-                        id: 12,
-                        data: module[range.start..range.end].to_vec(),
-                    });
+                Payload::DataCountSection { .. } => {
+                    has_data_count = true;
                 }
                 Payload::CustomSection(custom) => {
                     // TODO: Parse name section for function names
@@ -205,6 +255,8 @@ impl ModuleContext {
             imports,
             import_func_count,
             exports,
+            data_segments,
+            has_data_count,
             sections,
             function_names,
         })
@@ -236,17 +288,721 @@ impl ModuleContext {
         func_idx
     }
 
+    /// Insert the encoded `instructions` at the start of the body of the function at
+    /// `func_idx`, e.g. to run a hook whenever the function is entered.
+    ///
+    /// The instructions run before the original body and must leave the stack as they
+    /// found it. Fails if `func_idx` refers to an imported function or is out of range.
+    pub fn prepend_to_function(&mut self, func_idx: u32, instructions: &[u8]) -> Result<()> {
+        let defined_idx = func_idx
+            .checked_sub(self.import_func_count)
+            .ok_or_else(|| anyhow!("Function {func_idx} is imported and has no body"))?;
+        let ContextCode::New(_, body) = self
+            .code_section
+            .get_mut(defined_idx as usize)
+            .ok_or_else(|| anyhow!("Function index {func_idx} out of range"))?;
+        body.splice(0..0, instructions.iter().copied());
+        Ok(())
+    }
+
     /// Export a function by name
     pub fn add_function_export(&mut self, name: String, func_idx: u32) {
         self.exports
             .push(ContextExport::NewFunction(name, func_idx));
     }
 
+    /// Add a new active data segment that initializes `data` in memory `memory_index`
+    /// at the address computed by `offset`.
+    /// Returns the data segment index.
+    ///
+    /// The DataCount section is emitted alongside the data section on encode, so
+    /// modules using bulk-memory instructions stay valid.
+    pub fn add_data_segment(
+        &mut self,
+        memory_index: u32,
+        offset: wasm_encoder::ConstExpr,
+        data: Vec<u8>,
+    ) -> u32 {
+        let idx = self.data_segments.len() as u32;
+        self.data_segments
+            .push(ContextData::NewActive(memory_index, offset, data));
+        self.has_data_count = true;
+        idx
+    }
+
+    /// Add a global initialized by `init`.
+    /// Returns the global index, which comes after all imported and defined globals.
+    pub fn add_global(
+        &mut self,
+        ty: wasm_encoder::GlobalType,
+        init: &wasm_encoder::ConstExpr,
+    ) -> Result<u32> {
+        let imported = self
+            .imports
+            .iter()
+            .filter(|import| matches!(import.ty, wasm_encoder::EntityType::Global(_)))
+            .count() as u32;
+        let position = self.sections.iter().position(|section| section.id == 6);
+        let defined = match position {
+            Some(position) => {
+                let data = &self.sections[position].data;
+                wasmparser::GlobalSectionReader::new(wasmparser::BinaryReader::new(data, 0))?
+                    .count()
+            }
+            None => 0,
+        };
+
+        // The section starts with the number of globals, followed by the globals
+        let mut data = Vec::new();
+        (defined + 1).encode(&mut data);
+        if let Some(position) = position {
+            let old = &self.sections[position].data;
+            let mut reader = wasmparser::BinaryReader::new(old, 0);
+            reader.read_var_u32()?;
+            data.extend_from_slice(&old[reader.current_position()..]);
+        }
+        ty.encode(&mut data);
+        init.encode(&mut data);
+        match position {
+            Some(position) => self.sections[position].data = data,
+            None => {
+                // After any table and memory sections, before everything that follows globals
+                let position = self
+                    .sections
+                    .iter()
+                    .position(|section| !matches!(section.id, 4 | 5))
+                    .unwrap_or(self.sections.len());
+                self.sections.insert(position, RawSection { id: 6, data });
+            }
+        }
+        Ok(imported + defined)
+    }
+
+    /// Add a function import and shift every defined function up by one to make room.
+    /// Returns the function index of the new import.
+    ///
+    /// Function imports always occupy the lowest function indices, so the new import
+    /// takes the slot right after the existing ones. Every `call`, `return_call` and
+    /// `ref.func` in the function bodies, the element and global sections, the start
+    /// function and all function exports are rewritten to the shifted indices. The
+    /// `name` custom section is preserved as-is and is not remapped.
+    pub fn insert_function_import_remapping(
+        &mut self,
+        module: &str,
+        name: &str,
+        type_idx: u32,
+    ) -> Result<u32> {
+        let func_idx = self.import_func_count;
+        self.remap_function_indices(|idx| if idx >= func_idx { idx + 1 } else { idx })?;
+        self.imports.push(ParsedImport {
+            module: module.to_string(),
+            name: name.to_string(),
+            ty: wasm_encoder::EntityType::Function(type_idx),
+        });
+        self.import_func_count += 1;
+        Ok(func_idx)
+    }
+
+    /// Rewrite every reference to a function index through `remap`.
+    ///
+    /// All bodies and sections are decoded before anything is modified, so on error
+    /// the context is left untouched.
+    fn remap_function_indices(&mut self, remap: impl Fn(u32) -> u32) -> Result<()> {
+        let mut bodies = Vec::with_capacity(self.code_section.len());
+        for code in &self.code_section {
+            let ContextCode::New(_, body) = code;
+            let reader = wasmparser::OperatorsReader::new(wasmparser::BinaryReader::new(body, 0));
+            let offsets = function_index_offsets(reader)?;
+            bodies.push(rewrite_indices(body, &offsets, &remap)?);
+        }
+
+        let mut sections = Vec::new();
+        for (i, section) in self.sections.iter().enumerate() {
+            let offsets = match section.id {
+                6 => global_function_index_offsets(&section.data)?,
+                8 => vec![0],
+                9 => element_function_index_offsets(&section.data)?,
+                _ => continue,
+            };
+            sections.push((i, rewrite_indices(&section.data, &offsets, &remap)?));
+        }
+
+        for (code, new_body) in self.code_section.iter_mut().zip(bodies) {
+            let ContextCode::New(_, body) = code;
+            *body = new_body;
+        }
+        for (i, data) in sections {
+            self.sections[i].data = data;
+        }
+        for export in &mut self.exports {
+            match export {
+                ContextExport::NewFunction(_, index)
+                | ContextExport::Parsed {
+                    kind: wasmparser::ExternalKind::Func,
+                    index,
+                    ..
+                } => *index = remap(*index),
+                ContextExport::Parsed { .. } => {}
+            }
+        }
+        for index in self.function_names.values_mut() {
+            *index = remap(*index);
+        }
+        Ok(())
+    }
+
+    /// Count the operators in the body of the function at `func_idx`, including the
+    /// final `end`.
+    ///
+    /// Fails if `func_idx` refers to an imported function or is out of range.
+    pub fn function_instruction_count(&self, func_idx: u32) -> Result<usize> {
+        let defined_idx = func_idx
+            .checked_sub(self.import_func_count)
+            .ok_or_else(|| anyhow!("Function {func_idx} is imported and has no body"))?;
+        let ContextCode::New(_, body) = self
+            .code_section
+            .get(defined_idx as usize)
+            .ok_or_else(|| anyhow!("Function index {func_idx} out of range"))?;
+        let mut reader = wasmparser::OperatorsReader::new(wasmparser::BinaryReader::new(body, 0));
+        let mut count = 0;
+        while !reader.eof() {
+            reader.read()?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Number of functions defined in the module, including added ones but not imports
+    pub fn function_count(&self) -> usize {
+        self.functions.len()
+    }
+
+    /// Number of imported functions, they come first in the function index space
+    pub fn import_function_count(&self) -> u32 {
+        self.import_func_count
+    }
+
+    /// Number of function types (signatures), including added ones
+    pub fn type_count(&self) -> usize {
+        self.types.len()
+    }
+
+    /// Names of the preserved custom sections, in module order
+    pub fn custom_section_names(&self) -> Vec<String> {
+        self.sections
+            .iter()
+            .filter_map(|section| section.custom_name())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Drop every custom section called `name`, e.g. `.debug_info` or `producers`.
+    /// Returns the number of sections removed.
+    pub fn remove_custom_section(&mut self, name: &str) -> usize {
+        let before = self.sections.len();
+        self.sections
+            .retain(|section| section.custom_name() != Some(name));
+        before - self.sections.len()
+    }
+
+    /// Shrink the module without changing its behavior: drop debug info custom sections,
+    /// merge identical function types and remove types nothing refers to.
+    ///
+    /// The `name` section counts as debug info, its type names would be stale anyway.
+    /// Type references in imports, functions, `call_indirect` and blocks are rewritten to
+    /// the new indices. Bodies and sections are decoded before anything is modified, so on
+    /// error the context is left untouched, e.g. for typed function references in code,
+    /// tables, globals or elements, which aren't supported.
+    pub fn minify(&mut self) -> Result<()> {
+        for section in &self.sections {
+            if section_has_type_references(section)? {
+                return Err(anyhow!("Typed function references are not supported"));
+            }
+        }
+        let mut body_offsets = Vec::with_capacity(self.code_section.len());
+        for code in &self.code_section {
+            let ContextCode::New(_, body) = code;
+            body_offsets.push(type_index_offsets(body)?);
+        }
+
+        // Every referenced type, mapped to the first type with the same signature
+        let mut used = vec![false; self.types.len()];
+        let import_types = self.imports.iter().filter_map(|import| match import.ty {
+            wasm_encoder::EntityType::Function(idx)
+            | wasm_encoder::EntityType::FunctionExact(idx) => Some(idx),
+            wasm_encoder::EntityType::Tag(tag) => Some(tag.func_type_idx),
+            _ => None,
+        });
+        let mut referenced: Vec<u32> = import_types.chain(self.functions.iter().copied()).collect();
+        for (code, offsets) in self.code_section.iter().zip(&body_offsets) {
+            let ContextCode::New(_, body) = code;
+            for &(offset, _) in offsets {
+                let mut reader = wasmparser::BinaryReader::new(&body[offset..], 0);
+                referenced.push(reader.read_var_s33()? as u32);
+            }
+        }
+        for idx in referenced {
+            *used
+                .get_mut(idx as usize)
+                .ok_or_else(|| anyhow!("Type {idx} is out of range"))? = true;
+        }
+
+        let mut types: Vec<ContextType> = Vec::new();
+        let mut type_map = vec![0; self.types.len()];
+        for (idx, ContextType::New(params, returns)) in self.types.iter().enumerate() {
+            if !used[idx] {
+                continue;
+            }
+            let existing = types
+                .iter()
+                .position(|ContextType::New(p, r)| p == params && r == returns);
+            type_map[idx] = match existing {
+                Some(new_idx) => new_idx as u32,
+                None => {
+                    types.push(ContextType::New(params.clone(), returns.clone()));
+                    types.len() as u32 - 1
+                }
+            };
+        }
+        let remap = |idx: u32| type_map[idx as usize];
+
+        let mut bodies = Vec::with_capacity(self.code_section.len());
+        for (code, offsets) in self.code_section.iter().zip(&body_offsets) {
+            let ContextCode::New(_, body) = code;
+            bodies.push(rewrite_type_indices(body, offsets, remap)?);
+        }
+
+        for (code, new_body) in self.code_section.iter_mut().zip(bodies) {
+            let ContextCode::New(_, body) = code;
+            *body = new_body;
+        }
+        for type_idx in &mut self.functions {
+            *type_idx = remap(*type_idx);
+        }
+        for import in &mut self.imports {
+            match &mut import.ty {
+                wasm_encoder::EntityType::Function(idx)
+                | wasm_encoder::EntityType::FunctionExact(idx) => *idx = remap(*idx),
+                wasm_encoder::EntityType::Tag(tag) => tag.func_type_idx = remap(tag.func_type_idx),
+                _ => {}
+            }
+        }
+        self.types = types;
+        self.sections.retain(|section| {
+            !section.custom_name().is_some_and(|name| {
+                name.starts_with(".debug_")
+                    || name == "name"
+                    || name == "external_debug_info"
+                    || name == "sourceMappingURL"
+            })
+        });
+        Ok(())
+    }
+
+    /// The distinct module names of all imports, e.g. `lunatic::process` or
+    /// `wasi_snapshot_preview1`.
+    pub fn imported_namespaces(&self) -> std::collections::BTreeSet<String> {
+        self.imports
+            .iter()
+            .map(|import| import.module.clone())
+            .collect()
+    }
+
+    /// Change every import of `old_module::old_name` to import `new_module::new_name` instead.
+    /// Returns the number of imports renamed.
+    ///
+    /// Only the names change, the import types and all indices stay the same.
+    pub fn rename_import(
+        &mut self,
+        old_module: &str,
+        old_name: &str,
+        new_module: &str,
+        new_name: &str,
+    ) -> usize {
+        let mut renamed = 0;
+        for import in &mut self.imports {
+            if import.module == old_module && import.name == old_name {
+                import.module = new_module.to_string();
+                import.name = new_name.to_string();
+                renamed += 1;
+            }
+        }
+        renamed
+    }
+
+    /// Move every import from the `old_module` namespace to `new_module`, keeping their names.
+    /// Returns the number of imports moved.
+    pub fn rename_import_module(&mut self, old_module: &str, new_module: &str) -> usize {
+        let mut renamed = 0;
+        for import in &mut self.imports {
+            if import.module == old_module {
+                import.module = new_module.to_string();
+                renamed += 1;
+            }
+        }
+        renamed
+    }
+
+    /// Initial and maximum size, in pages, of the module's first memory, which may be
+    /// imported. `None` if the module has no memory or its limits don't fit in `u32`.
+    pub fn memory_limits(&self) -> Option<(u32, Option<u32>)> {
+        let imported = self.imports.iter().find_map(|import| match import.ty {
+            wasm_encoder::EntityType::Memory(ty) => Some((ty.minimum, ty.maximum)),
+            _ => None,
+        });
+        let (minimum, maximum) = match imported {
+            Some(limits) => limits,
+            None => {
+                let section = self.sections.iter().find(|section| section.id == 5)?;
+                let reader = wasmparser::MemorySectionReader::new(wasmparser::BinaryReader::new(
+                    &section.data,
+                    0,
+                ))
+                .ok()?;
+                let ty = reader.into_iter().next()?.ok()?;
+                (ty.initial, ty.maximum)
+            }
+        };
+        let maximum = match maximum {
+            Some(maximum) => Some(u32::try_from(maximum).ok()?),
+            None => None,
+        };
+        Some((u32::try_from(minimum).ok()?, maximum))
+    }
+
+    /// Check if the module exports anything called `name`
+    pub fn has_export(&self, name: &str) -> bool {
+        self.export_entries().any(|(export, _, _)| export == name)
+    }
+
+    /// Whether the module is a WASI command, reactor or a plain library
+    pub fn entry_kind(&self) -> EntryKind {
+        let exports_function = |name| {
+            self.export_entries()
+                .any(|(export, kind, _)| export == name && kind == wasmparser::ExternalKind::Func)
+        };
+        if exports_function("_start") {
+            EntryKind::Command
+        } else if exports_function("_initialize") {
+            EntryKind::Reactor
+        } else {
+            EntryKind::Library
+        }
+    }
+
+    /// Compare the exports of a module before and after a transform, by name.
+    ///
+    /// Exports are listed in the order they appear in the module that has them.
+    pub fn export_diff(before: &ModuleContext, after: &ModuleContext) -> ExportDiff {
+        let before_exports: std::collections::HashMap<_, _> = before
+            .export_entries()
+            .map(|(name, kind, index)| (name, (kind, index)))
+            .collect();
+        let after_exports: std::collections::HashMap<_, _> = after
+            .export_entries()
+            .map(|(name, kind, index)| (name, (kind, index)))
+            .collect();
+
+        let mut diff = ExportDiff::default();
+        for (name, kind, index) in after.export_entries() {
+            match before_exports.get(name) {
+                None => diff.added.push(name.to_string()),
+                Some(&previous) if previous != (kind, index) => {
+                    diff.reindexed.push(name.to_string())
+                }
+                Some(_) => {}
+            }
+        }
+        diff.removed = before
+            .export_entries()
+            .filter(|(name, _, _)| !after_exports.contains_key(name))
+            .map(|(name, _, _)| name.to_string())
+            .collect();
+        diff
+    }
+
+    /// All exports as (name, kind, index), in module order
+    fn export_entries(&self) -> impl Iterator<Item = (&str, wasmparser::ExternalKind, u32)> {
+        self.exports.iter().map(|export| match export {
+            ContextExport::NewFunction(name, index) => {
+                (name.as_str(), wasmparser::ExternalKind::Func, *index)
+            }
+            ContextExport::Parsed { name, kind, index } => (name.as_str(), *kind, *index),
+        })
+    }
+
     /// Look up a function index by its export name
     pub fn function_by_name(&self, name: &str) -> Option<u32> {
         self.function_names.get(name).copied()
     }
 
+    /// Point the function export `name` at `func_idx`, leaving the previously exported
+    /// function in place. Returns `false` if there is no function exported as `name`.
+    pub fn retarget_export(&mut self, name: &str, func_idx: u32) -> bool {
+        let mut found = false;
+        for export in &mut self.exports {
+            match export {
+                ContextExport::NewFunction(export_name, index)
+                | ContextExport::Parsed {
+                    name: export_name,
+                    kind: wasmparser::ExternalKind::Func,
+                    index,
+                } if export_name == name => {
+                    *index = func_idx;
+                    found = true;
+                }
+                _ => {}
+            }
+        }
+        if found {
+            self.function_names.insert(name.to_string(), func_idx);
+        }
+        found
+    }
+
+    /// Replace the function export `name` with a wrapper that calls `prologue`, the
+    /// original function and then `epilogue`.
+    ///
+    /// The wrapper has the same signature as the original and forwards all arguments
+    /// and results. Both hooks must take no arguments and return nothing. The original
+    /// function keeps its index, so internal calls to it are not traced.
+    pub fn wrap_export(&mut self, name: &str, prologue: u32, epilogue: u32) -> Result<()> {
+        let original = self
+            .function_by_name(name)
+            .ok_or_else(|| anyhow!("No function exported as `{name}`"))?;
+        for hook in [prologue, epilogue] {
+            let (params, returns) = self.function_signature(hook)?;
+            if !params.is_empty() || !returns.is_empty() {
+                return Err(anyhow!(
+                    "Hook function {hook} must take no arguments and return nothing"
+                ));
+            }
+        }
+        let type_idx = self.function_type(original)?;
+        let param_count = self.function_signature(original)?.0.len() as u32;
+
+        let mut body = Vec::new();
+        wasm_encoder::Instruction::Call(prologue).encode(&mut body);
+        for local in 0..param_count {
+            wasm_encoder::Instruction::LocalGet(local).encode(&mut body);
+        }
+        wasm_encoder::Instruction::Call(original).encode(&mut body);
+        // The results stay on the stack while the epilogue runs
+        wasm_encoder::Instruction::Call(epilogue).encode(&mut body);
+        wasm_encoder::Instruction::End.encode(&mut body);
+
+        let wrapper = self.add_function(type_idx, vec![], body);
+        self.retarget_export(name, wrapper);
+        Ok(())
+    }
+
+    /// Count the instructions the module executes in a mutable i64 global, readable
+    /// through a new `__instr_count() -> i64` export.
+    ///
+    /// Every defined function adds the number of instructions in its body, as reported
+    /// by [`function_instruction_count`](Self::function_instruction_count), to the counter
+    /// when it's entered. Loops and early returns aren't taken into account, so the count
+    /// is an estimate of the work done rather than an exact figure. The accessor itself
+    /// isn't counted. Fails if the module already exports `__instr_count`.
+    pub fn add_instruction_counter(&mut self) -> Result<()> {
+        const ACCESSOR: &str = "__instr_count";
+        if self.has_export(ACCESSOR) {
+            return Err(anyhow!("Module already exports `{ACCESSOR}`"));
+        }
+        let counter = self.add_global(
+            wasm_encoder::GlobalType {
+                val_type: wasm_encoder::ValType::I64,
+                mutable: true,
+                shared: false,
+            },
+            &wasm_encoder::ConstExpr::i64_const(0),
+        )?;
+        for func_idx in self.import_func_count..self.import_func_count + self.functions.len() as u32
+        {
+            let count = self.function_instruction_count(func_idx)?;
+            let mut increment = Vec::new();
+            wasm_encoder::Instruction::GlobalGet(counter).encode(&mut increment);
+            wasm_encoder::Instruction::I64Const(count as i64).encode(&mut increment);
+            wasm_encoder::Instruction::I64Add.encode(&mut increment);
+            wasm_encoder::Instruction::GlobalSet(counter).encode(&mut increment);
+            self.prepend_to_function(func_idx, &increment)?;
+        }
+
+        let type_idx = self.add_function_type(vec![], vec![wasm_encoder::ValType::I64]);
+        let mut body = Vec::new();
+        wasm_encoder::Instruction::GlobalGet(counter).encode(&mut body);
+        wasm_encoder::Instruction::End.encode(&mut body);
+        let accessor = self.add_function(type_idx, vec![], body);
+        self.add_function_export(ACCESSOR.to_string(), accessor);
+        Ok(())
+    }
+
+    /// Copy the functions of a small `helper` module into this one, e.g. runtime support
+    /// code written in WAT instead of hand-encoded bodies.
+    /// Returns the new indices of the helper's defined functions, in helper order.
+    ///
+    /// The helper can only define types and functions, its exports are ignored. Function
+    /// imports from the module `import_prefix` are linked to this module's function exported
+    /// under the import's name, so the helper can call into it. Other function imports are
+    /// added to this module, which shifts its defined functions like
+    /// [`insert_function_import_remapping`](Self::insert_function_import_remapping). A helper
+    /// may import one memory to use this module's first memory. Helper types are reused if
+    /// this module already has them. Blocks typed by a type index are not supported.
+    pub fn splice_module(&mut self, helper: &[u8], import_prefix: &str) -> Result<Vec<u32>> {
+        wasmparser::Validator::new().validate_all(helper)?;
+        let helper = ModuleContext::new(helper)?;
+        if helper.sections.iter().any(|section| section.id != 0) || !helper.data_segments.is_empty()
+        {
+            return Err(anyhow!(
+                "Helper modules can only define types and functions"
+            ));
+        }
+        let mut call_offsets = Vec::with_capacity(helper.code_section.len());
+        for code in &helper.code_section {
+            let ContextCode::New(_, body) = code;
+            call_offsets.push(helper_call_offsets(body)?);
+        }
+
+        // Check every import before changing anything
+        let mut memory_imports = 0;
+        for import in &helper.imports {
+            match import.ty {
+                wasm_encoder::EntityType::Function(type_idx) if import.module == import_prefix => {
+                    let target = self.function_by_name(&import.name).ok_or_else(|| {
+                        anyhow!("Helper imports `{}`, which isn't exported", import.name)
+                    })?;
+                    // The helper is valid, so the type exists
+                    let ContextType::New(params, returns) = &helper.types[type_idx as usize];
+                    if self.function_signature(target)? != (params.as_slice(), returns.as_slice()) {
+                        return Err(anyhow!(
+                            "Helper imports `{}` with a different signature",
+                            import.name
+                        ));
+                    }
+                }
+                wasm_encoder::EntityType::Function(_) => {}
+                wasm_encoder::EntityType::Memory(_) if self.has_memory() => memory_imports += 1,
+                _ => {
+                    return Err(anyhow!(
+                        "Helper import `{}::{}` is not supported",
+                        import.module,
+                        import.name
+                    ));
+                }
+            }
+        }
+        if memory_imports > 1 {
+            return Err(anyhow!("Helper modules can import at most one memory"));
+        }
+
+        let type_map: Vec<u32> = helper
+            .types
+            .iter()
+            .map(|ContextType::New(params, returns)| self.find_or_add_type(params, returns))
+            .collect();
+
+        // Added imports shift this module's functions, so linked ones are looked up after
+        let first_added = self.import_func_count;
+        for import in &helper.imports {
+            let wasm_encoder::EntityType::Function(type_idx) = import.ty else {
+                continue;
+            };
+            if import.module != import_prefix {
+                self.insert_function_import_remapping(
+                    &import.module,
+                    &import.name,
+                    type_map[type_idx as usize],
+                )?;
+            }
+        }
+        // Added imports took the slots right after the existing ones, in helper order
+        let mut added = first_added;
+        let mut import_map = Vec::new();
+        for import in &helper.imports {
+            let wasm_encoder::EntityType::Function(_) = import.ty else {
+                continue;
+            };
+            if import.module == import_prefix {
+                // Checked above
+                import_map.push(self.function_by_name(&import.name).unwrap());
+            } else {
+                import_map.push(added);
+                added += 1;
+            }
+        }
+
+        let first_defined = self.import_func_count + self.functions.len() as u32;
+        let remap = |idx: u32| match import_map.get(idx as usize) {
+            Some(&mapped) => mapped,
+            None => first_defined + idx - helper.import_func_count,
+        };
+        let mut indices = Vec::with_capacity(helper.functions.len());
+        for ((type_idx, code), offsets) in helper
+            .functions
+            .iter()
+            .zip(&helper.code_section)
+            .zip(&call_offsets)
+        {
+            let ContextCode::New(locals, body) = code;
+            let body = rewrite_indices(body, offsets, remap)?;
+            indices.push(self.add_function(type_map[*type_idx as usize], locals.clone(), body));
+        }
+        Ok(indices)
+    }
+
+    /// The index of a function type with these params and returns, added if missing
+    fn find_or_add_type(
+        &mut self,
+        params: &[wasm_encoder::ValType],
+        returns: &[wasm_encoder::ValType],
+    ) -> u32 {
+        let existing = self
+            .types
+            .iter()
+            .position(|ContextType::New(p, r)| p.as_slice() == params && r.as_slice() == returns);
+        match existing {
+            Some(idx) => idx as u32,
+            None => self.add_function_type(params.to_vec(), returns.to_vec()),
+        }
+    }
+
+    /// Check if the module defines or imports a memory
+    fn has_memory(&self) -> bool {
+        self.sections.iter().any(|section| section.id == 5)
+            || self
+                .imports
+                .iter()
+                .any(|import| matches!(import.ty, wasm_encoder::EntityType::Memory(_)))
+    }
+
+    /// The type index of the function at `func_idx`, imported or defined
+    fn function_type(&self, func_idx: u32) -> Result<u32> {
+        let type_idx = match func_idx.checked_sub(self.import_func_count) {
+            Some(defined_idx) => self.functions.get(defined_idx as usize).copied(),
+            None => self
+                .imports
+                .iter()
+                .filter_map(|import| match import.ty {
+                    wasm_encoder::EntityType::Function(type_idx) => Some(type_idx),
+                    _ => None,
+                })
+                .nth(func_idx as usize),
+        };
+        type_idx.ok_or_else(|| anyhow!("Function {func_idx} is out of range"))
+    }
+
+    /// The params and returns of the function at `func_idx`
+    fn function_signature(
+        &self,
+        func_idx: u32,
+    ) -> Result<(&[wasm_encoder::ValType], &[wasm_encoder::ValType])> {
+        let type_idx = self.function_type(func_idx)?;
+        match self.types.get(type_idx as usize) {
+            Some(ContextType::New(params, returns)) => Ok((params, returns)),
+            None => Err(anyhow!("Type {type_idx} is out of range")),
+        }
+    }
+
     /// Encode the (possibly modified) module back to WebAssembly binary format
     pub fn encode(&self) -> Result<Vec<u8>> {
         let mut module = wasm_encoder::Module::new();
@@ -326,18 +1082,16 @@ impl ModuleContext {
             });
         }
 
-        // DataCount section (12) - must come before code section
-        // We need to peek ahead for this since it may not be next
+        // Any other non-custom sections are emitted after the data section
         let mut deferred_sections = Vec::new();
-        while let Some(section) = section_iter.next_if(|s| !matches!(s.id, 0 | 11 | 12)) {
+        while let Some(section) = section_iter.next_if(|s| s.id != 0) {
             deferred_sections.push(section);
         }
 
-        // Emit DataCount if present
-        while let Some(section) = section_iter.next_if(|s| s.id == 12) {
-            module.section(&wasm_encoder::RawSection {
-                id: section.id,
-                data: &section.data,
+        // DataCount section (12) - must come before code section
+        if self.has_data_count {
+            module.section(&wasm_encoder::DataCountSection {
+                count: self.data_segments.len() as u32,
             });
         }
 
@@ -357,11 +1111,19 @@ impl ModuleContext {
         }
 
         // Data section (11)
-        while let Some(section) = section_iter.next_if(|s| s.id == 11) {
-            module.section(&wasm_encoder::RawSection {
-                id: section.id,
-                data: &section.data,
-            });
+        if !self.data_segments.is_empty() {
+            let mut data_section = wasm_encoder::DataSection::new();
+            for data in &self.data_segments {
+                match data {
+                    ContextData::Parsed(raw) => {
+                        data_section.raw(raw);
+                    }
+                    ContextData::NewActive(memory_index, offset, bytes) => {
+                        data_section.active(*memory_index, offset, bytes.iter().copied());
+                    }
+                }
+            }
+            module.section(&data_section);
         }
 
         // Emit any deferred sections
@@ -384,6 +1146,226 @@ impl ModuleContext {
     }
 }
 
+/// Collect the offsets of the function index immediates of `call`, `return_call`
+/// and `ref.func` instructions. Each of these has a single-byte opcode, so the
+/// LEB128 index starts right after the operator's offset.
+fn function_index_offsets(reader: wasmparser::OperatorsReader) -> Result<Vec<usize>> {
+    let mut offsets = Vec::new();
+    for op in reader.into_iter_with_offsets() {
+        let (op, offset) = op?;
+        if let wasmparser::Operator::Call { .. }
+        | wasmparser::Operator::ReturnCall { .. }
+        | wasmparser::Operator::RefFunc { .. } = op
+        {
+            offsets.push(offset + 1);
+        }
+    }
+    Ok(offsets)
+}
+
+/// [`function_index_offsets`] of a helper function body for
+/// [`ModuleContext::splice_module`], rejecting blocks typed by a type index
+fn helper_call_offsets(body: &[u8]) -> Result<Vec<usize>> {
+    let reader = wasmparser::OperatorsReader::new(wasmparser::BinaryReader::new(body, 0));
+    for op in reader.clone() {
+        if let wasmparser::Operator::Block { blockty }
+        | wasmparser::Operator::Loop { blockty }
+        | wasmparser::Operator::If { blockty } = op?
+            && let wasmparser::BlockType::FuncType(_) = blockty
+        {
+            return Err(anyhow!(
+                "Helper blocks typed by a type index are not supported"
+            ));
+        }
+    }
+    function_index_offsets(reader)
+}
+
+/// Collect the offsets of the type indices in a function body, each with whether it's a
+/// block type encoded as a signed LEB128 s33 rather than an unsigned u32. Only blocks
+/// typed by an index are included.
+fn type_index_offsets(body: &[u8]) -> Result<Vec<(usize, bool)>> {
+    let reader = wasmparser::OperatorsReader::new(wasmparser::BinaryReader::new(body, 0));
+    let mut offsets = Vec::new();
+    for op in reader.into_iter_with_offsets() {
+        let (op, offset) = op?;
+        match op {
+            // Single-byte opcodes followed by the type index
+            wasmparser::Operator::CallIndirect { .. }
+            | wasmparser::Operator::ReturnCallIndirect { .. } => offsets.push((offset + 1, false)),
+            wasmparser::Operator::Block { blockty }
+            | wasmparser::Operator::Loop { blockty }
+            | wasmparser::Operator::If { blockty }
+            | wasmparser::Operator::Try { blockty }
+                if matches!(blockty, wasmparser::BlockType::FuncType(_)) =>
+            {
+                offsets.push((offset + 1, true))
+            }
+            wasmparser::Operator::TryTable { try_table }
+                if matches!(try_table.ty, wasmparser::BlockType::FuncType(_)) =>
+            {
+                offsets.push((offset + 1, true))
+            }
+            wasmparser::Operator::CallRef { .. }
+            | wasmparser::Operator::ReturnCallRef { .. }
+            | wasmparser::Operator::RefNull {
+                hty: wasmparser::HeapType::Concrete(_) | wasmparser::HeapType::Exact(_),
+            } => return Err(anyhow!("Typed function references are not supported")),
+            _ => {}
+        }
+    }
+    Ok(offsets)
+}
+
+/// Check if a raw table, global or element section refers to a type, which it only does
+/// through typed function references
+fn section_has_type_references(section: &RawSection) -> Result<bool> {
+    let reader = wasmparser::BinaryReader::new(&section.data, 0);
+    match section.id {
+        4 => {
+            for table in wasmparser::TableSectionReader::new(reader)? {
+                let table = table?;
+                let init_has_types = match table.init {
+                    wasmparser::TableInit::RefNull => false,
+                    wasmparser::TableInit::Expr(expr) => const_expr_has_type_references(&expr)?,
+                };
+                if is_typed_ref(table.ty.element_type) || init_has_types {
+                    return Ok(true);
+                }
+            }
+        }
+        6 => {
+            for global in wasmparser::GlobalSectionReader::new(reader)? {
+                let global = global?;
+                let typed = match global.ty.content_type {
+                    wasmparser::ValType::Ref(ty) => is_typed_ref(ty),
+                    _ => false,
+                };
+                if typed || const_expr_has_type_references(&global.init_expr)? {
+                    return Ok(true);
+                }
+            }
+        }
+        9 => {
+            for element in wasmparser::ElementSectionReader::new(reader)? {
+                let element = element?;
+                if let wasmparser::ElementKind::Active { offset_expr, .. } = &element.kind
+                    && const_expr_has_type_references(offset_expr)?
+                {
+                    return Ok(true);
+                }
+                if let wasmparser::ElementItems::Expressions(ty, exprs) = element.items {
+                    if is_typed_ref(ty) {
+                        return Ok(true);
+                    }
+                    for expr in exprs {
+                        if const_expr_has_type_references(&expr?)? {
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(false)
+}
+
+fn is_typed_ref(ty: wasmparser::RefType) -> bool {
+    matches!(
+        ty.heap_type(),
+        wasmparser::HeapType::Concrete(_) | wasmparser::HeapType::Exact(_)
+    )
+}
+
+fn const_expr_has_type_references(expr: &wasmparser::ConstExpr) -> Result<bool> {
+    for op in expr.get_operators_reader() {
+        if let wasmparser::Operator::RefNull { hty } = op?
+            && matches!(
+                hty,
+                wasmparser::HeapType::Concrete(_) | wasmparser::HeapType::Exact(_)
+            )
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Like [`rewrite_indices`] for the offsets found by [`type_index_offsets`]
+fn rewrite_type_indices(
+    data: &[u8],
+    offsets: &[(usize, bool)],
+    remap: impl Fn(u32) -> u32,
+) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    for &(offset, block_type) in offsets {
+        let mut reader = wasmparser::BinaryReader::new(&data[offset..], 0);
+        out.extend_from_slice(&data[pos..offset]);
+        if block_type {
+            let index = remap(reader.read_var_s33()? as u32);
+            wasm_encoder::BlockType::FunctionType(index).encode(&mut out);
+        } else {
+            remap(reader.read_var_u32()?).encode(&mut out);
+        }
+        pos = offset + reader.current_position();
+    }
+    out.extend_from_slice(&data[pos..]);
+    Ok(out)
+}
+
+/// Offsets of function indices in the init expressions of a raw global section
+fn global_function_index_offsets(data: &[u8]) -> Result<Vec<usize>> {
+    let reader = wasmparser::GlobalSectionReader::new(wasmparser::BinaryReader::new(data, 0))?;
+    let mut offsets = Vec::new();
+    for global in reader {
+        offsets.extend(function_index_offsets(
+            global?.init_expr.get_operators_reader(),
+        )?);
+    }
+    Ok(offsets)
+}
+
+/// Offsets of function indices in the items of a raw element section
+fn element_function_index_offsets(data: &[u8]) -> Result<Vec<usize>> {
+    let reader = wasmparser::ElementSectionReader::new(wasmparser::BinaryReader::new(data, 0))?;
+    let mut offsets = Vec::new();
+    for element in reader {
+        match element?.items {
+            wasmparser::ElementItems::Functions(funcs) => {
+                for func in funcs.into_iter_with_offsets() {
+                    offsets.push(func?.0);
+                }
+            }
+            wasmparser::ElementItems::Expressions(_, exprs) => {
+                for expr in exprs {
+                    offsets.extend(function_index_offsets(expr?.get_operators_reader())?);
+                }
+            }
+        }
+    }
+    Ok(offsets)
+}
+
+/// Copy `data`, replacing the LEB128 u32 at each of the (ascending) `offsets` with
+/// its remapped value. The re-encoded index may be longer or shorter than the original.
+fn rewrite_indices(data: &[u8], offsets: &[usize], remap: impl Fn(u32) -> u32) -> Result<Vec<u8>> {
+    use wasm_encoder::Encode;
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    for &offset in offsets {
+        let mut reader = wasmparser::BinaryReader::new(&data[offset..], 0);
+        let index = reader.read_var_u32()?;
+        out.extend_from_slice(&data[pos..offset]);
+        remap(index).encode(&mut out);
+        pos = offset + reader.current_position();
+    }
+    out.extend_from_slice(&data[pos..]);
+    Ok(out)
+}
+
 /// Translate a wasmparser ValType to a wasm_encoder ValType
 fn translate_val_type(ty: wasmparser::ValType) -> Result<wasm_encoder::ValType> {
     match ty {
@@ -537,4 +1519,637 @@ mod tests {
         let ctx2 = ModuleContext::new(&output).unwrap();
         assert_eq!(ctx2.function_by_name("test_func"), Some(0));
     }
+
+    #[test]
+    fn test_add_first_data_segment() {
+        let original = wat::parse_str(r#"(module (memory (export "memory") 1))"#).unwrap();
+
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        let idx = ctx.add_data_segment(
+            0,
+            wasm_encoder::ConstExpr::i32_const(16),
+            b"injected".to_vec(),
+        );
+        assert_eq!(idx, 0);
+        let output = ctx.encode().unwrap();
+
+        let mut has_data_count = false;
+        for payload in Parser::new(0).parse_all(&output) {
+            if let Payload::DataCountSection { count, .. } = payload.unwrap() {
+                assert_eq!(count, 1);
+                has_data_count = true;
+            }
+        }
+        assert!(has_data_count);
+
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, &output).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let mut buf = [0u8; 8];
+        memory.read(&store, 16, &mut buf).unwrap();
+        assert_eq!(&buf, b"injected");
+    }
+
+    #[test]
+    fn test_add_data_segment_keeps_existing_segments() {
+        // `data.drop` requires a DataCount section covering every segment
+        let original = wat::parse_str(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "first")
+                (data $passive "passive")
+                (func (export "drop_passive") (data.drop $passive))
+            )
+            "#,
+        )
+        .unwrap();
+
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        let idx = ctx.add_data_segment(
+            0,
+            wasm_encoder::ConstExpr::i32_const(32),
+            b"second".to_vec(),
+        );
+        assert_eq!(idx, 2);
+        let output = ctx.encode().unwrap();
+        wasmparser::Validator::new().validate_all(&output).unwrap();
+
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, &output).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let mut buf = [0u8; 5];
+        memory.read(&store, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"first");
+        let mut buf = [0u8; 6];
+        memory.read(&store, 32, &mut buf).unwrap();
+        assert_eq!(&buf, b"second");
+    }
+
+    #[test]
+    fn test_insert_function_import_remaps_indices() {
+        let original = wat::parse_str(
+            r#"
+            (module
+                (type $ret_i32 (func (result i32)))
+                (import "env" "existing" (func $existing (result i32)))
+                (table 2 funcref)
+                (elem (i32.const 0) $helper)
+                (global $helper_ref funcref (ref.func $helper))
+                (func $helper (result i32) (i32.const 42))
+                (func (export "direct") (result i32)
+                    (i32.add (call $helper) (call $existing)))
+                (func (export "indirect") (result i32)
+                    (call_indirect (type $ret_i32) (i32.const 0)))
+                (func (export "via_global") (result i32)
+                    (table.set (i32.const 1) (global.get $helper_ref))
+                    (call_indirect (type $ret_i32) (i32.const 1)))
+                (func (export "via_ref_func") (result i32)
+                    (table.set (i32.const 1) (ref.func $helper))
+                    (call_indirect (type $ret_i32) (i32.const 1)))
+            )
+            "#,
+        )
+        .unwrap();
+
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        assert_eq!(ctx.function_by_name("direct"), Some(2));
+        let type_idx = ctx.add_function_type(vec![], vec![wasm_encoder::ValType::I32]);
+        let injected = ctx
+            .insert_function_import_remapping("env", "injected", type_idx)
+            .unwrap();
+        assert_eq!(injected, 1);
+        assert_eq!(ctx.function_by_name("direct"), Some(3));
+
+        // New functions are appended after the shifted ones
+        let wrapper = ctx.add_function(type_idx, vec![], vec![0x10, injected as u8, 0x0b]);
+        assert_eq!(wrapper, 7);
+        ctx.add_function_export("call_injected".to_string(), wrapper);
+
+        let output = ctx.encode().unwrap();
+        wasmparser::Validator::new().validate_all(&output).unwrap();
+
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, &output).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let mut linker = wasmtime::Linker::new(&engine);
+        linker.func_wrap("env", "existing", || 1i32).unwrap();
+        linker.func_wrap("env", "injected", || 7i32).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+
+        let call = |store: &mut wasmtime::Store<()>, name: &str| {
+            instance
+                .get_typed_func::<(), i32>(&mut *store, name)
+                .unwrap()
+                .call(store, ())
+                .unwrap()
+        };
+        assert_eq!(call(&mut store, "direct"), 43);
+        assert_eq!(call(&mut store, "indirect"), 42);
+        assert_eq!(call(&mut store, "via_global"), 42);
+        assert_eq!(call(&mut store, "via_ref_func"), 42);
+        assert_eq!(call(&mut store, "call_injected"), 7);
+    }
+
+    #[test]
+    fn test_function_instruction_count() {
+        let original = wat::parse_str(
+            r#"
+            (module
+                (import "env" "f" (func))
+                (func (export "add") (param i32 i32) (result i32)
+                    (local i32)
+                    local.get 0
+                    local.get 1
+                    i32.add
+                    local.tee 2
+                    drop
+                    local.get 2)
+            )
+            "#,
+        )
+        .unwrap();
+
+        let ctx = ModuleContext::new(&original).unwrap();
+        let add = ctx.function_by_name("add").unwrap();
+        // Six instructions plus the implicit `end`
+        assert_eq!(ctx.function_instruction_count(add).unwrap(), 7);
+        assert!(ctx.function_instruction_count(0).is_err());
+        assert!(ctx.function_instruction_count(2).is_err());
+    }
+
+    #[test]
+    fn test_remove_custom_section() {
+        let mut module = wasm_encoder::Module::new();
+        for (name, data) in [
+            ("producers", &b"rustc"[..]),
+            (".debug_info", &b"dwarf"[..]),
+            ("keep", &b"me"[..]),
+            (".debug_info", &b"more dwarf"[..]),
+        ] {
+            module.section(&wasm_encoder::CustomSection {
+                name: name.into(),
+                data: data.into(),
+            });
+        }
+        let original = module.finish();
+
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        assert_eq!(
+            ctx.custom_section_names(),
+            ["producers", ".debug_info", "keep", ".debug_info"]
+        );
+        assert_eq!(ctx.remove_custom_section(".debug_info"), 2);
+        assert_eq!(ctx.remove_custom_section("missing"), 0);
+        let output = ctx.encode().unwrap();
+
+        let mut names = Vec::new();
+        for payload in Parser::new(0).parse_all(&output) {
+            if let Payload::CustomSection(custom) = payload.unwrap() {
+                names.push(custom.name().to_string());
+            }
+        }
+        assert_eq!(names, ["producers", "keep"]);
+    }
+
+    #[test]
+    fn test_memory_limits() {
+        let module = wat::parse_str("(module (memory 2 10))").unwrap();
+        let ctx = ModuleContext::new(&module).unwrap();
+        assert_eq!(ctx.memory_limits(), Some((2, Some(10))));
+
+        let module = wat::parse_str(r#"(module (import "env" "memory" (memory 1)))"#).unwrap();
+        let ctx = ModuleContext::new(&module).unwrap();
+        assert_eq!(ctx.memory_limits(), Some((1, None)));
+
+        let ctx = ModuleContext::new(&wat::parse_str("(module)").unwrap()).unwrap();
+        assert_eq!(ctx.memory_limits(), None);
+    }
+
+    #[test]
+    fn test_entry_kind() {
+        let entry_kind = |wat: &str| {
+            let ctx = ModuleContext::new(&wat::parse_str(wat).unwrap()).unwrap();
+            (ctx.entry_kind(), ctx.has_export("_start"))
+        };
+        assert_eq!(
+            entry_kind(r#"(module (func (export "_start")) (func (export "helper")))"#),
+            (EntryKind::Command, true)
+        );
+        assert_eq!(
+            entry_kind(r#"(module (func (export "_initialize")) (func (export "helper")))"#),
+            (EntryKind::Reactor, false)
+        );
+        assert_eq!(
+            entry_kind(r#"(module (func (export "helper")) (memory (export "_start") 1))"#),
+            (EntryKind::Library, true)
+        );
+
+        let mut ctx = ModuleContext::new(&wat::parse_str("(module)").unwrap()).unwrap();
+        let ty = ctx.add_function_type(vec![], vec![]);
+        let func = ctx.add_function(ty, vec![], vec![0x0B]);
+        ctx.add_function_export("_start".into(), func);
+        assert_eq!(ctx.entry_kind(), EntryKind::Command);
+    }
+
+    #[test]
+    fn test_export_diff() {
+        let original = wat::parse_str(
+            r#"
+            (module
+                (func (export "_start"))
+                (func (export "helper"))
+                (memory (export "memory") 1)
+            )
+            "#,
+        )
+        .unwrap();
+        let before = ModuleContext::new(&original).unwrap();
+        assert!(ModuleContext::export_diff(&before, &before).is_empty());
+
+        // A transform that injects an import shifts every exported function
+        let mut after = ModuleContext::new(&original).unwrap();
+        let ty = after.add_function_type(vec![], vec![]);
+        after
+            .insert_function_import_remapping("env", "hook", ty)
+            .unwrap();
+        let diff = ModuleContext::export_diff(&before, &after);
+        assert_eq!(diff.reindexed, vec!["_start", "helper"]);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+
+        // A transform that rebuilds the module without `_start`
+        let mut after = ModuleContext::new(
+            &wat::parse_str(
+                r#"(module (func) (func (export "helper")) (memory (export "memory") 1))"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let ty = after.add_function_type(vec![], vec![]);
+        let func = after.add_function(ty, vec![], vec![0x0B]);
+        after.add_function_export("init".into(), func);
+        let diff = ModuleContext::export_diff(&before, &after);
+        assert_eq!(diff.removed, vec!["_start"]);
+        assert_eq!(diff.added, vec!["init"]);
+        assert!(diff.reindexed.is_empty());
+    }
+
+    #[test]
+    fn test_rename_import() {
+        let original = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+                (import "env" "foo" (func))
+                (func (export "run") (call 2))
+            )
+            "#,
+        )
+        .unwrap();
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        assert_eq!(ctx.rename_import("env", "foo", "lunatic", "foo"), 1);
+        assert_eq!(ctx.rename_import("env", "foo", "lunatic", "foo"), 0);
+        assert_eq!(
+            ctx.rename_import_module("wasi_snapshot_preview1", "wasi_snapshot_preview2"),
+            2
+        );
+
+        let encoded = ctx.encode().unwrap();
+        wasmparser::Validator::new().validate_all(&encoded).unwrap();
+        let imports: Vec<_> = Parser::new(0)
+            .parse_all(&encoded)
+            .filter_map(|payload| match payload.unwrap() {
+                Payload::ImportSection(reader) => Some(reader),
+                _ => None,
+            })
+            .flat_map(|reader| {
+                reader
+                    .into_iter()
+                    .map(|import| {
+                        let import = import.unwrap();
+                        format!("{}::{}", import.module, import.name)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(
+            imports,
+            [
+                "wasi_snapshot_preview2::fd_write",
+                "wasi_snapshot_preview2::proc_exit",
+                "lunatic::foo"
+            ]
+        );
+        // Indices are untouched, so `run` still calls the renamed import
+        assert_eq!(ctx.function_by_name("run"), Some(3));
+    }
+
+    #[test]
+    fn test_imported_namespaces() {
+        let module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "spawn" (func))
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+            )
+            "#,
+        )
+        .unwrap();
+        let ctx = ModuleContext::new(&module).unwrap();
+        assert_eq!(
+            ctx.imported_namespaces().into_iter().collect::<Vec<_>>(),
+            ["lunatic::process", "wasi_snapshot_preview1"]
+        );
+
+        let ctx = ModuleContext::new(&wat::parse_str("(module)").unwrap()).unwrap();
+        assert!(ctx.imported_namespaces().is_empty());
+    }
+
+    #[test]
+    fn test_wrap_export() {
+        let original = wat::parse_str(
+            r#"
+            (module
+                (import "env" "record" (func $record (param i32)))
+                (func $before (call $record (i32.const 1)))
+                (func $after (call $record (i32.const 3)))
+                (func (export "add") (param i32 i32) (result i32)
+                    (call $record (i32.const 2))
+                    (i32.add (local.get 0) (local.get 1)))
+            )
+            "#,
+        )
+        .unwrap();
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        assert!(ctx.wrap_export("missing", 1, 2).is_err());
+        // The hooks must be `() -> ()`
+        assert!(ctx.wrap_export("add", 0, 2).is_err());
+        ctx.wrap_export("add", 1, 2).unwrap();
+        assert_eq!(ctx.function_by_name("add"), Some(4));
+
+        let encoded = ctx.encode().unwrap();
+        wasmparser::Validator::new().validate_all(&encoded).unwrap();
+
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, &encoded).unwrap();
+        let mut store = wasmtime::Store::new(&engine, Vec::new());
+        let mut linker = wasmtime::Linker::new(&engine);
+        linker
+            .func_wrap(
+                "env",
+                "record",
+                |mut caller: wasmtime::Caller<'_, Vec<i32>>, step: i32| {
+                    caller.data_mut().push(step)
+                },
+            )
+            .unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let add = instance
+            .get_typed_func::<(i32, i32), i32>(&mut store, "add")
+            .unwrap();
+        assert_eq!(add.call(&mut store, (2, 3)).unwrap(), 5);
+        assert_eq!(store.data(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_function_and_type_counts() {
+        let module = wat::parse_str(
+            r#"
+            (module
+                (import "env" "log" (func (param i32)))
+                (import "env" "memory" (memory 1))
+                (import "env" "now" (func (result i64)))
+                (func (param i32))
+                (func)
+                (func (result i32) i32.const 0)
+            )
+            "#,
+        )
+        .unwrap();
+        let mut ctx = ModuleContext::new(&module).unwrap();
+        assert_eq!(ctx.function_count(), 3);
+        assert_eq!(ctx.import_function_count(), 2);
+        // (i32) -> () is shared by an import and a defined function
+        assert_eq!(ctx.type_count(), 4);
+
+        let ty = ctx.add_function_type(vec![], vec![]);
+        ctx.add_function(ty, vec![], vec![0x0B]);
+        assert_eq!(ctx.function_count(), 4);
+        assert_eq!(ctx.import_function_count(), 2);
+        assert_eq!(ctx.type_count(), 5);
+    }
+
+    #[test]
+    fn test_splice_module() {
+        let original = wat::parse_str(
+            r#"
+            (module
+                (import "env" "offset" (func $offset (result i32)))
+                (func (export "double") (param i32) (result i32)
+                    (i32.mul (local.get 0) (i32.const 2)))
+            )
+            "#,
+        )
+        .unwrap();
+        // `add` doubles its first argument through the target's `double` export
+        let helper = wat::parse_str(
+            r#"
+            (module
+                (import "target" "double" (func $double (param i32) (result i32)))
+                (import "env" "scale" (func $scale (result i32)))
+                (func $add (export "add") (param i32 i32) (result i32)
+                    (i32.add (call $double (local.get 0)) (local.get 1)))
+                (func $scaled (result i32)
+                    (call $scale))
+            )
+            "#,
+        )
+        .unwrap();
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        let types = ctx.type_count();
+        let spliced = ctx.splice_module(&helper, "target").unwrap();
+        // `env::scale` is added as the second import
+        assert_eq!(spliced, vec![3, 4]);
+        assert_eq!(ctx.import_function_count(), 2);
+        assert_eq!(ctx.function_by_name("double"), Some(2));
+        // (i32) -> i32 and () -> i32 already exist
+        assert_eq!(ctx.type_count(), types + 1);
+
+        // Call the spliced `add` from an injected export
+        let ty = ctx.add_function_type(vec![], vec![wasm_encoder::ValType::I32]);
+        let mut body = Vec::new();
+        wasm_encoder::Instruction::I32Const(5).encode(&mut body);
+        wasm_encoder::Instruction::I32Const(3).encode(&mut body);
+        wasm_encoder::Instruction::Call(spliced[0]).encode(&mut body);
+        wasm_encoder::Instruction::Call(spliced[1]).encode(&mut body);
+        wasm_encoder::Instruction::I32Add.encode(&mut body);
+        wasm_encoder::Instruction::End.encode(&mut body);
+        let run = ctx.add_function(ty, vec![], body);
+        ctx.add_function_export("run".to_string(), run);
+
+        let encoded = ctx.encode().unwrap();
+        wasmparser::Validator::new().validate_all(&encoded).unwrap();
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, &encoded).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let mut linker = wasmtime::Linker::new(&engine);
+        linker.func_wrap("env", "offset", || 0i32).unwrap();
+        linker.func_wrap("env", "scale", || 100i32).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let run = instance
+            .get_typed_func::<(), i32>(&mut store, "run")
+            .unwrap();
+        assert_eq!(run.call(&mut store, ()).unwrap(), 113);
+
+        // Linked imports must exist with the same signature
+        let missing = wat::parse_str(r#"(module (import "target" "triple" (func)))"#).unwrap();
+        assert!(ctx.splice_module(&missing, "target").is_err());
+        let mismatch = wat::parse_str(r#"(module (import "target" "double" (func)))"#).unwrap();
+        assert!(ctx.splice_module(&mismatch, "target").is_err());
+        let global = wat::parse_str("(module (global i32 (i32.const 0)))").unwrap();
+        assert!(ctx.splice_module(&global, "target").is_err());
+    }
+
+    #[test]
+    fn test_minify() {
+        let original = wat::parse_str(
+            r#"
+            (module
+                (type $a (func (param i32) (result i32)))
+                (type $b (func (param i32) (result i32)))
+                (type $unused (func (param f64)))
+                (type $ret (func (result i32)))
+                (import "env" "inc" (func $inc (type $b)))
+                (table 1 funcref)
+                (elem (i32.const 0) $double)
+                (func $double (type $a) (i32.mul (local.get 0) (i32.const 2)))
+                (func (export "run") (type $ret)
+                    (call_indirect (type $b) (i32.const 20) (i32.const 0))
+                    (block (type $b) (call $inc)))
+                (@custom ".debug_info" "dwarf dwarf dwarf")
+                (@custom "producers" "rustc")
+            )
+            "#,
+        )
+        .unwrap();
+        let run = |module: &[u8]| {
+            let engine = wasmtime::Engine::default();
+            let module = wasmtime::Module::new(&engine, module).unwrap();
+            let mut store = wasmtime::Store::new(&engine, ());
+            let mut linker = wasmtime::Linker::new(&engine);
+            linker.func_wrap("env", "inc", |x: i32| x + 1).unwrap();
+            let instance = linker.instantiate(&mut store, &module).unwrap();
+            instance
+                .get_typed_func::<(), i32>(&mut store, "run")
+                .unwrap()
+                .call(&mut store, ())
+                .unwrap()
+        };
+
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        assert_eq!(ctx.type_count(), 4);
+        ctx.minify().unwrap();
+        assert_eq!(ctx.type_count(), 2);
+        assert_eq!(ctx.custom_section_names(), ["producers"]);
+
+        let minified = ctx.encode().unwrap();
+        wasmparser::Validator::new()
+            .validate_all(&minified)
+            .unwrap();
+        assert!(minified.len() < original.len());
+        assert_eq!(run(&original), 41);
+        assert_eq!(run(&minified), 41);
+
+        let typed_refs = wat::parse_str(
+            r#"
+            (module
+                (type $f (func))
+                (func (ref.null $f) drop)
+            )
+            "#,
+        )
+        .unwrap();
+        let mut ctx = ModuleContext::new(&typed_refs).unwrap();
+        assert!(ctx.minify().is_err());
+        assert_eq!(ctx.type_count(), 1);
+
+        // Typed references outside of code are only in the raw sections
+        for typed_refs in [
+            "(module (type (func (param f64))) (type $f (func)) (table 1 (ref null $f)) (func (export \"run\")))",
+            "(module (type (func (param f64))) (type $f (func)) (global (ref null $f) (ref.null $f)))",
+            "(module (type (func (param f64))) (type $f (func)) (elem (ref null $f) (ref.null $f)))",
+        ] {
+            let typed_refs = wat::parse_str(typed_refs).unwrap();
+            let mut ctx = ModuleContext::new(&typed_refs).unwrap();
+            assert!(ctx.minify().is_err(), "{typed_refs:?}");
+            assert_eq!(ctx.type_count(), 2);
+            wasmparser::Validator::new()
+                .validate_all(&ctx.encode().unwrap())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_add_instruction_counter() {
+        let original = wat::parse_str(
+            r#"
+            (module
+                (import "env" "g" (global i32))
+                (global $other (mut i32) (i32.const 7))
+                (func $double (param i32) (result i32)
+                    (i32.mul (local.get 0) (i32.const 2)))
+                (func (export "run") (param i32) (result i32)
+                    (call $double (local.get 0)))
+            )
+            "#,
+        )
+        .unwrap();
+        let mut ctx = ModuleContext::new(&original).unwrap();
+        // local.get, i32.const, i32.mul, end and local.get, call, end
+        let double = ctx.function_instruction_count(0).unwrap() as i64;
+        let run = ctx.function_instruction_count(1).unwrap() as i64;
+        assert_eq!((double, run), (4, 3));
+        ctx.add_instruction_counter().unwrap();
+        assert!(ctx.add_instruction_counter().is_err());
+
+        let encoded = ctx.encode().unwrap();
+        wasmparser::Validator::new().validate_all(&encoded).unwrap();
+
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, &encoded).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let imported = wasmtime::Global::new(
+            &mut store,
+            wasmtime::GlobalType::new(wasmtime::ValType::I32, wasmtime::Mutability::Const),
+            wasmtime::Val::I32(0),
+        )
+        .unwrap();
+        let instance = wasmtime::Instance::new(&mut store, &module, &[imported.into()]).unwrap();
+        let run = instance
+            .get_typed_func::<i32, i32>(&mut store, "run")
+            .unwrap();
+        let count = instance
+            .get_typed_func::<(), i64>(&mut store, "__instr_count")
+            .unwrap();
+        assert_eq!(count.call(&mut store, ()).unwrap(), 0);
+        for i in 0..5 {
+            assert_eq!(run.call(&mut store, i).unwrap(), i * 2);
+        }
+        assert_eq!(count.call(&mut store, ()).unwrap(), 5 * (double + 3));
+
+        // A module without globals gets a global section
+        let mut ctx = ModuleContext::new(&wat::parse_str("(module (func))").unwrap()).unwrap();
+        ctx.add_instruction_counter().unwrap();
+        wasmparser::Validator::new()
+            .validate_all(&ctx.encode().unwrap())
+            .unwrap();
+    }
 }
@@ -0,0 +1,120 @@
+//! A plugin registry that can be changed while other threads use it.
+
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use anyhow::Result;
+
+use crate::{Plugin, PluginInfo, PluginRegistry};
+
+/// A [`PluginRegistry`] shared between threads that register plugins, e.g. a control node, and
+/// threads that transform modules or dispatch events.
+///
+/// Readers hold a read lock for as long as they use the registry, so a transform in flight runs
+/// the whole pipeline as it was when the transform started. Changes wait for the readers in
+/// flight and are applied at once: a change made through a single [`write`](Self::write) guard
+/// is never seen half done.
+#[derive(Clone, Default)]
+pub struct SharedPluginRegistry {
+    inner: Arc<RwLock<PluginRegistry>>,
+}
+
+impl SharedPluginRegistry {
+    pub fn new(registry: PluginRegistry) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(registry)),
+        }
+    }
+
+    /// Lock the registry for reading, changes wait until the guard is dropped
+    pub fn read(&self) -> RwLockReadGuard<'_, PluginRegistry> {
+        self.inner.read().unwrap()
+    }
+
+    /// Lock the registry for a change, e.g. to register several plugins that readers should only
+    /// see together
+    pub fn write(&self) -> RwLockWriteGuard<'_, PluginRegistry> {
+        self.inner.write().unwrap()
+    }
+
+    /// See [`PluginRegistry::register_wasm`]
+    pub fn register_wasm(&self, info: PluginInfo, wasm: &[u8]) -> Result<()> {
+        self.write().register_wasm(info, wasm)
+    }
+
+    /// See [`PluginRegistry::unregister`]
+    pub fn unregister(&self, name: &str) -> Result<Arc<Plugin>> {
+        self.write().unregister(name)
+    }
+
+    /// See [`PluginRegistry::set_enabled`]
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+        self.write().set_enabled(name, enabled)
+    }
+
+    /// [`PluginRegistry::transform_module`] over a consistent snapshot of the pipeline
+    pub fn transform_module(&self, module_bytes: &[u8]) -> Result<Vec<u8>> {
+        self.read().transform_module(module_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::{
+        Capability,
+        test_plugin::tests::{appender_wat, plugin_info},
+    };
+
+    #[test]
+    fn transforms_see_consistent_pipeline_while_registering() {
+        let shared = SharedPluginRegistry::default();
+        let info = |name| plugin_info(name, &[Capability::ModuleTransform]);
+        shared
+            .register_wasm(info("a"), appender_wat(b"A").as_bytes())
+            .unwrap();
+        let (b, c) = (appender_wat(b"B"), appender_wat(b"C"));
+        let done = AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            let transforms: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut transformed = 0;
+                        while !done.load(Ordering::Acquire) || transformed == 0 {
+                            let output = shared.transform_module(b"data").unwrap();
+                            // "B" and "C" are registered and removed together
+                            assert!(
+                                output == b"dataA" || output == b"dataABC",
+                                "{}",
+                                String::from_utf8_lossy(&output)
+                            );
+                            transformed += 1;
+                        }
+                    })
+                })
+                .collect();
+
+            for _ in 0..20 {
+                {
+                    let mut registry = shared.write();
+                    registry.register_wasm(info("b"), b.as_bytes()).unwrap();
+                    registry.register_wasm(info("c"), c.as_bytes()).unwrap();
+                }
+                {
+                    let mut registry = shared.write();
+                    registry.unregister("b").unwrap();
+                    registry.unregister("c").unwrap();
+                }
+            }
+            done.store(true, Ordering::Release);
+            for transform in transforms {
+                transform.join().unwrap();
+            }
+        });
+
+        assert_eq!(shared.read().len(), 1);
+        assert!(shared.unregister("b").is_err());
+    }
+}
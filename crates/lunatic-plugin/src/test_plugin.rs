@@ -10,11 +10,12 @@
 //! - Plugin isolation (each dispatch creates fresh instance)
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::sync::Arc;
 
     use crate::{
-        Capability, LifecycleDispatcher, LifecycleEvent, Plugin, PluginInfo, PluginRegistry,
+        Capability, ExitReason, LifecycleDispatcher, LifecycleEvent, Plugin, PluginInfo,
+        PluginRegistry, TransformErrorMode,
     };
 
     // ============================================================================
@@ -56,7 +57,7 @@ mod tests {
                     (i64.store (i32.const 32) (local.get $pid))
                 )
 
-                (func (export "lunatic_on_process_exited") (param $pid i64)
+                (func (export "lunatic_on_process_exited") (param $pid i64) (param $reason i32)
                     (i32.store (i32.const 12)
                         (i32.add (i32.load (i32.const 12)) (i32.const 1)))
                     (i64.store (i32.const 32) (local.get $pid))
@@ -110,6 +111,34 @@ mod tests {
         "#
     }
 
+    /// Creates a transform plugin that prepends the "LUNA" header unless the input
+    /// already starts with it.
+    fn ensure_header_wat() -> &'static str {
+        r#"
+            (module
+                (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
+                (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
+                (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
+                (memory (export "memory") 1)
+
+                (func (export "lunatic_transform_module")
+                    (local $size i32)
+                    (local.set $size (call $input_size))
+                    (call $read_input (i32.const 4))
+
+                    ;; Header already present: write nothing, the input passes through
+                    (if (i32.and
+                            (i32.ge_u (local.get $size) (i32.const 4))
+                            (i32.eq (i32.load (i32.const 4)) (i32.const 0x414E554C)))
+                        (then (return)))
+
+                    (i32.store (i32.const 0) (i32.const 0x414E554C))  ;; "LUNA"
+                    (call $write_output (i32.const 0) (i32.add (local.get $size) (i32.const 4)))
+                )
+            )
+        "#
+    }
+
     /// Creates a transform plugin that reverses all bytes in the input.
     fn reverse_bytes_wat() -> &'static str {
         r#"
@@ -117,20 +146,25 @@ mod tests {
                 (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
                 (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
                 (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
-                (memory (export "memory") 2)
+                (import "lunatic_plugin" "scratch_base" (func $scratch_base (result i32)))
+                (memory (export "memory") 1)
 
                 (func (export "lunatic_transform_module")
                     (local $size i32)
+                    (local $input i32)
+                    (local $scratch i32)
                     (local $i i32)
                     (local $j i32)
                     (local $byte i32)
 
                     (local.set $size (call $input_size))
+                    ;; Makes room for the input and its reversed copy
+                    (local.set $input (call $scratch_base))
+                    (local.set $scratch (call $scratch_base))
 
-                    ;; Read input into first page (offset 0)
-                    (call $read_input (i32.const 0))
+                    (call $read_input (local.get $input))
 
-                    ;; Reverse into second page (offset 65536)
+                    ;; Reverse into the second scratch area
                     (local.set $i (i32.const 0))
                     (local.set $j (i32.sub (local.get $size) (i32.const 1)))
 
@@ -139,11 +173,12 @@ mod tests {
                             (br_if $break (i32.ge_u (local.get $i) (local.get $size)))
 
                             ;; Read byte from input at position i
-                            (local.set $byte (i32.load8_u (local.get $i)))
+                            (local.set $byte
+                                (i32.load8_u (i32.add (local.get $input) (local.get $i))))
 
                             ;; Write to output at reversed position
                             (i32.store8
-                                (i32.add (i32.const 65536) (local.get $j))
+                                (i32.add (local.get $scratch) (local.get $j))
                                 (local.get $byte))
 
                             (local.set $i (i32.add (local.get $i) (i32.const 1)))
@@ -152,7 +187,7 @@ mod tests {
                         )
                     )
 
-                    (call $write_output (i32.const 65536) (local.get $size))
+                    (call $write_output (local.get $scratch) (local.get $size))
                 )
             )
         "#
@@ -215,6 +250,37 @@ mod tests {
         "#
     }
 
+    /// Creates a transform plugin that passes the input through and appends `bytes` to it.
+    pub(crate) fn appender_wat(bytes: &[u8]) -> String {
+        let data: String = bytes.iter().map(|byte| format!("\\{byte:02x}")).collect();
+        format!(
+            r#"
+            (module
+                (import "lunatic_plugin" "passthrough" (func $passthrough))
+                (import "lunatic_plugin" "append_output" (func $append_output (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{data}")
+
+                (func (export "lunatic_transform_module")
+                    (call $passthrough)
+                    (call $append_output (i32.const 0) (i32.const {len}))
+                )
+            )
+        "#,
+            len = bytes.len()
+        )
+    }
+
+    /// Creates the info of a plugin at version 0.1.0 without dependencies.
+    pub(crate) fn plugin_info(name: &str, capabilities: &[Capability]) -> PluginInfo {
+        PluginInfo {
+            name: name.into(),
+            version: semver::Version::new(0, 1, 0),
+            capabilities: capabilities.to_vec(),
+            dependencies: vec![],
+        }
+    }
+
     /// Creates a plugin with multiple capabilities.
     fn dual_capability_wat() -> &'static str {
         r#"
@@ -260,14 +326,14 @@ mod tests {
     fn transform_single_plugin_prepends_header() {
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "prepend-header".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                prepend_header_wat().as_bytes(),
+                prepend_header_wat(),
             )
             .unwrap();
 
@@ -283,14 +349,14 @@ mod tests {
     fn transform_single_plugin_reverses_bytes() {
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "reverse".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                reverse_bytes_wat().as_bytes(),
+                reverse_bytes_wat(),
             )
             .unwrap();
 
@@ -301,20 +367,73 @@ mod tests {
         assert_eq!(&output, b"EDCBA");
     }
 
+    #[test]
+    fn transform_reverse_fits_inputs_larger_than_memory() {
+        let mut registry = PluginRegistry::new();
+        registry
+            .register_wat(
+                plugin_info("reverse", &[Capability::ModuleTransform]),
+                reverse_bytes_wat(),
+            )
+            .unwrap();
+
+        // Three pages of input for a plugin declaring a single page of memory
+        let input: Vec<u8> = (0..3 * 65536 + 5).map(|i| (i % 251) as u8).collect();
+        let output = registry.transform_module(&input).unwrap();
+
+        let mut expected = input.clone();
+        expected.reverse();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn transform_scratch_keeps_plugin_data() {
+        // Traps unless the data at offset 16 survives filling the scratch area
+        let wat = r#"
+            (module
+                (import "lunatic_plugin" "input_size" (func $input_size (result i32)))
+                (import "lunatic_plugin" "read_input" (func $read_input (param i32)))
+                (import "lunatic_plugin" "write_output" (func $write_output (param i32 i32)))
+                (import "lunatic_plugin" "scratch_base" (func $scratch_base (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "KEEP")
+                (func (export "lunatic_transform_module")
+                    (local $size i32)
+                    (local $scratch i32)
+                    (local.set $size (call $input_size))
+                    (call $read_input (i32.const 0))
+                    (local.set $scratch (call $scratch_base))
+                    (if (i32.lt_u (local.get $scratch) (i32.const 65536))
+                        (then unreachable))
+                    (memory.fill (local.get $scratch) (i32.const 0) (local.get $size))
+                    (if (i32.ne (i32.load (i32.const 16)) (i32.const 0x5045454B))
+                        (then unreachable))
+                    (call $write_output (i32.const 0) (local.get $size)))
+            )
+        "#;
+        let mut registry = PluginRegistry::new();
+        registry
+            .register_wat(plugin_info("scratch", &[Capability::ModuleTransform]), wat)
+            .unwrap();
+
+        let input = [1u8; 12];
+        assert_eq!(registry.transform_module(&input).unwrap(), input);
+    }
+
     #[test]
     fn transform_xor_is_reversible() {
         let mut registry = PluginRegistry::new();
 
         // XOR with key 0x42
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "xor".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                xor_transform_wat(0x42).as_bytes(),
+                &xor_transform_wat(0x42),
             )
             .unwrap();
 
@@ -324,14 +443,14 @@ mod tests {
         // XOR is self-inverse, so applying twice should restore original
         let mut registry2 = PluginRegistry::new();
         registry2
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "xor2".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                xor_transform_wat(0x42).as_bytes(),
+                &xor_transform_wat(0x42),
             )
             .unwrap();
 
@@ -343,14 +462,14 @@ mod tests {
     fn transform_noop_plugin_passthrough() {
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "noop".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                noop_transform_wat().as_bytes(),
+                noop_transform_wat(),
             )
             .unwrap();
 
@@ -365,49 +484,49 @@ mod tests {
         // Test: header -> xor should produce different result than xor -> header
         let mut registry1 = PluginRegistry::new();
         registry1
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "header".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                prepend_header_wat().as_bytes(),
+                prepend_header_wat(),
             )
             .unwrap();
         registry1
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "xor".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                xor_transform_wat(0xFF).as_bytes(),
+                &xor_transform_wat(0xFF),
             )
             .unwrap();
 
         let mut registry2 = PluginRegistry::new();
         registry2
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "xor".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                xor_transform_wat(0xFF).as_bytes(),
+                &xor_transform_wat(0xFF),
             )
             .unwrap();
         registry2
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "header".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                prepend_header_wat().as_bytes(),
+                prepend_header_wat(),
             )
             .unwrap();
 
@@ -424,38 +543,38 @@ mod tests {
 
         // Chain: prepend header -> reverse -> XOR
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "header".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                prepend_header_wat().as_bytes(),
+                prepend_header_wat(),
             )
             .unwrap();
 
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "reverse".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                reverse_bytes_wat().as_bytes(),
+                reverse_bytes_wat(),
             )
             .unwrap();
 
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "xor".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                xor_transform_wat(0x01).as_bytes(),
+                &xor_transform_wat(0x01),
             )
             .unwrap();
 
@@ -473,18 +592,93 @@ mod tests {
         assert_eq!(output, expected);
     }
 
+    #[test]
+    fn transform_audit_is_deterministic() {
+        use sha2::{Digest, Sha256};
+
+        let build = || {
+            let mut registry = PluginRegistry::new();
+            registry
+                .register_wat(
+                    plugin_info("header", &[Capability::ModuleTransform]),
+                    prepend_header_wat(),
+                )
+                .unwrap();
+            registry
+                .register_wat(
+                    plugin_info("xor", &[Capability::ModuleTransform]),
+                    &xor_transform_wat(0x01),
+                )
+                .unwrap();
+            registry.add_native_transform(Arc::new(|bytes| Ok(bytes.repeat(2))));
+            registry
+        };
+
+        let input = b"ABC";
+        let (output, audit) = build().transform_module_audited(input).unwrap();
+        let after_header = b"LUNAABC";
+        let after_xor: Vec<u8> = after_header.iter().map(|b| b ^ 0x01).collect();
+        assert_eq!(output, after_xor.repeat(2));
+
+        let digest = |bytes: &[u8]| -> [u8; 32] { Sha256::digest(bytes).into() };
+        assert_eq!(audit.input_sha256, digest(input));
+        assert_eq!(audit.output_sha256, digest(&output));
+        assert_eq!(
+            audit.pipeline_fingerprint,
+            build().pipeline_fingerprint().unwrap()
+        );
+        assert_eq!(
+            audit.per_plugin,
+            vec![
+                ("header".to_string(), digest(after_header)),
+                ("xor".to_string(), digest(&after_xor)),
+                ("<native>".to_string(), digest(&output)),
+            ]
+        );
+
+        // Same input through an identical pipeline, the second time past the cache
+        let registry = build();
+        registry.transform_module(input).unwrap();
+        assert_eq!(registry.transform_module_audited(input).unwrap().1, audit);
+        assert_eq!(build().transform_module_audited(input).unwrap().1, audit);
+
+        let (_, other) = build().transform_module_audited(b"ABD").unwrap();
+        assert_ne!(other.input_sha256, audit.input_sha256);
+        assert_eq!(other.pipeline_fingerprint, audit.pipeline_fingerprint);
+    }
+
+    #[test]
+    fn check_idempotent_detects_repeated_changes() {
+        let registry = |wat: &str| {
+            let mut registry = PluginRegistry::new();
+            registry
+                .register_wat(plugin_info("header", &[Capability::ModuleTransform]), wat)
+                .unwrap();
+            registry
+        };
+
+        // Appends another header every time
+        let prepend = registry(prepend_header_wat());
+        assert!(!prepend.check_idempotent(b"ABC").unwrap());
+
+        let ensure = registry(ensure_header_wat());
+        assert_eq!(ensure.transform_module(b"ABC").unwrap(), b"LUNAABC");
+        assert!(ensure.check_idempotent(b"ABC").unwrap());
+        assert!(ensure.check_idempotent(b"").unwrap());
+    }
+
     #[test]
     fn transform_empty_input() {
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "header".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                prepend_header_wat().as_bytes(),
+                prepend_header_wat(),
             )
             .unwrap();
 
@@ -499,14 +693,14 @@ mod tests {
     fn transform_large_input() {
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "reverse".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                reverse_bytes_wat().as_bytes(),
+                reverse_bytes_wat(),
             )
             .unwrap();
 
@@ -552,7 +746,7 @@ mod tests {
         });
 
         let mut dispatcher = LifecycleDispatcher::new();
-        dispatcher.add_plugin(plugin);
+        dispatcher.add_plugin(plugin).unwrap();
         assert_eq!(dispatcher.plugin_count(), 1);
 
         // Dispatch all process events - should not panic
@@ -561,7 +755,7 @@ mod tests {
         dispatcher.dispatch(&LifecycleEvent::ProcessExiting { process_id: 100 });
         dispatcher.dispatch(&LifecycleEvent::ProcessExited {
             process_id: 100,
-            error: None,
+            reason: ExitReason::Normal,
         });
     }
 
@@ -580,7 +774,7 @@ mod tests {
         });
 
         let mut dispatcher = LifecycleDispatcher::new();
-        dispatcher.add_plugin(plugin);
+        dispatcher.add_plugin(plugin).unwrap();
 
         // Dispatch module events - should not panic
         dispatcher.dispatch(&LifecycleEvent::ModuleLoading {
@@ -609,7 +803,7 @@ mod tests {
                 },
                 module,
             });
-            dispatcher.add_plugin(plugin);
+            dispatcher.add_plugin(plugin).unwrap();
         }
 
         assert_eq!(dispatcher.plugin_count(), 3);
@@ -634,7 +828,7 @@ mod tests {
         });
 
         let mut dispatcher = LifecycleDispatcher::new();
-        dispatcher.add_plugin(plugin);
+        dispatcher.add_plugin(plugin).unwrap();
 
         // Should not panic - missing exports are logged and skipped
         dispatcher.dispatch(&LifecycleEvent::ProcessSpawned { process_id: 1 });
@@ -650,40 +844,40 @@ mod tests {
 
         // Register a lifecycle-only plugin
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "lifecycle".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::LifecycleHooks],
                     dependencies: vec![],
                 },
-                lifecycle_observer_wat().as_bytes(),
+                lifecycle_observer_wat(),
             )
             .unwrap();
 
         // Register a transform-only plugin
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "transform".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                prepend_header_wat().as_bytes(),
+                prepend_header_wat(),
             )
             .unwrap();
 
         // Register a host functions plugin
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "host".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::HostFunctions("my_plugin".into())],
                     dependencies: vec![],
                 },
-                "(module)".as_bytes(),
+                "(module)",
             )
             .unwrap();
 
@@ -703,14 +897,14 @@ mod tests {
         let mut registry = PluginRegistry::new();
 
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "dual".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::LifecycleHooks, Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                dual_capability_wat().as_bytes(),
+                dual_capability_wat(),
             )
             .unwrap();
 
@@ -723,10 +917,11 @@ mod tests {
         let output = registry.transform_module(b"test").unwrap();
         assert_eq!(&output, b"DUAL:test");
 
-        // Note: lifecycle dispatch for this dual plugin will fail to instantiate
-        // because the lifecycle linker doesn't provide lunatic_plugin imports.
-        // This is expected behavior - dual plugins should separate their modules
-        // or the lifecycle hooks should be no-import functions.
+        // Lifecycle dispatch instantiates the plugin with no-op lunatic_plugin
+        // imports, so its hooks run too - should not panic
+        registry
+            .lifecycle_dispatcher()
+            .dispatch(&LifecycleEvent::ProcessSpawned { process_id: 1 });
     }
 
     #[test]
@@ -734,14 +929,14 @@ mod tests {
         let mut registry = PluginRegistry::new();
 
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "my-plugin".into(),
                     version: semver::Version::new(2, 3, 4),
                     capabilities: vec![],
                     dependencies: vec![],
                 },
-                "(module)".as_bytes(),
+                "(module)",
             )
             .unwrap();
 
@@ -793,38 +988,38 @@ mod tests {
 
         // Register both types of plugins
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "observer".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::LifecycleHooks],
                     dependencies: vec![],
                 },
-                lifecycle_observer_wat().as_bytes(),
+                lifecycle_observer_wat(),
             )
             .unwrap();
 
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "header".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                prepend_header_wat().as_bytes(),
+                prepend_header_wat(),
             )
             .unwrap();
 
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "xor".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                xor_transform_wat(0xAA).as_bytes(),
+                &xor_transform_wat(0xAA),
             )
             .unwrap();
 
@@ -842,7 +1037,7 @@ mod tests {
         dispatcher.dispatch(&LifecycleEvent::ProcessExiting { process_id: 1 });
         dispatcher.dispatch(&LifecycleEvent::ProcessExited {
             process_id: 1,
-            error: Some("test error".into()),
+            reason: ExitReason::Trapped("test error".into()),
         });
         dispatcher.dispatch(&LifecycleEvent::ModuleLoaded {
             module_name: "test.wasm".into(),
@@ -893,7 +1088,7 @@ mod tests {
         });
 
         let mut dispatcher = LifecycleDispatcher::new();
-        dispatcher.add_plugin(plugin);
+        dispatcher.add_plugin(plugin).unwrap();
 
         // Dispatch 3 times
         dispatcher.dispatch(&LifecycleEvent::ProcessSpawned { process_id: 1 });
@@ -912,27 +1107,27 @@ mod tests {
 
         // Register a plugin without lunatic_transform_module export
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "no-transform".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                "(module (memory (export \"memory\") 1))".as_bytes(),
+                "(module (memory (export \"memory\") 1))",
             )
             .unwrap();
 
         // Then register a real transform
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "header".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                prepend_header_wat().as_bytes(),
+                prepend_header_wat(),
             )
             .unwrap();
 
@@ -965,14 +1160,14 @@ mod tests {
 
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "trapping".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                wat.as_bytes(),
+                wat,
             )
             .unwrap();
 
@@ -983,6 +1178,50 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn transform_error_mode_skips_trapping_plugin() {
+        let trapping = r#"
+            (module
+                (func (export "lunatic_transform_module")
+                    (drop (i32.div_u (i32.const 1) (i32.const 0)))
+                )
+            )
+        "#;
+        let build = |mode: TransformErrorMode| {
+            let mut registry = PluginRegistry::new();
+            registry.set_transform_error_mode(mode);
+            for (name, wat) in [
+                ("header", prepend_header_wat().to_string()),
+                ("trapping", trapping.to_string()),
+                ("xor", xor_transform_wat(0x01)),
+            ] {
+                registry
+                    .register_wat(plugin_info(name, &[Capability::ModuleTransform]), &wat)
+                    .unwrap();
+            }
+            registry
+        };
+
+        let fatal = build(TransformErrorMode::Fatal);
+        assert_eq!(fatal.transform_error_mode(), TransformErrorMode::Fatal);
+        assert!(fatal.transform_module(b"ABC").is_err());
+        assert_eq!(fatal.last_errors().len(), 1);
+
+        // The xor plugin gets the header plugin's output
+        let skipping = build(TransformErrorMode::SkipAndContinue);
+        let output = skipping.transform_module(b"ABC").unwrap();
+        let expected: Vec<u8> = b"LUNAABC".iter().map(|b| b ^ 0x01).collect();
+        assert_eq!(output, expected);
+        let errors = skipping.last_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].plugin, "trapping");
+
+        // Results a failed plugin was skipped for aren't cached, the plugin runs again
+        assert_eq!(skipping.transform_cache_len(), 0);
+        assert_eq!(skipping.transform_module(b"ABC").unwrap(), expected);
+        assert_eq!(skipping.last_errors().len(), 2);
+    }
+
     #[test]
     fn lifecycle_plugin_trap_does_not_crash_dispatcher() {
         let wat = r#"
@@ -1007,7 +1246,7 @@ mod tests {
         });
 
         let mut dispatcher = LifecycleDispatcher::new();
-        dispatcher.add_plugin(plugin);
+        dispatcher.add_plugin(plugin).unwrap();
 
         // Should not panic - error is logged and swallowed
         dispatcher.dispatch(&LifecycleEvent::ProcessSpawned { process_id: 1 });
@@ -1031,14 +1270,14 @@ mod tests {
 
         let mut registry = PluginRegistry::new();
         registry
-            .register_wasm(
+            .register_wat(
                 PluginInfo {
                     name: "oob".into(),
                     version: semver::Version::new(1, 0, 0),
                     capabilities: vec![Capability::ModuleTransform],
                     dependencies: vec![],
                 },
-                wat.as_bytes(),
+                wat,
             )
             .unwrap();
 
@@ -0,0 +1,115 @@
+use std::collections::{HashMap, VecDeque};
+
+use sha2::{Digest, Sha256};
+
+/// How many transform results are kept at most
+const MAX_CACHED_TRANSFORMS: usize = 64;
+/// How many bytes of transform output are kept at most
+const MAX_CACHED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Bounded cache of transform outputs, keyed by the SHA-256 of their input.
+///
+/// The oldest results are evicted once either limit would be exceeded. Every
+/// [`clear`](Self::clear) starts a new generation, a result computed during an
+/// older one is not stored.
+#[derive(Debug, Default)]
+pub(crate) struct TransformCache {
+    outputs: HashMap<[u8; 32], Vec<u8>>,
+    // Keys of `outputs`, oldest first
+    order: VecDeque<[u8; 32]>,
+    bytes: usize,
+    generation: u64,
+}
+
+impl TransformCache {
+    /// The key the output of transforming `input` is stored under
+    pub(crate) fn key(input: &[u8]) -> [u8; 32] {
+        Sha256::digest(input).into()
+    }
+
+    /// Current generation, to pass to [`insert`](Self::insert) once the result is computed
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub(crate) fn get(&self, key: &[u8; 32]) -> Option<&Vec<u8>> {
+        self.outputs.get(key)
+    }
+
+    /// Store `output`, unless the cache was cleared since `generation` was read or the
+    /// output alone exceeds the byte limit
+    pub(crate) fn insert(&mut self, key: [u8; 32], generation: u64, output: Vec<u8>) {
+        if generation != self.generation
+            || output.len() > MAX_CACHED_BYTES
+            || self.outputs.contains_key(&key)
+        {
+            return;
+        }
+        while self.order.len() >= MAX_CACHED_TRANSFORMS
+            || self.bytes + output.len() > MAX_CACHED_BYTES
+        {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.outputs.remove(&oldest) {
+                self.bytes -= evicted.len();
+            }
+        }
+        self.bytes += output.len();
+        self.order.push_back(key);
+        self.outputs.insert(key, output);
+    }
+
+    /// Drop all results and start a new generation
+    pub(crate) fn clear(&mut self) {
+        self.outputs.clear();
+        self.order.clear();
+        self.bytes = 0;
+        self.generation += 1;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.outputs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let mut cache = TransformCache::default();
+        for i in 0..MAX_CACHED_TRANSFORMS + 2 {
+            let input = i.to_string();
+            cache.insert(TransformCache::key(input.as_bytes()), 0, vec![0; 8]);
+        }
+        assert_eq!(cache.len(), MAX_CACHED_TRANSFORMS);
+        assert!(cache.get(&TransformCache::key(b"1")).is_none());
+        assert!(cache.get(&TransformCache::key(b"2")).is_some());
+
+        cache.insert(TransformCache::key(b"big"), 0, vec![0; MAX_CACHED_BYTES]);
+        assert_eq!(cache.len(), 1);
+        cache.insert(
+            TransformCache::key(b"bigger"),
+            0,
+            vec![0; MAX_CACHED_BYTES + 1],
+        );
+        assert!(cache.get(&TransformCache::key(b"bigger")).is_none());
+    }
+
+    #[test]
+    fn drops_results_of_older_generations() {
+        let mut cache = TransformCache::default();
+        let generation = cache.generation();
+        cache.clear();
+        cache.insert(TransformCache::key(b"x"), generation, b"xa".to_vec());
+        assert_eq!(cache.len(), 0);
+        cache.insert(
+            TransformCache::key(b"x"),
+            cache.generation(),
+            b"xa".to_vec(),
+        );
+        assert_eq!(cache.get(&TransformCache::key(b"x")).unwrap(), b"xa");
+    }
+}
@@ -0,0 +1,76 @@
+//! Built-in native transform that moves WASI imports to another version.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{ModuleContext, NativeTransform};
+
+/// Renames WASI imports so that modules built against one WASI version link against
+/// another, e.g. `wasi_unstable` modules against `wasi_snapshot_preview1`.
+///
+/// Only import names change, so the functions must be compatible under the new name.
+/// Register it with [`PluginRegistry::add_native_transform`](crate::PluginRegistry::add_native_transform)
+/// through [`into_native_transform`](Self::into_native_transform).
+#[derive(Debug, Clone, Default)]
+pub struct WasiShimTransform {
+    // (old module, new module), applied first
+    modules: Vec<(String, String)>,
+    // (old module, old name, new module, new name)
+    imports: Vec<(String, String, String, String)>,
+}
+
+impl WasiShimTransform {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves `wasi_unstable` imports to `wasi_snapshot_preview1`
+    pub fn preview1() -> Self {
+        Self::new().rename_module("wasi_unstable", "wasi_snapshot_preview1")
+    }
+
+    /// Move every import from the `old` namespace to `new`, keeping their names
+    pub fn rename_module(mut self, old: &str, new: &str) -> Self {
+        self.modules.push((old.to_string(), new.to_string()));
+        self
+    }
+
+    /// Import `new_module::new_name` instead of `old_module::old_name`. Runs after the
+    /// namespace renames, so `old_module` is matched after they are applied.
+    pub fn rename_import(
+        mut self,
+        old_module: &str,
+        old_name: &str,
+        new_module: &str,
+        new_name: &str,
+    ) -> Self {
+        self.imports.push((
+            old_module.to_string(),
+            old_name.to_string(),
+            new_module.to_string(),
+            new_name.to_string(),
+        ));
+        self
+    }
+
+    /// Apply the renames to `module`
+    pub fn transform(&self, module: &[u8]) -> Result<Vec<u8>> {
+        let mut context = ModuleContext::new(module)?;
+        let mut renamed = 0;
+        for (old, new) in &self.modules {
+            renamed += context.rename_import_module(old, new);
+        }
+        for (old_module, old_name, new_module, new_name) in &self.imports {
+            renamed += context.rename_import(old_module, old_name, new_module, new_name);
+        }
+        if renamed == 0 {
+            return Ok(module.to_vec());
+        }
+        context.encode()
+    }
+
+    pub fn into_native_transform(self) -> NativeTransform {
+        Arc::new(move |module| self.transform(module))
+    }
+}
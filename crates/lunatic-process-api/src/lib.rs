@@ -3,7 +3,10 @@ use std::{
     future::Future,
     io::Write,
     path::Path,
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
     time::{Duration, Instant},
 };
 
@@ -24,6 +27,9 @@ use lunatic_process::{
 use lunatic_wasi_api::LunaticWasiCtx;
 use wasmtime::{Caller, Linker, ResourceLimiter, Val};
 
+/// Longest exit reason a guest can set, in bytes
+const MAX_EXIT_REASON_LEN: u32 = 1024;
+
 pub type ProcessResources = HashMapId<Arc<dyn Process>>;
 pub type ModuleResources<S> = HashMapId<Arc<WasmtimeCompiledModule<S>>>;
 
@@ -34,6 +40,9 @@ pub trait ProcessConfigCtx {
     fn set_can_create_configs(&mut self, can: bool);
     fn can_spawn_processes(&self) -> bool;
     fn set_can_spawn_processes(&mut self, can: bool);
+    /// Maximum number of descendants a process may have alive at once, `None` if unlimited.
+    fn max_children(&self) -> Option<u32>;
+    fn set_max_children(&mut self, max_children: Option<u32>);
     fn can_access_fs_location(&self, path: &Path) -> Result<(), String>;
 }
 
@@ -43,6 +52,72 @@ pub trait ProcessCtx<S: ProcessState> {
     fn module_resources(&self) -> &ModuleResources<S>;
     fn module_resources_mut(&mut self) -> &mut ModuleResources<S>;
     fn environment(&self) -> Arc<dyn Environment>;
+    /// Limits the children spawned by this process count against.
+    fn child_limits(&self) -> &ChildLimits;
+    /// Hold `slot` until the process finishes.
+    fn set_child_slot(&mut self, slot: ChildSlot);
+    /// Reason reported to lifecycle plugins if the process finishes normally.
+    fn set_exit_reason(&mut self, reason: String);
+}
+
+/// Limits on the number of alive descendants of a process.
+///
+/// Holds the limit of the process itself, if it has one, and the limits of all its ancestors,
+/// so a child counts against every one of them.
+#[derive(Clone, Debug, Default)]
+pub struct ChildLimits(Vec<Arc<ChildLimit>>);
+
+#[derive(Debug)]
+struct ChildLimit {
+    alive: AtomicU32,
+    max: u32,
+}
+
+impl ChildLimits {
+    /// Limits of a process with `max_children` whose parent has these limits.
+    pub fn with_limit(&self, max_children: Option<u32>) -> Self {
+        let mut limits = self.0.clone();
+        if let Some(max) = max_children {
+            limits.push(Arc::new(ChildLimit {
+                alive: AtomicU32::new(0),
+                max,
+            }));
+        }
+        Self(limits)
+    }
+
+    /// Reserve a slot for a new child in every limit.
+    ///
+    /// Fails with the exceeded limit if any of them is reached.
+    pub fn try_acquire(&self) -> Result<ChildSlot, u32> {
+        let mut acquired = Vec::with_capacity(self.0.len());
+        for limit in &self.0 {
+            let reserved = limit
+                .alive
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |alive| {
+                    (alive < limit.max).then_some(alive + 1)
+                });
+            if reserved.is_err() {
+                // Dropping the slot releases the limits acquired so far
+                drop(ChildSlot(acquired));
+                return Err(limit.max);
+            }
+            acquired.push(limit.clone());
+        }
+        Ok(ChildSlot(acquired))
+    }
+}
+
+/// A child's place in its ancestors' [`ChildLimits`], released when dropped.
+#[derive(Debug)]
+pub struct ChildSlot(Vec<Arc<ChildLimit>>);
+
+impl Drop for ChildSlot {
+    fn drop(&mut self) {
+        for limit in &self.0 {
+            limit.alive.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
 }
 
 // Register the process APIs to the linker
@@ -136,6 +211,16 @@ where
         "config_get_max_fuel",
         config_get_max_fuel,
     )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_max_children",
+        config_set_max_children,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_max_children",
+        config_get_max_children,
+    )?;
     linker.func_wrap(
         "lunatic::process",
         "config_can_compile_modules",
@@ -174,12 +259,14 @@ where
 
     linker.func_wrap("lunatic::process", "process_id", process_id)?;
     linker.func_wrap("lunatic::process", "environment_id", environment_id)?;
+    linker.func_wrap("lunatic::process", "open_resources", open_resources)?;
     linker.func_wrap("lunatic::process", "link", link)?;
     linker.func_wrap("lunatic::process", "unlink", unlink)?;
     linker.func_wrap("lunatic::process", "monitor", monitor)?;
     linker.func_wrap("lunatic::process", "stop_monitoring", stop_monitoring)?;
     linker.func_wrap("lunatic::process", "kill", kill)?;
     linker.func_wrap("lunatic::process", "exists", exists)?;
+    linker.func_wrap("lunatic::process", "set_exit_reason", set_exit_reason)?;
     Ok(())
 }
 
@@ -289,7 +376,9 @@ fn drop_module<T: ProcessState + ProcessCtx<T>>(
 
 // Create a new configuration with all permissions denied.
 //
-// There is no memory or fuel limit set on the newly created configuration.
+// There is no memory or fuel limit set on the newly created configuration. If WASI is disabled
+// for the calling process, it's also disabled in the new configuration, and the limits on the
+// resources its messages can carry are kept.
 //
 // Returns:
 // * ID of newly created configuration in case of success
@@ -302,7 +391,9 @@ where
     if !caller.data().config().can_create_configs() {
         return -1;
     }
-    let config = T::Config::default();
+    let mut config = T::Config::default();
+    config.set_wasi_disabled(caller.data().config().get_wasi_disabled());
+    config.set_message_resource_limits(caller.data().config().get_message_resource_limits());
     #[cfg(feature = "metrics")]
     metrics::counter!("lunatic.process.configs.created").increment(1);
     #[cfg(feature = "metrics")]
@@ -527,6 +618,56 @@ where
     Ok(())
 }
 
+// Sets the maximum number of descendants that processes spawned from this configuration may have
+// alive at once.
+//
+// A value of 0 indicates no limit.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_max_children<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    max_children: u32,
+) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let max_children = match max_children {
+        0 => None,
+        max_children => Some(max_children),
+    };
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_max_children: Config ID doesn't exist")?
+        .set_max_children(max_children);
+    Ok(())
+}
+
+// Returns the maximum number of alive descendants of a configuration.
+//
+// A value of 0 indicates no limit.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_max_children<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let max_children = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_max_children: Config ID doesn't exist")?
+        .max_children();
+    Ok(max_children.unwrap_or(0))
+}
+
 // Spawns a new process using the passed in function inside a module as the entry point.
 //
 // If **link** is not 0, it will link the child and parent processes. The value of the **link**
@@ -546,7 +687,8 @@ where
 //
 // Returns:
 // * 0 on success - The ID of the newly created process is written to **id_ptr**
-// * 1 on error   - The error ID is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**, e.g. if this process or one of its
+//                  ancestors already has the maximum number of children alive
 //
 // Traps:
 // * If the module ID doesn't exist.
@@ -657,7 +799,7 @@ where
         // Inherit stdout and stderr streams if they are redirected by the parent.
         let stdout = if let Some(stdout) = caller.data().get_stdout() {
             let next_stream = stdout.next();
-            new_state.set_stdout(next_stream.clone());
+            new_state.set_stdout(next_stream.clone())?;
             Some((stdout.clone(), next_stream))
         } else {
             None
@@ -666,21 +808,27 @@ where
             // If stderr is same as stdout, use same `next_stream`.
             if let Some((stdout, next_stream)) = stdout {
                 if &stdout == stderr {
-                    new_state.set_stderr(next_stream);
+                    new_state.set_stderr(next_stream)?;
                 } else {
-                    new_state.set_stderr(stderr.next());
+                    new_state.set_stderr(stderr.next())?;
                 }
             } else {
-                new_state.set_stderr(stderr.next());
+                new_state.set_stderr(stderr.next())?;
             }
         }
 
         let env = caller.data().environment();
-        let (proc_or_error_id, result) = match lunatic_process::wasm::spawn_wasm(
-            env, runtime, &module, new_state, function, params, link,
-        )
-        .await
-        {
+        let spawned = match caller.data().child_limits().try_acquire() {
+            Ok(slot) => {
+                new_state.set_child_slot(slot);
+                lunatic_process::wasm::spawn_wasm(
+                    env, runtime, &module, new_state, function, params, link,
+                )
+                .await
+            }
+            Err(max) => Err(anyhow!("Limit of {max} alive child processes reached")),
+        };
+        let (proc_or_error_id, result) = match spawned {
             Ok((_, process)) => (process.id(), 0),
             Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
         };
@@ -854,7 +1002,7 @@ where
             // Inherit stdout and stderr streams if they are redirected by the parent.
             let stdout = if let Some(stdout) = state.get_stdout() {
                 let next_stream = stdout.next();
-                new_state.set_stdout(next_stream.clone());
+                new_state.set_stdout(next_stream.clone())?;
                 Some((stdout.clone(), next_stream))
             } else {
                 None
@@ -863,21 +1011,27 @@ where
                 // If stderr is same as stdout, use same `next_stream`.
                 if let Some((stdout, next_stream)) = stdout {
                     if &stdout == stderr {
-                        new_state.set_stderr(next_stream);
+                        new_state.set_stderr(next_stream)?;
                     } else {
-                        new_state.set_stderr(stderr.next());
+                        new_state.set_stderr(stderr.next())?;
                     }
                 } else {
-                    new_state.set_stderr(stderr.next());
+                    new_state.set_stderr(stderr.next())?;
                 }
             }
 
             let env = state.environment();
-            let (proc_or_error_id, result) = match lunatic_process::wasm::spawn_wasm(
-                env, runtime, &module, new_state, function, params, link,
-            )
-            .await
-            {
+            let spawned = match state.child_limits().try_acquire() {
+                Ok(slot) => {
+                    new_state.set_child_slot(slot);
+                    lunatic_process::wasm::spawn_wasm(
+                        env, runtime, &module, new_state, function, params, link,
+                    )
+                    .await
+                }
+                Err(max) => Err(anyhow!("Limit of {max} alive child processes reached")),
+            };
+            let (proc_or_error_id, result) = match spawned {
                 Ok((_, process)) => (process.id(), 0),
                 Err(error) => (state.error_resources_mut().add(error), 1),
             };
@@ -945,6 +1099,13 @@ fn environment_id<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
     caller.data().environment().id()
 }
 
+// Returns the number of open resources, like sockets, the process currently holds.
+//
+// Sampling it over time shows whether a long running process leaks handles.
+fn open_resources<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
+    caller.data().open_resource_count() as u64
+}
+
 // Link current process to **process_id**. This is not an atomic operation, any of the 2 processes
 // could fail before processing the `Link` signal and may not notify the other.
 //
@@ -1079,3 +1240,34 @@ fn exists<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -
         .get_process(process_id)
         .is_some() as i32
 }
+
+// lunatic::process::set_exit_reason(reason_ptr: u32, reason_len: u32)
+//
+// Set the reason for exiting that is reported to lifecycle plugins if the process finishes
+// normally, e.g. "config reload requested". A later call replaces the reason.
+//
+// Traps:
+// * If the reason is longer than 1024 bytes.
+// * If the reason is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn set_exit_reason<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    reason_ptr: u32,
+    reason_len: u32,
+) -> Result<()> {
+    if reason_len > MAX_EXIT_REASON_LEN {
+        return Err(anyhow!(
+            "lunatic::process::set_exit_reason: reason of {reason_len} bytes exceeds the limit of {MAX_EXIT_REASON_LEN} bytes"
+        ));
+    }
+    let memory = get_memory(&mut caller)?;
+    let reason = memory
+        .data(&caller)
+        .get(reason_ptr as usize..(reason_ptr as usize + reason_len as usize))
+        .or_trap("lunatic::process::set_exit_reason")?;
+    let reason = std::str::from_utf8(reason)
+        .or_trap("lunatic::process::set_exit_reason")?
+        .to_string();
+    caller.data_mut().set_exit_reason(reason);
+    Ok(())
+}
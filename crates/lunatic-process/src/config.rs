@@ -1,5 +1,9 @@
+use std::time::Duration;
+
 use serde::{Serialize, de::DeserializeOwned};
 
+use crate::message::ResourceLimits;
+
 // One unit of fuel represents around 100k instructions.
 pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
 
@@ -12,8 +16,9 @@ pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
 /// the process. This host functions are the ones that consider specific configuration while
 /// performing operations.
 ///
-/// However, two properties of a process are enforced by the runtime (maximum memory and maximum
-/// fuel usage). This two properties need to be part of every configuration.
+/// However, some properties of a process are enforced by the runtime (maximum memory, maximum
+/// fuel usage, maximum wall time, access to WASI and the resources its messages can carry). These
+/// properties need to be part of every configuration.
 ///
 /// `ProcessConfig` must be serializable in case it is used to spawn processes on other nodes.
 pub trait ProcessConfig: Clone + Serialize + DeserializeOwned {
@@ -21,4 +26,15 @@ pub trait ProcessConfig: Clone + Serialize + DeserializeOwned {
     fn get_max_fuel(&self) -> Option<u64>;
     fn set_max_memory(&mut self, max_memory: usize);
     fn get_max_memory(&self) -> usize;
+    /// Limit how long the process may run, measured in real time from the call of its entry
+    /// function. Unlike fuel, this also covers time spent waiting, e.g. on messages or sleeps.
+    fn set_max_wall_time(&mut self, max_wall_time: Option<Duration>);
+    fn get_max_wall_time(&self) -> Option<Duration>;
+    /// Remove all WASI access from the process. Every `wasi_snapshot_preview1` import traps
+    /// when called, so a pure-compute guest has no filesystem, clock, random or stdio access.
+    fn set_wasi_disabled(&mut self, disabled: bool);
+    fn get_wasi_disabled(&self) -> bool;
+    /// Cap how many resources of each kind a message created by the process can carry.
+    fn set_message_resource_limits(&mut self, limits: ResourceLimits);
+    fn get_message_resource_limits(&self) -> ResourceLimits;
 }
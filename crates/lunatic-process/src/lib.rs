@@ -170,6 +170,23 @@ pub enum DeathReason {
     NoProcess,
 }
 
+/// How a process finished, as reported to the lifecycle callback on exit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The entry function returned normally.
+    Normal,
+    /// The process failed with the given error, e.g. a Wasm trap.
+    Trapped(String),
+    /// The process ran out of fuel.
+    OutOfFuel,
+    /// The process ran longer than its maximum wall time.
+    TimedOut,
+    /// The process was terminated by an external `Kill` signal.
+    Killed,
+    /// The process died because a linked process failed.
+    LinkDied,
+}
+
 /// The reason of a process finishing
 pub enum Finished<T> {
     /// This just means that the process finished without external interaction.
@@ -178,6 +195,8 @@ pub enum Finished<T> {
     Normal(T),
     /// The process was terminated by an external `Kill` signal.
     KillSignal,
+    /// The process was terminated because a linked process failed.
+    LinkDied,
 }
 
 /// A `WasmProcess` represents an instance of a Wasm module that is being executed.
@@ -362,7 +381,7 @@ where
                                 if die_when_link_dies {
                                     // Even this was not a **kill** signal it has the same effect on
                                     // this process and should be propagated as such.
-                                    break Finished::KillSignal
+                                    break Finished::LinkDied
                                 } else {
                                     let message = Message::LinkDied(tag);
 
@@ -403,12 +422,12 @@ where
 
     // Dispatch process exiting event
     if let Some(ref cb) = lifecycle_cb {
-        cb("exiting", id);
+        cb("exiting", id, None);
     }
 
     env.remove_process(id);
 
-    let result = match result {
+    let (result, exit_status) = match result {
         Finished::Normal(result) => {
             let result: ExecutionResult<_> = result.into();
 
@@ -433,19 +452,28 @@ where
                 );
                 debug!("{}", failure);
 
-                Err(anyhow!(failure.to_string()))
+                let exit_status = match result.result {
+                    ResultValue::OutOfFuel(_) => ExitStatus::OutOfFuel,
+                    ResultValue::TimedOut(_) => ExitStatus::TimedOut,
+                    _ => ExitStatus::Trapped(failure.to_string()),
+                };
+                (Err(anyhow!(failure.to_string())), exit_status)
             } else {
-                Ok(result.into_state())
+                (Ok(result.into_state()), ExitStatus::Normal)
             }
         }
-        Finished::KillSignal => {
+        Finished::KillSignal | Finished::LinkDied => {
             warn!(
                 "Process {} was killed, notifying: {} links",
                 id,
                 links.len()
             );
 
-            Err(anyhow!("Process received Kill signal"))
+            let exit_status = match result {
+                Finished::LinkDied => ExitStatus::LinkDied,
+                _ => ExitStatus::Killed,
+            };
+            (Err(anyhow!("Process received Kill signal")), exit_status)
         }
     };
 
@@ -466,7 +494,7 @@ where
 
     // Dispatch process exited event
     if let Some(ref cb) = lifecycle_cb {
-        cb("exited", id);
+        cb("exited", id, Some(&exit_status));
     }
 
     result
@@ -548,6 +576,8 @@ impl<T> ExecutionResult<T> {
         match self.result {
             ResultValue::Failed(ref failure) => Some(failure),
             ResultValue::SpawnError(ref failure) => Some(failure),
+            ResultValue::OutOfFuel(ref failure) => Some(failure),
+            ResultValue::TimedOut(ref failure) => Some(failure),
             _ => None,
         }
     }
@@ -586,4 +616,6 @@ pub enum ResultValue {
     Ok,
     Failed(String),
     SpawnError(String),
+    OutOfFuel(String),
+    TimedOut(String),
 }
@@ -4,7 +4,7 @@ use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
 
-use crate::message::Message;
+use crate::message::{DataMessage, Message};
 
 /// The `MessageMailbox` is a data structure holding all messages of a process.
 ///
@@ -109,6 +109,32 @@ impl MessageMailbox {
         self.await
     }
 
+    /// Remove and return the first buffered data message that `predicate` matches, e.g. on its
+    /// tag or a header byte.
+    ///
+    /// Messages that are not matched stay in the mailbox in their original order. Returns `None`
+    /// right away if no buffered message matches, it doesn't wait for new ones.
+    pub fn receive_matching(
+        &self,
+        mut predicate: impl FnMut(&DataMessage) -> bool,
+    ) -> Option<DataMessage> {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+
+        // Don't skip a message found by a canceled `pop`, see `pop`.
+        if let Some(found) = mailbox.found.take() {
+            mailbox.messages.push_back(found);
+        }
+
+        let index = mailbox.messages.iter().position(|message| match message {
+            Message::Data(message) => predicate(message),
+            _ => false,
+        })?;
+        match mailbox.messages.remove(index) {
+            Some(Message::Data(message)) => Some(message),
+            _ => unreachable!("only data messages are matched"),
+        }
+    }
+
     /// Pushes a message into the mailbox.
     ///
     /// If the message is being .awaited on, this call will immediately notify the waker that it's
@@ -146,6 +172,21 @@ impl MessageMailbox {
         mailbox.messages.len()
     }
 
+    /// Returns the total size in bytes of the buffers of all data messages currently available,
+    /// e.g. to shed load once a process falls behind
+    pub fn total_buffered_bytes(&self) -> usize {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+
+        mailbox
+            .messages
+            .iter()
+            .map(|message| match message {
+                Message::Data(message) => message.size(),
+                Message::LinkDied(_) | Message::ProcessDied(_) => 0,
+            })
+            .sum()
+    }
+
     /// Returns true if the mailbox has no available messages
     pub fn is_empty(&self) -> bool {
         let mailbox = self.inner.lock().expect("only accessed by one process");
@@ -176,7 +217,7 @@ mod tests {
         task::{Context, Poll, Wake},
     };
 
-    use super::{Message, MessageMailbox};
+    use super::{DataMessage, Message, MessageMailbox};
 
     #[tokio::test]
     async fn no_tags_signal_message() {
@@ -350,4 +391,67 @@ mod tests {
             _ => panic!("Unexpected message"),
         }
     }
+
+    fn data(tag: i64, header: u8) -> Message {
+        Message::Data(DataMessage::new_from_vec(Some(tag), vec![header]))
+    }
+
+    #[test]
+    fn receive_matching_first_message() {
+        let mailbox = MessageMailbox::default();
+        mailbox.push(data(1, 0xAA));
+        mailbox.push(data(2, 0xAA));
+        let message = mailbox
+            .receive_matching(|message| message.buffer()[0] == 0xAA)
+            .unwrap();
+        assert_eq!(message.tag(), Some(1));
+        assert_eq!(mailbox.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn receive_matching_keeps_earlier_messages_in_order() {
+        let mailbox = MessageMailbox::default();
+        mailbox.push(data(1, 0x01));
+        mailbox.push(Message::LinkDied(Some(2)));
+        mailbox.push(data(3, 0x01));
+        mailbox.push(data(4, 0xFF));
+        mailbox.push(data(5, 0xFF));
+        let message = mailbox
+            .receive_matching(|message| message.buffer()[0] == 0xFF)
+            .unwrap();
+        assert_eq!(message.tag(), Some(4));
+        for tag in [1, 2, 3, 5] {
+            assert_eq!(mailbox.pop(None).await.tag(), Some(tag));
+        }
+        assert!(mailbox.is_empty());
+    }
+
+    #[test]
+    fn receive_matching_without_match() {
+        let mailbox = MessageMailbox::default();
+        assert!(mailbox.receive_matching(|_| true).is_none());
+        mailbox.push(data(1, 0x01));
+        // Signal messages are never matched
+        mailbox.push(Message::LinkDied(Some(2)));
+        assert!(
+            mailbox
+                .receive_matching(|message| message.tag() == Some(2))
+                .is_none()
+        );
+        assert_eq!(mailbox.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn len_and_total_buffered_bytes() {
+        let mailbox = MessageMailbox::default();
+        assert_eq!((mailbox.len(), mailbox.total_buffered_bytes()), (0, 0));
+        mailbox.push(data(1, 0x01));
+        mailbox.push(Message::Data(DataMessage::new_from_vec(None, vec![0; 10])));
+        // Signals turned into messages count towards the depth, but have no buffer
+        mailbox.push(Message::LinkDied(Some(2)));
+        assert_eq!((mailbox.len(), mailbox.total_buffered_bytes()), (3, 11));
+
+        mailbox.pop(None).await;
+        assert_eq!((mailbox.len(), mailbox.total_buffered_bytes()), (2, 10));
+    }
 }
@@ -6,18 +6,31 @@ kinds of messages, like the [`Message::LinkDied`], that is received if a linked
 
 use std::{
     any::Any,
-    fmt::Debug,
-    io::{Read, Write},
-    sync::Arc,
+    fmt::{Debug, Formatter},
+    io::{IoSlice, Read, Write},
+    sync::{Arc, Mutex, OnceLock},
 };
 
+use anyhow::{Result, anyhow, ensure};
 use lunatic_networking_api::{TcpConnection, TlsConnection};
-use tokio::net::UdpSocket;
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, sync::oneshot};
 
 use crate::runtimes::wasmtime::WasmtimeCompiledModule;
 
 pub type Resource = dyn Any + Send + Sync;
 
+/// Size of the header that starts every message produced by [`DataMessage::chunk`]: the chunk
+/// index followed by the total number of chunks, both little-endian `u32`s.
+pub const CHUNK_HEADER_SIZE: usize = 8;
+
+/// Number of bytes from each end of the buffer shown by the `Debug` output of a [`DataMessage`].
+const DEBUG_PREVIEW_BYTES: usize = 16;
+
+/// Vectored writes of at least this many bytes are kept as separate segments of a
+/// [`DataMessage`] instead of being appended to its buffer.
+const SEGMENT_THRESHOLD: usize = 64 * 1024;
+
 /// Can be sent between processes by being embedded into a  [`Signal::Message`][0]
 ///
 /// A [`Message`] has 2 variants:
@@ -61,15 +74,155 @@ impl Message {
     }
 }
 
+/// Number of resources of each kind attached to a [`DataMessage`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceKindCounts {
+    pub tcp_streams: usize,
+    pub tls_streams: usize,
+    pub udp_sockets: usize,
+    pub modules: usize,
+    pub reply_channels: usize,
+    /// Resources of any other type
+    pub other: usize,
+}
+
+/// Caps on the number of resources of each kind attached to a [`DataMessage`], see
+/// [`DataMessage::set_resource_limits`]. `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    pub tcp_streams: Option<usize>,
+    pub tls_streams: Option<usize>,
+    pub udp_sockets: Option<usize>,
+    pub modules: Option<usize>,
+}
+
+impl ResourceLimits {
+    /// Returns the cap for resources of `resource_type`.
+    pub fn limit(&self, resource_type: ResourceType) -> Option<usize> {
+        match resource_type {
+            ResourceType::TcpStream => self.tcp_streams,
+            ResourceType::TlsStream => self.tls_streams,
+            ResourceType::UdpSocket => self.udp_sockets,
+            ResourceType::Module => self.modules,
+            ResourceType::ReplyChannel | ResourceType::Unknown => None,
+        }
+    }
+}
+
+/// Type of a resource attached to a [`DataMessage`], telling the receiver which `take_*`
+/// method to use for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceType {
+    TcpStream,
+    TlsStream,
+    UdpSocket,
+    Module,
+    ReplyChannel,
+    /// Added with [`DataMessage::add_resource`], the type wasn't recorded
+    Unknown,
+}
+
+// Type of an untyped resource, as far as it can be told without knowing the process state the
+// modules were compiled for.
+fn recognized_type(resource: &Arc<Resource>) -> ResourceType {
+    if resource.is::<TcpConnection>() {
+        ResourceType::TcpStream
+    } else if resource.is::<TlsConnection>() {
+        ResourceType::TlsStream
+    } else if resource.is::<UdpSocket>() {
+        ResourceType::UdpSocket
+    } else if resource.is::<ReplyChannel>() {
+        ResourceType::ReplyChannel
+    } else {
+        ResourceType::Unknown
+    }
+}
+
+/// A resource whose [`ResourceType`] is known when it's added to a message.
+pub trait TypedResource: Any + Send + Sync {
+    const RESOURCE_TYPE: ResourceType;
+}
+
+impl TypedResource for TcpConnection {
+    const RESOURCE_TYPE: ResourceType = ResourceType::TcpStream;
+}
+
+impl TypedResource for TlsConnection {
+    const RESOURCE_TYPE: ResourceType = ResourceType::TlsStream;
+}
+
+impl TypedResource for UdpSocket {
+    const RESOURCE_TYPE: ResourceType = ResourceType::UdpSocket;
+}
+
+impl<T: 'static> TypedResource for WasmtimeCompiledModule<T>
+where
+    Self: Send + Sync,
+{
+    const RESOURCE_TYPE: ResourceType = ResourceType::Module;
+}
+
+impl TypedResource for ReplyChannel {
+    const RESOURCE_TYPE: ResourceType = ResourceType::ReplyChannel;
+}
+
+/// A one-shot channel attached to a request with [`DataMessage::set_reply`], so that the process
+/// handling the request can answer it directly.
+#[derive(Debug)]
+pub struct ReplyChannel {
+    sender: Mutex<Option<oneshot::Sender<DataMessage>>>,
+}
+
+impl ReplyChannel {
+    /// Creates a reply channel and the receiver the requesting side waits on for the reply.
+    pub fn new() -> (Self, oneshot::Receiver<DataMessage>) {
+        let (sender, receiver) = oneshot::channel();
+        let channel = Self {
+            sender: Mutex::new(Some(sender)),
+        };
+        (channel, receiver)
+    }
+
+    /// Sends the reply.
+    ///
+    /// Only the first reply is delivered. Fails, handing the message back, if a reply was already
+    /// sent or the receiver was dropped.
+    // Hands the message back unboxed, like `oneshot::Sender::send`
+    #[allow(clippy::result_large_err)]
+    pub fn reply(&self, message: DataMessage) -> Result<(), DataMessage> {
+        match self.sender.lock().unwrap().take() {
+            Some(sender) => sender.send(message),
+            None => Err(message),
+        }
+    }
+}
+
 /// A variant of a [`Message`] that has a buffer of data and resources attached to it.
 ///
 /// It implements the [`Read`](std::io::Read) and [`Write`](std::io::Write) traits.
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct DataMessage {
     tag: Option<i64>,
     read_ptr: usize,
     buffer: Vec<u8>,
+    // Data of large vectored writes following `buffer`, see `write_vectored`
+    segments: Option<Box<Segments>>,
     resources: Vec<Option<Arc<Resource>>>,
+    // Type of each resource, at the same index
+    resource_types: Vec<ResourceType>,
+    // CRC32 of the buffer, see `with_checksum`
+    checksum: Option<u32>,
+    resource_limits: ResourceLimits,
+}
+
+/// Segments of a [`DataMessage`], only joined with its buffer once the whole buffer is needed in
+/// one piece.
+#[derive(Default)]
+struct Segments {
+    // Never empty
+    parts: Vec<Vec<u8>>,
+    // The buffer and `parts` joined by `DataMessage::buffer`, which can't move them itself
+    joined: OnceLock<Vec<u8>>,
 }
 
 impl DataMessage {
@@ -77,26 +230,131 @@ impl DataMessage {
         self.tag
     }
 
+    /// The whole buffer. Joins the segments of large vectored writes on first use.
     pub fn buffer(&self) -> &[u8] {
-        &self.buffer
+        match &self.segments {
+            None => &self.buffer,
+            Some(segments) => segments
+                .joined
+                .get_or_init(|| self.parts_iter().collect::<Vec<_>>().concat()),
+        }
+    }
+
+    /// The buffer followed by the segments of large vectored writes
+    fn parts_iter(&self) -> impl Iterator<Item = &[u8]> {
+        let segments = self.segments.iter().flat_map(|segments| &segments.parts);
+        std::iter::once(self.buffer.as_slice()).chain(segments.map(Vec::as_slice))
+    }
+
+    /// Move the segments of large vectored writes into the buffer
+    fn flatten(&mut self) {
+        let Some(segments) = self.segments.take() else {
+            return;
+        };
+        let Segments { parts, joined } = *segments;
+        match joined.into_inner() {
+            Some(joined) => self.buffer = joined,
+            None => {
+                self.buffer.reserve(parts.iter().map(Vec::len).sum());
+                for part in parts {
+                    self.buffer.extend_from_slice(&part);
+                }
+            }
+        }
+    }
+
+    /// The part of the message writes are appended to, joining the segments first if
+    /// [`buffer`](Self::buffer) already did
+    fn tail(&mut self) -> &mut Vec<u8> {
+        if self
+            .segments
+            .as_ref()
+            .is_some_and(|segments| segments.joined.get().is_some())
+        {
+            self.flatten();
+        }
+        match &mut self.segments {
+            Some(segments) => segments.parts.last_mut().expect("segments are never empty"),
+            None => &mut self.buffer,
+        }
     }
 
     pub fn resources_is_empty(&self) -> bool {
         self.resources.is_empty()
     }
 
+    /// Replaces the buffer, keeping the tag and resources, and moves the read
+    /// pointer back to the start.
+    pub fn set_buffer(&mut self, buffer: Vec<u8>) {
+        self.buffer = buffer;
+        self.segments = None;
+        self.read_ptr = 0;
+    }
+
+    /// Returns the tag and buffer without consuming the message.
+    pub fn parts(&self) -> (Option<i64>, &[u8]) {
+        (self.tag, self.buffer())
+    }
+
+    /// Inserts `header` in front of the buffer, e.g. to add a routing header to a payload.
+    ///
+    /// Reading a fresh message starts with the header. If the message was already partially read,
+    /// the read pointer moves along with the data, so reading continues where it left off and the
+    /// header is only visible after seeking back.
+    pub fn prepend(&mut self, header: &[u8]) {
+        self.flatten();
+        self.buffer.splice(0..0, header.iter().copied());
+        if self.read_ptr > 0 {
+            self.read_ptr += header.len();
+        }
+    }
+
     /// Consumes the message and returns its tag and buffer.
-    pub fn into_parts(self) -> (Option<i64>, Vec<u8>) {
+    pub fn into_parts(mut self) -> (Option<i64>, Vec<u8>) {
+        self.flatten();
         (self.tag, self.buffer)
     }
 
+    /// Computes a CRC32 over the current buffer and stores it alongside the message.
+    ///
+    /// The checksum is host-side metadata and isn't part of the buffer, so it can be used to
+    /// detect corruption of a message on its way through the host, e.g. across the distributed
+    /// layer, with [`verify_checksum`](Self::verify_checksum).
+    pub fn with_checksum(mut self) -> Self {
+        self.checksum = Some(self.crc32());
+        self
+    }
+
+    /// Returns the checksum stored by [`with_checksum`](Self::with_checksum), if any.
+    pub fn checksum(&self) -> Option<u32> {
+        self.checksum
+    }
+
+    /// Checks that the buffer still matches the stored checksum.
+    ///
+    /// Messages without a checksum always verify.
+    pub fn verify_checksum(&self) -> bool {
+        self.checksum
+            .is_none_or(|checksum| checksum == self.crc32())
+    }
+
+    fn crc32(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        self.parts_iter().for_each(|part| hasher.update(part));
+        hasher.finalize()
+    }
+
     /// Create a new message.
     pub fn new(tag: Option<i64>, buffer_capacity: usize) -> Self {
         Self {
             tag,
             read_ptr: 0,
             buffer: Vec::with_capacity(buffer_capacity),
+            segments: None,
             resources: Vec::new(),
+            resource_types: Vec::new(),
+            checksum: None,
+            resource_limits: ResourceLimits::default(),
         }
     }
 
@@ -106,18 +364,142 @@ impl DataMessage {
             tag,
             read_ptr: 0,
             buffer,
+            segments: None,
             resources: Vec::new(),
+            resource_types: Vec::new(),
+            checksum: None,
+            resource_limits: ResourceLimits::default(),
         }
     }
 
+    /// Returns the caps enforced by [`add_resource_typed`](Self::add_resource_typed).
+    pub fn resource_limits(&self) -> ResourceLimits {
+        self.resource_limits
+    }
+
+    /// Caps how many resources of each kind can be attached with
+    /// [`add_resource_typed`](Self::add_resource_typed). Resources that are already attached are
+    /// kept, even if there are more than the new caps allow.
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+        self.resource_limits = limits;
+    }
+
     /// Adds a resource to the message and returns the index of it inside of the message.
     ///
-    /// The resource is `Any` and is downcasted when accessing later.
-    pub fn add_resource(&mut self, resource: Arc<Resource>) -> usize {
+    /// The resource is `Any` and is downcasted when accessing later. Its type isn't recorded,
+    /// but TCP and TLS streams and UDP sockets are recognized and count against the
+    /// [`resource_limits`](Self::resource_limits), failing like
+    /// [`add_resource_typed`](Self::add_resource_typed). Modules are only recognized if they are
+    /// added with `add_resource_typed`.
+    pub fn add_resource(&mut self, resource: Arc<Resource>) -> Result<usize> {
+        self.ensure_capacity(recognized_type(&resource))?;
+        Ok(self.push_resource(resource, ResourceType::Unknown))
+    }
+
+    /// Adds a resource to the message and records its type, so that the receiver can look it up
+    /// with [`resource_type`](Self::resource_type) instead of trying each `take_*` method.
+    ///
+    /// Fails if the message already holds as many resources of this type as its
+    /// [`resource_limits`](Self::resource_limits) allow. Resources that were taken don't count.
+    pub fn add_resource_typed<R: TypedResource>(&mut self, resource: Arc<R>) -> Result<usize> {
+        self.ensure_capacity(R::RESOURCE_TYPE)?;
+        Ok(self.push_resource(resource, R::RESOURCE_TYPE))
+    }
+
+    // Fails if the message can't hold another resource of `resource_type`.
+    fn ensure_capacity(&self, resource_type: ResourceType) -> Result<()> {
+        if let Some(limit) = self.resource_limits.limit(resource_type) {
+            let attached = self
+                .resources
+                .iter()
+                .zip(&self.resource_types)
+                .filter_map(|(resource, recorded)| match (resource, recorded) {
+                    (Some(resource), ResourceType::Unknown) => Some(recognized_type(resource)),
+                    (Some(_), recorded) => Some(*recorded),
+                    (None, _) => None,
+                })
+                .filter(|attached| *attached == resource_type)
+                .count();
+            ensure!(
+                attached < limit,
+                "Message can't hold more than {limit} resources of type {resource_type:?}"
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the type of the resource at `index`.
+    ///
+    /// If the index is out of bound or the resource was already taken the function will return
+    /// None.
+    pub fn resource_type(&self, index: usize) -> Option<ResourceType> {
+        match self.resources.get(index) {
+            Some(Some(_)) => self.resource_types.get(index).copied(),
+            _ => None,
+        }
+    }
+
+    fn push_resource(&mut self, resource: Arc<Resource>, resource_type: ResourceType) -> usize {
         self.resources.push(Some(resource));
+        self.resource_types.push(resource_type);
         self.resources.len() - 1
     }
 
+    /// Counts the resources still attached to the message by kind, without taking them.
+    ///
+    /// Modules are only recognized if they were compiled for the process state `T`, the same type
+    /// passed to [`take_module`](Self::take_module).
+    pub fn resource_kinds<T: 'static>(&self) -> ResourceKindCounts {
+        let mut counts = ResourceKindCounts::default();
+        for resource in self.resources.iter().flatten() {
+            if resource.is::<TcpConnection>() {
+                counts.tcp_streams += 1;
+            } else if resource.is::<TlsConnection>() {
+                counts.tls_streams += 1;
+            } else if resource.is::<UdpSocket>() {
+                counts.udp_sockets += 1;
+            } else if resource.is::<WasmtimeCompiledModule<T>>() {
+                counts.modules += 1;
+            } else if resource.is::<ReplyChannel>() {
+                counts.reply_channels += 1;
+            } else {
+                counts.other += 1;
+            }
+        }
+        counts
+    }
+
+    /// Iterates over the resource slots in index order without taking anything, yielding `None`
+    /// for the slots whose resource was already taken.
+    ///
+    /// Lets generic code, e.g. middleware forwarding messages, check which resources are still
+    /// attached or compare them by identity with [`Arc::ptr_eq`].
+    pub fn resources_iter(&self) -> impl Iterator<Item = Option<&Arc<Resource>>> {
+        self.resources.iter().map(Option::as_ref)
+    }
+
+    /// Attaches the channel the receiver should reply on, replacing any channel attached before.
+    pub fn set_reply(&mut self, reply: Arc<ReplyChannel>) {
+        match self.reply_index() {
+            Some(index) => self.resources[index] = Some(reply),
+            None => {
+                self.push_resource(reply, ResourceType::ReplyChannel);
+            }
+        }
+    }
+
+    /// Takes the reply channel attached with [`set_reply`](Self::set_reply), leaving the indexes
+    /// of all other resources unchanged.
+    pub fn take_reply(&mut self) -> Option<Arc<ReplyChannel>> {
+        let index = self.reply_index()?;
+        self.take_downcast(index)
+    }
+
+    fn reply_index(&self) -> Option<usize> {
+        (0..self.resources.len())
+            .find(|&index| self.resource_type(index) == Some(ResourceType::ReplyChannel))
+    }
+
     /// Takes a module from the message, but preserves the indexes of all others.
     ///
     /// If the index is out of bound or the resource is not a module the function will return
@@ -153,13 +535,111 @@ impl DataMessage {
         self.take_downcast(index)
     }
 
+    /// Splits the message into sequential messages of at most `max_size` bytes each.
+    ///
+    /// Every chunk keeps the tag and its buffer starts with a [`CHUNK_HEADER_SIZE`] byte header
+    /// holding the chunk index and the total number of chunks, so the receiver can reassemble
+    /// them with [`DataMessage::reassemble`]. Resources are attached to the first chunk only. An
+    /// empty message turns into a single chunk.
+    ///
+    /// Panics if `max_size` doesn't leave room for at least one byte after the header.
+    pub fn chunk(mut self, max_size: usize) -> Vec<DataMessage> {
+        self.flatten();
+        assert!(
+            max_size > CHUNK_HEADER_SIZE,
+            "chunk size must be larger than the {CHUNK_HEADER_SIZE} byte header"
+        );
+        let payload_size = max_size - CHUNK_HEADER_SIZE;
+        let total = u32::try_from(self.buffer.len().div_ceil(payload_size).max(1))
+            .expect("message split into more than u32::MAX chunks");
+        let mut resources = Some((self.resources, self.resource_types));
+        (0..total)
+            .map(|index| {
+                let start = index as usize * payload_size;
+                let end = (start + payload_size).min(self.buffer.len());
+                let mut buffer = Vec::with_capacity(CHUNK_HEADER_SIZE + end - start);
+                buffer.extend_from_slice(&index.to_le_bytes());
+                buffer.extend_from_slice(&total.to_le_bytes());
+                buffer.extend_from_slice(&self.buffer[start..end]);
+                let (resources, resource_types) = resources.take().unwrap_or_default();
+                Self {
+                    tag: self.tag,
+                    read_ptr: 0,
+                    buffer,
+                    segments: None,
+                    resources,
+                    resource_types,
+                    checksum: None,
+                    resource_limits: self.resource_limits,
+                }
+            })
+            .collect()
+    }
+
+    /// Reassembles a message that was split with [`DataMessage::chunk`].
+    ///
+    /// The chunks can arrive in any order; they are sorted by their index and their payloads are
+    /// concatenated into one message with the shared tag. Fails if a chunk is missing, duplicated,
+    /// malformed or carries a different tag. Resources of the chunks are kept in chunk order.
+    pub fn reassemble(chunks: Vec<DataMessage>) -> Result<DataMessage> {
+        ensure!(!chunks.is_empty(), "No chunks to reassemble");
+        let tag = chunks[0].tag;
+        let (_, total) = chunks[0].chunk_header()?;
+        ensure!(
+            total as usize <= chunks.len(),
+            "Missing chunks, expected {total} but got {}",
+            chunks.len()
+        );
+
+        let mut slots: Vec<Option<DataMessage>> = Vec::new();
+        slots.resize_with(total as usize, || None);
+        let mut size = 0;
+        for chunk in chunks {
+            let (index, chunk_total) = chunk.chunk_header()?;
+            ensure!(
+                chunk_total == total,
+                "Chunk {index} belongs to a set of {chunk_total} chunks, expected {total}"
+            );
+            ensure!(chunk.tag == tag, "Chunk {index} has a different tag");
+            let slot = slots
+                .get_mut(index as usize)
+                .ok_or_else(|| anyhow!("Chunk index {index} out of range for {total} chunks"))?;
+            ensure!(slot.is_none(), "Duplicate chunk {index}");
+            size += chunk.size() - CHUNK_HEADER_SIZE;
+            *slot = Some(chunk);
+        }
+
+        let mut message = DataMessage::new(tag, size);
+        for (index, slot) in slots.into_iter().enumerate() {
+            let mut chunk = slot.ok_or_else(|| anyhow!("Missing chunk {index}"))?;
+            message.resource_limits = chunk.resource_limits;
+            message
+                .buffer
+                .extend_from_slice(&chunk.buffer()[CHUNK_HEADER_SIZE..]);
+            message.resources.append(&mut chunk.resources);
+            message.resource_types.append(&mut chunk.resource_types);
+        }
+        Ok(message)
+    }
+
+    /// Returns the (index, total) header of a chunk created by [`DataMessage::chunk`].
+    fn chunk_header(&self) -> Result<(u32, u32)> {
+        let header = self
+            .buffer()
+            .get(..CHUNK_HEADER_SIZE)
+            .ok_or_else(|| anyhow!("Chunk is missing its header"))?;
+        let index = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let total = u32::from_le_bytes(header[4..].try_into().unwrap());
+        Ok((index, total))
+    }
+
     /// Moves read pointer to index.
     pub fn seek(&mut self, index: usize) {
         self.read_ptr = index;
     }
 
     pub fn size(&self) -> usize {
-        self.buffer.len()
+        self.parts_iter().map(<[u8]>::len).sum()
     }
 
     #[cfg(feature = "metrics")]
@@ -189,6 +669,106 @@ impl DataMessage {
     }
 }
 
+impl Write for DataMessage {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tail().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Appends all slices to the message.
+    ///
+    /// Small writes are appended to the buffer, which is grown once for the combined length.
+    /// Writes of at least 64 KiB keep each slice as a separate segment instead, so building a
+    /// large message from several parts never copies the data written so far into a bigger
+    /// allocation. The segments are joined only when the whole buffer is needed in one piece,
+    /// by [`buffer`](DataMessage::buffer), [`into_parts`](DataMessage::into_parts) and the like.
+    /// Reading the message goes through the segments without joining them.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        let total = bufs.iter().map(|buf| buf.len()).sum();
+        if total < SEGMENT_THRESHOLD {
+            let tail = self.tail();
+            tail.reserve(total);
+            for buf in bufs {
+                tail.extend_from_slice(buf);
+            }
+            return Ok(total);
+        }
+        let segments = self.segments.get_or_insert_default();
+        segments.joined.take();
+        segments.parts.extend(
+            bufs.iter()
+                .filter(|buf| !buf.is_empty())
+                .map(|buf| buf.to_vec()),
+        );
+        Ok(total)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Only a preview of the buffer is printed, messages can be megabytes in size.
+impl Debug for DataMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataMessage")
+            .field("tag", &self.tag)
+            .field("len", &self.size())
+            .field("read_ptr", &self.read_ptr)
+            .field(
+                "resources",
+                &self.resources.iter().filter(|r| r.is_some()).count(),
+            )
+            .field("buffer", &BufferPreview(self.buffer()))
+            .finish()
+    }
+}
+
+/// Hex dump of the first and last [`DEBUG_PREVIEW_BYTES`] of a buffer.
+struct BufferPreview<'a>(&'a [u8]);
+
+impl Debug for BufferPreview<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hex = |f: &mut Formatter<'_>, bytes: &[u8]| {
+            bytes.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+        };
+        if self.0.len() <= 2 * DEBUG_PREVIEW_BYTES {
+            return hex(f, self.0);
+        }
+        hex(f, &self.0[..DEBUG_PREVIEW_BYTES])?;
+        write!(f, "..({} bytes)..", self.0.len() - 2 * DEBUG_PREVIEW_BYTES)?;
+        hex(f, &self.0[self.0.len() - DEBUG_PREVIEW_BYTES..])
+    }
+}
+
+impl Read for DataMessage {
+    /// Reads from the part of the buffer or the segment the read pointer is in.
+    fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut offset = self.read_ptr;
+        let mut remaining = None;
+        for part in self.parts_iter() {
+            if offset < part.len() {
+                remaining = Some(&part[offset..]);
+                break;
+            }
+            offset -= part.len();
+        }
+        let slice = match remaining {
+            Some(slice) => slice,
+            None if offset == 0 => &[],
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::OutOfMemory,
+                    "Reading outside message buffer",
+                ));
+            }
+        };
+        let bytes = buf.write(slice)?;
+        self.read_ptr += bytes;
+        Ok(bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -230,7 +810,7 @@ mod tests {
     fn resources_is_not_empty_after_add() {
         let mut msg = DataMessage::new(None, 0);
         let resource: Arc<Resource> = Arc::new(42_i32);
-        msg.add_resource(resource);
+        msg.add_resource(resource).unwrap();
         assert!(!msg.resources_is_empty());
     }
 
@@ -272,9 +852,9 @@ mod tests {
     fn read_advances_pointer() {
         let mut msg = DataMessage::new_from_vec(None, vec![1, 2, 3, 4]);
         let mut buf = [0u8; 2];
-        msg.read(&mut buf).unwrap();
+        msg.read_exact(&mut buf).unwrap();
         assert_eq!(buf, [1, 2]);
-        msg.read(&mut buf).unwrap();
+        msg.read_exact(&mut buf).unwrap();
         assert_eq!(buf, [3, 4]);
     }
 
@@ -282,13 +862,43 @@ mod tests {
     fn seek_resets_read_position() {
         let mut msg = DataMessage::new_from_vec(None, vec![1, 2, 3]);
         let mut buf = [0u8; 3];
-        msg.read(&mut buf).unwrap();
+        msg.read_exact(&mut buf).unwrap();
         assert_eq!(buf, [1, 2, 3]);
         msg.seek(0);
-        msg.read(&mut buf).unwrap();
+        msg.read_exact(&mut buf).unwrap();
         assert_eq!(buf, [1, 2, 3]);
     }
 
+    #[test]
+    fn set_buffer_keeps_tag_and_resources() {
+        let mut msg = DataMessage::new_from_vec(Some(7), vec![1, 2, 3]);
+        msg.add_resource(Arc::new(42_i32)).unwrap();
+        let mut buf = [0u8; 2];
+        msg.read_exact(&mut buf).unwrap();
+
+        msg.set_buffer(vec![9, 8, 7, 6]);
+        let mut buf = [0u8; 4];
+        msg.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [9, 8, 7, 6]);
+        assert_eq!(msg.size(), 4);
+        assert_eq!(msg.tag(), Some(7));
+        assert_eq!(msg.resource_kinds::<()>().other, 1);
+    }
+
+    #[test]
+    fn debug_output_truncates_large_buffers() {
+        let mut msg = DataMessage::new_from_vec(Some(3), vec![0xAB; 1024 * 1024]);
+        msg.add_resource(Arc::new(42_i32)).unwrap();
+        let debug = format!("{msg:?}");
+        assert!(debug.len() < 256, "{debug}");
+        assert!(debug.contains("len: 1048576"), "{debug}");
+        assert!(debug.contains("resources: 1"), "{debug}");
+        assert!(debug.contains("..(1048544 bytes).."), "{debug}");
+
+        let msg = DataMessage::new_from_vec(None, vec![1, 2, 0xFF]);
+        assert!(format!("{msg:?}").contains("buffer: 0102ff"));
+    }
+
     #[test]
     fn default_message_is_empty() {
         let msg = DataMessage::default();
@@ -347,7 +957,7 @@ mod tests {
         let mut msg = DataMessage::new(None, 0);
         msg.write_all(b"data").unwrap();
         let resource: Arc<Resource> = Arc::new(String::from("a resource"));
-        msg.add_resource(resource);
+        msg.add_resource(resource).unwrap();
         assert!(!msg.resources_is_empty());
         let (tag, buffer) = msg.into_parts();
         assert_eq!(tag, None);
@@ -360,8 +970,8 @@ mod tests {
         assert!(msg.resources_is_empty());
         let r1: Arc<Resource> = Arc::new(1_u32);
         let r2: Arc<Resource> = Arc::new(2_u32);
-        let idx1 = msg.add_resource(r1);
-        let idx2 = msg.add_resource(r2);
+        let idx1 = msg.add_resource(r1).unwrap();
+        let idx2 = msg.add_resource(r2).unwrap();
         assert_eq!(idx1, 0);
         assert_eq!(idx2, 1);
         assert!(!msg.resources_is_empty());
@@ -379,11 +989,11 @@ mod tests {
     fn partial_read_then_seek_back() {
         let mut msg = DataMessage::new_from_vec(None, vec![10, 20, 30, 40, 50]);
         let mut buf = [0u8; 3];
-        msg.read(&mut buf).unwrap();
+        msg.read_exact(&mut buf).unwrap();
         assert_eq!(buf, [10, 20, 30]);
         msg.seek(1);
         let mut buf2 = [0u8; 2];
-        msg.read(&mut buf2).unwrap();
+        msg.read_exact(&mut buf2).unwrap();
         assert_eq!(buf2, [20, 30]);
     }
 
@@ -404,31 +1014,338 @@ mod tests {
         let (tag, _) = msg.into_parts();
         assert_eq!(tag, Some(-1));
     }
-}
 
-impl Write for DataMessage {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.buffer.extend(buf);
-        Ok(buf.len())
+    #[test]
+    fn write_vectored_matches_sequential_writes() {
+        let parts: [&[u8]; 4] = [b"header", b"", b"body", &[0xFF; 300]];
+
+        let mut sequential = DataMessage::new(Some(1), 0);
+        for part in parts {
+            sequential.write_all(part).unwrap();
+        }
+
+        let mut vectored = DataMessage::new(Some(1), 0);
+        let slices: Vec<IoSlice> = parts.iter().map(|part| IoSlice::new(part)).collect();
+        let written = vectored.write_vectored(&slices).unwrap();
+
+        assert_eq!(written, sequential.size());
+        assert_eq!(vectored.buffer(), sequential.buffer());
+        assert_eq!(vectored.into_parts(), sequential.into_parts());
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        Ok(())
+    #[test]
+    fn write_vectored_appends_after_existing_data() {
+        let mut msg = DataMessage::new_from_vec(None, vec![1, 2]);
+        let written = msg
+            .write_vectored(&[IoSlice::new(&[3]), IoSlice::new(&[4, 5])])
+            .unwrap();
+        assert_eq!(written, 3);
+        assert_eq!(msg.buffer(), &[1, 2, 3, 4, 5]);
     }
-}
 
-impl Read for DataMessage {
-    fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
-        let slice = if let Some(slice) = self.buffer.get(self.read_ptr..) {
-            slice
-        } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::OutOfMemory,
-                "Reading outside message buffer",
-            ));
-        };
-        let bytes = buf.write(slice)?;
-        self.read_ptr += bytes;
-        Ok(bytes)
+    #[test]
+    fn large_vectored_writes_are_joined_lazily() {
+        let header = [1_u8; 16];
+        let body: Vec<u8> = (0..SEGMENT_THRESHOLD).map(|i| (i % 251) as u8).collect();
+        let trailer = [2_u8; 8];
+
+        let mut sequential = DataMessage::new(Some(3), 0);
+        let mut vectored = DataMessage::new(Some(3), 0);
+        for part in [&header[..], &body, &trailer] {
+            sequential.write_all(part).unwrap();
+        }
+        vectored.write_all(&header).unwrap();
+        let written = vectored
+            .write_vectored(&[IoSlice::new(&body), IoSlice::new(&[])])
+            .unwrap();
+        assert_eq!(written, body.len());
+        vectored.write_all(&trailer).unwrap();
+
+        // The body stays a separate segment, the small trailer is appended to it
+        assert_eq!(vectored.segments.as_ref().unwrap().parts.len(), 1);
+        assert_eq!(vectored.size(), sequential.size());
+        assert_eq!(vectored.crc32(), sequential.crc32());
+        let mut read = Vec::new();
+        vectored.read_to_end(&mut read).unwrap();
+        assert_eq!(read, sequential.buffer());
+        assert!(vectored.segments.as_ref().unwrap().joined.get().is_none());
+
+        assert_eq!(vectored.buffer(), sequential.buffer());
+        // Writing after the segments were joined keeps the order
+        vectored.write_all(&[3]).unwrap();
+        sequential.write_all(&[3]).unwrap();
+        assert!(vectored.segments.is_none());
+        assert_eq!(vectored.buffer(), sequential.buffer());
+
+        assert_eq!(
+            vectored.write_vectored(&[IoSlice::new(&body)]).unwrap(),
+            body.len()
+        );
+        sequential.write_all(&body).unwrap();
+        assert_eq!(vectored.into_parts(), sequential.into_parts());
+    }
+
+    #[test]
+    fn chunk_and_reassemble_roundtrip() {
+        let payload: Vec<u8> = (0..10 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut msg = DataMessage::new_from_vec(Some(5), payload.clone());
+        let resource: Arc<Resource> = Arc::new(1_u32);
+        msg.add_resource(resource).unwrap();
+
+        let mut chunks = msg.chunk(4 * 1024);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.size() <= 4 * 1024));
+        assert!(chunks.iter().all(|chunk| chunk.tag() == Some(5)));
+        assert!(!chunks[0].resources_is_empty());
+        assert!(chunks[1..].iter().all(|chunk| chunk.resources_is_empty()));
+        assert_eq!(
+            &chunks[2].buffer()[..CHUNK_HEADER_SIZE],
+            &[2, 0, 0, 0, 3, 0, 0, 0]
+        );
+
+        // Order of arrival doesn't matter
+        chunks.swap(0, 2);
+        let mut reassembled = DataMessage::reassemble(chunks).unwrap();
+        assert_eq!(reassembled.tag(), Some(5));
+        assert_eq!(reassembled.buffer(), &payload);
+        assert_eq!(reassembled.take_downcast::<u32>(0).as_deref(), Some(&1));
+    }
+
+    #[test]
+    fn reassemble_in_order() {
+        let payload: Vec<u8> = (0..100).collect();
+        let chunks =
+            DataMessage::new_from_vec(Some(3), payload.clone()).chunk(CHUNK_HEADER_SIZE + 10);
+        assert_eq!(chunks.len(), 10);
+        let msg = DataMessage::reassemble(chunks).unwrap();
+        assert_eq!(msg.tag(), Some(3));
+        assert_eq!(msg.buffer(), &payload);
+
+        let empty = DataMessage::new(Some(1), 0).chunk(64);
+        assert_eq!(empty.len(), 1);
+        assert!(DataMessage::reassemble(empty).unwrap().buffer().is_empty());
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let payload: Vec<u8> = (0..100).collect();
+        let mut chunks =
+            DataMessage::new_from_vec(None, payload.clone()).chunk(CHUNK_HEADER_SIZE + 30);
+        chunks.reverse();
+        let msg = DataMessage::reassemble(chunks).unwrap();
+        assert_eq!(msg.buffer(), &payload);
+    }
+
+    #[test]
+    fn reassemble_rejects_missing_chunks() {
+        let mut chunks =
+            DataMessage::new_from_vec(None, vec![7; 100]).chunk(CHUNK_HEADER_SIZE + 10);
+        chunks.remove(4);
+        let err = DataMessage::reassemble(chunks).unwrap_err();
+        assert!(err.to_string().contains("Missing"), "{err}");
+        assert!(DataMessage::reassemble(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn reassemble_rejects_duplicate_chunks() {
+        let mut chunks = DataMessage::new_from_vec(None, vec![7; 30]).chunk(CHUNK_HEADER_SIZE + 10);
+        let duplicate = DataMessage::new_from_vec(None, chunks[0].buffer().to_vec());
+        chunks[2] = duplicate;
+        let err = DataMessage::reassemble(chunks).unwrap_err();
+        assert!(err.to_string().contains("Duplicate chunk 0"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn resource_kinds_counts_present_resources() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut msg = DataMessage::new(None, 0);
+        for _ in 0..2 {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            msg.add_resource(Arc::new(TcpConnection::new(stream)))
+                .unwrap();
+        }
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        msg.add_resource(Arc::new(udp)).unwrap();
+        msg.add_resource(Arc::new(String::from("not a known resource")))
+            .unwrap();
+        let taken = msg
+            .add_resource(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()))
+            .unwrap();
+        assert!(msg.take_udp_socket(taken).is_some());
+
+        let counts = msg.resource_kinds::<()>();
+        assert_eq!(
+            counts,
+            ResourceKindCounts {
+                tcp_streams: 2,
+                udp_sockets: 1,
+                other: 1,
+                ..Default::default()
+            }
+        );
+        // Counting doesn't consume anything
+        assert_eq!(msg.resource_kinds::<()>(), counts);
+    }
+
+    #[tokio::test]
+    async fn typed_resources_record_their_type() {
+        let mut msg = DataMessage::new(None, 0);
+        let udp = msg
+            .add_resource_typed(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()))
+            .unwrap();
+        let untyped = msg.add_resource(Arc::new(42_i32)).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let tcp = msg
+            .add_resource_typed(Arc::new(TcpConnection::new(stream)))
+            .unwrap();
+
+        assert_eq!(msg.resource_type(udp), Some(ResourceType::UdpSocket));
+        assert_eq!(msg.resource_type(untyped), Some(ResourceType::Unknown));
+        assert_eq!(msg.resource_type(tcp), Some(ResourceType::TcpStream));
+        assert_eq!(msg.resource_type(3), None);
+
+        // Types survive chunking and stay at the index of their resource
+        let mut msg = DataMessage::reassemble(msg.chunk(CHUNK_HEADER_SIZE + 1)).unwrap();
+        assert_eq!(msg.resource_type(tcp), Some(ResourceType::TcpStream));
+        assert!(msg.take_tcp_stream(tcp).is_some());
+        assert_eq!(msg.resource_type(tcp), None);
+        assert_eq!(msg.resource_type(udp), Some(ResourceType::UdpSocket));
+    }
+
+    #[test]
+    fn checksum_detects_modified_buffer() {
+        let msg = DataMessage::new_from_vec(Some(1), b"hello world".to_vec());
+        assert_eq!(msg.checksum(), None);
+        assert!(msg.verify_checksum());
+
+        let mut msg = msg.with_checksum();
+        assert!(msg.checksum().is_some());
+        assert!(msg.verify_checksum());
+
+        let mut buffer = msg.buffer().to_vec();
+        buffer[4] ^= 1;
+        msg.set_buffer(buffer);
+        assert!(!msg.verify_checksum());
+    }
+
+    #[test]
+    fn parts_matches_into_parts() {
+        let msg = DataMessage::new_from_vec(Some(7), vec![1, 2, 3]);
+        let (tag, buffer) = msg.parts();
+        let (tag, buffer) = (tag, buffer.to_vec());
+        assert_eq!(msg.into_parts(), (tag, buffer));
+    }
+
+    #[test]
+    fn prepend_header() {
+        let mut msg = DataMessage::new_from_vec(None, b"payload".to_vec());
+        msg.prepend(b"hdr:");
+        assert_eq!(msg.buffer(), b"hdr:payload");
+        let mut read = Vec::new();
+        msg.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"hdr:payload");
+    }
+
+    #[test]
+    fn prepend_keeps_read_position() {
+        let mut msg = DataMessage::new_from_vec(None, b"payload".to_vec());
+        let mut start = [0; 3];
+        msg.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"pay");
+
+        msg.prepend(b"hdr:");
+        let mut rest = Vec::new();
+        msg.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"load");
+
+        msg.seek(0);
+        let mut all = Vec::new();
+        msg.read_to_end(&mut all).unwrap();
+        assert_eq!(all, b"hdr:payload");
+    }
+
+    #[tokio::test]
+    async fn reply_channel_can_be_taken_once() {
+        let (reply, receiver) = ReplyChannel::new();
+        let mut request = DataMessage::new_from_vec(Some(1), b"ping".to_vec());
+        let other = request.add_resource(Arc::new(7_u32)).unwrap();
+        request.set_reply(Arc::new(reply));
+        assert_eq!(request.resource_kinds::<()>().reply_channels, 1);
+
+        let reply = request.take_reply().unwrap();
+        assert!(request.take_reply().is_none());
+        assert_eq!(request.resource_kinds::<()>().reply_channels, 0);
+        // Other resources keep their index
+        assert_eq!(request.resource_type(other), Some(ResourceType::Unknown));
+
+        reply
+            .reply(DataMessage::new_from_vec(Some(1), b"pong".to_vec()))
+            .unwrap();
+        assert!(reply.reply(DataMessage::new(None, 0)).is_err());
+        assert_eq!(receiver.await.unwrap().buffer(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn resource_limits_cap_each_kind() {
+        let mut msg = DataMessage::new(None, 0);
+        msg.set_resource_limits(ResourceLimits {
+            udp_sockets: Some(2),
+            tcp_streams: Some(0),
+            ..Default::default()
+        });
+        let udp = || async { Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()) };
+
+        let first = msg.add_resource_typed(udp().await).unwrap();
+        msg.add_resource_typed(udp().await).unwrap();
+        assert!(msg.add_resource_typed(udp().await).is_err());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert!(
+            msg.add_resource_typed(Arc::new(TcpConnection::new(stream)))
+                .is_err()
+        );
+        // Untyped resources are recognized, other kinds aren't capped
+        let untyped: Arc<Resource> = udp().await;
+        assert!(msg.add_resource(untyped).is_err());
+        msg.add_resource(Arc::new(42_i32)).unwrap();
+        msg.set_reply(Arc::new(ReplyChannel::new().0));
+
+        // Taking a socket frees its slot
+        assert!(msg.take_udp_socket(first).is_some());
+        msg.add_resource_typed(udp().await).unwrap();
+        assert!(msg.add_resource_typed(udp().await).is_err());
+
+        let msg = DataMessage::reassemble(msg.chunk(CHUNK_HEADER_SIZE + 1)).unwrap();
+        assert_eq!(msg.resource_limits().udp_sockets, Some(2));
+    }
+
+    #[tokio::test]
+    async fn resources_iter_reports_taken_slots() {
+        let mut msg = DataMessage::new(None, 0);
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        msg.add_resource_typed(udp.clone()).unwrap();
+        let tcp = msg
+            .add_resource_typed(Arc::new(TcpConnection::new(stream)))
+            .unwrap();
+        msg.add_resource(Arc::new(42_i32)).unwrap();
+        msg.set_reply(Arc::new(ReplyChannel::new().0));
+
+        assert!(msg.take_tcp_stream(tcp).is_some());
+        let present: Vec<bool> = msg.resources_iter().map(|slot| slot.is_some()).collect();
+        assert_eq!(present, [true, false, true, true]);
+        let first = msg.resources_iter().next().flatten().unwrap();
+        assert!(std::ptr::addr_eq(Arc::as_ptr(first), Arc::as_ptr(&udp)));
+        // Nothing was taken by iterating
+        assert_eq!(msg.resource_kinds::<()>().udp_sockets, 1);
     }
 }
@@ -1,6 +1,6 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use wasmtime::ResourceLimiter;
 
 use crate::{
@@ -11,6 +11,9 @@ use crate::{
 
 use super::RawWasm;
 
+// Import namespace of all WASI host functions.
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
 #[derive(Clone)]
 pub struct WasmtimeRuntime {
     engine: wasmtime::Engine,
@@ -32,7 +35,25 @@ impl WasmtimeRuntime {
         // Register host functions to linker.
         <T as ProcessState>::register(&mut linker)?;
         let instance_pre = linker.instantiate_pre(&module)?;
-        let compiled_module = WasmtimeCompiledModule::new(data, module, instance_pre);
+        // Processes with WASI disabled use the same host functions, except that every WASI
+        // import of the module is shadowed by one that traps.
+        linker.allow_shadowing(true);
+        for import in module.imports() {
+            if import.module() != WASI_MODULE {
+                continue;
+            }
+            if let wasmtime::ExternType::Func(ty) = import.ty() {
+                let name = import.name().to_string();
+                linker.func_new(WASI_MODULE, import.name(), ty, move |_, _, _| {
+                    Err(anyhow!(
+                        "{WASI_MODULE}::{name} called, but WASI is disabled for this process"
+                    ))
+                })?;
+            }
+        }
+        let no_wasi_instance_pre = linker.instantiate_pre(&module)?;
+        let compiled_module =
+            WasmtimeCompiledModule::new(data, module, instance_pre, no_wasi_instance_pre);
         Ok(compiled_module)
     }
 
@@ -45,6 +66,8 @@ impl WasmtimeRuntime {
         T: ProcessState + Send + ResourceLimiter + 'static,
     {
         let max_fuel = state.config().get_max_fuel();
+        let max_wall_time = state.config().get_max_wall_time();
+        let wasi_disabled = state.config().get_wasi_disabled();
         let mut store = wasmtime::Store::new(&self.engine, state);
         // Set limits of the store
         store.limiter(|state| state);
@@ -53,13 +76,19 @@ impl WasmtimeRuntime {
         store.set_fuel(fuel)?;
         store.fuel_async_yield_interval(Some(UNIT_OF_COMPUTE_IN_INSTRUCTIONS))?;
         // Create instance
-        let instance = compiled_module
-            .instantiator()
-            .instantiate_async(&mut store)
-            .await?;
+        let instantiator = if wasi_disabled {
+            compiled_module.no_wasi_instantiator()
+        } else {
+            compiled_module.instantiator()
+        };
+        let instance = instantiator.instantiate_async(&mut store).await?;
         // Mark state as initialized
         store.data_mut().initialize();
-        Ok(WasmtimeInstance { store, instance })
+        Ok(WasmtimeInstance {
+            store,
+            instance,
+            max_wall_time,
+        })
     }
 }
 
@@ -71,6 +100,7 @@ pub struct WasmtimeCompiledModuleInner<T> {
     source: RawWasm,
     module: wasmtime::Module,
     instance_pre: wasmtime::InstancePre<T>,
+    no_wasi_instance_pre: wasmtime::InstancePre<T>,
 }
 
 impl<T> WasmtimeCompiledModule<T> {
@@ -78,11 +108,13 @@ impl<T> WasmtimeCompiledModule<T> {
         source: RawWasm,
         module: wasmtime::Module,
         instance_pre: wasmtime::InstancePre<T>,
+        no_wasi_instance_pre: wasmtime::InstancePre<T>,
     ) -> WasmtimeCompiledModule<T> {
         let inner = Arc::new(WasmtimeCompiledModuleInner {
             source,
             module,
             instance_pre,
+            no_wasi_instance_pre,
         });
         Self { inner }
     }
@@ -98,6 +130,11 @@ impl<T> WasmtimeCompiledModule<T> {
     pub fn instantiator(&self) -> &wasmtime::InstancePre<T> {
         &self.inner.instance_pre
     }
+
+    /// Like [`instantiator`](Self::instantiator), but all WASI imports trap when called.
+    pub fn no_wasi_instantiator(&self) -> &wasmtime::InstancePre<T> {
+        &self.inner.no_wasi_instance_pre
+    }
 }
 
 impl<T> Clone for WasmtimeCompiledModule<T> {
@@ -114,6 +151,7 @@ where
 {
     store: wasmtime::Store<T>,
     instance: wasmtime::Instance,
+    max_wall_time: Option<Duration>,
 }
 
 impl<T> WasmtimeInstance<T>
@@ -130,20 +168,36 @@ where
             };
         }
 
-        let result = entry
-            .unwrap()
-            .call_async(&mut self.store, &params, &mut [])
-            .await;
+        let entry = entry.unwrap();
+        let call = entry.call_async(&mut self.store, &params, &mut []);
+        // The guest yields at least every `UNIT_OF_COMPUTE_IN_INSTRUCTIONS`, so even a guest
+        // stuck in a loop gives the timeout a chance to drop the call.
+        let result = match self.max_wall_time {
+            Some(max_wall_time) => tokio::time::timeout(max_wall_time, call).await.ok(),
+            None => Some(call.await),
+        };
 
         ExecutionResult {
             state: self.store.into_data(),
             result: match result {
-                Ok(()) => ResultValue::Ok,
-                Err(err) => {
+                None => ResultValue::TimedOut(format!(
+                    "Process exceeded its wall time limit of {:?}",
+                    self.max_wall_time.unwrap_or_default()
+                )),
+                Some(Ok(())) => ResultValue::Ok,
+                Some(Err(err)) => {
                     // If the trap is a result of calling `proc_exit(0)`, treat it as an no-error finish.
                     match err.downcast_ref::<wasmtime_wasi::I32Exit>() {
                         Some(wasmtime_wasi::I32Exit(0)) => ResultValue::Ok,
-                        _ => ResultValue::Failed(err.to_string()),
+                        _ if matches!(
+                            err.downcast_ref::<wasmtime::Trap>(),
+                            Some(wasmtime::Trap::OutOfFuel)
+                        ) =>
+                        {
+                            ResultValue::OutOfFuel(err.to_string())
+                        }
+                        // The alternate format keeps the host error below the wasm backtrace
+                        _ => ResultValue::Failed(format!("{err:#}")),
                     }
                 }
             },
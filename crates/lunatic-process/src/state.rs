@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use hash_map_id::HashMapId;
@@ -9,7 +9,7 @@ use tokio::sync::{
 use wasmtime::Linker;
 
 use crate::{
-    Signal,
+    ExitStatus, Signal,
     config::ProcessConfig,
     mailbox::MessageMailbox,
     runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime},
@@ -20,7 +20,23 @@ pub type SignalSender = UnboundedSender<Signal>;
 pub type SignalReceiver = Arc<Mutex<UnboundedReceiver<Signal>>>;
 
 /// Callback invoked during process lifecycle phases ("spawned", "exiting", "exited").
-pub type LifecycleCallback = Arc<dyn Fn(&str, u64) + Send + Sync>;
+///
+/// The [`ExitStatus`] is only present for the "exited" phase.
+pub type LifecycleCallback = Arc<dyn Fn(&str, u64, Option<&ExitStatus>) + Send + Sync>;
+
+/// What happens to a message a process is about to send, see [`ProcessState::on_send`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendDecision {
+    /// Send the message right away
+    Proceed,
+    /// Wait for the given duration, then send the message
+    Throttle(Duration),
+    /// Drop the message, the guest is told that it wasn't sent
+    Reject,
+}
+
+/// Decides on each message a process sends, given the receiver's ID and the message size.
+pub type SendHook = Arc<dyn Fn(u64, usize) -> SendDecision + Send + Sync>;
 
 /// The internal state of a process.
 ///
@@ -70,8 +86,30 @@ pub trait ProcessState: Sized {
     /// Called before a process is spawned. Default: no-op.
     fn on_spawning(&self, _process_id: u64) {}
 
+    /// Called before this process sends a message of `size` bytes to process `to`, e.g. to slow
+    /// down a sender whose receiver can't keep up.
+    /// Default: [`SendDecision::Proceed`].
+    fn on_send(&self, _to: u64, _size: usize) -> SendDecision {
+        SendDecision::Proceed
+    }
+
+    /// Current size in bytes of the process' linear memory, e.g. for schedulers or monitors
+    /// deciding where to place or what to evict.
+    /// Default: 0 (unknown).
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    /// Number of open resources, like sockets, the process holds, e.g. for monitors sampling it
+    /// to detect leaked handles.
+    /// Default: 0 (unknown).
+    fn open_resource_count(&self) -> usize {
+        0
+    }
+
     /// Returns a lifecycle callback that persists after the state is consumed.
-    /// The callback receives a lifecycle phase string and a process_id.
+    /// The callback receives a lifecycle phase string, a process_id and, on exit, how the
+    /// process finished.
     /// Phases: "spawned", "exiting", "exited"
     /// Default: None (no lifecycle hooks).
     fn lifecycle_callback(&self) -> Option<LifecycleCallback> {
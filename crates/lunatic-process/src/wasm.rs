@@ -54,7 +54,7 @@ where
     env.add_process(id, child_process_handle.clone());
 
     if let Some(ref cb) = lifecycle_cb {
-        cb("spawned", id);
+        cb("spawned", id, None);
     }
 
     // **Child link guarantees**:
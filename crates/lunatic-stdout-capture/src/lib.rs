@@ -1,13 +1,62 @@
 use std::{
+    collections::VecDeque,
     fmt::{Display, Formatter},
-    io::{Cursor, Read, Seek, SeekFrom, Write, stdout},
-    sync::{Arc, Mutex, RwLock},
+    fs::{File, OpenOptions},
+    io::{Write, stderr, stdout},
+    path::Path,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+    },
 };
 
 // This signature looks scary, but it just means that the vector holding all output streams
 // is rarely extended and often accessed (`RwLock`). The `Mutex` is necessary to allow
-// parallel writes for independent processes, it doesn't have any contention.
-type StdOutVec = Arc<RwLock<Vec<Mutex<Cursor<Vec<u8>>>>>>;
+// parallel writes for independent processes, it doesn't have any contention. Each stream is a
+// ring buffer, so dropping its oldest bytes at the limit doesn't move the rest.
+type StdOutVec = Arc<RwLock<Vec<Mutex<VecDeque<u8>>>>>;
+
+/// Where the writes captured by a [`StdoutCapture`] are echoed to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EchoTarget {
+    /// The host's stdout
+    Stdout,
+    /// The host's stderr, e.g. for a capture holding a process' stderr
+    Stderr,
+    /// The writer set with [`StdoutCapture::echo_to_writer`]
+    Writer,
+    /// Writes are only captured
+    None,
+}
+
+impl EchoTarget {
+    fn from_u8(target: u8) -> Self {
+        match target {
+            1 => EchoTarget::Stdout,
+            2 => EchoTarget::Stderr,
+            3 => EchoTarget::Writer,
+            _ => EchoTarget::None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            EchoTarget::None => 0,
+            EchoTarget::Stdout => 1,
+            EchoTarget::Stderr => 2,
+            EchoTarget::Writer => 3,
+        }
+    }
+}
+
+// Writer captured writes are echoed to with `EchoTarget::Writer`.
+struct EchoWriter(Box<dyn Write + Send>);
+
+impl std::fmt::Debug for EchoWriter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EchoWriter")
+    }
+}
 
 /// `StdoutCapture` holds the standard output from multiple processes.
 ///
@@ -15,12 +64,21 @@ type StdOutVec = Arc<RwLock<Vec<Mutex<Cursor<Vec<u8>>>>>>;
 /// and all sub-processes. E.g. Hide output of sub-processes during testing.
 #[derive(Clone, Debug)]
 pub struct StdoutCapture {
-    // If true, all captured writes are echoed to stdout. This is used in testing scenarios with
+    // The `EchoTarget` all captured writes are echoed to. This is used in testing scenarios with
     // the flag `--nocapture` set, because we still need to capture the output to inspect panics.
-    echo: bool,
+    // Shared by all clones.
+    echo: Arc<AtomicU8>,
+    // Maximum number of bytes kept per stream, older bytes are dropped to make room for new
+    // ones. 0 means unlimited. Shared by all clones.
+    limit: Arc<AtomicUsize>,
     writers: StdOutVec,
     // Index of the stdout currently in use by a process
     index: usize,
+    // If set, all captured writes are also appended to this file. Shared by all clones.
+    // Writes go straight to the file, so a partial line is not lost if the process crashes.
+    tee: Arc<Mutex<Option<File>>>,
+    // Writer used by `EchoTarget::Writer`. Shared by all clones.
+    echo_writer: Arc<Mutex<Option<EchoWriter>>>,
 }
 
 impl PartialEq for StdoutCapture {
@@ -32,29 +90,29 @@ impl PartialEq for StdoutCapture {
 // Displays content of all processes contained inside `StdoutCapture`.
 impl Display for StdoutCapture {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        let streams = RwLock::read(&self.writers).unwrap();
-        // If there is only one process, don't enumerate the output
-        if streams.len() == 1 {
-            write!(f, "{}", self.content()).unwrap();
-        } else {
-            for (i, stream) in streams.iter().enumerate() {
-                writeln!(f, " --- process {i} stdout ---").unwrap();
-                let stream = stream.lock().unwrap();
-                let content = String::from_utf8_lossy(stream.get_ref()).to_string();
-                write!(f, "{content}").unwrap();
-            }
-        }
-        Ok(())
+        self.write_streams(f, false)
     }
 }
 
 impl StdoutCapture {
-    // Create a new `StdoutCapture` with one stream inside.
+    // Create a new `StdoutCapture` with one stream inside, echoing to stdout if `echo` is true.
     pub fn new(echo: bool) -> Self {
+        Self::with_echo_target(if echo {
+            EchoTarget::Stdout
+        } else {
+            EchoTarget::None
+        })
+    }
+
+    /// Create a new `StdoutCapture` with one stream inside, echoing to `target`.
+    pub fn with_echo_target(target: EchoTarget) -> Self {
         Self {
-            echo,
-            writers: Arc::new(RwLock::new(vec![Mutex::new(Cursor::new(Vec::new()))])),
+            echo: Arc::new(AtomicU8::new(target.to_u8())),
+            limit: Arc::new(AtomicUsize::new(0)),
+            writers: Arc::new(RwLock::new(vec![Mutex::new(VecDeque::new())])),
             index: 0,
+            tee: Arc::new(Mutex::new(None)),
+            echo_writer: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -68,52 +126,422 @@ impl StdoutCapture {
         let index = {
             let mut writers = RwLock::write(&self.writers).unwrap();
             // If the stream already exists don't add a new one, e.g. stdout & stderr share the same stream.
-            writers.push(Mutex::new(Cursor::new(Vec::new())));
+            writers.push(Mutex::new(VecDeque::new()));
             writers.len() - 1
         };
         Self {
-            echo: self.echo,
+            echo: self.echo.clone(),
+            limit: self.limit.clone(),
             writers: self.writers.clone(),
             index,
+            tee: self.tee.clone(),
+            echo_writer: self.echo_writer.clone(),
+        }
+    }
+
+    /// Additionally append everything written with [`write_bytes`](Self::write_bytes) to the
+    /// file at `path`, creating it if necessary.
+    ///
+    /// The file is shared by all clones of this `StdoutCapture`, including ones pointing to
+    /// other streams, and replaces any previously configured file. Every write goes straight
+    /// to the file, unbuffered.
+    pub fn tee_to_file<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        *self.tee.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    /// Echo captured writes into `writer` instead of the host's stdout or stderr, for this
+    /// capture and all clones of it, including ones pointing to other streams.
+    ///
+    /// Output the writer buffers is written out by [`flush`](Self::flush).
+    pub fn echo_to_writer(&self, writer: impl Write + Send + 'static) {
+        *self.echo_writer.lock().unwrap() = Some(EchoWriter(Box::new(writer)));
+        self.set_echo_target(EchoTarget::Writer);
+    }
+
+    /// Write out output that is still buffered, i.e. the echoed output in the host's stdout
+    /// or stderr, or in the writer set with [`echo_to_writer`](Self::echo_to_writer).
+    pub fn flush(&self) -> std::io::Result<()> {
+        match self.echo_target() {
+            EchoTarget::Stdout => stdout().flush()?,
+            EchoTarget::Stderr => stderr().flush()?,
+            EchoTarget::Writer => {
+                if let Some(writer) = self.echo_writer.lock().unwrap().as_mut() {
+                    writer.0.flush()?;
+                }
+            }
+            EchoTarget::None => {}
+        }
+        Ok(())
+    }
+
+    /// Enable or disable echoing captured writes to stdout, for this capture and all clones
+    /// of it, including ones pointing to other streams.
+    pub fn set_echo(&self, echo: bool) {
+        self.set_echo_target(if echo {
+            EchoTarget::Stdout
+        } else {
+            EchoTarget::None
+        });
+    }
+
+    /// Returns `true` if captured writes are echoed to stdout or stderr
+    pub fn echo_enabled(&self) -> bool {
+        self.echo_target() != EchoTarget::None
+    }
+
+    /// Echo captured writes to `target`, for this capture and all clones of it, including
+    /// ones pointing to other streams.
+    pub fn set_echo_target(&self, target: EchoTarget) {
+        self.echo.store(target.to_u8(), Ordering::Relaxed);
+    }
+
+    /// Where captured writes are echoed to
+    pub fn echo_target(&self) -> EchoTarget {
+        EchoTarget::from_u8(self.echo.load(Ordering::Relaxed))
+    }
+
+    /// Keep at most `limit` bytes per stream, dropping the oldest ones once it's reached. `None`
+    /// keeps everything. Applies to this capture and all clones of it.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Maximum number of bytes kept per stream, `None` if unlimited
+    pub fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
         }
     }
 
+    /// Index of the stream this capture writes to
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Number of streams shared by this capture and its clones
+    pub fn stream_count(&self) -> usize {
+        RwLock::read(&self.writers).unwrap().len()
+    }
+
     /// Returns true if all streams are empty
     pub fn is_empty(&self) -> bool {
         let streams = RwLock::read(&self.writers).unwrap();
         streams.iter().all(|stream| {
             let stream = stream.lock().unwrap();
-            stream.get_ref().is_empty()
+            stream.is_empty()
         })
     }
 
+    /// Number of bytes in this capture's stream
+    pub fn len(&self) -> usize {
+        let streams = RwLock::read(&self.writers).unwrap();
+        let stream = streams[self.index].lock().unwrap();
+        stream.len()
+    }
+
     /// Returns stream's content
     pub fn content(&self) -> String {
+        let streams = RwLock::read(&self.writers).unwrap();
+        let mut stream = streams[self.index].lock().unwrap();
+        String::from_utf8_lossy(stream.make_contiguous()).to_string()
+    }
+
+    /// Returns a copy of the stream's raw bytes, including invalid UTF-8
+    pub fn content_bytes(&self) -> Vec<u8> {
         let streams = RwLock::read(&self.writers).unwrap();
         let stream = streams[self.index].lock().unwrap();
-        String::from_utf8_lossy(stream.get_ref()).to_string()
+        stream.iter().copied().collect()
+    }
+
+    /// Like the `Display` output, but leaves out the streams nothing was written to.
+    /// Streams keep their process number, so the output still matches the full one.
+    pub fn format_nonempty(&self) -> String {
+        let mut output = String::new();
+        self.write_streams(&mut output, true).unwrap();
+        output
+    }
+
+    // Writes the content of all streams, each under a header with its process number unless
+    // there is only one. With `skip_empty` the streams without content are left out.
+    fn write_streams(&self, f: &mut impl std::fmt::Write, skip_empty: bool) -> std::fmt::Result {
+        let streams = RwLock::read(&self.writers).unwrap();
+        // If there is only one process, don't enumerate the output
+        if streams.len() == 1 {
+            write!(f, "{}", self.content())?;
+        } else {
+            for (i, stream) in streams.iter().enumerate() {
+                let mut stream = stream.lock().unwrap();
+                if skip_empty && stream.is_empty() {
+                    continue;
+                }
+                writeln!(f, " --- process {i} stdout ---")?;
+                write!(f, "{}", String::from_utf8_lossy(stream.make_contiguous()))?;
+            }
+        }
+        Ok(())
     }
 
     /// Add string to end of the stream
     pub fn push_str(&self, content: &str) {
         let streams = RwLock::read(&self.writers).unwrap();
         let mut stream = streams[self.index].lock().unwrap();
-        write!(stream, "{content}").unwrap();
+        let content = match self.limit() {
+            Some(limit) => &content.as_bytes()[content.len().saturating_sub(limit)..],
+            None => content.as_bytes(),
+        };
+        self.append(&mut stream, content);
     }
 
-    /// Write bytes to the capture, echoing to stdout if configured.
-    /// Returns the number of bytes written.
+    /// Write bytes to the capture, echoing to the [`echo_target`](Self::echo_target).
+    ///
+    /// Returns the number of bytes written. With a [`limit`](Self::limit) smaller than `buf`
+    /// only that many bytes from the start of `buf` are accepted, the caller is expected to
+    /// write the rest again like with any partial write.
     pub fn write_bytes(&self, buf: &[u8]) -> std::io::Result<usize> {
-        let streams = RwLock::read(&self.writers).unwrap();
-        let mut stream = streams[self.index].lock().unwrap();
-        let n = stream.write(buf)?;
-        // Echo the captured part to stdout
-        if self.echo {
-            stream.seek(SeekFrom::End(-(n as i64)))?;
-            let mut echo = vec![0; n];
-            stream.read_exact(&mut echo)?;
-            stdout().write_all(&echo)?;
+        self.write_captured(buf, &mut stdout(), &mut stderr())
+    }
+
+    // Stores the accepted part of `buf` and echoes exactly that part to `stdout` or `stderr`,
+    // depending on the echo target.
+    fn write_captured(
+        &self,
+        buf: &[u8],
+        stdout: &mut impl Write,
+        stderr: &mut impl Write,
+    ) -> std::io::Result<usize> {
+        let n = match self.limit() {
+            Some(limit) => buf.len().min(limit),
+            None => buf.len(),
+        };
+        let accepted = &buf[..n];
+        {
+            let streams = RwLock::read(&self.writers).unwrap();
+            let mut stream = streams[self.index].lock().unwrap();
+            self.append(&mut stream, accepted);
+        }
+        match self.echo_target() {
+            EchoTarget::Stdout => stdout.write_all(accepted)?,
+            EchoTarget::Stderr => stderr.write_all(accepted)?,
+            EchoTarget::Writer => {
+                if let Some(writer) = self.echo_writer.lock().unwrap().as_mut() {
+                    writer.0.write_all(accepted)?;
+                }
+            }
+            EchoTarget::None => {}
+        }
+        if let Some(file) = self.tee.lock().unwrap().as_mut() {
+            file.write_all(accepted)?;
         }
         Ok(n)
     }
+
+    // Appends `bytes` to `stream`, dropping its oldest bytes if it grows past the limit. The
+    // rest of a UTF-8 character cut by the limit is dropped too, so the stream doesn't start in
+    // the middle of one.
+    fn append(&self, stream: &mut VecDeque<u8>, bytes: &[u8]) {
+        stream.extend(bytes);
+        if let Some(limit) = self.limit() {
+            let overflow = stream.len().saturating_sub(limit);
+            if overflow > 0 {
+                stream.drain(..overflow);
+                while stream.front().is_some_and(|byte| byte & 0xC0 == 0x80) {
+                    stream.pop_front();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tee_to_file_appends_writes_from_all_clones() {
+        let path = std::env::temp_dir().join(format!(
+            "lunatic-stdout-capture-tee-{}.log",
+            std::process::id()
+        ));
+        std::fs::write(&path, "existing\n").unwrap();
+
+        let capture = StdoutCapture::new(false);
+        capture.tee_to_file(&path).unwrap();
+        let sub_process = capture.next();
+        capture.write_bytes(b"hello ").unwrap();
+        sub_process.write_bytes(b"from sub ").unwrap();
+        capture.clone().write_bytes(b"world").unwrap();
+
+        let teed = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(teed, "existing\nhello from sub world");
+        assert_eq!(capture.content(), "hello world");
+        assert_eq!(sub_process.content(), "from sub ");
+    }
+
+    #[test]
+    fn tee_writes_out_unterminated_output_immediately() {
+        let path = std::env::temp_dir().join(format!(
+            "lunatic-stdout-capture-flush-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let capture = StdoutCapture::new(false);
+        capture.tee_to_file(&path).unwrap();
+        capture.write_bytes(b"line\npartial").unwrap();
+        let teed = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(teed, "line\npartial");
+    }
+
+    #[test]
+    fn set_echo_is_shared_by_clones() {
+        let capture = StdoutCapture::new(false);
+        let clone = capture.clone();
+        let sub_process = capture.next();
+        assert!(!clone.echo_enabled());
+
+        clone.set_echo(true);
+        assert!(capture.echo_enabled());
+        assert!(sub_process.echo_enabled());
+
+        sub_process.set_echo(false);
+        assert!(!capture.echo_enabled());
+        assert!(!clone.echo_enabled());
+    }
+
+    #[test]
+    fn limit_drops_oldest_bytes_and_echoes_accepted_ones() {
+        let capture = StdoutCapture::new(true);
+        capture.set_limit(Some(8));
+        let sub_process = capture.next();
+        assert_eq!(sub_process.limit(), Some(8));
+
+        let mut echoed = Vec::new();
+        assert_eq!(
+            capture
+                .write_captured(b"abcdef", &mut echoed, &mut std::io::sink())
+                .unwrap(),
+            6
+        );
+        // Only part of a write larger than the limit is accepted
+        assert_eq!(
+            capture
+                .write_captured(b"ghijklmnop", &mut echoed, &mut std::io::sink())
+                .unwrap(),
+            8
+        );
+        assert_eq!(capture.content(), "ghijklmn");
+        assert_eq!(
+            capture
+                .write_captured(b"op", &mut echoed, &mut std::io::sink())
+                .unwrap(),
+            2
+        );
+        assert_eq!(capture.content(), "ijklmnop");
+        assert_eq!(echoed, b"abcdefghijklmnop");
+
+        capture.push_str("0123456789");
+        assert_eq!(capture.content(), "23456789");
+        assert!(sub_process.content().is_empty());
+
+        capture.set_limit(None);
+        assert_eq!(
+            capture
+                .write_captured(b"qrstuvwxyz", &mut echoed, &mut std::io::sink())
+                .unwrap(),
+            10
+        );
+        assert_eq!(capture.content(), "23456789qrstuvwxyz");
+    }
+
+    #[test]
+    fn limit_keeps_utf8_characters_whole() {
+        let capture = StdoutCapture::new(false);
+        capture.set_limit(Some(4));
+        capture.push_str("aé");
+        // Dropping the "a" and half of the "é" leaves "ü!" and nothing of the "é"
+        capture.push_str("ü!");
+        assert_eq!(capture.content(), "ü!");
+        assert_eq!(capture.len(), 3);
+        capture.write_bytes(b"xy").unwrap();
+        assert_eq!(capture.content(), "!xy");
+    }
+
+    #[test]
+    fn len_counts_bytes_of_own_stream() {
+        let capture = StdoutCapture::new(false);
+        assert_eq!(capture.len(), 0);
+        capture.write_bytes("héllo".as_bytes()).unwrap();
+        capture.push_str("!");
+        assert_eq!(capture.len(), 7);
+
+        let sub_process = capture.next();
+        sub_process.write_bytes(b"abc").unwrap();
+        assert_eq!(sub_process.len(), 3);
+        assert_eq!(capture.len(), 7);
+    }
+
+    #[test]
+    fn echo_target_selects_host_stream() {
+        let capture = StdoutCapture::with_echo_target(EchoTarget::Stderr);
+        let sub_process = capture.next();
+        assert_eq!(sub_process.echo_target(), EchoTarget::Stderr);
+        assert!(capture.echo_enabled());
+
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        capture
+            .write_captured(b"error", &mut out, &mut err)
+            .unwrap();
+        assert!(out.is_empty());
+        assert_eq!(err, b"error");
+
+        capture.set_echo_target(EchoTarget::None);
+        sub_process
+            .write_captured(b"quiet", &mut out, &mut err)
+            .unwrap();
+        assert!(out.is_empty());
+        assert_eq!(err, b"error");
+        assert_eq!(sub_process.content(), "quiet");
+
+        assert_eq!(StdoutCapture::new(true).echo_target(), EchoTarget::Stdout);
+        assert_eq!(StdoutCapture::new(false).echo_target(), EchoTarget::None);
+    }
+
+    #[test]
+    fn format_nonempty_skips_empty_streams() {
+        let capture = StdoutCapture::new(false);
+        let first = capture.next();
+        let second = capture.next();
+        first.push_str("hello\n");
+        second.push_str("world\n");
+        let _empty = capture.next();
+
+        assert_eq!(
+            capture.format_nonempty(),
+            " --- process 1 stdout ---\nhello\n --- process 2 stdout ---\nworld\n"
+        );
+        // The full output still lists every stream
+        let full = capture.to_string();
+        assert!(full.contains(" --- process 0 stdout ---\n"));
+        assert!(full.contains(" --- process 3 stdout ---\n"));
+
+        let single = StdoutCapture::new(false);
+        assert_eq!(single.format_nonempty(), "");
+        single.push_str("only");
+        assert_eq!(single.format_nonempty(), "only");
+    }
+
+    #[test]
+    fn content_bytes_keeps_invalid_utf8() {
+        let capture = StdoutCapture::new(false);
+        capture.write_bytes(b"ok \xFF").unwrap();
+        assert_eq!(capture.content_bytes(), b"ok \xFF");
+        assert_eq!(capture.content(), "ok \u{FFFD}");
+        // Reading doesn't consume the stream
+        assert_eq!(capture.len(), 4);
+    }
 }
@@ -1,8 +1,11 @@
+use std::ffi::OsString;
+use std::io::Write;
 use std::path::Path;
 use std::pin::Pin;
+use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use lunatic_common_api::{IntoTrap, get_memory};
 use lunatic_process::state::ProcessState;
 use lunatic_stdout_capture::StdoutCapture;
@@ -12,9 +15,28 @@ use wasmtime_wasi::cli::{IsTerminal, StdoutStream};
 use wasmtime_wasi::p1::WasiP1Ctx;
 use wasmtime_wasi::{DirPerms, FilePerms, WasiCtxBuilder};
 
-/// Adapts `StdoutCapture` to `wasmtime_wasi::cli::StdoutStream`.
+/// The `StdoutCapture` a WASI output stream writes into.
+///
+/// It's shared with the `WasiP1Ctx` built from it, so [`replace`](Self::replace) redirects the
+/// output of a running process without rebuilding the context and closing the guest's file
+/// descriptors.
+#[derive(Clone)]
+pub struct CaptureTarget(Arc<RwLock<StdoutCapture>>);
+
+impl CaptureTarget {
+    pub fn new(capture: StdoutCapture) -> Self {
+        Self(Arc::new(RwLock::new(capture)))
+    }
+
+    /// Write all further output into `capture`.
+    pub fn replace(&self, capture: StdoutCapture) {
+        *self.0.write().unwrap() = capture;
+    }
+}
+
+/// Adapts `CaptureTarget` to `wasmtime_wasi::cli::StdoutStream`.
 #[derive(Clone)]
-struct CaptureOutputStream(StdoutCapture);
+struct CaptureOutputStream(CaptureTarget);
 
 impl IsTerminal for CaptureOutputStream {
     fn is_terminal(&self) -> bool {
@@ -28,11 +50,14 @@ impl StdoutStream for CaptureOutputStream {
     }
 }
 
-/// Implements `tokio::io::AsyncWrite` by delegating to `StdoutCapture::write_bytes()`.
+/// Implements `tokio::io::AsyncWrite` by delegating to `StdoutCapture::write_bytes()` of the
+/// capture the target currently points to.
 ///
 /// All writes are synchronous (always `Poll::Ready`) since `StdoutCapture`
-/// writes to an in-memory buffer behind a mutex.
-struct CaptureWriter(StdoutCapture);
+/// writes to an in-memory buffer behind a mutex. Flushing is a no-op, WASI flushes after
+/// every write and the echoed output is only flushed when the guest calls
+/// `lunatic::wasi::flush_stdout`.
+struct CaptureWriter(CaptureTarget);
 
 impl AsyncWrite for CaptureWriter {
     fn poll_write(
@@ -40,7 +65,7 @@ impl AsyncWrite for CaptureWriter {
         _cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        Poll::Ready(self.0.write_bytes(buf))
+        Poll::Ready(self.0.0.read().unwrap().write_bytes(buf))
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
@@ -53,12 +78,15 @@ impl AsyncWrite for CaptureWriter {
 }
 
 /// Create a `WasiP1Ctx` from configuration settings.
+///
+/// Host paths of preopened directories are used as is, configurations resolve
+/// `${VAR}` references when the directory is added, see [`interpolate_env`].
 pub fn build_wasi(
     args: Option<&Vec<String>>,
     envs: Option<&Vec<(String, String)>>,
     dirs: &[(String, String)],
-    stdout: Option<StdoutCapture>,
-    stderr: Option<StdoutCapture>,
+    stdout: Option<CaptureTarget>,
+    stderr: Option<CaptureTarget>,
 ) -> Result<WasiP1Ctx> {
     let mut builder = WasiCtxBuilder::new();
     builder.inherit_stdin();
@@ -89,18 +117,119 @@ pub fn build_wasi(
     Ok(builder.build_p1())
 }
 
+/// Create a `WasiP1Ctx` without any ambient authority.
+///
+/// It has no preopened directories, environment variables or arguments, reads stdin as empty
+/// and discards everything written to stdout and stderr.
+pub fn build_null_wasi() -> WasiP1Ctx {
+    WasiCtxBuilder::new().build_p1()
+}
+
+/// Replace every `${VAR}` in `path` with the value of the host environment
+/// variable `VAR`.
+///
+/// `${TMPDIR}` falls back to the platform temporary directory if the variable
+/// isn't set. A `$` not followed by `{` is kept as is.
+///
+/// Fails if a variable is not set or a `${` is not closed.
+pub fn interpolate_env(path: &str) -> Result<String> {
+    interpolate_env_with(path, |name| std::env::var(name).ok())
+}
+
+/// Like [`interpolate_env`], but looks up variables with `lookup` instead of
+/// reading the host environment.
+pub fn interpolate_env_with(path: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut result = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated '${{' in preopened dir path '{path}'"))?;
+        let name = &after[..end];
+        let value = match lookup(name) {
+            Some(value) => value,
+            None if name == "TMPDIR" => std::env::temp_dir().to_string_lossy().into_owned(),
+            None => {
+                return Err(anyhow!(
+                    "Environment variable '{name}' in preopened dir path '{path}' is not set"
+                ));
+            }
+        };
+        result.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
 pub trait LunaticWasiConfigCtx {
     fn add_environment_variable(&mut self, key: String, value: String);
     fn add_command_line_argument(&mut self, argument: String);
-    fn preopen_dir(&mut self, dir: String);
+    /// Grant access to the host directory `dir`, resolving `${VAR}` references in it with
+    /// [`interpolate_env`]. Fails if a referenced variable isn't set.
+    fn preopen_dir(&mut self, dir: String) -> Result<()>;
+    /// Environment variables in the order they were added.
+    fn environment_variables(&self) -> &[(String, String)];
+    /// Command line arguments in the order they were added.
+    fn command_line_arguments(&self) -> &[String];
+    /// Preopened directories as `(guest path, resolved host path)` pairs, in the
+    /// order they were added.
+    fn preopened_dirs(&self) -> &[(String, String)];
+
+    /// Add every host environment variable whose key starts with `prefix`, ordered by key, and
+    /// return how many were added. Variables that aren't valid unicode are skipped.
+    ///
+    /// The values are copied when this is called, so the configuration lists exactly what the
+    /// guest will see. Fails on an empty prefix, which would inherit the whole environment.
+    ///
+    /// This is meant for the host setting up a configuration. It's not exposed to guests, as a
+    /// short prefix would hand them the host's secrets.
+    fn inherit_environment_prefix(&mut self, prefix: &str) -> Result<usize>
+    where
+        Self: Sized,
+    {
+        self.inherit_environment_prefix_from(prefix, std::env::vars_os())
+    }
+
+    /// Like [`inherit_environment_prefix`](Self::inherit_environment_prefix), but reads the
+    /// variables from `vars` instead of the host environment.
+    fn inherit_environment_prefix_from(
+        &mut self,
+        prefix: &str,
+        vars: impl IntoIterator<Item = (OsString, OsString)>,
+    ) -> Result<usize>
+    where
+        Self: Sized,
+    {
+        if prefix.is_empty() {
+            return Err(anyhow!(
+                "Refusing to inherit the whole host environment, the prefix is empty"
+            ));
+        }
+        let mut inherited: Vec<(String, String)> = vars
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(key, _)| key.starts_with(prefix))
+            .collect();
+        inherited.sort();
+        let count = inherited.len();
+        for (key, value) in inherited {
+            self.add_environment_variable(key, value);
+        }
+        Ok(count)
+    }
 }
 
 pub trait LunaticWasiCtx {
     fn wasi(&self) -> &WasiP1Ctx;
     fn wasi_mut(&mut self) -> &mut WasiP1Ctx;
-    fn set_stdout(&mut self, stdout: StdoutCapture);
+    /// Redirect stdout into `stdout`. Fails if the WASI context can't be built.
+    fn set_stdout(&mut self, stdout: StdoutCapture) -> Result<()>;
     fn get_stdout(&self) -> Option<&StdoutCapture>;
-    fn set_stderr(&mut self, stderr: StdoutCapture);
+    /// Redirect stderr into `stderr`. Fails if the WASI context can't be built.
+    fn set_stderr(&mut self, stderr: StdoutCapture) -> Result<()>;
     fn get_stderr(&self) -> Option<&StdoutCapture>;
 }
 
@@ -124,7 +253,28 @@ where
         "config_add_command_line_argument",
         add_command_line_argument,
     )?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "config_append_command_line_arguments",
+        append_command_line_arguments,
+    )?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "config_command_line_arguments_len",
+        command_line_arguments_len,
+    )?;
     linker.func_wrap("lunatic::wasi", "config_preopen_dir", preopen_dir)?;
+    linker.func_wrap("lunatic::wasi", "preopened_dirs_size", preopened_dirs_size)?;
+    linker.func_wrap("lunatic::wasi", "list_preopened_dirs", list_preopened_dirs)?;
+    linker.func_wrap("lunatic::wasi", "flush_stdout", flush_stdout)?;
+    linker.func_wrap("lunatic::wasi", "new_stdout_stream", new_stdout_stream)?;
+    linker.func_wrap("lunatic::wasi", "stdout_len", stdout_len)?;
+    linker.func_wrap("lunatic::wasi", "stdout_stream_count", stdout_stream_count)?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "read_stdout_snapshot",
+        read_stdout_snapshot,
+    )?;
 
     Ok(())
 }
@@ -134,6 +284,7 @@ where
 // Traps:
 // * If the config ID doesn't exist.
 // * If the key or value string is not a valid utf8 string.
+// * If the key or value string contains a NUL byte.
 // * If any of the memory slices falls outside the memory.
 fn add_environment_variable<T>(
     mut caller: Caller<T>,
@@ -162,6 +313,16 @@ where
     let value = std::str::from_utf8(value_str)
         .or_trap("lunatic::wasi::config_add_environment_variable")?
         .to_string();
+    ensure_no_nul(
+        &key,
+        "key",
+        "lunatic::wasi::config_add_environment_variable",
+    )?;
+    ensure_no_nul(
+        &value,
+        "value",
+        "lunatic::wasi::config_add_environment_variable",
+    )?;
 
     caller
         .data_mut()
@@ -177,6 +338,7 @@ where
 // Traps:
 // * If the config ID doesn't exist.
 // * If the argument string is not a valid utf8 string.
+// * If the argument string contains a NUL byte.
 // * If any of the memory slices falls outside the memory.
 fn add_command_line_argument<T>(
     mut caller: Caller<T>,
@@ -196,6 +358,11 @@ where
     let argument = std::str::from_utf8(argument_str)
         .or_trap("lunatic::wasi::add_command_line_argument")?
         .to_string();
+    ensure_no_nul(
+        &argument,
+        "argument",
+        "lunatic::wasi::add_command_line_argument",
+    )?;
 
     caller
         .data_mut()
@@ -206,11 +373,103 @@ where
     Ok(())
 }
 
+// Appends a list of command line arguments to a configuration in one call.
+//
+// The list is a sequence of arguments, each prefixed by its length in bytes as a little-endian
+// u32. Either all arguments are appended or none.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If an argument's length prefix or bytes run past the end of the list.
+// * If any argument is not a valid utf8 string.
+// * If any argument contains a NUL byte.
+// * If any of the memory slices falls outside the memory.
+fn append_command_line_arguments<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    arguments_ptr: u32,
+    arguments_len: u32,
+) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let arguments = memory
+        .data(&caller)
+        .get(arguments_ptr as usize..(arguments_ptr as usize + arguments_len as usize))
+        .or_trap("lunatic::wasi::config_append_command_line_arguments")?;
+    let arguments = parse_length_prefixed(arguments)
+        .map_err(|e| anyhow!("lunatic::wasi::config_append_command_line_arguments: {e}"))?;
+    for argument in &arguments {
+        ensure_no_nul(
+            argument,
+            "argument",
+            "lunatic::wasi::config_append_command_line_arguments",
+        )?;
+    }
+
+    let config = caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::config_append_command_line_arguments: Config ID doesn't exist")?;
+    for argument in arguments {
+        config.add_command_line_argument(argument);
+    }
+    Ok(())
+}
+
+// Returns the number of command line arguments added to a configuration.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn command_line_arguments_len<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let config = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::wasi::config_command_line_arguments_len: Config ID doesn't exist")?;
+    Ok(config.command_line_arguments().len() as u32)
+}
+
+// Splits a buffer of strings, each prefixed by its little-endian u32 length.
+fn parse_length_prefixed(mut buffer: &[u8]) -> Result<Vec<String>> {
+    let mut strings = Vec::new();
+    while !buffer.is_empty() {
+        let (len, rest) = buffer
+            .split_first_chunk::<4>()
+            .ok_or_else(|| anyhow!("truncated length prefix"))?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            return Err(anyhow!("length {len} runs past the end of the list"));
+        }
+        let (string, rest) = rest.split_at(len);
+        strings.push(std::str::from_utf8(string)?.to_string());
+        buffer = rest;
+    }
+    Ok(strings)
+}
+
+// WASI hands environment variables and arguments to the guest as C strings, so an interior NUL
+// would only fail later when the process is spawned. Reject it where it's added instead.
+fn ensure_no_nul(value: &str, what: &str, function: &str) -> Result<()> {
+    if value.contains('\0') {
+        return Err(anyhow!("{function}: {what} contains a NUL byte"));
+    }
+    Ok(())
+}
+
 // Mark a directory as preopened in the configuration.
 //
 // Traps:
 // * If the config ID doesn't exist.
 // * If the directory string is not a valid utf8 string.
+// * If the directory references an environment variable that isn't set.
 // * If any of the memory slices falls outside the memory.
 fn preopen_dir<T>(mut caller: Caller<T>, config_id: u64, dir_ptr: u32, dir_len: u32) -> Result<()>
 where
@@ -231,6 +490,231 @@ where
         .config_resources_mut()
         .get_mut(config_id)
         .or_trap("lunatic::wasi::preopen_dir: Config ID doesn't exist")?
-        .preopen_dir(dir);
+        .preopen_dir(dir)
+        .map_err(|e| anyhow!("lunatic::wasi::preopen_dir: {e}"))
+}
+
+// Returns the size of the newline separated list of directories preopened for this process.
+fn preopened_dirs_size<T>(caller: Caller<T>) -> u32
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    joined_preopened_dirs(caller.data().config().as_ref()).len() as u32
+}
+
+// Writes the guest paths of all directories preopened for this process to the guest memory,
+// separated by newlines. `lunatic::wasi::preopened_dirs_size` can be used to get the size.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn list_preopened_dirs<T>(mut caller: Caller<T>, dirs_ptr: u32) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let dirs = joined_preopened_dirs(caller.data().config().as_ref());
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, dirs_ptr as usize, dirs.as_bytes())
+        .or_trap("lunatic::wasi::list_preopened_dirs")?;
     Ok(())
 }
+
+// Writes out any output of this process that is still buffered, e.g. a partial line that
+// is echoed to the host's stdout.
+//
+// Traps:
+// * If flushing fails.
+fn flush_stdout<T>(caller: Caller<T>) -> Result<()>
+where
+    T: LunaticWasiCtx,
+{
+    match caller.data().get_stdout() {
+        Some(capture) => capture.flush(),
+        None => std::io::stdout().flush(),
+    }
+    .or_trap("lunatic::wasi::flush_stdout")
+}
+
+// Moves the captured stdout of this process to a new stream and returns the stream's index.
+// Earlier output stays in the previous stream, e.g. so that a supervisor can keep the output of
+// each child apart.
+//
+// Only the stream the WASI context writes into is swapped, file descriptors the guest opened
+// stay valid.
+//
+// Traps:
+// * If the output of this process is not captured.
+fn new_stdout_stream<T>(mut caller: Caller<T>) -> Result<u64>
+where
+    T: LunaticWasiCtx,
+{
+    let stream = caller
+        .data()
+        .get_stdout()
+        .or_trap("lunatic::wasi::new_stdout_stream: stdout is not captured")?
+        .next();
+    let index = stream.index() as u64;
+    caller
+        .data_mut()
+        .set_stdout(stream)
+        .or_trap("lunatic::wasi::new_stdout_stream")?;
+    Ok(index)
+}
+
+// Returns the number of bytes in the captured stdout stream of this process.
+//
+// Traps:
+// * If the output of this process is not captured.
+fn stdout_len<T>(caller: Caller<T>) -> Result<u64>
+where
+    T: LunaticWasiCtx,
+{
+    let len = caller
+        .data()
+        .get_stdout()
+        .or_trap("lunatic::wasi::stdout_len: stdout is not captured")?
+        .len();
+    Ok(len as u64)
+}
+
+// Copies up to **max_len** bytes from the start of the captured stdout stream of this process to
+// **dest_ptr** and returns the number of bytes copied. The stream is left as is, so a guest can
+// log what it wrote so far and keep going.
+//
+// Traps:
+// * If the output of this process is not captured.
+// * If any memory outside the guest heap space is referenced.
+fn read_stdout_snapshot<T>(mut caller: Caller<T>, dest_ptr: u32, max_len: u32) -> Result<u64>
+where
+    T: LunaticWasiCtx,
+{
+    let mut content = caller
+        .data()
+        .get_stdout()
+        .or_trap("lunatic::wasi::read_stdout_snapshot: stdout is not captured")?
+        .content_bytes();
+    content.truncate(max_len as usize);
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, dest_ptr as usize, &content)
+        .or_trap("lunatic::wasi::read_stdout_snapshot")?;
+    Ok(content.len() as u64)
+}
+
+// Returns the number of streams in the stdout capture of this process, including the ones of
+// other processes sharing it, e.g. children spawned with their own stream.
+//
+// Traps:
+// * If the output of this process is not captured.
+fn stdout_stream_count<T>(caller: Caller<T>) -> Result<u64>
+where
+    T: LunaticWasiCtx,
+{
+    let count = caller
+        .data()
+        .get_stdout()
+        .or_trap("lunatic::wasi::stdout_stream_count: stdout is not captured")?
+        .stream_count();
+    Ok(count as u64)
+}
+
+fn joined_preopened_dirs<C: LunaticWasiConfigCtx>(config: &C) -> String {
+    config
+        .preopened_dirs()
+        .iter()
+        .map(|(guest_path, _)| guest_path.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preopen_dir_interpolates_env() {
+        let lookup = |name: &str| (name == "APP_DATA").then(|| "/srv/app".to_string());
+        let path = interpolate_env_with("${APP_DATA}/$x", lookup).unwrap();
+        assert_eq!(path, "/srv/app/$x");
+
+        let tmp = interpolate_env_with("${TMPDIR}", |_| None).unwrap();
+        assert_eq!(tmp, std::env::temp_dir().to_string_lossy());
+    }
+
+    #[test]
+    fn preopen_dir_rejects_unresolved_env() {
+        let err = interpolate_env_with("${APP_DATA}/data", |_| None).unwrap_err();
+        assert!(err.to_string().contains("APP_DATA"), "{err}");
+        assert!(interpolate_env_with("/data/${HOME", |_| None).is_err());
+    }
+
+    #[test]
+    fn parse_length_prefixed_keeps_order() {
+        let mut buffer = Vec::new();
+        for argument in ["--first", "", "third"] {
+            buffer.extend_from_slice(&(argument.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(argument.as_bytes());
+        }
+        assert_eq!(
+            parse_length_prefixed(&buffer).unwrap(),
+            vec!["--first", "", "third"]
+        );
+        assert!(parse_length_prefixed(&[]).unwrap().is_empty());
+
+        assert!(parse_length_prefixed(&buffer[..buffer.len() - 1]).is_err());
+        assert!(parse_length_prefixed(&[1, 0]).is_err());
+        assert!(parse_length_prefixed(&[1, 0, 0, 0, 0xFF]).is_err());
+    }
+
+    #[derive(Default)]
+    struct TestConfig {
+        envs: Vec<(String, String)>,
+    }
+
+    impl LunaticWasiConfigCtx for TestConfig {
+        fn add_environment_variable(&mut self, key: String, value: String) {
+            self.envs.push((key, value));
+        }
+        fn add_command_line_argument(&mut self, _argument: String) {}
+        fn preopen_dir(&mut self, _dir: String) -> Result<()> {
+            Ok(())
+        }
+        fn environment_variables(&self) -> &[(String, String)] {
+            &self.envs
+        }
+        fn command_line_arguments(&self) -> &[String] {
+            &[]
+        }
+        fn preopened_dirs(&self) -> &[(String, String)] {
+            &[]
+        }
+    }
+
+    #[test]
+    fn inherit_environment_prefix_copies_only_prefixed_vars() {
+        let vars = || {
+            [("MYAPP_B", "b"), ("MYAPP_A", "a"), ("OTHER", "other")]
+                .map(|(key, value)| (OsString::from(key), OsString::from(value)))
+        };
+
+        let mut config = TestConfig::default();
+        config.add_environment_variable("EXPLICIT".into(), "1".into());
+        let count = config
+            .inherit_environment_prefix_from("MYAPP_", vars())
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            config.environment_variables(),
+            [
+                ("EXPLICIT".to_string(), "1".to_string()),
+                ("MYAPP_A".to_string(), "a".to_string()),
+                ("MYAPP_B".to_string(), "b".to_string()),
+            ]
+        );
+
+        assert!(config.inherit_environment_prefix_from("", vars()).is_err());
+        assert_eq!(config.environment_variables().len(), 3);
+    }
+}
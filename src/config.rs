@@ -2,11 +2,12 @@ use std::{
     fmt::Debug,
     fs,
     path::{Component, Path, PathBuf},
+    time::Duration,
 };
 
-use lunatic_process::config::ProcessConfig;
+use lunatic_process::{config::ProcessConfig, message::ResourceLimits};
 use lunatic_process_api::ProcessConfigCtx;
-use lunatic_wasi_api::LunaticWasiConfigCtx;
+use lunatic_wasi_api::{LunaticWasiConfigCtx, interpolate_env};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -15,12 +16,20 @@ pub struct DefaultProcessConfig {
     max_memory: usize,
     // Maximum amount of compute expressed in units of 100k instructions.
     max_fuel: Option<u64>,
+    // Maximum real time the process can run for
+    max_wall_time: Option<Duration>,
     // Can this process compile new WebAssembly modules
     can_compile_modules: bool,
     // Can this process create new configurations
     can_create_configs: bool,
     // Can this process spawn sub-processes
     can_spawn_processes: bool,
+    // Maximum number of descendants this process can have alive at once
+    max_children: Option<u32>,
+    // Is WASI unavailable to this process, overriding all WASI configs below
+    wasi_disabled: bool,
+    // Caps on the resources of each kind attached to messages this process creates
+    message_resource_limits: ResourceLimits,
     // WASI configs
     preopened_dirs: Vec<(String, String)>,
     command_line_arguments: Vec<String>,
@@ -32,6 +41,10 @@ impl Debug for DefaultProcessConfig {
         f.debug_struct("EnvConfig")
             .field("max_memory", &self.max_memory)
             .field("max_fuel", &self.max_fuel)
+            .field("max_wall_time", &self.max_wall_time)
+            .field("max_children", &self.max_children)
+            .field("wasi_disabled", &self.wasi_disabled)
+            .field("message_resource_limits", &self.message_resource_limits)
             .field("preopened_dirs", &self.preopened_dirs)
             .field("args", &self.command_line_arguments)
             .field("envs", &self.environment_variables)
@@ -55,6 +68,30 @@ impl ProcessConfig for DefaultProcessConfig {
     fn get_max_memory(&self) -> usize {
         self.max_memory
     }
+
+    fn set_max_wall_time(&mut self, max_wall_time: Option<Duration>) {
+        self.max_wall_time = max_wall_time;
+    }
+
+    fn get_max_wall_time(&self) -> Option<Duration> {
+        self.max_wall_time
+    }
+
+    fn set_wasi_disabled(&mut self, disabled: bool) {
+        self.wasi_disabled = disabled;
+    }
+
+    fn get_wasi_disabled(&self) -> bool {
+        self.wasi_disabled
+    }
+
+    fn set_message_resource_limits(&mut self, limits: ResourceLimits) {
+        self.message_resource_limits = limits;
+    }
+
+    fn get_message_resource_limits(&self) -> ResourceLimits {
+        self.message_resource_limits
+    }
 }
 
 impl LunaticWasiConfigCtx for DefaultProcessConfig {
@@ -66,7 +103,7 @@ impl LunaticWasiConfigCtx for DefaultProcessConfig {
         self.command_line_arguments.push(argument);
     }
 
-    fn preopen_dir(&mut self, dir: String) {
+    fn preopen_dir(&mut self, dir: String) -> anyhow::Result<()> {
         let resolved_path = if &dir == "~" {
             dirs::home_dir()
                 .and_then(|p| p.to_str().map(String::from))
@@ -77,19 +114,30 @@ impl LunaticWasiConfigCtx for DefaultProcessConfig {
                     dir.clone()
                 })
         } else {
-            dir.clone()
+            interpolate_env(&dir)?
         };
         self.preopened_dirs.push((dir, resolved_path));
+        Ok(())
     }
-}
 
-impl DefaultProcessConfig {
-    pub fn preopened_dirs(&self) -> &[(String, String)] {
+    fn environment_variables(&self) -> &[(String, String)] {
+        &self.environment_variables
+    }
+
+    fn command_line_arguments(&self) -> &[String] {
+        &self.command_line_arguments
+    }
+
+    fn preopened_dirs(&self) -> &[(String, String)] {
         &self.preopened_dirs
     }
+}
 
+impl DefaultProcessConfig {
     /// Grant access to the given directory with this config.
-    pub fn preopen_dir<S: Into<String>>(&mut self, dir: S) {
+    ///
+    /// `${VAR}` references in the path are resolved right away, see [`interpolate_env`].
+    pub fn preopen_dir<S: Into<String>>(&mut self, dir: S) -> anyhow::Result<()> {
         let dir = dir.into();
         let resolved_path = if &dir == "~" {
             fs::canonicalize(&dir)
@@ -97,9 +145,10 @@ impl DefaultProcessConfig {
                 .and_then(|p| p.to_str().map(String::from))
                 .unwrap_or_else(|| dir.clone())
         } else {
-            dir.clone()
+            interpolate_env(&dir)?
         };
-        self.preopened_dirs.push((dir, resolved_path))
+        self.preopened_dirs.push((dir, resolved_path));
+        Ok(())
     }
 
     pub fn set_command_line_arguments(&mut self, args: Vec<String>) {
@@ -144,7 +193,20 @@ impl ProcessConfigCtx for DefaultProcessConfig {
         self.can_spawn_processes = can
     }
 
+    fn max_children(&self) -> Option<u32> {
+        self.max_children
+    }
+
+    fn set_max_children(&mut self, max_children: Option<u32>) {
+        self.max_children = max_children
+    }
+
     fn can_access_fs_location(&self, path: &std::path::Path) -> Result<(), String> {
+        if self.wasi_disabled {
+            return Err(format!(
+                "Permission to '{path:?}' denied, WASI is disabled for this process"
+            ));
+        }
         let (file_path, parent_dir) = match strip_file(path) {
             Ok(p) => p,
             Err(e) => {
@@ -241,9 +303,13 @@ impl Default for DefaultProcessConfig {
         Self {
             max_memory: u32::MAX as usize, // = 4 GB
             max_fuel: None,
+            max_wall_time: None,
             can_compile_modules: false,
             can_create_configs: false,
             can_spawn_processes: false,
+            max_children: None,
+            wasi_disabled: false,
+            message_resource_limits: ResourceLimits::default(),
             preopened_dirs: vec![],
             command_line_arguments: vec![],
             environment_variables: vec![],
@@ -255,9 +321,11 @@ impl Default for DefaultProcessConfig {
 mod tests {
     use std::path::Path;
 
+    use lunatic_wasi_api::{LunaticWasiConfigCtx, interpolate_env};
+
     use crate::config::{get_absolute_path, path_is_ancestor};
 
-    use super::normalize_path;
+    use super::{DefaultProcessConfig, normalize_path};
 
     #[test]
     fn test_accessible_paths() {
@@ -305,4 +373,69 @@ mod tests {
         assert_eq!(src, normalize_path(&sneaky_src));
         assert_eq!(crates, normalize_path(&sneaky_path));
     }
+
+    #[test]
+    fn wasi_config_read_back_in_order() {
+        let mut config = DefaultProcessConfig::default();
+        config.add_environment_variable("A".into(), "1".into());
+        config.add_environment_variable("B".into(), "2".into());
+        config.add_command_line_argument("first".into());
+        config.add_command_line_argument("second".into());
+        LunaticWasiConfigCtx::preopen_dir(&mut config, "/tmp".into()).unwrap();
+        LunaticWasiConfigCtx::preopen_dir(&mut config, "/var".into()).unwrap();
+
+        assert_eq!(
+            LunaticWasiConfigCtx::environment_variables(&config),
+            &[
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "2".to_string())
+            ]
+        );
+        assert_eq!(
+            LunaticWasiConfigCtx::command_line_arguments(&config),
+            &["first".to_string(), "second".to_string()]
+        );
+        assert_eq!(
+            config.preopened_dirs(),
+            &[
+                ("/tmp".to_string(), "/tmp".to_string()),
+                ("/var".to_string(), "/var".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn wasi_disabled_denies_fs_access() {
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let dir = get_absolute_path(Path::new("crates")).unwrap();
+        let file = dir.join("Cargo.toml");
+        let mut config = DefaultProcessConfig::default();
+        LunaticWasiConfigCtx::preopen_dir(&mut config, dir.to_str().unwrap().into()).unwrap();
+        assert!(config.can_access_fs_location(&file).is_ok());
+
+        config.set_wasi_disabled(true);
+        assert!(config.can_access_fs_location(&file).is_err());
+    }
+
+    #[test]
+    fn preopen_dir_stores_interpolated_path() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let mut config = DefaultProcessConfig::default();
+        LunaticWasiConfigCtx::preopen_dir(&mut config, "${TMPDIR}".into()).unwrap();
+        let resolved = interpolate_env("${TMPDIR}").unwrap();
+        assert_eq!(
+            config.preopened_dirs(),
+            &[("${TMPDIR}".to_string(), resolved.clone())]
+        );
+        // The access check sees the same directory WASI preopens
+        let file = Path::new(&resolved).join("file");
+        assert!(config.can_access_fs_location(&file).is_ok());
+
+        let unset = "${LUNATIC_CONFIG_TEST_UNSET}".to_string();
+        assert!(LunaticWasiConfigCtx::preopen_dir(&mut config, unset).is_err());
+        assert_eq!(config.preopened_dirs().len(), 1);
+    }
 }
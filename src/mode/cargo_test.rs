@@ -87,9 +87,9 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
     config.set_environment_variables(env::vars().collect());
 
     // Always preopen the current dir
-    config.preopen_dir(".");
+    config.preopen_dir(".")?;
     for dir in args.dir {
-        config.preopen_dir(dir);
+        config.preopen_dir(dir)?;
     }
 
     // Create wasmtime runtime
@@ -248,8 +248,8 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
         // If --nocapture is not set, use in-memory stdout & stderr to hide output in case of
         // success
         let stdout = StdoutCapture::new(args.nocapture);
-        state.set_stdout(stdout.clone());
-        state.set_stderr(stdout.clone());
+        state.set_stdout(stdout.clone())?;
+        state.set_stderr(stdout.clone())?;
 
         env.can_spawn_next_process().await?;
         let (task, _) = spawn_wasm(
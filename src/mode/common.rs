@@ -4,6 +4,7 @@ use anyhow::{Context, Result, anyhow};
 use clap::Args;
 
 use lunatic_distributed::DistributedProcessState;
+use lunatic_plugin::{LifecycleEvent, PluginRegistry};
 use lunatic_process::{
     env::{Environment, LunaticEnvironment, LunaticEnvironments},
     runtimes::{RawWasm, wasmtime::WasmtimeRuntime},
@@ -46,10 +47,10 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
     config.set_environment_variables(std::env::vars().collect());
 
     // Always preopen the current dir
-    config.preopen_dir(".");
+    config.preopen_dir(".")?;
     for dir in args.dir {
         if let Some(s) = dir.as_os_str().to_str() {
-            config.preopen_dir(s);
+            config.preopen_dir(s)?;
         }
     }
 
@@ -64,6 +65,7 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
         module.into()
     };
 
+    let plugin_registry: Arc<PluginRegistry> = Default::default();
     let module = Arc::new(args.runtime.compile_module::<DefaultProcessState>(module)?);
     let state = DefaultProcessState::new(
         args.env.clone(),
@@ -72,28 +74,36 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
         module.clone(),
         Arc::new(config),
         Default::default(),
-        Default::default(),
+        plugin_registry.clone(),
     )
     .unwrap();
 
-    args.env.can_spawn_next_process().await?;
-    let (task, _) = spawn_wasm(
-        args.env,
-        args.runtime,
-        &module,
-        state,
-        "_start",
-        Vec::new(),
-        None,
-    )
-    .await
-    .context(format!(
-        "Failed to spawn process from {}::_start()",
-        path.to_string_lossy()
-    ))?;
-
-    // Wait on the main process to finish
-    task.await.map(|_| ()).map_err(|e| anyhow!(e.to_string()))
+    // Lifecycle plugins set up before the main process runs and clean up once it finished
+    let dispatcher = plugin_registry.lifecycle_dispatcher();
+    dispatcher.dispatch(&LifecycleEvent::RuntimeStarted);
+    let result = async {
+        args.env.can_spawn_next_process().await?;
+        let (task, _) = spawn_wasm(
+            args.env,
+            args.runtime,
+            &module,
+            state,
+            "_start",
+            Vec::new(),
+            None,
+        )
+        .await
+        .context(format!(
+            "Failed to spawn process from {}::_start()",
+            path.to_string_lossy()
+        ))?;
+
+        // Wait on the main process to finish
+        task.await.map(|_| ()).map_err(|e| anyhow!(e.to_string()))
+    }
+    .await;
+    dispatcher.dispatch(&LifecycleEvent::RuntimeStopping);
+    result
 }
 
 #[cfg(feature = "prometheus")]
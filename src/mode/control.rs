@@ -1,43 +1,264 @@
 use std::net::{SocketAddr, TcpListener};
+use std::ops::RangeInclusive;
 
 use anyhow::{Result, anyhow};
 use clap::Parser;
+use tokio_util::sync::CancellationToken;
+
+/// Port nodes connect to by default, see `node::Args::control`
+const DEFAULT_PORT: u16 = 3030;
 
 #[derive(Parser, Debug)]
 pub(crate) struct Args {
     #[arg(long, value_name = "CONTROL_SERVER_SOCKET")]
     bind_socket: Option<SocketAddr>,
+
+    /// Without a bind socket, listen on the first free port of this range on 127.0.0.1
+    /// (e.g. 3030-3999). By default port 3030 is used if it's free, otherwise one picked
+    /// by the OS
+    #[arg(long, value_name = "START-END", value_parser = parse_port_range, conflicts_with = "bind_socket")]
+    port_range: Option<RangeInclusive<u16>>,
 }
 
-pub(crate) async fn start(args: Args) -> Result<()> {
-    if let Some(socket) = args.bind_socket {
-        log::info!("Register URL: http://{}/", socket);
-        lunatic_control_axum::server::control_server(socket).await?;
-    } else if let Some(std_listener) = get_available_localhost() {
-        log::info!(
-            "Register URL: http://{}/",
-            std_listener.local_addr().unwrap()
-        );
-        std_listener.set_nonblocking(true)?;
-        let listener = tokio::net::TcpListener::from_std(std_listener)?;
-        lunatic_control_axum::server::control_server_from_tcp(listener).await?;
-    }
+/// Start the control server and run it until `cancel` is triggered.
+///
+/// Cancelling the token interrupts the free port scan or shuts the running
+/// server down gracefully; in both cases `Ok(())` is returned.
+pub(crate) async fn start(args: Args, cancel: CancellationToken) -> Result<()> {
+    let Some(std_listener) = bind_listener(&args, &cancel)? else {
+        return Ok(());
+    };
+
+    log::info!(
+        "Register URL: http://{}/",
+        std_listener.local_addr().unwrap()
+    );
+    std_listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(std_listener)?;
+    lunatic_control_axum::server::control_server_from_tcp_with_shutdown(
+        listener,
+        cancel.cancelled_owned(),
+    )
+    .await
+}
 
-    Err(anyhow!("No available port on 127.0.0.1. Aborting"))
+/// Bind the control server's listener, `None` if the port scan was cancelled
+fn bind_listener(args: &Args, cancel: &CancellationToken) -> Result<Option<TcpListener>> {
+    let listener = match (args.bind_socket, &args.port_range) {
+        (Some(socket), _) => TcpListener::bind(socket)?,
+        (None, Some(range)) => match get_available_localhost(range.clone(), cancel) {
+            Some(listener) => listener,
+            None if cancel.is_cancelled() => return Ok(None),
+            None => {
+                return Err(anyhow!(
+                    "No available port in {}-{} on 127.0.0.1. Aborting",
+                    range.start(),
+                    range.end()
+                ));
+            }
+        },
+        // Let the OS pick a free port rather than scanning for one
+        (None, None) => TcpListener::bind(("127.0.0.1", DEFAULT_PORT))
+            .or_else(|_| TcpListener::bind(("127.0.0.1", 0)))?,
+    };
+    Ok(Some(listener))
 }
 
-fn get_available_localhost() -> Option<TcpListener> {
-    for port in 3030..3999u16 {
+fn get_available_localhost(
+    range: RangeInclusive<u16>,
+    cancel: &CancellationToken,
+) -> Option<TcpListener> {
+    for port in range {
+        if cancel.is_cancelled() {
+            return None;
+        }
         if let Ok(s) = TcpListener::bind(("127.0.0.1", port)) {
             return Some(s);
         }
     }
 
-    for port in 1025..65535u16 {
-        if let Ok(s) = TcpListener::bind(("127.0.0.1", port)) {
-            return Some(s);
+    None
+}
+
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| anyhow!("Port range '{s}' is not formatted as START-END"))?;
+    let (start, end): (u16, u16) = (start.parse()?, end.parse()?);
+    if start > end {
+        return Err(anyhow!("Port range '{s}' starts after it ends"));
+    }
+    Ok(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelled_before_scan_returns_ok() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let args = Args {
+            bind_socket: None,
+            port_range: Some(3030..=3999),
+        };
+        let result = tokio::time::timeout(Duration::from_secs(5), start(args, cancel)).await;
+        assert!(matches!(result, Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn cancel_waits_for_in_flight_request() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let cancel = CancellationToken::new();
+        let args = Args {
+            bind_socket: Some(addr),
+            port_range: None,
+        };
+        let server = tokio::spawn(start(args, cancel.clone()));
+
+        let mut stream = loop {
+            match tokio::net::TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        // The handler waits for the rest of the body
+        let body = r#"{"node_name": "not a uuid"}"#;
+        let head = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(&body.as_bytes()[..4]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        cancel.cancel();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.is_finished());
+
+        stream.write_all(&body.as_bytes()[4..]).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 "), "{response}");
+        let result = tokio::time::timeout(Duration::from_secs(5), server).await;
+        assert!(matches!(result, Ok(Ok(Ok(())))));
+    }
+
+    #[tokio::test]
+    async fn health_endpoints_answer_ok() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(
+            lunatic_control_axum::server::control_server_from_tcp_with_shutdown(
+                listener,
+                cancel.clone().cancelled_owned(),
+            ),
+        );
+
+        for path in ["/healthz", "/readyz"] {
+            let response = tokio::task::spawn_blocking(move || {
+                use std::io::{Read, Write};
+                let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                write!(
+                    stream,
+                    "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            })
+            .await
+            .unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"), "{path}: {response}");
         }
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
     }
 
-    None
+    #[tokio::test]
+    async fn readyz_reports_unavailable_while_shutting_down() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(
+            lunatic_control_axum::server::control_server_from_tcp_with_shutdown(
+                listener,
+                cancel.clone().cancelled_owned(),
+            ),
+        );
+
+        // The request is only complete after the shutdown started
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        cancel.cancel();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stream
+            .write_all(b"Connection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn cancelled_scan_finds_no_port() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        assert!(get_available_localhost(1025..=65535, &cancel).is_none());
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!(parse_port_range("3030-3999").unwrap(), 3030..=3999);
+        assert_eq!(parse_port_range("4000-4000").unwrap(), 4000..=4000);
+        assert!(parse_port_range("3999-3030").is_err());
+        assert!(parse_port_range("3030").is_err());
+        assert!(parse_port_range("3030-70000").is_err());
+    }
+
+    #[test]
+    fn binds_os_assigned_port_without_range() {
+        // Holds the default port, unless something else already does
+        let _default = TcpListener::bind(("127.0.0.1", DEFAULT_PORT));
+        // A scan gives up on a cancelled token, so getting a listener shows none ran
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let args = Args {
+            bind_socket: None,
+            port_range: None,
+        };
+        let listener = bind_listener(&args, &cancel).unwrap().unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_ne!(addr.port(), DEFAULT_PORT);
+    }
 }
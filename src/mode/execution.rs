@@ -1,5 +1,6 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -20,7 +21,7 @@ enum Commands {
     /// containing the Cargo.toml file. It will add configuration options to it
     /// in the `cargo/config.toml` file, setting the compilation target to
     /// `wasm32-wasi` and the default runner for this target to `lunatic run`.
-    Init,
+    Init(super::init::Args),
     /// Executes a .wasm file
     Run(super::run::Args),
     /// Starts a control node
@@ -44,9 +45,9 @@ pub(crate) async fn execute(augmented_args: Option<Vec<String>>) -> Result<()> {
     };
 
     match args.command {
-        Commands::Init => super::init::start(),
+        Commands::Init(a) => super::init::start(a),
         Commands::Run(a) => super::run::start(a).await,
-        Commands::Control(a) => super::control::start(a).await,
+        Commands::Control(a) => super::control::start(a, CancellationToken::new()).await,
         Commands::Node(a) => super::node::start(a).await,
         Commands::Login(a) => super::login::start(a).await,
         Commands::App(a) => super::app::start(a).await,
@@ -1,39 +1,105 @@
 use std::{
-    fs::{OpenOptions, create_dir_all},
-    io::{Read, Seek, Write},
+    fmt::{self, Display},
+    fs::{self, create_dir_all},
+    io::ErrorKind,
     path::Path,
 };
 
 use anyhow::{Context, Result, anyhow};
+use clap::Parser;
 use toml::{Value, value::Table};
 
-pub(crate) fn start() -> Result<()> {
-    // Check if the current directory is a Rust cargo project.
-    if !Path::new("Cargo.toml").exists() {
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Print the changes to `.cargo/config.toml` instead of writing them, and
+    /// fail if there are any
+    #[arg(long)]
+    dry_run: bool,
+    /// Print each change made to `.cargo/config.toml`
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+/// A single edit `init` makes to `.cargo/config.toml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfigChange {
+    /// `build.target` was missing and is set to `wasm32-wasi`.
+    SetBuildTarget,
+    /// `target.wasm32-wasi.runner` was missing and is set to `lunatic run`.
+    SetRunner,
+    /// `target.wasm32-wasi.runner` is changed from the old `lunatic` to `lunatic run`.
+    UpgradeRunner,
+}
+
+impl Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigChange::SetBuildTarget => write!(f, "set `build.target` to `wasm32-wasi`"),
+            ConfigChange::SetRunner => {
+                write!(f, "set `target.wasm32-wasi.runner` to `lunatic run`")
+            }
+            ConfigChange::UpgradeRunner => write!(
+                f,
+                "upgrade `target.wasm32-wasi.runner` from `lunatic` to `lunatic run`"
+            ),
+        }
+    }
+}
+
+pub(crate) fn start(args: Args) -> Result<()> {
+    init(Path::new(""), args.dry_run, args.verbose)
+}
+
+fn init(project: &Path, dry_run: bool, verbose: bool) -> Result<()> {
+    // Check if the directory is a Rust cargo project.
+    if !project.join("Cargo.toml").exists() {
         return Err(anyhow!("Must be called inside a cargo project"));
     }
+    let config_path = project.join(".cargo/config.toml");
+
+    let content = match fs::read_to_string(&config_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context("failed to read `.cargo/config.toml`"),
+    };
+    let (new_config, changes) = compute_changes(&content)?;
+
+    if dry_run {
+        if changes.is_empty() {
+            println!("Cargo project is already initialized");
+            return Ok(());
+        }
+        print!("{}", diff(&content, &new_config));
+        return Err(anyhow!("`.cargo/config.toml` would be changed"));
+    }
+
+    if !changes.is_empty() {
+        create_dir_all(project.join(".cargo")).context("failed to create `.cargo/` directory")?;
+        fs::write(&config_path, new_config).context("failed to write `.cargo/config.toml`")?;
+    }
+    if verbose {
+        for change in &changes {
+            println!("{change}");
+        }
+    }
+
+    println!("Cargo project initialized!");
+
+    Ok(())
+}
 
-    // Open or create cargo config file.
-    create_dir_all(".cargo").context("failed to create `.cargo/` directory")?;
-    let mut config_toml = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open(".cargo/config.toml")
-        .context("failed to open `.cargo/config.toml`")?;
-
-    let mut content = String::new();
-    config_toml
-        .read_to_string(&mut content)
-        .context("failed to read `.cargo/config.toml`")?;
-
-    let mut content = content
+/// Returns the content `.cargo/config.toml` needs to have to use lunatic as the runner for
+/// `wasm32-wasi`, together with the changes made to `existing` to get there.
+///
+/// If there are no changes, `existing` is returned as is.
+pub(crate) fn compute_changes(existing: &str) -> Result<(String, Vec<ConfigChange>)> {
+    let mut content = existing
         .parse::<Value>()
         .context("failed to parse `.cargo/config.toml` as TOML")?;
     let table = content
         .as_table_mut()
         .ok_or_else(|| anyhow!("`.cargo/config.toml` root is not a TOML table"))?;
+    let mut changes = Vec::new();
 
     // Set correct target
     match table.get_mut("build") {
@@ -53,12 +119,14 @@ pub(crate) fn start() -> Result<()> {
             } else {
                 // If value is missing, add it.
                 build.insert("target".to_owned(), Value::String("wasm32-wasi".to_owned()));
+                changes.push(ConfigChange::SetBuildTarget);
             }
         }
         None => {
             let mut new_build = Table::new();
             new_build.insert("target".to_owned(), Value::String("wasm32-wasi".to_owned()));
             table.insert("build".to_owned(), Value::Table(new_build));
+            changes.push(ConfigChange::SetBuildTarget);
         }
     };
 
@@ -86,6 +154,7 @@ pub(crate) fn start() -> Result<()> {
                                     "runner".to_owned(),
                                     Value::String("lunatic run".to_owned()),
                                 );
+                                changes.push(ConfigChange::UpgradeRunner);
                             }
                             "lunatic run" => {
                                 // Correct value is already set, don't do anything.
@@ -100,6 +169,7 @@ pub(crate) fn start() -> Result<()> {
                         // If value is missing, add it.
                         wasm_target
                             .insert("runner".to_owned(), Value::String("lunatic run".to_owned()));
+                        changes.push(ConfigChange::SetRunner);
                     }
                 }
                 None => {
@@ -108,6 +178,7 @@ pub(crate) fn start() -> Result<()> {
                     new_wasm32_wasi
                         .insert("runner".to_owned(), Value::String("lunatic run".to_owned()));
                     target.insert("wasm32-wasi".to_owned(), Value::Table(new_wasm32_wasi));
+                    changes.push(ConfigChange::SetRunner);
                 }
             }
         }
@@ -119,22 +190,129 @@ pub(crate) fn start() -> Result<()> {
             let mut new_target = Table::new();
             new_target.insert("wasm32-wasi".to_owned(), Value::Table(new_wasm32_wasi));
             table.insert("target".to_owned(), Value::Table(new_target));
+            changes.push(ConfigChange::SetRunner);
         }
     };
 
-    let new_config = toml::to_string(table).context("failed to serialize `.cargo/config.toml`")?;
-    // Truncate existing config
-    config_toml
-        .set_len(0)
-        .context("failed to truncate `.cargo/config.toml`")?;
-    config_toml
-        .rewind()
-        .context("failed to rewind `.cargo/config.toml`")?;
-    config_toml
-        .write_all(new_config.as_bytes())
-        .context("failed to write `.cargo/config.toml`")?;
+    if changes.is_empty() {
+        return Ok((existing.to_owned(), changes));
+    }
+    let new_config =
+        toml::to_string(&content).context("failed to serialize `.cargo/config.toml`")?;
+    Ok((new_config, changes))
+}
 
-    println!("Cargo project initialized!");
+// Line diff of `old` and `new`. Lines only in `old` start with `-`, lines only in `new` with `+`.
+fn diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // Length of the longest common subsequence of `old[i..]` and `new[j..]`
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
 
-    Ok(())
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        let line = if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+            format!(" {}", old[i - 1])
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            j += 1;
+            format!("+{}", new[j - 1])
+        } else {
+            i += 1;
+            format!("-{}", old[i - 1])
+        };
+        diff.push_str(&line);
+        diff.push('\n');
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_run_leaves_config_untouched() {
+        let project =
+            std::env::temp_dir().join(format!("lunatic-init-dry-run-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&project);
+        fs::create_dir_all(project.join(".cargo")).unwrap();
+        fs::write(project.join("Cargo.toml"), "[package]\nname = \"app\"\n").unwrap();
+        let config_path = project.join(".cargo/config.toml");
+        let config = "[build]\ntarget = \"wasm32-wasi\"\n";
+        fs::write(&config_path, config).unwrap();
+
+        // The runner is missing, so there are changes to report
+        assert!(init(&project, true, false).is_err());
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), config);
+
+        init(&project, false, false).unwrap();
+        let initialized = fs::read_to_string(&config_path).unwrap();
+        assert_ne!(initialized, config);
+        init(&project, true, false).unwrap();
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), initialized);
+
+        fs::remove_dir_all(&project).unwrap();
+    }
+
+    #[test]
+    fn diff_marks_added_and_removed_lines() {
+        assert_eq!(diff("a\nb\nc\n", "a\nc\nd\n"), " a\n-b\n c\n+d\n");
+        assert_eq!(diff("", "a\n"), "+a\n");
+    }
+
+    #[test]
+    fn compute_changes_on_fresh_config() {
+        let (new_config, changes) = compute_changes("").unwrap();
+        assert_eq!(
+            changes,
+            [ConfigChange::SetBuildTarget, ConfigChange::SetRunner]
+        );
+        let value = new_config.parse::<Value>().unwrap();
+        assert_eq!(value["build"]["target"].as_str(), Some("wasm32-wasi"));
+        assert_eq!(
+            value["target"]["wasm32-wasi"]["runner"].as_str(),
+            Some("lunatic run")
+        );
+        // Applying the changes again is a no-op
+        assert_eq!(compute_changes(&new_config).unwrap(), (new_config, vec![]));
+    }
+
+    #[test]
+    fn compute_changes_on_correct_config() {
+        let existing = "# lunatic\n[build]\ntarget = \"wasm32-wasi\"\n\n[target.wasm32-wasi]\nrunner = \"lunatic run\"\n";
+        let (new_config, changes) = compute_changes(existing).unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(new_config, existing);
+    }
+
+    #[test]
+    fn compute_changes_upgrades_old_runner() {
+        let existing =
+            "[build]\ntarget = \"wasm32-wasi\"\n\n[target.wasm32-wasi]\nrunner = \"lunatic\"\n";
+        let (new_config, changes) = compute_changes(existing).unwrap();
+        assert_eq!(changes, [ConfigChange::UpgradeRunner]);
+        let value = new_config.parse::<Value>().unwrap();
+        assert_eq!(
+            value["target"]["wasm32-wasi"]["runner"].as_str(),
+            Some("lunatic run")
+        );
+    }
+
+    #[test]
+    fn compute_changes_rejects_conflicts() {
+        assert!(compute_changes("[build]\ntarget = \"wasm32-unknown-unknown\"\n").is_err());
+        assert!(compute_changes("[target.wasm32-wasi]\nrunner = \"wasmtime\"\n").is_err());
+    }
 }
@@ -8,20 +8,23 @@ use lunatic_distributed::{DistributedCtx, DistributedProcessState};
 use lunatic_error_api::{ErrorCtx, ErrorResource};
 use lunatic_networking_api::{DnsIterator, TlsConnection, TlsListener};
 use lunatic_networking_api::{NetworkingCtx, TcpConnection};
-use lunatic_plugin::{LifecycleEvent, PluginCtx, PluginRegistry};
+use lunatic_plugin::{ExitReason, LifecycleEvent, PluginCtx, PluginRegistry};
 use lunatic_process::env::{Environment, LunaticEnvironment};
 use lunatic_process::runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
-use lunatic_process::state::{ConfigResources, ProcessState};
+use lunatic_process::state::{ConfigResources, ProcessState, SendDecision, SendHook};
 use lunatic_process::{
+    ExitStatus,
     config::ProcessConfig,
     state::{SignalReceiver, SignalSender},
 };
 use lunatic_process::{mailbox::MessageMailbox, message::Message};
-use lunatic_process_api::{ProcessConfigCtx, ProcessCtx};
+use lunatic_process_api::{ChildLimits, ChildSlot, ProcessConfigCtx, ProcessCtx};
 use lunatic_sqlite_api::{SQLiteConnections, SQLiteCtx, SQLiteGuestAllocators, SQLiteStatements};
 use lunatic_stdout_capture::StdoutCapture;
 use lunatic_timer_api::{TimerCtx, TimerResources};
-use lunatic_wasi_api::{LunaticWasiCtx, build_wasi};
+use lunatic_wasi_api::{
+    CaptureTarget, LunaticWasiConfigCtx, LunaticWasiCtx, build_null_wasi, build_wasi,
+};
 use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::{Mutex, RwLock};
@@ -67,6 +70,8 @@ pub struct DefaultProcessState {
     wasi_stdout: Option<StdoutCapture>,
     // WASI stderr stream
     wasi_stderr: Option<StdoutCapture>,
+    // Targets the WASI context writes stdout and stderr into, if they are captured
+    wasi_targets: (Option<CaptureTarget>, Option<CaptureTarget>),
     // Set to true if the WASM module has been instantiated
     initialized: bool,
     // database resources
@@ -74,6 +79,16 @@ pub struct DefaultProcessState {
     registry: Arc<RwLock<HashMap<String, (u64, u64)>>>,
     // Plugin registry shared across all processes
     plugin_registry: Arc<PluginRegistry>,
+    // Limits on the alive descendants of this process and its ancestors
+    child_limits: ChildLimits,
+    // This process' place in its ancestors' child limits, released when it finishes
+    child_slot: Option<ChildSlot>,
+    // Decides on messages sent by this process, inherited by its children
+    send_hook: Option<SendHook>,
+    // Size of the linear memory, updated whenever the resource limiter allows it to grow
+    memory_size: usize,
+    // Reason for exiting set by the guest, shared with the lifecycle callback
+    exit_reason: Arc<std::sync::Mutex<Option<String>>>,
 }
 
 impl DefaultProcessState {
@@ -100,22 +115,28 @@ impl DefaultProcessState {
             signal_mailbox,
             message_mailbox,
             resources: Resources::default(),
-            wasi: build_wasi(
-                Some(config.command_line_arguments()),
-                Some(config.environment_variables()),
-                config.preopened_dirs(),
-                None,
-                None,
-            )?,
+            wasi: build_process_wasi(&config, None, None)?,
             wasi_stdout: None,
             wasi_stderr: None,
+            wasi_targets: (None, None),
             initialized: false,
             registry,
             db_resources: DbResources::default(),
             plugin_registry,
+            child_limits: ChildLimits::default().with_limit(config.max_children()),
+            child_slot: None,
+            send_hook: None,
+            memory_size: 0,
+            exit_reason: Default::default(),
         };
         Ok(state)
     }
+
+    /// Let `hook` delay or reject the messages sent by this process and the processes it spawns
+    /// from now on, e.g. to apply backpressure.
+    pub fn set_send_hook(&mut self, hook: Option<SendHook>) {
+        self.send_hook = hook;
+    }
 }
 
 impl ProcessState for DefaultProcessState {
@@ -140,19 +161,19 @@ impl ProcessState for DefaultProcessState {
             signal_mailbox,
             message_mailbox,
             resources: Resources::default(),
-            wasi: build_wasi(
-                Some(config.command_line_arguments()),
-                Some(config.environment_variables()),
-                config.preopened_dirs(),
-                None,
-                None,
-            )?,
+            wasi: build_process_wasi(&config, None, None)?,
             wasi_stdout: None,
             wasi_stderr: None,
+            wasi_targets: (None, None),
             initialized: false,
             registry: self.registry.clone(),
             db_resources: DbResources::default(),
             plugin_registry: self.plugin_registry.clone(),
+            child_limits: self.child_limits.with_limit(config.max_children()),
+            child_slot: None,
+            send_hook: self.send_hook.clone(),
+            memory_size: 0,
+            exit_reason: Default::default(),
         };
         Ok(state)
     }
@@ -226,23 +247,57 @@ impl ProcessState for DefaultProcessState {
             .dispatch(&LifecycleEvent::ProcessSpawning { process_id });
     }
 
+    fn on_send(&self, to: u64, size: usize) -> SendDecision {
+        match &self.send_hook {
+            Some(hook) => hook(to, size),
+            None => SendDecision::Proceed,
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.memory_size
+    }
+
+    // Network handles: TCP and TLS listeners and streams, and UDP sockets
+    fn open_resource_count(&self) -> usize {
+        let resources = &self.resources;
+        resources.tcp_listeners.len()
+            + resources.tcp_streams.len()
+            + resources.tls_listeners.len()
+            + resources.tls_streams.len()
+            + resources.udp_sockets.len()
+    }
+
     fn lifecycle_callback(&self) -> Option<lunatic_process::state::LifecycleCallback> {
         if self.plugin_registry.lifecycle_dispatcher().plugin_count() == 0 {
             return None;
         }
         let registry = self.plugin_registry.clone();
-        Some(Arc::new(move |phase: &str, process_id: u64| {
-            let event = match phase {
-                "spawned" => LifecycleEvent::ProcessSpawned { process_id },
-                "exiting" => LifecycleEvent::ProcessExiting { process_id },
-                "exited" => LifecycleEvent::ProcessExited {
-                    process_id,
-                    error: None,
-                },
-                _ => return,
-            };
-            registry.lifecycle_dispatcher().dispatch(&event);
-        }))
+        let requested = self.exit_reason.clone();
+        Some(Arc::new(
+            move |phase: &str, process_id: u64, status: Option<&ExitStatus>| {
+                let event = match phase {
+                    "spawned" => LifecycleEvent::ProcessSpawned { process_id },
+                    "exiting" => LifecycleEvent::ProcessExiting { process_id },
+                    "exited" => {
+                        let reason = exit_reason(status, requested.lock().unwrap().take());
+                        // Hooks only receive the reason code, they can look up the message
+                        // as the `error` label.
+                        let labels = reason
+                            .error()
+                            .map(|error| HashMap::from([("error".to_string(), error)]))
+                            .unwrap_or_default();
+                        let event = LifecycleEvent::ProcessExited { process_id, reason };
+                        registry
+                            .lifecycle_dispatcher()
+                            .dispatch_labeled(&event, &labels);
+                        return;
+                    }
+                    _ => return,
+                };
+                registry.lifecycle_dispatcher().dispatch(&event);
+            },
+        ))
     }
 
     fn transform_module(&self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
@@ -258,6 +313,40 @@ impl Debug for DefaultProcessState {
     }
 }
 
+// Translate how the process finished into the reason reported to lifecycle plugins.
+//
+// The reason set by the guest is only reported if the process finished normally.
+fn exit_reason(status: Option<&ExitStatus>, requested: Option<String>) -> ExitReason {
+    match status {
+        None | Some(ExitStatus::Normal) => {
+            requested.map_or(ExitReason::Normal, ExitReason::Requested)
+        }
+        Some(ExitStatus::Trapped(error)) => ExitReason::Trapped(error.clone()),
+        Some(ExitStatus::OutOfFuel) => ExitReason::OutOfFuel,
+        Some(ExitStatus::TimedOut) => ExitReason::Timeout,
+        Some(ExitStatus::Killed) => ExitReason::Killed,
+        Some(ExitStatus::LinkDied) => ExitReason::LinkDied,
+    }
+}
+
+// A process with WASI disabled gets no WASI configs and no stdio, even if captures are set.
+fn build_process_wasi(
+    config: &DefaultProcessConfig,
+    stdout: Option<CaptureTarget>,
+    stderr: Option<CaptureTarget>,
+) -> Result<WasiP1Ctx> {
+    if config.get_wasi_disabled() {
+        return Ok(build_null_wasi());
+    }
+    build_wasi(
+        Some(config.command_line_arguments()),
+        Some(config.environment_variables()),
+        config.preopened_dirs(),
+        stdout,
+        stderr,
+    )
+}
+
 // Limit the maximum memory of the process depending on the environment it was spawned in.
 impl ResourceLimiter for DefaultProcessState {
     fn memory_growing(
@@ -266,7 +355,11 @@ impl ResourceLimiter for DefaultProcessState {
         desired: usize,
         _maximum: Option<usize>,
     ) -> Result<bool> {
-        Ok(desired <= self.config().get_max_memory())
+        let allowed = desired <= self.config().get_max_memory();
+        if allowed {
+            self.memory_size = desired;
+        }
+        Ok(allowed)
     }
 
     fn table_growing(
@@ -326,6 +419,18 @@ impl ProcessCtx<DefaultProcessState> for DefaultProcessState {
     fn environment(&self) -> Arc<dyn Environment> {
         self.environment.clone()
     }
+
+    fn child_limits(&self) -> &ChildLimits {
+        &self.child_limits
+    }
+
+    fn set_child_slot(&mut self, slot: ChildSlot) {
+        self.child_slot = Some(slot);
+    }
+
+    fn set_exit_reason(&mut self, reason: String) {
+        *self.exit_reason.lock().unwrap() = Some(reason);
+    }
 }
 
 impl NetworkingCtx for DefaultProcessState {
@@ -397,32 +502,40 @@ impl LunaticWasiCtx for DefaultProcessState {
         &mut self.wasi
     }
 
-    // Redirect the stdout stream by rebuilding the WasiP1Ctx with the capture wired in.
-    // This is always called before the process starts executing.
-    fn set_stdout(&mut self, stdout: StdoutCapture) {
-        self.wasi_stdout = Some(stdout);
-        self.wasi = build_wasi(
-            Some(self.config.command_line_arguments()),
-            Some(self.config.environment_variables()),
-            self.config.preopened_dirs(),
-            self.wasi_stdout.clone(),
-            self.wasi_stderr.clone(),
-        )
-        .expect("failed to rebuild WasiP1Ctx with stdout capture");
-    }
-
-    // Redirect the stderr stream by rebuilding the WasiP1Ctx with the capture wired in.
-    // This is always called before the process starts executing.
-    fn set_stderr(&mut self, stderr: StdoutCapture) {
-        self.wasi_stderr = Some(stderr);
-        self.wasi = build_wasi(
-            Some(self.config.command_line_arguments()),
-            Some(self.config.environment_variables()),
-            self.config.preopened_dirs(),
-            self.wasi_stdout.clone(),
-            self.wasi_stderr.clone(),
-        )
-        .expect("failed to rebuild WasiP1Ctx with stderr capture");
+    // Redirect the stdout stream. If it's already captured only the target is swapped, so the
+    // guest keeps its file descriptors. Otherwise the WasiP1Ctx is rebuilt with the capture
+    // wired in, which only happens before the process starts executing.
+    fn set_stdout(&mut self, stdout: StdoutCapture) -> Result<()> {
+        self.wasi_stdout = Some(stdout.clone());
+        match &self.wasi_targets.0 {
+            Some(target) => target.replace(stdout),
+            None => {
+                self.wasi_targets.0 = Some(CaptureTarget::new(stdout));
+                self.wasi = build_process_wasi(
+                    &self.config,
+                    self.wasi_targets.0.clone(),
+                    self.wasi_targets.1.clone(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Redirect the stderr stream, like `set_stdout`.
+    fn set_stderr(&mut self, stderr: StdoutCapture) -> Result<()> {
+        self.wasi_stderr = Some(stderr.clone());
+        match &self.wasi_targets.1 {
+            Some(target) => target.replace(stderr),
+            None => {
+                self.wasi_targets.1 = Some(CaptureTarget::new(stderr));
+                self.wasi = build_process_wasi(
+                    &self.config,
+                    self.wasi_targets.0.clone(),
+                    self.wasi_targets.1.clone(),
+                )?;
+            }
+        }
+        Ok(())
     }
 
     fn get_stdout(&self) -> Option<&StdoutCapture> {
@@ -524,19 +637,19 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
             signal_mailbox,
             message_mailbox,
             resources: Resources::default(),
-            wasi: build_wasi(
-                Some(config.command_line_arguments()),
-                Some(config.environment_variables()),
-                config.preopened_dirs(),
-                None,
-                None,
-            )?,
+            wasi: build_process_wasi(&config, None, None)?,
             wasi_stdout: None,
             wasi_stderr: None,
+            wasi_targets: (None, None),
             initialized: false,
             registry: Default::default(), // Registry is shared across processes via Arc<RwLock<>> rather than moved into Environment
             db_resources: DbResources::default(),
             plugin_registry: Default::default(),
+            child_limits: ChildLimits::default().with_limit(config.max_children()),
+            child_slot: None,
+            send_hook: None,
+            memory_size: 0,
+            exit_reason: Default::default(),
         };
         Ok(state)
     }
@@ -637,4 +750,1058 @@ mod tests {
         let stored = modules.get(42);
         assert!(stored.is_some(), "module should be stored with id 42");
     }
+
+    // Instantiates `wat` with the given process config and calls `function`, returning the
+    // failure message if the call trapped.
+    async fn call_guest(
+        config: crate::DefaultProcessConfig,
+        wat: &str,
+        function: &str,
+    ) -> Option<String> {
+        call_guest_with_stdout(config, None, wat, function).await
+    }
+
+    // Like `call_guest`, capturing the guest's stdout in `stdout` if set.
+    async fn call_guest_with_stdout(
+        config: crate::DefaultProcessConfig,
+        stdout: Option<lunatic_stdout_capture::StdoutCapture>,
+        wat: &str,
+        function: &str,
+    ) -> Option<String> {
+        use lunatic_wasi_api::LunaticWasiCtx;
+
+        call_guest_with_state(
+            config,
+            |state| {
+                if let Some(stdout) = stdout {
+                    state.set_stdout(stdout).unwrap();
+                }
+            },
+            wat,
+            function,
+        )
+        .await
+    }
+
+    // Like `call_guest`, letting `setup` adjust the process state before it's instantiated.
+    async fn call_guest_with_state(
+        config: crate::DefaultProcessConfig,
+        setup: impl FnOnce(&mut crate::state::DefaultProcessState),
+        wat: &str,
+        function: &str,
+    ) -> Option<String> {
+        let result = run_guest(config, setup, wat, function).await;
+        result.failure().map(str::to_string)
+    }
+
+    // Like `call_guest_with_state`, returning the whole result including the final state.
+    async fn run_guest(
+        config: crate::DefaultProcessConfig,
+        setup: impl FnOnce(&mut crate::state::DefaultProcessState),
+        wat: &str,
+        function: &str,
+    ) -> lunatic_process::ExecutionResult<crate::state::DefaultProcessState> {
+        use std::collections::HashMap;
+        use tokio::sync::RwLock;
+
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::RawWasm;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use std::sync::Arc;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        let raw_module = wat::parse_str(wat).unwrap();
+        let module = Arc::new(
+            runtime
+                .compile_module::<DefaultProcessState>(RawWasm::new(None, raw_module))
+                .unwrap(),
+        );
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let registry = Arc::new(RwLock::new(HashMap::new()));
+        let mut state = DefaultProcessState::new(
+            env,
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            registry,
+            Default::default(),
+        )
+        .unwrap();
+        setup(&mut state);
+
+        let instance = runtime.instantiate(&module, state).await.unwrap();
+        instance.call(function, Vec::new()).await
+    }
+
+    // Registry with a lifecycle plugin `exit-{code}` for every exit reason code, whose
+    // `lunatic_on_process_exited` hook traps on that code. The plugin error log then
+    // records how each process exited, see `trapped_plugins`.
+    fn exit_watchers() -> lunatic_plugin::PluginRegistry {
+        use lunatic_plugin::{Capability, PluginInfo, PluginRegistry};
+
+        let mut registry = PluginRegistry::new();
+        for code in 0..=5 {
+            let wat = format!(
+                r#"
+                (module
+                    (func (export "lunatic_on_process_exited") (param i64 i32)
+                        (if (i32.eq (local.get 1) (i32.const {code}))
+                            (then unreachable)))
+                )
+                "#
+            );
+            let info = PluginInfo {
+                name: format!("exit-{code}"),
+                version: "0.1.0".parse().unwrap(),
+                capabilities: vec![Capability::LifecycleHooks],
+                dependencies: Vec::new(),
+            };
+            registry.register_wat(info, &wat).unwrap();
+        }
+        registry
+    }
+
+    // Names of the plugins whose lifecycle hooks trapped, sorted
+    fn trapped_plugins(registry: &lunatic_plugin::PluginRegistry) -> Vec<String> {
+        let mut names: Vec<_> = registry
+            .last_errors()
+            .into_iter()
+            .map(|error| error.plugin)
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Spawns `function` of `wat` as a process whose lifecycle events are dispatched to the
+    // plugins of `registry`, linked to `link` if set.
+    async fn spawn_guest(
+        config: crate::DefaultProcessConfig,
+        registry: &std::sync::Arc<lunatic_plugin::PluginRegistry>,
+        wat: &str,
+        function: &str,
+        link: Option<std::sync::Arc<dyn lunatic_process::Process>>,
+    ) -> (
+        tokio::task::JoinHandle<anyhow::Result<crate::state::DefaultProcessState>>,
+        std::sync::Arc<dyn lunatic_process::Process>,
+    ) {
+        use std::collections::HashMap;
+        use std::sync::Arc;
+        use tokio::sync::RwLock;
+
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::RawWasm;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use lunatic_process::wasm::spawn_wasm;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        let raw_module = wat::parse_str(wat).unwrap();
+        let module = Arc::new(
+            runtime
+                .compile_module::<DefaultProcessState>(RawWasm::new(None, raw_module))
+                .unwrap(),
+        );
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            Arc::new(RwLock::new(HashMap::new())),
+            registry.clone(),
+        )
+        .unwrap();
+        let link = link.map(|process| (None, process));
+        spawn_wasm(env, runtime, &module, state, function, Vec::new(), link)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn process_exited_event_carries_exit_reason() {
+        use lunatic_process::Signal;
+        use lunatic_process::config::ProcessConfig;
+        use std::sync::Arc;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (func (export "quick"))
+                (func (export "trap") unreachable)
+                (func (export "spin")
+                    (loop $spin (br $spin)))
+                (func (export "sleep")
+                    (loop $sleep
+                        (call $sleep_ms (i64.const 60000))
+                        (br $sleep)))
+            )
+        "#;
+        let cases = [
+            ("quick", None, "exit-0"),
+            ("trap", None, "exit-1"),
+            ("spin", Some(1), "exit-2"),
+        ];
+        for (function, max_fuel, plugin) in cases {
+            let registry = Arc::new(exit_watchers());
+            let mut config = crate::DefaultProcessConfig::default();
+            config.set_max_fuel(max_fuel);
+            let (join, _) = spawn_guest(config, &registry, wat, function, None).await;
+            join.await.unwrap().ok();
+            assert_eq!(trapped_plugins(&registry), [plugin], "{function}");
+        }
+
+        // Killing a process also ends the processes linked to it
+        let registry = Arc::new(exit_watchers());
+        let config = crate::DefaultProcessConfig::default;
+        let (parent_join, parent) = spawn_guest(config(), &registry, wat, "sleep", None).await;
+        let (child_join, _) =
+            spawn_guest(config(), &registry, wat, "sleep", Some(parent.clone())).await;
+        parent.send(Signal::Kill);
+        assert!(parent_join.await.unwrap().is_err());
+        assert!(child_join.await.unwrap().is_err());
+        assert_eq!(trapped_plugins(&registry), ["exit-3", "exit-4"]);
+    }
+
+    #[tokio::test]
+    async fn process_exited_event_carries_requested_reason() {
+        use lunatic_plugin::{Capability, PluginInfo};
+        use std::sync::Arc;
+
+        let reason = "config reload requested";
+        let wat = format!(
+            r#"
+            (module
+                (import "lunatic::process" "set_exit_reason" (func $set_exit_reason (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{reason}")
+                (func (export "reason")
+                    (call $set_exit_reason (i32.const 0) (i32.const {len})))
+                (func (export "reason_then_trap")
+                    (call $set_exit_reason (i32.const 0) (i32.const {len}))
+                    unreachable)
+                (func (export "too_long")
+                    (call $set_exit_reason (i32.const 0) (i32.const 1025)))
+            )
+            "#,
+            len = reason.len()
+        );
+        // Traps if the `error` label of the exit is the reason
+        let watcher = format!(
+            r#"
+            (module
+                (import "lunatic_plugin" "label_len" (func $label_len (param i32 i32) (result i32)))
+                (import "lunatic_plugin" "label_get" (func $label_get (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "error")
+                (data (i32.const 16) "{reason}")
+                (func (export "lunatic_on_process_exited") (param i64 i32)
+                    (local $i i32)
+                    (if (i32.ne (call $label_len (i32.const 0) (i32.const 5)) (i32.const {len}))
+                        (then return))
+                    (drop (call $label_get (i32.const 0) (i32.const 5) (i32.const 1024)))
+                    (loop $compare
+                        (if (i32.ne
+                                (i32.load8_u offset=16 (local.get $i))
+                                (i32.load8_u offset=1024 (local.get $i)))
+                            (then return))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $compare (i32.lt_u (local.get $i) (i32.const {len}))))
+                    unreachable)
+            )
+            "#,
+            len = reason.len()
+        );
+
+        let cases = [
+            ("reason", vec!["exit-0", "requested"]),
+            ("reason_then_trap", vec!["exit-1"]),
+            ("too_long", vec!["exit-1"]),
+        ];
+        for (function, plugins) in cases {
+            let mut registry = exit_watchers();
+            let info = PluginInfo {
+                name: "requested".into(),
+                version: "0.1.0".parse().unwrap(),
+                capabilities: vec![Capability::LifecycleHooks],
+                dependencies: Vec::new(),
+            };
+            registry.register_wat(info, &watcher).unwrap();
+            let registry = Arc::new(registry);
+            let config = crate::DefaultProcessConfig::default();
+            let (join, _) = spawn_guest(config, &registry, &wat, function, None).await;
+            let result = join.await.unwrap();
+            if function == "too_long" {
+                let failure = result.unwrap_err().to_string();
+                assert!(
+                    failure.contains("exceeds the limit of 1024 bytes"),
+                    "{failure}"
+                );
+            }
+            assert_eq!(trapped_plugins(&registry), plugins, "{function}");
+        }
+    }
+
+    #[tokio::test]
+    async fn process_exited_event_reports_wall_time_timeout() {
+        use lunatic_process::config::ProcessConfig;
+        use std::sync::Arc;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (func (export "sleep")
+                    (call $sleep_ms (i64.const 60000)))
+            )
+        "#;
+        let registry = Arc::new(exit_watchers());
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_max_wall_time(Some(std::time::Duration::from_millis(100)));
+        let (join, _) = spawn_guest(config, &registry, wat, "sleep", None).await;
+        let failure = join.await.unwrap().unwrap_err().to_string();
+        assert!(failure.contains("wall time"), "{failure}");
+        assert_eq!(trapped_plugins(&registry), ["exit-5"]);
+    }
+
+    #[tokio::test]
+    async fn guest_rotates_stdout_stream() {
+        use lunatic_stdout_capture::StdoutCapture;
+
+        // Traps unless both calls return new, distinct stream indices.
+        let wat = r#"
+            (module
+                (import "lunatic::wasi" "new_stdout_stream" (func $new (result i64)))
+                (func (export "run")
+                    (local $first i64)
+                    (local.set $first (call $new))
+                    (if (i64.eqz (local.get $first)) (then unreachable))
+                    (if (i64.eq (call $new) (local.get $first)) (then unreachable)))
+            )
+        "#;
+        let capture = StdoutCapture::new(false);
+        let failure = call_guest_with_stdout(
+            crate::DefaultProcessConfig::default(),
+            Some(capture.clone()),
+            wat,
+            "run",
+        )
+        .await;
+        assert_eq!(failure, None);
+        assert_eq!(capture.stream_count(), 3);
+
+        // Without a capture there is no stream to rotate
+        let failure = call_guest(crate::DefaultProcessConfig::default(), wat, "run").await;
+        assert!(failure.is_some());
+    }
+
+    #[tokio::test]
+    async fn guest_keeps_fds_across_stdout_streams() {
+        use lunatic_stdout_capture::StdoutCapture;
+
+        // Opens "kept.txt" in the preopened dir, rotates stdout and then writes "kept" to the
+        // file and "after" to stdout, trapping if either write fails.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open
+                        (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::wasi" "new_stdout_stream" (func $new (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\20\00\00\00\04\00\00\00")
+                (data (i32.const 8) "\30\00\00\00\05\00\00\00")
+                (data (i32.const 32) "kept")
+                (data (i32.const 48) "after")
+                (data (i32.const 64) "kept.txt")
+                (func (export "run")
+                    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 64) (i32.const 8)
+                            (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0)
+                            (i32.const 80))
+                        (then unreachable))
+                    (drop (call $new))
+                    (if (call $fd_write (i32.load (i32.const 80)) (i32.const 0) (i32.const 1)
+                            (i32.const 96))
+                        (then unreachable))
+                    (if (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 96))
+                        (then unreachable)))
+            )
+        "#;
+        let dir = std::env::temp_dir().join(format!("lunatic-kept-fds-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap()).unwrap();
+        let capture = StdoutCapture::new(false);
+        let failure = call_guest_with_stdout(config, Some(capture.clone()), wat, "run").await;
+        let kept = std::fs::read_to_string(dir.join("kept.txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(failure, None);
+        assert_eq!(kept.unwrap(), "kept");
+        assert_eq!(capture.content(), "");
+        assert_eq!(
+            capture.format_nonempty(),
+            " --- process 1 stdout ---\nafter"
+        );
+    }
+
+    #[tokio::test]
+    async fn guest_flushes_stdout() {
+        use lunatic_stdout_capture::StdoutCapture;
+        use std::io::{BufWriter, Write};
+        use std::sync::{Arc, Mutex};
+
+        // Echo target that only sees what the `BufWriter` around it flushes
+        struct Flushed(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Flushed {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Writes an unterminated "partial" through WASI and flushes it.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::wasi" "flush_stdout" (func $flush_stdout))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\10\00\00\00\07\00\00\00")
+                (data (i32.const 16) "partial")
+                (func (export "run")
+                    (if (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))
+                        (then unreachable))
+                    (call $flush_stdout))
+            )
+        "#;
+        let echoed = Arc::new(Mutex::new(Vec::new()));
+        let capture = StdoutCapture::new(false);
+        capture.echo_to_writer(BufWriter::new(Flushed(echoed.clone())));
+        let failure = call_guest_with_stdout(
+            crate::DefaultProcessConfig::default(),
+            Some(capture.clone()),
+            wat,
+            "run",
+        )
+        .await;
+        assert_eq!(failure, None);
+        assert_eq!(*echoed.lock().unwrap(), b"partial");
+        assert_eq!(capture.content(), "partial");
+
+        // Without a capture the host's stdout is flushed
+        let failure = call_guest(crate::DefaultProcessConfig::default(), wat, "run").await;
+        assert_eq!(failure, None);
+    }
+
+    #[tokio::test]
+    async fn guest_reads_stdout_len() {
+        use lunatic_stdout_capture::StdoutCapture;
+
+        // Writes "hello" through WASI and traps unless the host reports 5 captured bytes.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::wasi" "stdout_len" (func $stdout_len (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\10\00\00\00\05\00\00\00")
+                (data (i32.const 16) "hello")
+                (func (export "run")
+                    (if (i64.ne (call $stdout_len) (i64.const 0)) (then unreachable))
+                    (if (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))
+                        (then unreachable))
+                    (if (i64.ne (call $stdout_len) (i64.const 5)) (then unreachable)))
+            )
+        "#;
+        let capture = StdoutCapture::new(false);
+        let failure = call_guest_with_stdout(
+            crate::DefaultProcessConfig::default(),
+            Some(capture.clone()),
+            wat,
+            "run",
+        )
+        .await;
+        assert_eq!(failure, None);
+        assert_eq!(capture.content(), "hello");
+    }
+
+    #[tokio::test]
+    async fn guest_reads_stdout_snapshot() {
+        use lunatic_stdout_capture::StdoutCapture;
+
+        // Writes "hello world", copies the first 5 bytes of its stdout to offset 64 and traps
+        // unless they match and the stream still holds everything.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::wasi" "stdout_len" (func $stdout_len (result i64)))
+                (import "lunatic::wasi" "read_stdout_snapshot"
+                    (func $read_stdout_snapshot (param i32 i32) (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\10\00\00\00\0b\00\00\00")
+                (data (i32.const 16) "hello world")
+                (func (export "run")
+                    (if (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))
+                        (then unreachable))
+                    (if (i64.ne (call $read_stdout_snapshot (i32.const 64) (i32.const 5))
+                                (i64.const 5))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 64)) (i32.load (i32.const 16)))
+                        (then unreachable))
+                    (if (i32.ne (i32.load8_u (i32.const 68)) (i32.const 0x6f))
+                        (then unreachable))
+                    (if (i32.load8_u (i32.const 69)) (then unreachable))
+                    ;; A larger buffer gets the whole stream
+                    (if (i64.ne (call $read_stdout_snapshot (i32.const 64) (i32.const 100))
+                                (i64.const 11))
+                        (then unreachable))
+                    (if (i64.ne (call $stdout_len) (i64.const 11)) (then unreachable)))
+            )
+        "#;
+        let capture = StdoutCapture::new(false);
+        let failure = call_guest_with_stdout(
+            crate::DefaultProcessConfig::default(),
+            Some(capture.clone()),
+            wat,
+            "run",
+        )
+        .await;
+        assert_eq!(failure, None);
+        assert_eq!(capture.content(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn guest_counts_stdout_streams() {
+        use lunatic_stdout_capture::StdoutCapture;
+
+        // Expects the 3 streams created by the host, adds one and traps on a wrong count
+        let wat = r#"
+            (module
+                (import "lunatic::wasi" "new_stdout_stream" (func $new_stdout_stream (result i64)))
+                (import "lunatic::wasi" "stdout_stream_count"
+                    (func $stdout_stream_count (result i64)))
+                (func (export "run")
+                    (if (i64.ne (call $stdout_stream_count) (i64.const 3)) (then unreachable))
+                    (drop (call $new_stdout_stream))
+                    (if (i64.ne (call $stdout_stream_count) (i64.const 4)) (then unreachable)))
+            )
+        "#;
+        let capture = StdoutCapture::new(false);
+        let child = capture.next().next();
+        let failure = call_guest_with_stdout(
+            crate::DefaultProcessConfig::default(),
+            Some(child),
+            wat,
+            "run",
+        )
+        .await;
+        assert_eq!(failure, None);
+        assert_eq!(capture.stream_count(), 4);
+    }
+
+    #[tokio::test]
+    async fn send_hook_throttles_and_rejects() {
+        use lunatic_process::state::SendDecision;
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        // Sends an 8 byte message to process 1000 and traps unless `send` returns `expected`
+        let wat = r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (func $send_expecting (param $expected i32)
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 0) (i32.const 8)))
+                    (if (i32.ne (call $send (i64.const 1000)) (local.get $expected))
+                        (then unreachable)))
+                (func (export "sent") (call $send_expecting (i32.const 0)))
+                (func (export "rejected") (call $send_expecting (i32.const 1)))
+            )
+        "#;
+        let hook = |decision: SendDecision| {
+            move |state: &mut crate::state::DefaultProcessState| {
+                state.set_send_hook(Some(Arc::new(move |to, size| {
+                    assert_eq!((to, size), (1000, 8));
+                    decision
+                })));
+            }
+        };
+
+        let delay = Duration::from_millis(100);
+        let start = Instant::now();
+        let failure = call_guest_with_state(
+            crate::DefaultProcessConfig::default(),
+            hook(SendDecision::Throttle(delay)),
+            wat,
+            "sent",
+        )
+        .await;
+        assert_eq!(failure, None);
+        assert!(start.elapsed() >= delay, "{:?}", start.elapsed());
+
+        let failure = call_guest_with_state(
+            crate::DefaultProcessConfig::default(),
+            hook(SendDecision::Reject),
+            wat,
+            "rejected",
+        )
+        .await;
+        assert_eq!(failure, None);
+        assert_eq!(
+            call_guest(crate::DefaultProcessConfig::default(), wat, "sent").await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn message_resource_limits_reject_push() {
+        use lunatic_process::{config::ProcessConfig, message::ResourceLimits};
+
+        // Pushes both UDP sockets into one message
+        let wat = r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "push_udp_socket"
+                    (func $push_udp_socket (param i64) (result i64)))
+                (func (export "run")
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $push_udp_socket (i64.const 0)))
+                    (drop (call $push_udp_socket (i64.const 1))))
+            )
+        "#;
+        let add_sockets = |state: &mut crate::state::DefaultProcessState| {
+            for _ in 0..2 {
+                let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket.set_nonblocking(true).unwrap();
+                let socket = tokio::net::UdpSocket::from_std(socket).unwrap();
+                state.resources.udp_sockets.add(std::sync::Arc::new(socket));
+            }
+        };
+
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_message_resource_limits(ResourceLimits {
+            udp_sockets: Some(2),
+            ..Default::default()
+        });
+        let failure = call_guest_with_state(config.clone(), add_sockets, wat, "run").await;
+        assert_eq!(failure, None);
+
+        config.set_message_resource_limits(ResourceLimits {
+            udp_sockets: Some(1),
+            ..Default::default()
+        });
+        let failure = call_guest_with_state(config, add_sockets, wat, "run").await;
+        assert!(failure.is_some());
+    }
+
+    #[tokio::test]
+    async fn memory_usage_tracks_allowed_growth() {
+        use lunatic_process::{config::ProcessConfig, state::ProcessState};
+        use wasmtime::ResourceLimiter;
+
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_max_memory(2 * 65536);
+        let wat = r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "hello"))
+            )
+        "#;
+        let failure = call_guest_with_state(
+            config,
+            |state| {
+                assert_eq!(state.memory_usage(), 0);
+                assert!(state.memory_growing(0, 65536, None).unwrap());
+                assert_eq!(state.memory_usage(), 65536);
+                // Denied growth doesn't change the reported usage
+                assert!(!state.memory_growing(65536, 3 * 65536, None).unwrap());
+                assert_eq!(state.memory_usage(), 65536);
+            },
+            wat,
+            "hello",
+        )
+        .await;
+        assert_eq!(failure, None);
+    }
+
+    #[tokio::test]
+    async fn guest_exit_reason_reaches_exited_event() {
+        use lunatic_plugin::ExitReason;
+        use lunatic_process::ExitStatus;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "set_exit_reason"
+                    (func $set_exit_reason (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "config reload requested")
+                (func (export "run")
+                    (call $set_exit_reason (i32.const 0) (i32.const 23)))
+            )
+        "#;
+        let mut requested = None;
+        let failure = call_guest_with_state(
+            crate::DefaultProcessConfig::default(),
+            |state| requested = Some(state.exit_reason.clone()),
+            wat,
+            "run",
+        )
+        .await;
+        assert_eq!(failure, None);
+
+        // The lifecycle callback takes the reason from the same shared slot on exit
+        let requested = requested.unwrap().lock().unwrap().take();
+        let reason = super::exit_reason(Some(&ExitStatus::Normal), requested.clone());
+        assert_eq!(
+            reason,
+            ExitReason::Requested("config reload requested".into())
+        );
+        assert_eq!(reason.code(), 0);
+        assert_eq!(reason.error().as_deref(), Some("config reload requested"));
+        // A trap still wins over the reason the guest gave
+        assert_eq!(
+            super::exit_reason(Some(&ExitStatus::Trapped("boom".into())), requested),
+            ExitReason::Trapped("boom".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn spawn_respects_max_children() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // Traps unless the first spawn succeeds and the second one hits the limit.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep (param i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (func (export "child")
+                    (call $sleep (i64.const 60000)))
+                (func (export "run")
+                    (if (i32.ne
+                            (call $spawn (i64.const 0) (i64.const -1) (i64.const -1)
+                                (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0)
+                                (i32.const 16))
+                            (i32.const 0))
+                        (then unreachable))
+                    (if (i32.ne
+                            (call $spawn (i64.const 0) (i64.const -1) (i64.const -1)
+                                (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0)
+                                (i32.const 16))
+                            (i32.const 1))
+                        (then unreachable)))
+            )
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        config.set_max_children(Some(1));
+        assert_eq!(call_guest(config, wat, "run").await, None);
+    }
+
+    #[tokio::test]
+    async fn guest_lists_preopened_dirs() {
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir("src").unwrap();
+        config.preopen_dir("wat").unwrap();
+
+        // Traps unless the host reports exactly "src\nwat".
+        let wat = r#"
+            (module
+                (import "lunatic::wasi" "preopened_dirs_size" (func $size (result i32)))
+                (import "lunatic::wasi" "list_preopened_dirs" (func $list (param i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "src\nwat")
+                (func (export "check")
+                    (if (i32.ne (call $size) (i32.const 7)) (then unreachable))
+                    (call $list (i32.const 100))
+                    (if (i32.ne (i32.load (i32.const 0)) (i32.load (i32.const 100)))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 3)) (i32.load (i32.const 103)))
+                        (then unreachable))
+                )
+            )
+        "#;
+        assert_eq!(call_guest(config, wat, "check").await, None);
+    }
+
+    #[tokio::test]
+    async fn wasi_config_rejects_nul_bytes() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // "A\00B" at offset 0 has an interior NUL, "ok" at offset 16 is valid
+        let wat = r#"
+            (module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (import "lunatic::wasi" "config_add_environment_variable"
+                    (func $add_env (param i64 i32 i32 i32 i32)))
+                (import "lunatic::wasi" "config_add_command_line_argument"
+                    (func $add_arg (param i64 i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "A\00B")
+                (data (i32.const 16) "ok")
+                (func (export "env_key")
+                    (call $add_env (call $create_config)
+                        (i32.const 0) (i32.const 3) (i32.const 16) (i32.const 2)))
+                (func (export "env_value")
+                    (call $add_env (call $create_config)
+                        (i32.const 16) (i32.const 2) (i32.const 0) (i32.const 3)))
+                (func (export "arg")
+                    (call $add_arg (call $create_config) (i32.const 0) (i32.const 3)))
+                (func (export "valid")
+                    (call $add_env (call $create_config)
+                        (i32.const 16) (i32.const 2) (i32.const 16) (i32.const 2))
+                    (call $add_arg (call $create_config) (i32.const 16) (i32.const 2)))
+            )
+        "#;
+        let config = || {
+            let mut config = crate::DefaultProcessConfig::default();
+            config.set_can_create_configs(true);
+            config
+        };
+
+        for (function, what) in [
+            ("env_key", "key"),
+            ("env_value", "value"),
+            ("arg", "argument"),
+        ] {
+            let failure = call_guest(config(), wat, function)
+                .await
+                .unwrap_or_else(|| panic!("{function} should trap"));
+            assert!(
+                failure.contains(&format!("{what} contains a NUL byte")),
+                "{failure}"
+            );
+        }
+        assert_eq!(call_guest(config(), wat, "valid").await, None);
+    }
+
+    #[tokio::test]
+    async fn wasi_config_appends_command_line_arguments() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // Three length-prefixed arguments, 23 bytes in total
+        let wat = r#"
+            (module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (import "lunatic::wasi" "config_append_command_line_arguments"
+                    (func $append (param i64 i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\03\00\00\00one\03\00\00\00two\05\00\00\00three")
+                (func (export "valid")
+                    (call $append (call $create_config) (i32.const 0) (i32.const 23)))
+                (func (export "truncated")
+                    (call $append (call $create_config) (i32.const 0) (i32.const 22)))
+            )
+        "#;
+        let config = || {
+            let mut config = crate::DefaultProcessConfig::default();
+            config.set_can_create_configs(true);
+            config
+        };
+
+        assert_eq!(call_guest(config(), wat, "valid").await, None);
+        assert!(call_guest(config(), wat, "truncated").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn wasi_config_reports_command_line_arguments_len() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (import "lunatic::wasi" "config_add_command_line_argument"
+                    (func $add_arg (param i64 i32 i32)))
+                (import "lunatic::wasi" "config_command_line_arguments_len"
+                    (func $args_len (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "arg")
+                (func (export "count") (local $config i64)
+                    (local.set $config (call $create_config))
+                    (if (i32.ne (call $args_len (local.get $config)) (i32.const 0))
+                        (then unreachable))
+                    (call $add_arg (local.get $config) (i32.const 0) (i32.const 3))
+                    (call $add_arg (local.get $config) (i32.const 0) (i32.const 3))
+                    (if (i32.ne (call $args_len (local.get $config)) (i32.const 2))
+                        (then unreachable)))
+                (func (export "missing")
+                    (drop (call $args_len (i64.const 1000))))
+            )
+        "#;
+        let config = || {
+            let mut config = crate::DefaultProcessConfig::default();
+            config.set_can_create_configs(true);
+            config
+        };
+
+        assert_eq!(call_guest(config(), wat, "count").await, None);
+        assert!(call_guest(config(), wat, "missing").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn max_wall_time_stops_guest() {
+        use lunatic_plugin::ExitReason;
+        use lunatic_process::ExitStatus;
+        use lunatic_process::config::ProcessConfig;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (func (export "spin")
+                    (loop $spin (br $spin)))
+                (func (export "sleep")
+                    (call $sleep_ms (i64.const 60000)))
+                (func (export "quick"))
+            )
+        "#;
+        let config = || {
+            let mut config = crate::DefaultProcessConfig::default();
+            config.set_max_wall_time(Some(std::time::Duration::from_millis(100)));
+            config
+        };
+
+        for function in ["spin", "sleep"] {
+            let start = std::time::Instant::now();
+            let failure = call_guest(config(), wat, function).await.unwrap();
+            assert!(failure.contains("wall time"), "{failure}");
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        }
+        assert_eq!(call_guest(config(), wat, "quick").await, None);
+        assert_eq!(
+            super::exit_reason(Some(&ExitStatus::TimedOut), None),
+            ExitReason::Timeout
+        );
+    }
+
+    #[tokio::test]
+    async fn wasi_disabled_traps_fd_operations() {
+        use lunatic_process::config::ProcessConfig;
+
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_fdstat_get"
+                    (func $fd_fdstat_get (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (if (i32.ne (call $fd_fdstat_get (i32.const 1) (i32.const 0)) (i32.const 0))
+                        (then unreachable))
+                )
+                (func (export "compute"))
+            )
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        assert_eq!(call_guest(config.clone(), wat, "hello").await, None);
+
+        config.set_wasi_disabled(true);
+        assert!(call_guest(config.clone(), wat, "hello").await.is_some());
+        assert_eq!(call_guest(config, wat, "compute").await, None);
+    }
+
+    #[tokio::test]
+    async fn wasi_disabled_is_inherited_by_new_configs() {
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process::state::ProcessState;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (func (export "create")
+                    (if (i64.ne (call $create_config) (i64.const 0))
+                        (then unreachable)))
+            )
+        "#;
+        for wasi_disabled in [false, true] {
+            let mut config = crate::DefaultProcessConfig::default();
+            config.set_can_create_configs(true);
+            config.set_wasi_disabled(wasi_disabled);
+            let result = run_guest(config, |_| {}, wat, "create").await;
+            assert_eq!(result.failure(), None);
+            let created = result.state().config_resources().get(0).unwrap();
+            assert_eq!(created.get_wasi_disabled(), wasi_disabled);
+        }
+    }
+
+    #[tokio::test]
+    async fn open_resources_counts_network_handles() {
+        use lunatic_process::state::ProcessState;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "open_resources" (func $open_resources (result i64)))
+                (func (export "hello")
+                    (if (i64.ne (call $open_resources) (i64.const 1))
+                        (then unreachable))
+                )
+            )
+        "#;
+        let failure = call_guest_with_state(
+            crate::DefaultProcessConfig::default(),
+            |state| {
+                assert_eq!(state.open_resource_count(), 0);
+                let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket.set_nonblocking(true).unwrap();
+                let socket = tokio::net::UdpSocket::from_std(socket).unwrap();
+                state.resources.udp_sockets.add(std::sync::Arc::new(socket));
+                assert_eq!(state.open_resource_count(), 1);
+            },
+            wat,
+            "hello",
+        )
+        .await;
+        assert_eq!(failure, None);
+    }
+
+    #[tokio::test]
+    async fn guest_reads_mailbox_depth_and_bytes() {
+        use lunatic_process::message::{DataMessage, Message};
+        use lunatic_process::state::ProcessState;
+
+        let wat = r#"
+            (module
+                (import "lunatic::message" "mailbox_depth" (func $depth (result i64)))
+                (import "lunatic::message" "mailbox_bytes" (func $bytes (result i64)))
+                (func (export "hello")
+                    (if (i64.ne (call $depth) (i64.const 3))
+                        (then unreachable))
+                    (if (i64.ne (call $bytes) (i64.const 12))
+                        (then unreachable))
+                )
+            )
+        "#;
+        let failure = call_guest_with_state(
+            crate::DefaultProcessConfig::default(),
+            |state| {
+                let mailbox = state.message_mailbox();
+                for size in [4, 8] {
+                    mailbox.push(Message::Data(DataMessage::new_from_vec(
+                        None,
+                        vec![0; size],
+                    )));
+                }
+                mailbox.push(Message::LinkDied(None));
+            },
+            wat,
+            "hello",
+        )
+        .await;
+        assert_eq!(failure, None);
+    }
 }
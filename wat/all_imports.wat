@@ -18,6 +18,8 @@
     (import "lunatic::message" "send" (func (param i64) (result i32)))
     (import "lunatic::message" "send_receive_skip_search" (func (param i64 i64 i64) (result i32)))
     (import "lunatic::message" "receive" (func (param i32 i32 i64) (result i32)))
+    (import "lunatic::message" "mailbox_depth" (func (result i64)))
+    (import "lunatic::message" "mailbox_bytes" (func (result i64)))
 
     (import "lunatic::timer" "send_after" (func (param i64 i64) (result i64)))
     (import "lunatic::timer" "cancel_timer" (func (param i64) (result i32)))
@@ -83,16 +85,20 @@
     (import "lunatic::process" "config_set_can_compile_modules" (func (param i64 i32)))
     (import "lunatic::process" "config_can_create_configs" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_create_configs" (func (param i64 i32)))
+    (import "lunatic::process" "config_set_max_children" (func (param i64 i32)))
+    (import "lunatic::process" "config_get_max_children" (func (param i64) (result i32)))
     (import "lunatic::process" "config_can_spawn_processes" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_spawn_processes" (func (param i64 i32)))
     (import "lunatic::process" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::process" "sleep_ms" (func (param i64)))
     (import "lunatic::process" "die_when_link_dies" (func (param i32)))
     (import "lunatic::process" "process_id" (func (result i64)))
+    (import "lunatic::process" "open_resources" (func (result i64)))
     (import "lunatic::process" "link" (func (param i64 i64)))
     (import "lunatic::process" "unlink" (func (param i64)))
     (import "lunatic::process" "kill" (func (param i64)))
     (import "lunatic::process" "exists" (func (param i64) (result i32)))
+    (import "lunatic::process" "set_exit_reason" (func (param i32 i32)))
 
     (import "lunatic::version" "major" (func (result i32)))
     (import "lunatic::version" "minor" (func (result i32)))
@@ -100,7 +106,16 @@
 
     (import "lunatic::wasi" "config_add_environment_variable" (func (param i64 i32 i32 i32 i32)))
     (import "lunatic::wasi" "config_add_command_line_argument" (func (param i64 i32 i32)))
+    (import "lunatic::wasi" "config_append_command_line_arguments" (func (param i64 i32 i32)))
+    (import "lunatic::wasi" "config_command_line_arguments_len" (func (param i64) (result i32)))
     (import "lunatic::wasi" "config_preopen_dir" (func (param i64 i32 i32)))
+    (import "lunatic::wasi" "preopened_dirs_size" (func (result i32)))
+    (import "lunatic::wasi" "list_preopened_dirs" (func (param i32)))
+    (import "lunatic::wasi" "flush_stdout" (func))
+    (import "lunatic::wasi" "new_stdout_stream" (func (result i64)))
+    (import "lunatic::wasi" "stdout_len" (func (result i64)))
+    (import "lunatic::wasi" "stdout_stream_count" (func (result i64)))
+    (import "lunatic::wasi" "read_stdout_snapshot" (func (param i32 i32) (result i64)))
 
     (import "lunatic::registry" "put" (func (param i32 i32 i64 i64)))
     (import "lunatic::registry" "get" (func (param i32 i32 i32 i32) (result i32)))